@@ -1,11 +1,261 @@
 #![no_std]
 
+// Capacity of the SERVICE_LIST map shared by the eBPF program and the agent
+pub const SERVICE_LIST_MAX_ENTRIES: u32 = 1024;
+
+// Version of the layout of the pinned maps, bumped whenever a key, value, flag or event of them
+// changes.
+// The maps of each version are pinned apart, an agent migrates the ones of older versions
+pub const MAP_SCHEMA_VERSION: u32 = 3;
+
+// Version of the structs the eBPF program shares with the agent (PacketLog, CaptureHeader,
+// WakeThreshold, WakeAttempts, ServicePolicy), bumped whenever one of them changes
+pub const ABI_VERSION: u32 = 3;
+
+// Symbol of the ABI in the eBPF object, read by the agent before loading it
+pub const ABI_SYMBOL: &str = "SCALE_TO_ZERO_ABI";
+pub const ABI_LEN: usize = 6;
+
+// ABI_VERSION followed by the sizes of the shared structs, as compiled into each side. A stale
+// eBPF object with the same version still differs in the sizes
+pub const fn abi() -> [u32; ABI_LEN] {
+    [
+        ABI_VERSION,
+        core::mem::size_of::<PacketLog>() as u32,
+        core::mem::size_of::<CaptureHeader>() as u32,
+        core::mem::size_of::<WakeThreshold>() as u32,
+        core::mem::size_of::<WakeAttempts>() as u32,
+        core::mem::size_of::<ServicePolicy>() as u32,
+    ]
+}
+
+// Size of the XDP_PROGRAMS and TC_PROGRAMS arrays the dispatchers tail call into, and the index of
+// each protocol program. A protocol without a program passes
+pub const DATAPATH_PROGRAMS: u32 = 8;
+pub const PROGRAM_IPV4: u32 = 0;
+pub const PROGRAM_IPV6: u32 = 1;
+
+// Indexes of AGENT_TRAFFIC: the PID of the agent as seen from the host, the source ports of its own
+// connections (first port << 16 | last port), and the pod port of the mesh sidecar metrics the
+// agents scrape through the API server. 0 disables any of them
+pub const AGENT_TGID: u32 = 0;
+pub const AGENT_SOURCE_PORTS: u32 = 1;
+pub const AGENT_SCRAPE_PORT: u32 = 2;
+
+// Source of the packets crafted by the self-test, from the benchmarking range (RFC 2544). They get
+// the verdict and events of any packet, but leave no state behind: no gated client, wake attempt,
+// drop count or held packet
+pub const SELFTEST_SOURCE: u32 = u32::from_be_bytes([198, 18, 0, 1]);
+
+// Flags of ServicePolicy
+// The backends of the service are available, packets pass
+pub const SERVICE_AVAILABLE: u32 = 1;
+// Gated packets are redirected to the agent's AF_XDP socket and reinjected after the wake
+pub const SERVICE_HOLD: u32 = 1 << 1;
+// Gated UDP datagrams are held like with SERVICE_HOLD, UDP clients don't retransmit
+pub const SERVICE_HOLD_UDP: u32 = 1 << 2;
+
+// Protocols of ServicePolicy. Packets of the other ones are neither activity nor wake the service,
+// they are still gated
+pub const PROTOCOL_TCP: u32 = 1;
+pub const PROTOCOL_UDP: u32 = 1 << 1;
+pub const PROTOCOL_ICMP: u32 = 1 << 2;
+pub const PROTOCOL_OTHER: u32 = 1 << 3;
+pub const PROTOCOL_ALL: u32 = PROTOCOL_TCP | PROTOCOL_UDP | PROTOCOL_ICMP | PROTOCOL_OTHER;
+
+// Gated actions of ServicePolicy, what happens to the packets of a scaled down service
+// Dropped, or held with SERVICE_HOLD
+pub const GATED_DROP: u32 = 0;
+// Passed, the service is woken but traffic is not enforced (observe-only)
+pub const GATED_PASS: u32 = 1;
+// Connects and TCP/UDP packets go to the GATE_REDIRECTS address of the service instead
+pub const GATED_REDIRECT: u32 = 2;
+
+// Port ranges of a ServicePolicy
+pub const SERVICE_POLICY_PORT_RANGES: usize = 8;
+
+// Bytes of a ClientHello packet copied to the agent, a whole frame at the usual MTU. A server name
+// beyond them (e.g. in a ClientHello spanning several segments) can't be read
+pub const CLIENT_HELLO_SNAPLEN: u32 = 1514;
+
+// Words of the DESTINATION_FILTER bloom filter, 2^19 bits. With the SERVICE_LIST, POD_TO_SERVICE and
+// OBSERVED_SERVICES maps full, about 0.6% of the other destinations still need a lookup
+pub const DESTINATION_FILTER_WORDS: u32 = 8192;
+
+// The two bits of an IPv4 address in DESTINATION_FILTER, from two multiplicative hashes
+#[inline(always)]
+pub fn destination_filter_bits(address: u32) -> [u32; 2] {
+    [
+        address.wrapping_mul(0x9e37_79b1) >> 13,
+        (address ^ (address >> 15)).wrapping_mul(0x85eb_ca6b) >> 13,
+    ]
+}
+
+// Value of GATE_REDIRECTS, the address in the high half and the port (0 keeps the one connected
+// to) in the low bits, both in host byte order
+#[inline(always)]
+pub fn gate_redirect(address: u32, port: u16) -> u64 {
+    (address as u64) << 32 | port as u64
+}
+
+// Same for a target that only speaks plain HTTP, like the waking page: only the TCP packets to
+// the HTTP port of the service (bits 16-31) are redirected, the others are dropped
+#[inline(always)]
+pub fn gate_redirect_http(address: u32, port: u16, http_port: u16) -> u64 {
+    gate_redirect(address, port) | (http_port as u64) << 16
+}
+
+// Whether a packet or connect of the IP protocol to the service port goes to a GATE_REDIRECTS
+// target
+#[inline(always)]
+pub fn is_redirected(target: u64, protocol: u8, port: Option<u16>) -> bool {
+    match (target >> 16) as u16 {
+        0 => true,
+        http_port => protocol == 6 && port == Some(http_port),
+    }
+}
+
+// Key of REDIRECTED_FLOWS, a client of a redirected service by its address, port and IP protocol
+#[inline(always)]
+pub fn redirect_flow_key(client: u32, client_port: u16, protocol: u8) -> u64 {
+    (client as u64) << 32 | (client_port as u64) << 16 | protocol as u64
+}
+
+// Key of HOST_PORTS, a node address with a host port and IP protocol of a backend pod
+#[inline(always)]
+pub fn host_port_key(address: u32, port: u16, protocol: u8) -> u64 {
+    (address as u64) << 32 | (port as u64) << 16 | protocol as u64
+}
+
+// Key of GATED_CLIENTS, the service in the high half and the client in the low one
+#[inline(always)]
+pub fn gated_client_key(service: u32, client: u32) -> u64 {
+    (service as u64) << 32 | client as u64
+}
+
+// Index of the DROPPED_PACKETS counters by protocol
+pub const DROPPED_TCP: u32 = 0;
+pub const DROPPED_UDP: u32 = 1;
+pub const DROPPED_ICMP: u32 = 2;
+pub const DROPPED_OTHER: u32 = 3;
+pub const DROPPED_PROTOCOLS: u32 = 4;
+
+// DROPPED_PACKETS index of an IP protocol number
+#[inline(always)]
+pub fn dropped_index(protocol: u8) -> u32 {
+    match protocol {
+        6 => DROPPED_TCP,
+        17 => DROPPED_UDP,
+        1 => DROPPED_ICMP,
+        _ => DROPPED_OTHER,
+    }
+}
+
+// PROTOCOL_* bit of an IP protocol number
+#[inline(always)]
+pub fn protocol_bit(protocol: u8) -> u32 {
+    match protocol {
+        6 => PROTOCOL_TCP,
+        17 => PROTOCOL_UDP,
+        1 => PROTOCOL_ICMP,
+        _ => PROTOCOL_OTHER,
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct PacketLog {
     pub ipv4_address: u32,
     pub action: i32,
+    // the client that sent the packet, 0 when it is unknown
+    pub source_address: u32,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for PacketLog {}
+
+// Header of a packet captured by the debug capture or of a ClientHello sent to an SNI entrypoint,
+// followed by `captured_len` bytes of the packet
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CaptureHeader {
+    pub ipv4_address: u32,
+    pub packet_len: u32,
+    pub captured_len: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for CaptureHeader {}
+
+// Wake threshold of a service: a wake is only requested once `packets` gated packets arrived
+// within `window_ms`. Services without an entry wake on the first packet
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "user", derive(Debug))]
+pub struct WakeThreshold {
+    pub packets: u32,
+    pub window_ms: u32,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for WakeThreshold {}
+
+// Destination ports from `start` to `end`, both included
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "user", derive(Debug))]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+// Value of SERVICE_LIST, everything the datapath does with the packets of a service. A new per
+// service setting of the datapath is a field here rather than another map
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "user", derive(Debug))]
+pub struct ServicePolicy {
+    // SERVICE_* flags
+    pub flags: u32,
+    // PROTOCOL_* of the packets that are activity and wake the service
+    pub protocols: u32,
+    // GATED_* action on the packets while the service is scaled down
+    pub gated_action: u32,
+    // Used entries of `ports`. Without any, packets to every port are activity
+    pub port_ranges: u32,
+    // Destination ports of the TCP and UDP packets that are activity and wake the service
+    pub ports: [PortRange; SERVICE_POLICY_PORT_RANGES],
+    // Wakes on the first gated packet with `packets` of 0 or 1
+    pub wake_threshold: WakeThreshold,
+}
+
+impl ServicePolicy {
+    // Whether a packet of the IP protocol is activity of the service. The port is the destination
+    // port of a TCP or UDP packet, packets without one only go by their protocol
+    #[inline(always)]
+    pub fn counts(&self, protocol: u8, port: Option<u16>) -> bool {
+        if self.protocols & protocol_bit(protocol) == 0 {
+            return false;
+        }
+        let port = match port {
+            Some(port) if self.port_ranges != 0 => port,
+            _ => return true,
+        };
+        self.ports
+            .iter()
+            .take(self.port_ranges as usize)
+            .any(|range| range.start <= port && port <= range.end)
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ServicePolicy {}
+
+// Gated packets of a service counted in the current window
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct WakeAttempts {
+    pub window_start_ns: u64,
+    pub packets: u32,
+    pub _pad: u32,
+}
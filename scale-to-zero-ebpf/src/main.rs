@@ -3,12 +3,25 @@
 #![allow(nonstandard_style, dead_code)]
 
 use aya_bpf::{
-    bindings::xdp_action,
-    macros::{map, xdp},
-    maps::{HashMap, PerfEventArray},
-    programs::XdpContext,
+    bindings::{xdp_action, BPF_F_MARK_MANGLED_0, BPF_F_PSEUDO_HDR, TC_ACT_SHOT, TC_ACT_UNSPEC},
+    helpers::{bpf_get_current_pid_tgid, bpf_get_smp_processor_id, bpf_ktime_get_ns},
+    macros::{cgroup_sock_addr, classifier, map, xdp},
+    maps::{
+        Array, HashMap, LruHashMap, LruPerCpuHashMap, PerCpuArray, PerfEventArray, ProgramArray,
+        XskMap,
+    },
+    programs::{SockAddrContext, TcContext, XdpContext},
+    BpfContext,
+};
+use aya_log_ebpf::debug;
+use scale_to_zero_common::{
+    abi, destination_filter_bits, dropped_index, gate_redirect, gated_client_key, host_port_key,
+    is_redirected, redirect_flow_key, CaptureHeader, PacketLog, ServicePolicy, WakeAttempts,
+    WakeThreshold, ABI_LEN, AGENT_SCRAPE_PORT, AGENT_SOURCE_PORTS, AGENT_TGID,
+    CLIENT_HELLO_SNAPLEN, DATAPATH_PROGRAMS, DESTINATION_FILTER_WORDS, DROPPED_PROTOCOLS,
+    GATED_PASS, GATED_REDIRECT, PROGRAM_IPV4, PROGRAM_IPV6, SELFTEST_SOURCE, SERVICE_AVAILABLE,
+    SERVICE_HOLD, SERVICE_HOLD_UDP, SERVICE_LIST_MAX_ENTRIES,
 };
-use scale_to_zero_common::PacketLog;
 
 use core::mem;
 use network_types::{
@@ -16,29 +29,330 @@ use network_types::{
     ip::Ipv4Hdr,
 };
 
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+// TLS record type of handshakes, and handshake type of a ClientHello
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     unsafe { core::hint::unreachable_unchecked() }
 }
 
+// Checked by the agent against its own before the object is loaded
+#[no_mangle]
+#[used]
+static SCALE_TO_ZERO_ABI: [u32; ABI_LEN] = abi();
+
+// The state maps are pinned, so an upgraded agent picks them up with their content
+
+// MAP_SCHEMA_VERSION of the pinned maps, written by the agent
+#[map]
+static MAP_SCHEMA: Array<u32> = Array::pinned(1, 0);
+
+#[map]
+static SCALE_REQUESTS: PerfEventArray<PacketLog> = PerfEventArray::pinned(1024, 0);
+
+// Events of services that need a wake (action 1), apart so a flood of activity can't delay them
+#[map]
+static WAKE_REQUESTS: PerfEventArray<PacketLog> = PerfEventArray::pinned(1024, 0);
+
+#[map]
+static SERVICE_LIST: HashMap<u32, ServicePolicy> =
+    HashMap::<u32, ServicePolicy>::pinned(SERVICE_LIST_MAX_ENTRIES, 0);
+
+// ClusterIPs of services that are not gated, value is the last time (ns since boot) a packet was seen
+#[map]
+static OBSERVED_SERVICES: HashMap<u32, u64> = HashMap::<u32, u64>::pinned(4096, 0);
+
+// Backend pod IPs of watched services, value is the ClusterIP of the service
+#[map]
+static POD_TO_SERVICE: HashMap<u32, u32> = HashMap::<u32, u32>::pinned(16384, 0);
+
+// AF_XDP sockets of the agent by RX queue, gated packets of held services are redirected there
+#[map]
+static HELD_PACKETS: XskMap = XskMap::pinned(64, 0);
+
+// ifindex of the interface the HELD_PACKETS sockets are bound to, written by the agent. Queue ids
+// are only unique per interface, packets received on any other one are dropped
+#[map]
+static HOLD_INTERFACE: Array<u32> = Array::pinned(1, 0);
+
+// Addresses announced for LoadBalancer services by a bare-metal load balancer (MetalLB, kube-vip),
+// value is the ClusterIP of the service. Their packets are gated like the ones of the ClusterIP
+#[map]
+static LOAD_BALANCER_IPS: HashMap<u32, u32> =
+    HashMap::<u32, u32>::with_max_entries(SERVICE_LIST_MAX_ENTRIES, 0);
+
+// IPv6 addresses of dual-stack services (ClusterIPs and load balancer addresses), value is the IPv4
+// ClusterIP that identifies the service in the other maps and in the events
+#[map]
+static IPV6_SERVICES: HashMap<[u8; 16], u32> =
+    HashMap::<[u8; 16], u32>::with_max_entries(SERVICE_LIST_MAX_ENTRIES, 0);
+
+// Node addresses and ports of backend pods with a hostPort or on the host network, by
+// host_port_key, value is the ClusterIP of the service
+#[map]
+static HOST_PORTS: HashMap<u64, u32> =
+    HashMap::<u64, u32>::with_max_entries(SERVICE_LIST_MAX_ENTRIES, 0);
+
+// Services with a running debug capture, value is the snapshot length
+#[map]
+static CAPTURE_LIST: HashMap<u32, u32> = HashMap::<u32, u32>::pinned(64, 0);
+
+// Gated packets dropped, by protocol (DROPPED_* index)
+#[map]
+static DROPPED_PACKETS: PerCpuArray<u64> = PerCpuArray::with_max_entries(DROPPED_PROTOCOLS, 0);
+
+#[map]
+static CAPTURED_PACKETS: PerfEventArray<CaptureHeader> = PerfEventArray::pinned(1024, 0);
+
+// Gated packets per service counted against its threshold, entries of idle services get evicted
 #[map]
-static SCALE_REQUESTS: PerfEventArray<PacketLog> = PerfEventArray::with_max_entries(1024, 0);
+static WAKE_ATTEMPTS: LruHashMap<u32, WakeAttempts> =
+    LruHashMap::<u32, WakeAttempts>::with_max_entries(SERVICE_LIST_MAX_ENTRIES, 0);
 
+// Clients sending packets to scaled down services by gated_client_key, value is their gated packets.
+// The agent reads and removes the entries of a service when it wakes, to size the wake
 #[map]
-static SERVICE_LIST: HashMap<u32, u32> = HashMap::<u32, u32>::with_max_entries(1024, 0);
+static GATED_CLIENTS: LruHashMap<u64, u64> = LruHashMap::<u64, u64>::with_max_entries(16384, 0);
 
+// Address (gate_redirect) the connects to each gated service with GATED_REDIRECT go to, e.g.
+// a placeholder answering while the service wakes
+#[map]
+static GATE_REDIRECTS: HashMap<u32, u64> =
+    HashMap::<u32, u64>::with_max_entries(SERVICE_LIST_MAX_ENTRIES, 0);
+
+// Clients whose packets to a gated service were sent to its redirect address, by redirect_flow_key,
+// value is gate_redirect of the service and the port they connected to. The TC egress program
+// gives the replies of the redirect address back the address of the service
+#[map]
+static REDIRECTED_FLOWS: LruHashMap<u64, u64> = LruHashMap::<u64, u64>::with_max_entries(16384, 0);
+
+// Set by the agent when ClusterIPs don't show up on the interfaces (eBPF kube-proxy replacement),
+// the connect hook then reports the connects to every watched service. Otherwise the interfaces
+// see them and the hook only redirects
+#[map]
+static CONNECT_REPORTS: Array<u32> = Array::with_max_entries(1, 0);
+
+// The agent's own traffic, its probes, the connections it makes to the services it wakes and its
+// scrapes of their sidecars, is never activity: it would keep them awake or wake them again.
+// Written by the agent at start
+#[map]
+static AGENT_TRAFFIC: Array<u32> = Array::with_max_entries(3, 0);
+
+// Packets dropped per service after they asked for its wake, until the gate opens. The agent reads
+// and removes the entry of a service when its gate opens
+#[map]
+static WAKE_DROPS: LruPerCpuHashMap<u32, u64> =
+    LruPerCpuHashMap::<u32, u64>::pinned(SERVICE_LIST_MAX_ENTRIES, 0);
+
+// Bloom filter of the destinations with an entry in SERVICE_LIST, POD_TO_SERVICE, OBSERVED_SERVICES
+// or SNI_ENTRYPOINTS, so most packets pass after two array loads instead of several hash lookups.
+// The kernel bloom filter map (5.16+) would fail the whole object on older kernels, and can neither
+// delete nor be cleared. The last word is set by the agent once the filter is complete, until then
+// every packet takes the lookups
+#[map]
+static DESTINATION_FILTER: Array<u64> = Array::with_max_entries(DESTINATION_FILTER_WORDS + 1, 0);
+
+// IPs shared by several services (e.g. the load balancer of an ingress controller), the server name
+// of the TLS connections to them tells which service is woken
+#[map]
+static SNI_ENTRYPOINTS: HashMap<u32, u32> = HashMap::<u32, u32>::with_max_entries(64, 0);
+
+#[map]
+static CLIENT_HELLOS: PerfEventArray<CaptureHeader> = PerfEventArray::with_max_entries(1024, 0);
+
+// Protocol programs by PROGRAM_* index, filled by the agent before the dispatchers are attached.
+// A program array only takes programs of one type, hence one per hook. They are pinned, the kernel
+// empties a program array once nothing but programs refer to it, e.g. while no agent runs
+#[map]
+static XDP_PROGRAMS: ProgramArray = ProgramArray::pinned(DATAPATH_PROGRAMS, 0);
+
+#[map]
+static TC_PROGRAMS: ProgramArray = ProgramArray::pinned(DATAPATH_PROGRAMS, 0);
+
+// Dispatcher attached to the interfaces, the program of the protocol of the packet takes over.
+// Each protocol program goes through the verifier on its own
 #[xdp]
 pub fn xdp_scale_to_zero_fw(ctx: XdpContext) -> u32 {
+    if let Ok(index) = protocol_program(ctx.data(), ctx.data_end()) {
+        // only returns when there is no program for the protocol
+        let _ = unsafe { XDP_PROGRAMS.tail_call(&ctx, index) };
+    }
+    xdp_action::XDP_PASS
+}
+
+#[xdp]
+pub fn xdp_scale_to_zero_ipv4(ctx: XdpContext) -> u32 {
     match try_xdp_scale_to_zero_fw(ctx) {
         Ok(ret) => ret,
         Err(_) => xdp_action::XDP_ABORTED,
     }
 }
 
+#[xdp]
+pub fn xdp_scale_to_zero_ipv6(ctx: XdpContext) -> u32 {
+    let hook = Hook {
+        program: "xdp_scale_to_zero_ipv6",
+        ifindex: unsafe { (*ctx.ctx).ingress_ifindex },
+    };
+    match try_scale_to_zero_ipv6(&ctx, hook, ctx.data(), ctx.data_end()) {
+        Ok(Verdict::Drop) => xdp_action::XDP_DROP,
+        Ok(_) => xdp_action::XDP_PASS,
+        Err(_) => xdp_action::XDP_ABORTED,
+    }
+}
+
+// Same as the XDP dispatcher, attached to the TC ingress hook of interfaces where another XDP
+// program is attached. TC_ACT_UNSPEC hands passed packets to the next filter so other programs
+// still run
+#[classifier]
+pub fn tc_scale_to_zero_fw(ctx: TcContext) -> i32 {
+    if let Ok(index) = protocol_program(ctx.data(), ctx.data_end()) {
+        let _ = unsafe { TC_PROGRAMS.tail_call(&ctx, index) };
+    }
+    TC_ACT_UNSPEC
+}
+
+#[classifier]
+pub fn tc_scale_to_zero_ipv4(mut ctx: TcContext) -> i32 {
+    let hook = Hook {
+        program: "tc_scale_to_zero_ipv4",
+        ifindex: unsafe { (*ctx.skb.skb).ifindex },
+    };
+    match try_scale_to_zero_fw(&ctx, hook, ctx.data(), ctx.data_end()) {
+        Ok(Verdict::Drop) => TC_ACT_SHOT as i32,
+        Ok(Verdict::Redirect { service, target }) => {
+            match redirect_skb(&mut ctx, service, target) {
+                Ok(_) => TC_ACT_UNSPEC,
+                Err(_) => TC_ACT_SHOT as i32,
+            }
+        }
+        // nothing to redirect to from TC
+        Ok(Verdict::Hold { protocol, service }) => {
+            count_dropped(protocol);
+            count_wake_drop(service);
+            TC_ACT_SHOT as i32
+        }
+        Ok(Verdict::Pass) | Err(_) => TC_ACT_UNSPEC,
+    }
+}
+
+#[classifier]
+pub fn tc_scale_to_zero_ipv6(ctx: TcContext) -> i32 {
+    let hook = Hook {
+        program: "tc_scale_to_zero_ipv6",
+        ifindex: unsafe { (*ctx.skb.skb).ifindex },
+    };
+    match try_scale_to_zero_ipv6(&ctx, hook, ctx.data(), ctx.data_end()) {
+        Ok(Verdict::Drop) => TC_ACT_SHOT as i32,
+        Ok(_) | Err(_) => TC_ACT_UNSPEC,
+    }
+}
+
+// Attached to the TC egress hook of the interfaces, rewrites the replies of redirect addresses to
+// redirected clients so they come from the service the clients sent to
+#[classifier]
+pub fn tc_scale_to_zero_egress(mut ctx: TcContext) -> i32 {
+    let _ = try_unredirect_skb(&mut ctx);
+    TC_ACT_UNSPEC
+}
+
+// With an eBPF service load balancer the ClusterIP is replaced when the socket connects and never
+// shows up on an interface, so connects to gated services are reported from the cgroup hook.
+// The connect is always allowed, like the first packets dropped at ingress it fails until a
+// backend is up. A connect comes from a process of the node, not a scanner, so it wakes the service
+// without going through the wake threshold, which only counts the packets of the ingress hooks
+#[cgroup_sock_addr(connect4)]
+pub fn connect4_scale_to_zero(ctx: SockAddrContext) -> i32 {
+    let dst = u32::from_be(unsafe { (*ctx.sock_addr).user_ip4 });
+    let protocol = unsafe { (*ctx.sock_addr).protocol } as u8;
+    if !may_be_tracked(dst) || is_agent_process() {
+        return 1;
+    }
+    // in network byte order in the low 16 bits
+    let port = u16::from_be(unsafe { (*ctx.sock_addr).user_port } as u16);
+    let service = service_address(dst);
+    let policy = is_scalable_dst(service);
+    if let Some(policy) = policy.as_ref() {
+        if policy.flags & SERVICE_AVAILABLE == 0
+            && policy.gated_action == GATED_REDIRECT
+            && redirect_connect(&ctx, service, protocol, port)
+        {
+            if policy.counts(protocol, Some(port)) {
+                report(&ctx, service, 1, 0);
+            }
+            return 1;
+        }
+    }
+    if !matches!(CONNECT_REPORTS.get(0), Some(reports) if *reports != 0) {
+        return 1;
+    }
+    match policy {
+        Some(policy) if !policy.counts(protocol, Some(port)) => {}
+        Some(policy) => {
+            let action = if policy.flags & SERVICE_AVAILABLE == 0 {
+                1
+            } else {
+                0
+            };
+            // the socket isn't bound yet, its source address is unknown
+            report(&ctx, service, action, 0);
+        }
+        None => {
+            observe_dst(dst);
+            // a pod of the node connecting to the node address never reaches an interface
+            if let Some((service, policy)) = host_port_service(dst, port, protocol) {
+                let action = if policy.flags & SERVICE_AVAILABLE == 0 {
+                    1
+                } else {
+                    0
+                };
+                report(&ctx, service, action, 0);
+            }
+        }
+    }
+    1
+}
+
+// Send the connect to the redirect address of the service, kube-proxy or the eBPF load balancer
+// then handle it like any other connect. Returns whether it was redirected
+fn redirect_connect(ctx: &SockAddrContext, service: u32, protocol: u8, service_port: u16) -> bool {
+    let target = match unsafe { GATE_REDIRECTS.get(&service) } {
+        Some(target) if is_redirected(*target, protocol, Some(service_port)) => *target,
+        _ => return false,
+    };
+    let port = (target & 0xffff) as u16;
+    unsafe {
+        (*ctx.sock_addr).user_ip4 = ((target >> 32) as u32).to_be();
+        if port != 0 {
+            // the port is in network byte order in the low 16 bits
+            (*ctx.sock_addr).user_port = port.to_be() as u32;
+        }
+    }
+    true
+}
+
+// Where a packet was seen, logged with the eBPF records. No interface (0) for the connect hook
+#[derive(Clone, Copy)]
+struct Hook {
+    program: &'static str,
+    ifindex: u32,
+}
+
+enum Verdict {
+    Pass,
+    Drop,
+    // Drop, except where the packet can be handed to the agent (XDP with an AF_XDP socket)
+    Hold { protocol: u8, service: u32 },
+    // Pass to the GATE_REDIRECTS address (target) of the service instead
+    Redirect { service: u32, target: u64 },
+}
+
 #[inline(always)]
-unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
-    let start = ctx.data();
-    let end = ctx.data_end();
+unsafe fn ptr_at<T>(start: usize, end: usize, offset: usize) -> Result<*const T, ()> {
     let len = mem::size_of::<T>();
 
     if start + offset + len > end {
@@ -49,46 +363,684 @@ unsafe fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
     Ok(&*ptr)
 }
 
+// False when none of the maps has an entry for the destination. True can be a false positive
+#[inline(always)]
+fn may_be_tracked(address: u32) -> bool {
+    match DESTINATION_FILTER.get(DESTINATION_FILTER_WORDS) {
+        Some(ready) if *ready != 0 => {}
+        _ => return true,
+    }
+    for bit in destination_filter_bits(address) {
+        match DESTINATION_FILTER.get(bit / 64) {
+            Some(word) if *word & (1 << (bit % 64)) != 0 => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+// The ClusterIP of the service an announced load balancer address belongs to, other addresses are
+// their own
+#[inline(always)]
+fn service_address(address: u32) -> u32 {
+    match unsafe { LOAD_BALANCER_IPS.get(&address) } {
+        Some(service) => *service,
+        None => address,
+    }
+}
+
 //
-fn is_scalable_dst(address: u32) -> Option<u32> {
+fn is_scalable_dst(address: u32) -> Option<ServicePolicy> {
     unsafe { SERVICE_LIST.get(&address).cloned() }
 }
 
-fn try_xdp_scale_to_zero_fw(ctx: XdpContext) -> Result<u32, ()> {
-    let ethhdr: *const EthHdr = unsafe { ptr_at(&ctx, 0)? };
-    match unsafe { (*ethhdr).ether_type } {
-        EtherType::Ipv4 => {}
-        _ => return Ok(xdp_action::XDP_PASS),
+// The connect comes from the agent, its PID is only known with the host PID namespace
+fn is_agent_process() -> bool {
+    let tgid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    matches!(AGENT_TRAFFIC.get(AGENT_TGID), Some(agent) if *agent != 0 && *agent == tgid)
+}
+
+// The packet was sent from the source ports of the agents, on this node or another one
+#[inline(always)]
+fn from_agent_port(start: usize, end: usize, protocol: u8) -> bool {
+    let ports = match AGENT_TRAFFIC.get(AGENT_SOURCE_PORTS) {
+        Some(ports) if *ports != 0 => *ports,
+        _ => return false,
+    };
+    match l4_port(start, end, protocol, 0) {
+        Some(port) => port >= (ports >> 16) as u16 && port <= ports as u16,
+        None => false,
     }
+}
 
-    let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(&ctx, EthHdr::LEN)? };
-    let dst = u32::from_be(unsafe { (*ipv4hdr).dst_addr });
+// The packet goes to the sidecar metrics port scraped by the agents. The API server connects to the
+// pod for them, from an address the agent doesn't know
+#[inline(always)]
+fn to_scrape_port(start: usize, end: usize, protocol: u8) -> bool {
+    match AGENT_TRAFFIC.get(AGENT_SCRAPE_PORT) {
+        Some(port) if *port != 0 => dst_port(start, end, protocol) == Some(*port as u16),
+        _ => false,
+    }
+}
+
+// Record activity for services watched by the learning mode
+fn observe_dst(address: u32) {
+    if let Some(last_seen) = OBSERVED_SERVICES.get_ptr_mut(&address) {
+        unsafe { *last_seen = bpf_ktime_get_ns() };
+    }
+}
 
-    match is_scalable_dst(dst) {
-        Some(value) => {
-            if value == 0 {
-                SCALE_REQUESTS.output(
-                    &ctx,
-                    &PacketLog {
-                        ipv4_address: dst,
-                        action: 1,
-                    },
-                    0,
+// Packets addressed to a backend pod of a watched service (after DNAT) are activity of the service
+fn report_backend<C: BpfContext>(ctx: &C, address: u32, source: u32, protocol: u8) {
+    if let Some(service_ip) = unsafe { POD_TO_SERVICE.get(&address) } {
+        // the port is the one of the pod, not of the service
+        if let Some(policy) = is_scalable_dst(*service_ip) {
+            if !policy.counts(protocol, None) {
+                return;
+            }
+        }
+        report(ctx, *service_ip, 0, source);
+    }
+}
+
+// Packets to a node address and host port of a backend pod are activity of its service, and wake
+// it while it is scaled down. Nothing listens on the port then, the packet passes and the client
+// retries once the pod is up
+fn report_host_port<C: BpfContext>(
+    ctx: &C,
+    hook: Hook,
+    start: usize,
+    end: usize,
+    address: u32,
+    source: u32,
+    protocol: u8,
+) {
+    let (service_ip, policy) = match dst_port(start, end, protocol)
+        .and_then(|port| host_port_service(address, port, protocol))
+    {
+        Some(service) => service,
+        None => return,
+    };
+    if policy.flags & SERVICE_AVAILABLE != 0 {
+        report(ctx, service_ip, 0, source);
+    } else if wake_threshold_reached(ctx, hook, service_ip, policy.wake_threshold) {
+        report(ctx, service_ip, 1, source);
+    }
+}
+
+// The service of a backend pod with this host port, when its activity protocols count it
+fn host_port_service(address: u32, port: u16, protocol: u8) -> Option<(u32, ServicePolicy)> {
+    let service_ip = *unsafe { HOST_PORTS.get(&host_port_key(address, port, protocol)) }?;
+    let policy = is_scalable_dst(service_ip)?;
+    // the port is the one of the pod, not of the service
+    if !policy.counts(protocol, None) {
+        return None;
+    }
+    Some((service_ip, policy))
+}
+
+// Send an event for a service and the client that sent the packet (0 when unknown), wakes and
+// activity go through their own perf event arrays
+fn report<C: BpfContext>(ctx: &C, ipv4_address: u32, action: i32, source_address: u32) {
+    let event = PacketLog {
+        ipv4_address,
+        action,
+        source_address,
+    };
+    if action == 1 {
+        WAKE_REQUESTS.output(ctx, &event, 0);
+    } else {
+        SCALE_REQUESTS.output(ctx, &event, 0);
+    }
+}
+
+// Count a gated packet of the service, true once enough of them arrived within the window to wake
+// it up. The count is shared by all CPUs without locking, a lost increment only delays the wake
+fn wake_threshold_reached<C: BpfContext>(
+    ctx: &C,
+    hook: Hook,
+    address: u32,
+    threshold: WakeThreshold,
+) -> bool {
+    if threshold.packets <= 1 {
+        return true;
+    }
+    let now = unsafe { bpf_ktime_get_ns() };
+    match WAKE_ATTEMPTS.get_ptr_mut(&address) {
+        Some(attempts) => unsafe {
+            if now - (*attempts).window_start_ns > threshold.window_ms as u64 * 1_000_000 {
+                (*attempts).window_start_ns = now;
+                (*attempts).packets = 1;
+            } else {
+                (*attempts).packets += 1;
+            }
+            if (*attempts).packets == threshold.packets {
+                // fields first, the agent turns them into structured ones
+                debug!(
+                    ctx,
+                    target: "ebpf",
+                    "program={} ifindex={} cpu={} wake threshold of {:i} reached",
+                    hook.program,
+                    hook.ifindex,
+                    bpf_get_smp_processor_id(),
+                    address
                 );
+            }
+            (*attempts).packets >= threshold.packets
+        },
+        None => {
+            let attempts = WakeAttempts {
+                window_start_ns: now,
+                packets: 1,
+                _pad: 0,
+            };
+            let _ = WAKE_ATTEMPTS.insert(&address, &attempts, 0);
+            false
+        }
+    }
+}
+
+fn count_dropped(protocol: u8) {
+    if let Some(count) = DROPPED_PACKETS.get_ptr_mut(dropped_index(protocol)) {
+        unsafe { *count += 1 };
+    }
+}
+
+// Demand turned away while the service wakes, below the wake threshold packets are not counted
+fn count_wake_drop(service: u32) {
+    match WAKE_DROPS.get_ptr_mut(&service) {
+        Some(count) => unsafe { *count += 1 },
+        None => {
+            let _ = WAKE_DROPS.insert(&service, &1, 0);
+        }
+    }
+}
+
+// Packets of a client gated while its service is scaled down, they size its wake
+fn count_gated_client(service: u32, client: u32) {
+    let key = gated_client_key(service, client);
+    match GATED_CLIENTS.get_ptr_mut(&key) {
+        Some(packets) => unsafe { *packets += 1 },
+        None => {
+            let _ = GATED_CLIENTS.insert(&key, &1, 0);
+        }
+    }
+}
+
+// Copy the headers of a dropped packet to userspace if a capture is running for the service
+fn capture_dropped<C: BpfContext>(ctx: &C, packet_len: u32, address: u32) {
+    let snaplen = match unsafe { CAPTURE_LIST.get(&address) } {
+        Some(snaplen) => *snaplen,
+        None => return,
+    };
+    let captured_len = if snaplen < packet_len {
+        snaplen
+    } else {
+        packet_len
+    };
+
+    // the flags of output are the number of packet bytes appended after the header
+    CAPTURED_PACKETS.output(
+        ctx,
+        &CaptureHeader {
+            ipv4_address: address,
+            packet_len,
+            captured_len,
+        },
+        captured_len,
+    );
+}
+
+// Copy a TLS ClientHello sent to an SNI entrypoint to the agent, which reads the server name. Only
+// the first packet of the payload of a connection matches, the packet itself passes
+fn report_client_hello<C: BpfContext>(
+    ctx: &C,
+    start: usize,
+    end: usize,
+    dst: u32,
+) -> Result<(), ()> {
+    if unsafe { SNI_ENTRYPOINTS.get(&dst) }.is_none() {
+        return Ok(());
+    }
+    let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(start, end, EthHdr::LEN)? };
+    if unsafe { (*ipv4hdr).proto } as u8 != IPPROTO_TCP {
+        return Ok(());
+    }
+    // the header lengths are counted in 32-bit words, in the low bits of the first IPv4 byte and
+    // the high bits of the 13th TCP byte
+    let version_ihl: *const u8 = unsafe { ptr_at(start, end, EthHdr::LEN)? };
+    let tcp_offset = EthHdr::LEN + (unsafe { *version_ihl } & 0x0f) as usize * 4;
+    let data_offset: *const u8 = unsafe { ptr_at(start, end, tcp_offset + 12)? };
+    let payload_offset = tcp_offset + (unsafe { *data_offset } >> 4) as usize * 4;
+
+    let record_type: *const u8 = unsafe { ptr_at(start, end, payload_offset)? };
+    let handshake_type: *const u8 = unsafe { ptr_at(start, end, payload_offset + 5)? };
+    if unsafe { *record_type } != TLS_HANDSHAKE || unsafe { *handshake_type } != TLS_CLIENT_HELLO {
+        return Ok(());
+    }
+
+    let packet_len = (end - start) as u32;
+    let captured_len = if CLIENT_HELLO_SNAPLEN < packet_len {
+        CLIENT_HELLO_SNAPLEN
+    } else {
+        packet_len
+    };
+    CLIENT_HELLOS.output(
+        ctx,
+        &CaptureHeader {
+            ipv4_address: dst,
+            packet_len,
+            captured_len,
+        },
+        captured_len,
+    );
+    Ok(())
+}
+
+// Index in the program arrays of the program for the protocol of the packet
+#[inline(always)]
+fn protocol_program(start: usize, end: usize) -> Result<u32, ()> {
+    let ethhdr: *const EthHdr = unsafe { ptr_at(start, end, 0)? };
+    match unsafe { (*ethhdr).ether_type } {
+        EtherType::Ipv4 => Ok(PROGRAM_IPV4),
+        EtherType::Ipv6 => Ok(PROGRAM_IPV6),
+        _ => Err(()),
+    }
+}
+
+fn try_xdp_scale_to_zero_fw(ctx: XdpContext) -> Result<u32, ()> {
+    let hook = Hook {
+        program: "xdp_scale_to_zero_ipv4",
+        ifindex: unsafe { (*ctx.ctx).ingress_ifindex },
+    };
+    match try_scale_to_zero_fw(&ctx, hook, ctx.data(), ctx.data_end())? {
+        Verdict::Pass => Ok(xdp_action::XDP_PASS),
+        Verdict::Drop => Ok(xdp_action::XDP_DROP),
+        Verdict::Redirect { service, target } => {
+            match redirect_xdp(ctx.data(), ctx.data_end(), service, target) {
+                Ok(_) => Ok(xdp_action::XDP_PASS),
+                Err(_) => Ok(xdp_action::XDP_DROP),
+            }
+        }
+        Verdict::Hold { protocol, service } => {
+            let ifindex = unsafe { (*ctx.ctx).ingress_ifindex };
+            if HOLD_INTERFACE.get(0).copied() != Some(ifindex) {
+                count_dropped(protocol);
+                count_wake_drop(service);
                 return Ok(xdp_action::XDP_DROP);
             }
-            SCALE_REQUESTS.output(
-                &ctx,
-                &PacketLog {
-                    ipv4_address: dst,
-                    action: 0,
-                },
-                0,
-            );
-            return Ok(xdp_action::XDP_PASS);
+            let queue = unsafe { (*ctx.ctx).rx_queue_index };
+            // without a socket bound to the queue the packet is dropped
+            Ok(HELD_PACKETS.redirect(queue, 0).unwrap_or_else(|_| {
+                count_dropped(protocol);
+                count_wake_drop(service);
+                xdp_action::XDP_DROP
+            }))
+        }
+    }
+}
+
+// Packet data is in [start, end) and starts at the ethernet header for both hooks, the dispatcher
+// already checked that an IPv4 header follows
+fn try_scale_to_zero_fw<C: BpfContext>(
+    ctx: &C,
+    hook: Hook,
+    start: usize,
+    end: usize,
+) -> Result<Verdict, ()> {
+    let ipv4hdr: *const Ipv4Hdr = unsafe { ptr_at(start, end, EthHdr::LEN)? };
+    let dst = u32::from_be(unsafe { (*ipv4hdr).dst_addr });
+    if !may_be_tracked(dst) {
+        return Ok(Verdict::Pass);
+    }
+    let src = u32::from_be(unsafe { (*ipv4hdr).src_addr });
+
+    // announced load balancer addresses are gated like the ClusterIP of their service
+    let service = service_address(dst);
+    match is_scalable_dst(service) {
+        Some(policy) => {
+            let protocol = unsafe { (*ipv4hdr).proto } as u8;
+            // e.g. ICMP probes or a metrics port, configured per service not to count
+            let ignored = !policy.counts(protocol, dst_port(start, end, protocol))
+                || from_agent_port(start, end, protocol);
+            let selftest = src == SELFTEST_SOURCE;
+            if policy.flags & SERVICE_AVAILABLE == 0 {
+                capture_dropped(ctx, (end - start) as u32, service);
+                if !ignored && !selftest {
+                    count_gated_client(service, src);
+                }
+                // observe-only services let their gated packets through
+                let pass = policy.gated_action == GATED_PASS;
+                // by the address the client sent to, its replies come back from it
+                let redirect = if policy.gated_action == GATED_REDIRECT {
+                    redirect_target(dst, protocol, dst_port(start, end, protocol))
+                } else {
+                    None
+                };
+                // below the wake threshold the packet is dropped without waking the service, the
+                // self-test goes straight to the wake
+                let reached =
+                    selftest || wake_threshold_reached(ctx, hook, service, policy.wake_threshold);
+                if ignored || !reached {
+                    if pass {
+                        return Ok(Verdict::Pass);
+                    }
+                    if let Some(target) = redirect {
+                        return Ok(Verdict::Redirect {
+                            service: dst,
+                            target,
+                        });
+                    }
+                    if !selftest {
+                        count_dropped(protocol);
+                    }
+                    return Ok(Verdict::Drop);
+                }
+                report(ctx, service, 1, src);
+                if pass {
+                    return Ok(Verdict::Pass);
+                }
+                if let Some(target) = redirect {
+                    return Ok(Verdict::Redirect {
+                        service: dst,
+                        target,
+                    });
+                }
+                let hold_udp = policy.flags & SERVICE_HOLD_UDP != 0 && protocol == IPPROTO_UDP;
+                if (policy.flags & SERVICE_HOLD != 0 || hold_udp) && !selftest {
+                    return Ok(Verdict::Hold { protocol, service });
+                }
+                if !selftest {
+                    count_dropped(protocol);
+                    count_wake_drop(service);
+                }
+                return Ok(Verdict::Drop);
+            }
+            if !ignored {
+                report(ctx, service, 0, src);
+            }
+            return Ok(Verdict::Pass);
         }
         None => {
-            return Ok(xdp_action::XDP_PASS);
+            observe_dst(dst);
+            // not a ClientHello, or too short to be one
+            let _ = report_client_hello(ctx, start, end, dst);
+            let protocol = unsafe { (*ipv4hdr).proto } as u8;
+            if !from_agent_port(start, end, protocol) {
+                if !to_scrape_port(start, end, protocol) {
+                    report_backend(ctx, dst, src, protocol);
+                }
+                report_host_port(ctx, hook, start, end, dst, src, protocol);
+            }
+            return Ok(Verdict::Pass);
         }
     };
 }
+
+// Packets to the IPv6 addresses of a dual-stack service are gated like the ones to its ClusterIP.
+// The IPv4 events have no room for the client, and gated packets are passed or dropped, never
+// redirected or held. Extension headers are not followed, such packets have no port
+fn try_scale_to_zero_ipv6<C: BpfContext>(
+    ctx: &C,
+    hook: Hook,
+    start: usize,
+    end: usize,
+) -> Result<Verdict, ()> {
+    let dst: [u8; 16] = unsafe { *ptr_at(start, end, IPV6_DST)? };
+    let service = match unsafe { IPV6_SERVICES.get(&dst) } {
+        Some(service) => *service,
+        None => return Ok(Verdict::Pass),
+    };
+    let policy = match is_scalable_dst(service) {
+        Some(policy) => policy,
+        None => return Ok(Verdict::Pass),
+    };
+    let protocol: u8 = unsafe { *ptr_at(start, end, IPV6_NEXT_HDR)? };
+    let port = if protocol == IPPROTO_TCP || protocol == IPPROTO_UDP {
+        let port: Result<*const u16, ()> = unsafe { ptr_at(start, end, IPV6_L4 + 2) };
+        port.ok().map(|port| u16::from_be(unsafe { *port }))
+    } else {
+        None
+    };
+    let ignored = !policy.counts(protocol, port);
+    if policy.flags & SERVICE_AVAILABLE != 0 {
+        if !ignored {
+            report(ctx, service, 0, 0);
+        }
+        return Ok(Verdict::Pass);
+    }
+    let reached = !ignored && wake_threshold_reached(ctx, hook, service, policy.wake_threshold);
+    if reached {
+        report(ctx, service, 1, 0);
+    }
+    if policy.gated_action == GATED_PASS {
+        return Ok(Verdict::Pass);
+    }
+    count_dropped(protocol);
+    if reached {
+        count_wake_drop(service);
+    }
+    Ok(Verdict::Drop)
+}
+
+// Destination port of a TCP or UDP packet
+#[inline(always)]
+fn dst_port(start: usize, end: usize, protocol: u8) -> Option<u16> {
+    l4_port(start, end, protocol, 2)
+}
+
+// Port at an offset of the TCP or UDP header, 0 for the source and 2 for the destination.
+// Fragments after the first have none
+#[inline(always)]
+fn l4_port(start: usize, end: usize, protocol: u8, offset: usize) -> Option<u16> {
+    if protocol != IPPROTO_TCP && protocol != IPPROTO_UDP {
+        return None;
+    }
+    let frag_off: *const u16 = unsafe { ptr_at(start, end, IPV4_FRAG_OFF).ok()? };
+    if u16::from_be(unsafe { *frag_off }) & 0x1fff != 0 {
+        return None;
+    }
+    let l4 = l4_offset(start, end).ok()?;
+    let port: *const u16 = unsafe { ptr_at(start, end, l4 + offset).ok()? };
+    Some(u16::from_be(unsafe { *port }))
+}
+
+// Redirect address of a gated service, only TCP and UDP have the ports to tell the replies apart
+fn redirect_target(service: u32, protocol: u8, port: Option<u16>) -> Option<u64> {
+    if protocol != IPPROTO_TCP && protocol != IPPROTO_UDP {
+        return None;
+    }
+    let target = unsafe { GATE_REDIRECTS.get(&service).cloned() }?;
+    is_redirected(target, protocol, port).then_some(target)
+}
+
+// Offsets in the packet of the IPv4 header fields and of the TCP or UDP checksum
+const IPV4_FRAG_OFF: usize = EthHdr::LEN + 6;
+const IPV4_CHECK: usize = EthHdr::LEN + 10;
+const IPV4_SRC: usize = EthHdr::LEN + 12;
+const IPV4_DST: usize = EthHdr::LEN + 16;
+const TCP_CHECK: usize = 16;
+const UDP_CHECK: usize = 6;
+// Same for the fixed IPv6 header, and the TCP or UDP header right after it
+const IPV6_NEXT_HDR: usize = EthHdr::LEN + 6;
+const IPV6_DST: usize = EthHdr::LEN + 24;
+const IPV6_L4: usize = EthHdr::LEN + 40;
+
+// Offset of the TCP or UDP header, from the header length in the low bits of the first IPv4 byte
+#[inline(always)]
+fn l4_offset(start: usize, end: usize) -> Result<usize, ()> {
+    let version_ihl: *const u8 = unsafe { ptr_at(start, end, EthHdr::LEN)? };
+    Ok(EthHdr::LEN + (unsafe { *version_ihl } & 0x0f) as usize * 4)
+}
+
+#[inline(always)]
+fn l4_check(protocol: u8) -> usize {
+    if protocol == IPPROTO_TCP {
+        TCP_CHECK
+    } else {
+        UDP_CHECK
+    }
+}
+
+// Record the flow of the client and send the packet to the redirect address, the packet is the
+// client's so its checksums are complete. Fragments after the first have no ports and are dropped
+fn redirect_xdp(start: usize, end: usize, service: u32, target: u64) -> Result<(), ()> {
+    let ipv4hdr: *mut Ipv4Hdr = unsafe { ptr_at::<Ipv4Hdr>(start, end, EthHdr::LEN)? as *mut _ };
+    if u16::from_be(unsafe { (*ipv4hdr).frag_off }) & 0x1fff != 0 {
+        return Err(());
+    }
+    let protocol = unsafe { (*ipv4hdr).proto } as u8;
+    let l4 = l4_offset(start, end)?;
+    let src_port: *mut u16 = unsafe { ptr_at::<u16>(start, end, l4)? as *mut _ };
+    let dst_port: *mut u16 = unsafe { ptr_at::<u16>(start, end, l4 + 2)? as *mut _ };
+    let check: *mut u16 = unsafe { ptr_at::<u16>(start, end, l4 + l4_check(protocol))? as *mut _ };
+
+    let address = ((target >> 32) as u32).to_be();
+    let port = match (target & 0xffff) as u16 {
+        0 => unsafe { *dst_port },
+        port => port.to_be(),
+    };
+    unsafe {
+        let key = redirect_flow_key(
+            u32::from_be((*ipv4hdr).src_addr),
+            u16::from_be(*src_port),
+            protocol,
+        );
+        let flow = gate_redirect(service, u16::from_be(*dst_port));
+        REDIRECTED_FLOWS.insert(&key, &flow, 0).map_err(|_| ())?;
+
+        let old_address = (*ipv4hdr).dst_addr;
+        (*ipv4hdr).check = csum_replace((*ipv4hdr).check, old_address, address);
+        // a UDP checksum of 0 means there is none
+        if protocol == IPPROTO_TCP || *check != 0 {
+            let mut sum = csum_replace(*check, old_address, address);
+            sum = csum_replace(sum, *dst_port as u32, port as u32);
+            *check = if sum == 0 && protocol == IPPROTO_UDP {
+                0xffff
+            } else {
+                sum
+            };
+        }
+        (*ipv4hdr).dst_addr = address;
+        *dst_port = port;
+    }
+    Ok(())
+}
+
+// RFC 1624 update of a checksum for a field changed from `from` to `to`, as read from the packet. A
+// 16-bit field is passed in the low half
+#[inline(always)]
+fn csum_replace(check: u16, from: u32, to: u32) -> u16 {
+    let mut sum = (!check) as u32 + (!from >> 16) + (!from & 0xffff) + (to >> 16) + (to & 0xffff);
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    !(sum as u16)
+}
+
+// Same as redirect_xdp for TC, where the checksum helpers also handle the checksums left to the
+// NIC
+fn redirect_skb(ctx: &mut TcContext, service: u32, target: u64) -> Result<(), i64> {
+    let protocol: u8 = ctx.load(EthHdr::LEN + 9)?;
+    if u16::from_be(ctx.load(IPV4_FRAG_OFF)?) & 0x1fff != 0 {
+        return Err(-1);
+    }
+    let l4 = l4_offset(ctx.data(), ctx.data_end()).map_err(|_| -1i64)?;
+    let client: u32 = ctx.load(IPV4_SRC)?;
+    let client_port: u16 = ctx.load(l4)?;
+    let service_port: u16 = ctx.load(l4 + 2)?;
+
+    let address = ((target >> 32) as u32).to_be();
+    let port = match (target & 0xffff) as u16 {
+        0 => service_port,
+        port => port.to_be(),
+    };
+    let key = redirect_flow_key(u32::from_be(client), u16::from_be(client_port), protocol);
+    let flow = gate_redirect(service, u16::from_be(service_port));
+    REDIRECTED_FLOWS.insert(&key, &flow, 0)?;
+    rewrite_skb(
+        ctx,
+        protocol,
+        l4,
+        (IPV4_DST, service.to_be(), address),
+        (l4 + 2, service_port, port),
+    )
+}
+
+// Give a reply of a redirect address to a redirected client the address and port of the service
+fn try_unredirect_skb(ctx: &mut TcContext) -> Result<(), i64> {
+    let start = ctx.data();
+    let end = ctx.data_end();
+    let ethhdr: *const EthHdr = unsafe { ptr_at(start, end, 0).map_err(|_| -1i64)? };
+    if !matches!(unsafe { (*ethhdr).ether_type }, EtherType::Ipv4) {
+        return Ok(());
+    }
+    let protocol: u8 = ctx.load(EthHdr::LEN + 9)?;
+    if protocol != IPPROTO_TCP && protocol != IPPROTO_UDP {
+        return Ok(());
+    }
+    let l4 = l4_offset(start, end).map_err(|_| -1i64)?;
+    let client: u32 = ctx.load(IPV4_DST)?;
+    let client_port: u16 = ctx.load(l4 + 2)?;
+    let key = redirect_flow_key(u32::from_be(client), u16::from_be(client_port), protocol);
+    let flow = match unsafe { REDIRECTED_FLOWS.get(&key) } {
+        Some(flow) => *flow,
+        None => return Ok(()),
+    };
+    let service = (flow >> 32) as u32;
+    let service_port = (flow & 0xffff) as u16;
+    let target = match unsafe { GATE_REDIRECTS.get(&service) } {
+        Some(target) => *target,
+        None => return Ok(()),
+    };
+
+    // only the replies of the redirect address, the client may talk to others from the same port
+    let address: u32 = ctx.load(IPV4_SRC)?;
+    let port: u16 = ctx.load(l4)?;
+    let target_port = match (target & 0xffff) as u16 {
+        0 => service_port,
+        port => port,
+    };
+    if u32::from_be(address) != (target >> 32) as u32 || u16::from_be(port) != target_port {
+        return Ok(());
+    }
+    rewrite_skb(
+        ctx,
+        protocol,
+        l4,
+        (IPV4_SRC, address, service.to_be()),
+        (l4, port, service_port.to_be()),
+    )
+}
+
+// Replace an address and a port of the packet, given as (offset, old, new) in network byte order,
+// and update the checksums
+fn rewrite_skb(
+    ctx: &mut TcContext,
+    protocol: u8,
+    l4: usize,
+    address: (usize, u32, u32),
+    port: (usize, u16, u16),
+) -> Result<(), i64> {
+    let check = l4 + l4_check(protocol);
+    // leaves a UDP checksum of 0 alone
+    let flags = if protocol == IPPROTO_UDP {
+        BPF_F_MARK_MANGLED_0 as u64
+    } else {
+        0
+    };
+    let (offset, from, to) = address;
+    ctx.l4_csum_replace(
+        check,
+        from as u64,
+        to as u64,
+        flags | BPF_F_PSEUDO_HDR as u64 | 4,
+    )?;
+    ctx.l3_csum_replace(IPV4_CHECK, from as u64, to as u64, 4)?;
+    ctx.store(offset, &to, 0)?;
+    let (offset, from, to) = port;
+    if from != to {
+        ctx.l4_csum_replace(check, from as u64, to as u64, flags | 2)?;
+        ctx.store(offset, &to, 0)?;
+    }
+    Ok(())
+}
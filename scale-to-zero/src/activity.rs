@@ -0,0 +1,343 @@
+use aya::{
+    maps::{perf::AsyncPerfEventArray, MapData},
+    util::online_cpus,
+};
+use bytes::BytesMut;
+use log::{error, info, warn};
+use scale_to_zero_common::PacketLog;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task;
+
+use crate::agent_traffic;
+use crate::config;
+use crate::kubernetes::controller;
+use crate::kubernetes::models::WATCHED_SERVICES;
+use crate::metrics;
+use crate::runtime;
+use crate::utils;
+
+// A page of a perf buffer holds about 250 activity events
+const MIN_PERF_BUFFER_PAGES: usize = 8;
+const MAX_PERF_BUFFER_PAGES: usize = 256;
+// Bounds of the number of events read at once when it adapts to the load
+const MIN_BATCH_SIZE: usize = 16;
+const MAX_BATCH_SIZE: usize = 1024;
+// Wake events are rare, at most one burst per sleeping service
+const WAKE_BUFFER_PAGES: usize = 8;
+const WAKE_BATCH_SIZE: usize = 16;
+// Pause of a perf buffer reader after a failed read, a lasting failure (e.g. a closed buffer) would
+// spin otherwise
+pub const READ_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+// A source of packet events, every event is handed to `utils::process_packet`
+pub trait ActivitySource {
+    fn name(&self) -> &'static str;
+
+    // Start producing events in background tasks
+    fn start(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+// Events sent by the XDP program, activity through the SCALE_REQUESTS perf event array and wakes
+// through WAKE_REQUESTS. Wakes have their own readers, so they are never queued behind activity
+pub struct XdpSource {
+    perf_array: AsyncPerfEventArray<MapData>,
+    wake_array: AsyncPerfEventArray<MapData>,
+}
+
+impl XdpSource {
+    pub fn new(
+        perf_array: AsyncPerfEventArray<MapData>,
+        wake_array: AsyncPerfEventArray<MapData>,
+    ) -> Self {
+        XdpSource {
+            perf_array,
+            wake_array,
+        }
+    }
+}
+
+impl ActivitySource for XdpSource {
+    fn name(&self) -> &'static str {
+        "xdp"
+    }
+
+    fn start(self: Box<Self>) -> anyhow::Result<()> {
+        let XdpSource {
+            perf_array,
+            mut wake_array,
+        } = *self;
+        let opts = config::get();
+        if let Some(pages) = opts.perf_buffer_pages {
+            if !pages.is_power_of_two() {
+                anyhow::bail!("--perf-buffer-pages must be a power of two, got {}", pages);
+            }
+        }
+        let consumers = opts.perf_consumers.max(1);
+
+        for cpu_id in online_cpus()? {
+            let mut buf = wake_array.open(cpu_id, Some(WAKE_BUFFER_PAGES))?;
+
+            task::spawn(runtime::instrument("wake-readers", async move {
+                let mut buffers = (0..WAKE_BATCH_SIZE)
+                    .map(|_| BytesMut::with_capacity(mem::size_of::<PacketLog>()))
+                    .collect::<Vec<_>>();
+
+                loop {
+                    let events = match buf.read_events(&mut buffers).await {
+                        Ok(events) => events,
+                        Err(err) => {
+                            warn!(target: "activity", "Failed to read the wake events of CPU {}: {}", cpu_id, err);
+                            tokio::time::sleep(READ_RETRY_INTERVAL).await;
+                            continue;
+                        }
+                    };
+                    if events.lost > 0 {
+                        metrics::PERF_EVENTS_LOST.inc_by(events.lost as u64);
+                        warn!(target: "activity", "Lost {} wake events on CPU {}", events.lost, cpu_id);
+                    }
+                    for buf in buffers.iter_mut().take(events.read) {
+                        let ptr = buf.as_ptr() as *const PacketLog;
+                        let data = unsafe { ptr.read_unaligned() };
+                        // a wake waits for the scale up, the next ones don't wait for it
+                        task::spawn(utils::process_packet(data));
+                    }
+                }
+            }));
+        }
+
+        // Sized for the services watched once the controller synced them, unless configured
+        match opts.perf_buffer_pages {
+            Some(pages) => start_readers(perf_array, pages, consumers)?,
+            None => {
+                task::spawn(async move {
+                    while !controller::services_synced() {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                    let pages = perf_buffer_pages(WATCHED_SERVICES.lock().unwrap().len());
+                    if let Err(err) = start_readers(perf_array, pages, consumers) {
+                        error!(target: "activity", "Failed to read the activity events: {:#}", err);
+                        std::process::exit(1);
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Poll the activity perf event array in background
+fn start_readers(
+    mut perf_array: AsyncPerfEventArray<MapData>,
+    pages: usize,
+    consumers: usize,
+) -> anyhow::Result<()> {
+    let opts = config::get();
+    info!(target: "activity", "Reading activity events with {} pages and {} consumers per CPU", pages, consumers);
+    for cpu_id in online_cpus()? {
+        let mut buf = perf_array.open(cpu_id, Some(pages))?;
+        let consumers = start_consumers(consumers);
+
+        task::spawn(runtime::instrument("activity-readers", async move {
+            let mut batch_size = opts.perf_batch_size.unwrap_or(MIN_BATCH_SIZE).max(1);
+            let mut buffers = (0..batch_size)
+                .map(|_| BytesMut::with_capacity(mem::size_of::<PacketLog>()))
+                .collect::<Vec<_>>();
+
+            loop {
+                let events = match buf.read_events(&mut buffers).await {
+                    Ok(events) => events,
+                    Err(err) => {
+                        warn!(target: "activity", "Failed to read the activity events of CPU {}: {}", cpu_id, err);
+                        tokio::time::sleep(READ_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                };
+                if events.lost > 0 {
+                    metrics::PERF_EVENTS_LOST.inc_by(events.lost as u64);
+                    warn!(target: "activity", "Lost {} activity events on CPU {}, raise --perf-buffer-pages", events.lost, cpu_id);
+                }
+                for buf in buffers.iter_mut().take(events.read) {
+                    let ptr = buf.as_ptr() as *const PacketLog;
+                    let data = unsafe { ptr.read_unaligned() };
+                    match consumers.as_slice() {
+                        [] => utils::process_packet(data).await,
+                        consumers => {
+                            let consumer = data.ipv4_address as usize % consumers.len();
+                            let _ = consumers[consumer].send(data).await;
+                        }
+                    }
+                }
+
+                // a full batch means more events are waiting, an almost empty one that the
+                // node is quiet
+                if opts.perf_batch_size.is_none() {
+                    if events.read == batch_size && batch_size < MAX_BATCH_SIZE {
+                        batch_size *= 2;
+                    } else if events.read < batch_size / 4 && batch_size > MIN_BATCH_SIZE {
+                        batch_size /= 2;
+                    }
+                    buffers.resize_with(batch_size, || {
+                        BytesMut::with_capacity(mem::size_of::<PacketLog>())
+                    });
+                }
+            }
+        }));
+    }
+    Ok(())
+}
+
+// Pages per CPU for the number of watched services, one per 8 services
+fn perf_buffer_pages(services: usize) -> usize {
+    (services / 8)
+        .next_power_of_two()
+        .clamp(MIN_PERF_BUFFER_PAGES, MAX_PERF_BUFFER_PAGES)
+}
+
+// Tasks processing the events of a CPU when there is more than one, the events of a service always
+// go to the same task so they are processed in order. Without any, the reader processes them itself
+fn start_consumers(consumers: usize) -> Vec<mpsc::Sender<PacketLog>> {
+    if consumers == 1 {
+        return Vec::new();
+    }
+    (0..consumers)
+        .map(|_| {
+            let (events_tx, mut events) = mpsc::channel::<PacketLog>(MAX_BATCH_SIZE);
+            runtime::watch_channel("activity-consumers", &events_tx);
+            task::spawn(runtime::instrument("activity-consumers", async move {
+                while let Some(event) = events.recv().await {
+                    utils::process_packet(event).await;
+                }
+            }));
+            events_tx
+        })
+        .collect()
+}
+
+// Destination port of a TCP or UDP packet starting at its IPv4 header, fragments after the first
+// have none
+fn dst_port(packet: &[u8]) -> Option<u16> {
+    l4_port(packet, 2)
+}
+
+// Same as l4_port of the XDP program, 0 for the source port and 2 for the destination one
+fn l4_port(packet: &[u8], offset: usize) -> Option<u16> {
+    if packet[9] != 6 && packet[9] != 17 {
+        return None;
+    }
+    if u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0 {
+        return None;
+    }
+    let l4 = (packet[0] & 0x0f) as usize * 4;
+    let port = packet.get(l4 + offset..l4 + offset + 2)?;
+    Some(u16::from_be_bytes([port[0], port[1]]))
+}
+
+// Degraded datapath for kernels where the eBPF program can't run: every IPv4 packet of the node is
+// read from a packet socket, so idle services are still detected and woken up, but nothing is
+// dropped while a service is scaled down
+pub struct PacketSocketSource {
+    socket: OwnedFd,
+}
+
+impl PacketSocketSource {
+    pub fn new() -> io::Result<Self> {
+        // SOCK_DGRAM strips the link layer header, packets start at the IPv4 header
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                (libc::ETH_P_IP as u16).to_be() as libc::c_int,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PacketSocketSource {
+            socket: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+}
+
+impl ActivitySource for PacketSocketSource {
+    fn name(&self) -> &'static str {
+        "packet-socket"
+    }
+
+    fn start(self: Box<Self>) -> anyhow::Result<()> {
+        // only packets to watched services are forwarded, dropped when the channel is full since a
+        // burst of packets to a service is the same activity as one of them. Wakes have their own
+        // channel, a full activity channel doesn't drop them
+        let (events_tx, mut events) = mpsc::channel::<PacketLog>(1024);
+        let (wakes_tx, mut wakes) = mpsc::channel::<PacketLog>(256);
+        runtime::watch_channel("packet-socket-activity", &events_tx);
+        runtime::watch_channel("packet-socket-wakes", &wakes_tx);
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            loop {
+                let len = unsafe {
+                    libc::recv(
+                        self.socket.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                        libc::MSG_TRUNC,
+                    )
+                };
+                if len < 0 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    warn!("Packet socket failed: {}", err);
+                    return;
+                }
+                if (len as usize) < 20 {
+                    continue;
+                }
+
+                let dst = Ipv4Addr::new(buf[16], buf[17], buf[18], buf[19]);
+                let packet = &buf[..(len as usize).min(buf.len())];
+                if l4_port(packet, 0).is_some_and(agent_traffic::is_agent_port) {
+                    continue;
+                }
+                let port = dst_port(packet);
+                let backend_available = match WATCHED_SERVICES.lock().unwrap().get(&dst.to_string())
+                {
+                    Some(service) if !utils::service_policy(service).counts(buf[9], port) => {
+                        continue
+                    }
+                    Some(service) => service.backend_available,
+                    None => continue,
+                };
+                let event = PacketLog {
+                    ipv4_address: dst.into(),
+                    action: if backend_available { 0 } else { 1 },
+                    source_address: Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15]).into(),
+                };
+                let _ = if backend_available {
+                    events_tx.try_send(event)
+                } else {
+                    wakes_tx.try_send(event)
+                };
+            }
+        });
+
+        task::spawn(runtime::instrument("packet-socket-activity", async move {
+            while let Some(event) = events.recv().await {
+                utils::process_packet(event).await;
+            }
+        }));
+        task::spawn(runtime::instrument("packet-socket-wakes", async move {
+            while let Some(event) = wakes.recv().await {
+                task::spawn(utils::process_packet(event));
+            }
+        }));
+        Ok(())
+    }
+}
@@ -0,0 +1,556 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{Html, IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use futures::stream;
+use k8s_openapi::serde_json::{json, Value};
+use log::{info, warn};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::time::{timeout_at, Instant};
+
+use crate::auth;
+use crate::capture::{self, Capture};
+use crate::config;
+use crate::custom_metrics;
+use crate::dashboard;
+use crate::diagnostics;
+use crate::kubernetes::models::{
+    ServiceData, IDLE_GAPS, LAST_CALLED, OBSERVED_SERVICES, RECENT_WAKES, WATCHED_SERVICES,
+};
+use crate::kubernetes::{explain, ingress, maintenance, scaler, warm_up};
+use crate::learning;
+use crate::logging;
+use crate::metrics;
+use crate::recorder::{self, RecordedEvent};
+use crate::selftest;
+use crate::talkers;
+use crate::utils;
+
+const DEFAULT_CAPTURE_SECONDS: u64 = 30;
+const MAX_CAPTURE_SECONDS: u64 = 600;
+const DEFAULT_SNAPLEN: u32 = 128;
+// Captured bytes have to fit in the perf event buffers next to the capture header
+const MAX_SNAPLEN: u32 = 512;
+const DEFAULT_RECOMMENDATION_MARGIN: f64 = 1.5;
+const DEFAULT_TOP_TALKERS: usize = 10;
+
+pub async fn serve(addr: SocketAddr) -> anyhow::Result<()> {
+    let opts = config::get();
+    // waking scales workloads up, it always takes a token even when the rest of the API doesn't.
+    // So do maintenance mode, which can wake every sleeping service at once, the self-test, which
+    // wakes its target, and the log level, whose trace records flood the logs with packet details
+    let wake_route = Router::new()
+        .route("/wake", post(wake))
+        .route("/maintenance", put(set_maintenance))
+        .route("/selftest", post(selftest))
+        .route("/log-level", put(set_log_level));
+    let wake_route = if opts.admin_token_auth {
+        wake_route
+    } else {
+        wake_route.layer(middleware::from_fn(auth::require_token))
+    };
+    let app = Router::new()
+        .route("/", get(|| async { Html(dashboard::PAGE) }))
+        .route("/dashboard.json", get(|| async { Json(dashboard::data()) }))
+        .route("/state", get(get_state))
+        .route("/health", get(get_health))
+        .route("/metrics", get(get_metrics))
+        .route("/capture/:service_ip", get(capture_packets))
+        .route("/events", get(get_all_events))
+        .route("/events/:service_ip", get(get_events))
+        .route("/recommendations", get(get_recommendations))
+        .route("/log-level", get(get_log_level))
+        .merge(wake_route)
+        .route("/explain/:namespace/:service", get(get_explanation))
+        .route("/top-talkers/:namespace/:service", get(get_top_talkers))
+        .route("/maintenance", get(get_maintenance))
+        .merge(custom_metrics::routes());
+
+    let app = if opts.admin_token_auth {
+        app.layer(middleware::from_fn(auth::require_token))
+    } else {
+        app
+    };
+
+    match auth::tls_config(opts)? {
+        Some(tls) => {
+            info!(target: "admin", "Admin API listening on {} (HTTPS)", addr);
+            axum_server::bind_rustls(addr, tls)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            info!(target: "admin", "Admin API listening on {}", addr);
+            axum::serve(listener, app).await?;
+        }
+    }
+    Ok(())
+}
+
+// Scrapers asking for OpenMetrics get the exemplars of the cold starts too
+async fn get_metrics(headers: HeaderMap) -> Response {
+    let openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(|accept| accept.contains("application/openmetrics-text"))
+        .unwrap_or(false);
+    if openmetrics {
+        return (
+            [(
+                header::CONTENT_TYPE,
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            )],
+            metrics::render_openmetrics(),
+        )
+            .into_response();
+    }
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+        .into_response()
+}
+
+// Everything the agent knows in one document, for tooling and support snapshots
+async fn get_state() -> Json<Value> {
+    let service_list: std::collections::BTreeMap<String, Value> = utils::SERVICE_LIST_SNAPSHOT
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(ip, policy)| {
+            let ports: Vec<(u16, u16)> = policy
+                .ports
+                .iter()
+                .take(policy.port_ranges as usize)
+                .map(|range| (range.start, range.end))
+                .collect();
+            let policy = json!({
+                "flags": policy.flags,
+                "protocols": policy.protocols,
+                "gated_action": policy.gated_action,
+                "ports": ports,
+                "wake_threshold": {
+                    "packets": policy.wake_threshold.packets,
+                    "window_ms": policy.wake_threshold.window_ms,
+                },
+            });
+            (Ipv4Addr::from(*ip).to_string(), policy)
+        })
+        .collect();
+    // unix seconds of the last scale-up of each service, another one is rate limited for 5s
+    let last_called: std::collections::BTreeMap<String, u64> = LAST_CALLED
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(ip, time)| {
+            let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            (ip.clone(), seconds.as_secs())
+        })
+        .collect();
+
+    Json(json!({
+        "watched_services": *WATCHED_SERVICES.lock().unwrap(),
+        "observed_services": *OBSERVED_SERVICES.lock().unwrap(),
+        "kernel_service_list": service_list,
+        "rate_limits": last_called,
+        "interfaces": *utils::ATTACH_STATUS.lock().unwrap(),
+        "maintenance": maintenance::get(),
+        "warm_up": warm_up::get(),
+    }))
+}
+
+// Why the eBPF datapath is not running: the load failures with their verifier log, what the
+// node lacks, and the interfaces our XDP program was replaced on
+async fn get_health() -> Json<Value> {
+    let load_failures = diagnostics::LOAD_FAILURES.lock().unwrap().clone();
+    let detached: std::collections::BTreeMap<String, String> = utils::ATTACH_STATUS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, status)| status.starts_with("detached"))
+        .map(|(itf, status)| (itf.clone(), status.clone()))
+        .collect();
+    let healthy = load_failures.is_empty() && detached.is_empty();
+    Json(json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "kernel": diagnostics::kernel_release(),
+        "missing_features": diagnostics::missing_features(),
+        "load_failures": load_failures,
+        "detached_interfaces": detached,
+    }))
+}
+
+#[derive(Deserialize)]
+struct RecommendationQuery {
+    margin: Option<f64>,
+}
+
+// Scale-down-time of each watched service from the p99 of the pauses between its packets
+async fn get_recommendations(Query(query): Query<RecommendationQuery>) -> Json<Value> {
+    let margin = query.margin.unwrap_or(DEFAULT_RECOMMENDATION_MARGIN);
+    let min_idle = config::get().learning_min_idle;
+    let idle_gaps = IDLE_GAPS.lock().unwrap().clone();
+    let recommendations: std::collections::BTreeMap<String, Value> = WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(ip, service)| {
+            let gaps = idle_gaps.get(ip).cloned().unwrap_or_default();
+            let recommended =
+                learning::recommend_scale_down_time(gaps.iter().copied(), min_idle, 99, margin);
+            let recommendation = json!({
+                "namespace": service.namespace,
+                "service": service.service_name,
+                "scale_down_time": service.scale_down_time,
+                "gaps": gaps.len(),
+                "recommended_scale_down_time": recommended,
+            });
+            (ip.clone(), recommendation)
+        })
+        .collect();
+    Json(json!(recommendations))
+}
+
+fn log_level_json() -> Json<Value> {
+    let filter = logging::filter();
+    let targets: std::collections::BTreeMap<&String, String> = filter
+        .targets
+        .iter()
+        .map(|(target, level)| (target, level.to_string().to_lowercase()))
+        .collect();
+    Json(json!({
+        "filter": filter.spec(),
+        "default": filter.default.map(|level| level.to_string().to_lowercase()),
+        "targets": targets,
+    }))
+}
+
+async fn get_log_level() -> Json<Value> {
+    log_level_json()
+}
+
+#[derive(Deserialize)]
+struct LogLevelRequest {
+    // the default level when not set, eBPF records use the "ebpf" target
+    target: Option<String>,
+    // a target without a level goes back to the default level
+    level: Option<String>,
+}
+
+// Change the verbosity without restarting the agent, which would lose the activity timers
+async fn set_log_level(Json(request): Json<LogLevelRequest>) -> Response {
+    let level = match request.level.as_deref().map(str::parse).transpose() {
+        Ok(level) => level,
+        Err(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Unknown log level: {}", request.level.unwrap_or_default()),
+            )
+                .into_response()
+        }
+    };
+    logging::set_level(request.target.as_deref(), level);
+    log_level_json().into_response()
+}
+
+async fn get_maintenance() -> Json<maintenance::Maintenance> {
+    Json(maintenance::get())
+}
+
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    enabled: bool,
+    // wake every sleeping service when turning it on
+    #[serde(default)]
+    wake_all: bool,
+}
+
+// Pause the scale-downs of every agent during a maintenance window or an incident
+async fn set_maintenance(Json(request): Json<MaintenanceRequest>) -> Response {
+    if let Err(err) = maintenance::change(request.enabled, request.wake_all).await {
+        warn!("Failed to change the maintenance mode: {:#}", err);
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Failed to change the maintenance mode: {:#}", err),
+        )
+            .into_response();
+    }
+    Json(maintenance::get()).into_response()
+}
+
+#[derive(Deserialize)]
+struct WakeQuery {
+    host: Option<String>,
+}
+
+// Wake the services behind a host, for ingress controllers whose traffic never reaches a service
+// IP. The custom error backend of ingress-nginx sends the namespace and service of the failed
+// request, anything else is resolved from the host through the watched Ingresses and HTTPRoutes
+async fn wake(headers: HeaderMap, Query(query): Query<WakeQuery>) -> Response {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let (service_ips, requested) = match (header("X-Namespace"), header("X-Service-Name")) {
+        (Some(namespace), Some(service_name)) if !service_name.is_empty() => (
+            ingress::service_ips(namespace, service_name),
+            format!("service {}/{}", namespace, service_name),
+        ),
+        _ => {
+            let host = header("X-Forwarded-Host")
+                .or(query.host.as_deref())
+                .or(header("Host"))
+                .unwrap_or_default();
+            // an empty host would match every wildcard route
+            if host.is_empty() {
+                return (StatusCode::BAD_REQUEST, "No host to wake").into_response();
+            }
+            (ingress::services_for_host(host), format!("host {}", host))
+        }
+    };
+    if service_ips.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("No watched service for {}", requested),
+        )
+            .into_response();
+    }
+
+    let sleeping: Vec<String> = {
+        let watched_services = WATCHED_SERVICES.lock().unwrap();
+        service_ips
+            .into_iter()
+            .filter(|ip| matches!(watched_services.get(ip), Some(service) if !service.backend_available))
+            .collect()
+    };
+    if sleeping.is_empty() {
+        return (StatusCode::OK, format!("Awake: {}", requested)).into_response();
+    }
+    for service_ip in sleeping {
+        info!(target: "admin", "Waking {} for {}", service_ip, requested);
+        // a rate limited wake is already in progress
+        if let Err(err) = scaler::scale_up(service_ip.clone()).await {
+            warn!(target: "admin", "Failed to wake {}: {}", service_ip, err);
+        }
+    }
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, "5")],
+        format!("Waking up: {}", requested),
+    )
+        .into_response()
+}
+
+const DEFAULT_SELFTEST_PORT: u16 = 80;
+const DEFAULT_SELFTEST_SECONDS: u64 = 300;
+
+#[derive(Deserialize)]
+struct SelftestQuery {
+    // namespace/name of a watched service
+    service: String,
+    port: Option<u16>,
+    timeout: Option<u64>,
+}
+
+// Smoke test of the whole pipeline for a service, the report lists the steps it went through. A
+// failed test answers 503 so `curl -f` exits with an error
+async fn selftest(Query(query): Query<SelftestQuery>) -> Response {
+    let (namespace, service) = match query.service.split_once('/') {
+        Some(parts) => parts,
+        None => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "service has to be <namespace>/<name>",
+            )
+                .into_response()
+        }
+    };
+    let port = query.port.unwrap_or(DEFAULT_SELFTEST_PORT);
+    let timeout = Duration::from_secs(query.timeout.unwrap_or(DEFAULT_SELFTEST_SECONDS));
+    match selftest::run(namespace, service, port, timeout).await {
+        Ok(report) if report.passed => Json(report).into_response(),
+        Ok(report) => (StatusCode::SERVICE_UNAVAILABLE, Json(report)).into_response(),
+        Err(err) => (StatusCode::NOT_FOUND, format!("{:#}", err)).into_response(),
+    }
+}
+
+async fn get_all_events() -> Response {
+    events_response(recorder::events(None))
+}
+
+async fn get_events(Path(service_ip): Path<String>) -> Response {
+    if !WATCHED_SERVICES.lock().unwrap().contains_key(&service_ip) {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Service {} is not watched", service_ip),
+        )
+            .into_response();
+    }
+    events_response(recorder::events(Some(&service_ip)))
+}
+
+// Recorded events as JSON lines, the recording can be replayed as is with --simulate
+fn events_response(events: Vec<RecordedEvent>) -> Response {
+    let lines: String = events
+        .iter()
+        .filter_map(|event| k8s_openapi::serde_json::to_string(event).ok())
+        .map(|line| line + "\n")
+        .collect();
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"events.jsonl\"",
+            ),
+        ],
+        lines,
+    )
+        .into_response()
+}
+
+// The inputs of the last scale-down evaluation and wake of a service, to tell why it is up or down
+async fn get_explanation(Path((namespace, service_name)): Path<(String, String)>) -> Response {
+    let (service_ip, service) = match watched_service(&namespace, &service_name) {
+        Some(found) => found,
+        None => return not_watched(&namespace, &service_name),
+    };
+    Json(json!({
+        "service": format!("{}/{}", namespace, service_name),
+        "service_ip": service_ip,
+        "backend_available": service.backend_available,
+        "policies": service.policies,
+        "maintenance": maintenance::enabled(),
+        "recent_wakes": RECENT_WAKES.lock().unwrap().get(&service_ip),
+        "scale_down": explain::scale_down(&service_ip),
+        "wake": explain::wake(&service_ip),
+    }))
+    .into_response()
+}
+
+#[derive(Deserialize)]
+struct TopTalkersQuery {
+    limit: Option<usize>,
+}
+
+// The clients whose packets woke the service or kept it active the most, on this node
+async fn get_top_talkers(
+    Path((namespace, service_name)): Path<(String, String)>,
+    Query(query): Query<TopTalkersQuery>,
+) -> Response {
+    let (service_ip, _) = match watched_service(&namespace, &service_name) {
+        Some(found) => found,
+        None => return not_watched(&namespace, &service_name),
+    };
+    Json(json!({
+        "service": format!("{}/{}", namespace, service_name),
+        "service_ip": service_ip,
+        "talkers": talkers::top(&service_ip, query.limit.unwrap_or(DEFAULT_TOP_TALKERS)),
+    }))
+    .into_response()
+}
+
+// The IP and data of a watched service
+fn watched_service(namespace: &str, service_name: &str) -> Option<(String, ServiceData)> {
+    WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, service)| service.namespace == namespace && service.service_name == service_name)
+        .map(|(ip, service)| (ip.clone(), service.clone()))
+}
+
+fn not_watched(namespace: &str, service_name: &str) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        format!("Service {}/{} is not watched", namespace, service_name),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct CaptureQuery {
+    seconds: Option<u64>,
+    format: Option<String>,
+    snaplen: Option<u32>,
+}
+
+// Capture the packets dropped for a gated service, either as a pcap file or streamed as JSON lines
+async fn capture_packets(
+    Path(service_ip): Path<String>,
+    Query(query): Query<CaptureQuery>,
+) -> Response {
+    let ip: Ipv4Addr = match service_ip.parse() {
+        Ok(ip) => ip,
+        Err(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid service IP: {}", service_ip),
+            )
+                .into_response()
+        }
+    };
+    if !WATCHED_SERVICES.lock().unwrap().contains_key(&service_ip) {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Service {} is not watched", service_ip),
+        )
+            .into_response();
+    }
+
+    let seconds = query
+        .seconds
+        .unwrap_or(DEFAULT_CAPTURE_SECONDS)
+        .min(MAX_CAPTURE_SECONDS);
+    let snaplen = query.snaplen.unwrap_or(DEFAULT_SNAPLEN).min(MAX_SNAPLEN);
+    let deadline = Instant::now() + Duration::from_secs(seconds);
+    let mut capture = Capture::start(ip.into(), snaplen);
+    info!(target: "admin", "Capturing dropped packets of {} for {}s", service_ip, seconds);
+
+    match query.format.as_deref().unwrap_or("json") {
+        "pcap" => {
+            let mut packets = Vec::new();
+            while let Ok(Some(packet)) = timeout_at(deadline, capture.next()).await {
+                packets.push(packet);
+            }
+            (
+                [
+                    (header::CONTENT_TYPE, "application/vnd.tcpdump.pcap"),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        "attachment; filename=\"capture.pcap\"",
+                    ),
+                ],
+                capture::to_pcap(&packets, snaplen),
+            )
+                .into_response()
+        }
+        "json" => {
+            let lines = stream::unfold(capture, move |mut capture| async move {
+                match timeout_at(deadline, capture.next()).await {
+                    Ok(Some(packet)) => Some((
+                        Ok::<_, Infallible>(format!("{}\n", packet.to_json())),
+                        capture,
+                    )),
+                    _ => None,
+                }
+            });
+            (
+                [(header::CONTENT_TYPE, "application/x-ndjson")],
+                Body::from_stream(lines),
+            )
+                .into_response()
+        }
+        format => (
+            StatusCode::BAD_REQUEST,
+            format!("Unknown capture format: {}", format),
+        )
+            .into_response(),
+    }
+}
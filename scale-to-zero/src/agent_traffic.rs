@@ -0,0 +1,109 @@
+use anyhow::Context;
+use aya::maps::{Array, MapData};
+use hyper::{header, Body, Method, Request, StatusCode, Uri};
+use log::info;
+use scale_to_zero_common::{AGENT_SCRAPE_PORT, AGENT_SOURCE_PORTS, AGENT_TGID};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+
+use crate::config;
+
+// Connections of the agent take the ports of --agent-source-ports in turn
+static NEXT_PORT: AtomicU32 = AtomicU32::new(0);
+
+// Tell the datapath which traffic is the agent's own: its connects on this node by its PID, its
+// connections seen by the other nodes by their source port, and the scrapes of the mesh sidecars
+// made by the API server on its behalf by their port
+pub fn init(mut array: Array<MapData, u32>) -> anyhow::Result<()> {
+    let opts = config::get();
+    array.set(AGENT_TGID, std::process::id(), 0)?;
+    let (first, last) = opts.agent_source_ports;
+    array.set(AGENT_SOURCE_PORTS, (first as u32) << 16 | last as u32, 0)?;
+    array.set(AGENT_SCRAPE_PORT, opts.mesh_metrics_port as u32, 0)?;
+    info!(
+        "Packets from source ports {}-{} and to pod port {} are the agents' own traffic",
+        first, last, opts.mesh_metrics_port
+    );
+    Ok(())
+}
+
+// The port is one the agents connect from
+pub fn is_agent_port(port: u16) -> bool {
+    let (first, last) = config::get().agent_source_ports;
+    (first..=last).contains(&port)
+}
+
+// Connect from a port of --agent-source-ports. Ports still in use by a previous connection are
+// skipped
+pub async fn connect(address: SocketAddr) -> io::Result<TcpStream> {
+    let (first, last) = config::get().agent_source_ports;
+    let count = last as u32 - first as u32 + 1;
+    for _ in 0..count {
+        let port = first as u32 + NEXT_PORT.fetch_add(1, Ordering::Relaxed) % count;
+        let socket = TcpSocket::new_v4()?;
+        if socket
+            .bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port as u16)))
+            .is_err()
+        {
+            continue;
+        }
+        return socket.connect(address).await;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("No free port in --agent-source-ports {}-{}", first, last),
+    ))
+}
+
+// An HTTP request of the agent. A plain HTTP one is sent from a port of --agent-source-ports on a
+// connection of its own, an HTTPS one goes through reqwest from an ephemeral port
+pub async fn request(
+    method: &str,
+    url: &str,
+    body: String,
+    timeout: Duration,
+) -> anyhow::Result<(StatusCode, String)> {
+    let uri: Uri = url
+        .parse()
+        .with_context(|| format!("Invalid URL {}", url))?;
+    if uri.scheme_str() != Some("http") {
+        let response = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()?
+            .request(method.parse()?, url)
+            .body(body)
+            .send()
+            .await?;
+        return Ok((response.status(), response.text().await?));
+    }
+    tokio::time::timeout(timeout, send(method.parse()?, uri, body))
+        .await
+        .map_err(|_| anyhow::anyhow!("{} {} timed out after {}s", method, url, timeout.as_secs()))?
+}
+
+async fn send(method: Method, uri: Uri, body: String) -> anyhow::Result<(StatusCode, String)> {
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow::anyhow!("{} has no host", uri))?;
+    let address = tokio::net::lookup_host((host, uri.port_u16().unwrap_or(80)))
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| anyhow::anyhow!("{} has no IPv4 address", host))?;
+    let (mut sender, connection) = hyper::client::conn::handshake(connect(address).await?).await?;
+    tokio::spawn(connection);
+    let request = Request::builder()
+        .method(method)
+        .uri(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(
+            header::HOST,
+            uri.authority().map_or(host, |authority| authority.as_str()),
+        )
+        .body(Body::from(body))?;
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
@@ -0,0 +1,1162 @@
+use aya::maps::{Array, Map, MapData, ProgramArray};
+use aya::programs::links::{FdLink, PinnedLink};
+use aya::programs::xdp::{XdpLink, XdpLinkId};
+use aya::programs::{tc, CgroupSockAddr, SchedClassifier, TcAttachType, Xdp, XdpFlags};
+use aya::util::KernelVersion;
+use aya::Bpf;
+use k8s_openapi::serde_json;
+use log::{info, warn};
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::mem;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::pipe;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+
+use scale_to_zero_common::{PROGRAM_IPV4, PROGRAM_IPV6};
+
+use crate::config;
+use crate::diagnostics;
+use crate::metrics;
+use crate::selftest;
+use crate::utils;
+
+pub const TC_PROGRAM_NAME: &str = "tc_scale_to_zero_fw";
+// Protocol programs tail called by the dispatchers, by their index in the program arrays
+const XDP_PROTOCOL_PROGRAMS: [(u32, &str); 2] = [
+    (PROGRAM_IPV4, "xdp_scale_to_zero_ipv4"),
+    (PROGRAM_IPV6, "xdp_scale_to_zero_ipv6"),
+];
+const TC_PROTOCOL_PROGRAMS: [(u32, &str); 2] = [
+    (PROGRAM_IPV4, "tc_scale_to_zero_ipv4"),
+    (PROGRAM_IPV6, "tc_scale_to_zero_ipv6"),
+];
+pub const CONNECT_PROGRAM_NAME: &str = "connect4_scale_to_zero";
+// Gives the replies of gated-action redirect addresses the address of their service
+const EGRESS_PROGRAM_NAME: &str = "tc_scale_to_zero_egress";
+// Appended to the status of the interfaces with the egress program
+const EGRESS_STATUS: &str = ", tc egress";
+
+// Key of the connect hook in ATTACH_STATUS, shown next to the interfaces as it covers every
+// socket of the node
+const CGROUP_STATUS: &str = "cgroup";
+
+// Filesystem type of bpffs, links can only be pinned there
+const BPF_FS_MAGIC: u64 = 0xcafe4a11;
+
+// Directory where the eBPF datapaths of the CNIs pin their maps
+const BPF_PINS: &str = "/sys/fs/bpf/tc/globals";
+
+// Status of an interface our XDP program was removed from by another one
+const DETACHED_STATUS: &str = "detached";
+
+// Status of an interface left out for an upper or lower interface of its bond or bridge
+const STACKED_STATUS: &str = "skipped: stacked";
+
+// Link attributes carrying the XDP program and the place in a bond or bridge of an interface, see
+// if_link.h
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MASTER: u16 = 10;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+const IFLA_XDP: u16 = 43;
+const IFLA_XDP_ATTACHED: u16 = 2;
+const IFLA_XDP_PROG_ID: u16 = 4;
+const IFLA_XDP_SKB_PROG_ID: u16 = 6;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cni {
+    Cilium,
+    CalicoEbpf,
+    Calico,
+    Flannel,
+    Unknown,
+}
+
+impl fmt::Display for Cni {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Cni::Cilium => "Cilium",
+            Cni::CalicoEbpf => "Calico (eBPF dataplane)",
+            Cni::Calico => "Calico",
+            Cni::Flannel => "Flannel",
+            Cni::Unknown => "unknown",
+        };
+        f.write_str(name)
+    }
+}
+
+// How ClusterIPs are translated to pod IPs on the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProxyMode {
+    Iptables,
+    Ipvs,
+    // kube-proxy replaced by an eBPF load balancer translating ClusterIPs at connect time
+    Ebpf,
+}
+
+impl fmt::Display for ProxyMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ProxyMode::Iptables => "iptables",
+            ProxyMode::Ipvs => "IPVS",
+            ProxyMode::Ebpf => "eBPF",
+        };
+        f.write_str(name)
+    }
+}
+
+// Which interfaces of a bond or bridge get the datapath. An upper interface sees the packets of its
+// lower ones again, attaching to both counts them twice
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AttachLayer {
+    // Bonds rather than their slaves, the ports of bridges rather than the bridges: bridged
+    // traffic between pods never reaches the bridge itself
+    Auto,
+    // Bonds and bridges, not the interfaces enslaved to them
+    Upper,
+    // The interfaces enslaved to bonds and bridges, not the bonds and bridges
+    Lower,
+    // Every interface
+    All,
+}
+
+impl fmt::Display for AttachLayer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AttachLayer::Auto => "auto",
+            AttachLayer::Upper => "upper",
+            AttachLayer::Lower => "lower",
+            AttachLayer::All => "all",
+        };
+        f.write_str(name)
+    }
+}
+
+// An interface in a netlink dump of the links
+#[derive(Debug, Clone, Default)]
+struct Link {
+    name: String,
+    index: u32,
+    // Index of the bond or bridge the interface is enslaved to, 0 for none
+    master: u32,
+    // Kind of a virtual interface (bond, bridge, veth...), empty for a physical one
+    kind: String,
+    // Id of the attached XDP program, 0 without one
+    xdp_program: u32,
+    // Mode the XDP program runs in, e.g. "SKB mode", empty without one
+    xdp_mode: &'static str,
+}
+
+fn is_bond(link: &Link) -> bool {
+    matches!(link.kind.as_str(), "bond" | "team")
+}
+
+// Interfaces left out under the attach layer and why, by name. Nothing is left out when the links
+// can't be dumped
+fn stacked_interfaces(layer: AttachLayer) -> HashMap<String, String> {
+    let links = match links() {
+        Ok(links) => links,
+        Err(err) => {
+            warn!(target: "attach", "Failed to read the bonds and bridges of the interfaces, attaching to all of them: {}", err);
+            return HashMap::new();
+        }
+    };
+    let by_index: HashMap<u32, &Link> = links.iter().map(|link| (link.index, link)).collect();
+    let mut skipped = HashMap::new();
+    for link in links.iter() {
+        let master = by_index.get(&link.master);
+        let has_lower = links.iter().any(|other| other.master == link.index);
+        let reason = match layer {
+            AttachLayer::All => None,
+            AttachLayer::Upper => {
+                master.map(|master| format!("enslaved to {} {}", master.kind, master.name))
+            }
+            AttachLayer::Lower if has_lower => {
+                Some(format!("{} with its lower interfaces attached", link.kind))
+            }
+            AttachLayer::Lower => None,
+            AttachLayer::Auto => match master {
+                Some(master) if is_bond(master) => {
+                    Some(format!("slave of {} {}", master.kind, master.name))
+                }
+                _ if link.kind == "bridge" && has_lower => {
+                    Some("bridge with its ports attached".to_string())
+                }
+                _ => None,
+            },
+        };
+        if let Some(reason) = reason {
+            skipped.insert(link.name.clone(), format!("{}, {}", STACKED_STATUS, reason));
+        }
+    }
+    skipped
+}
+
+// IPVS mode binds the ClusterIPs to the kube-ipvs0 dummy interface, and without a kube-proxy
+// process an eBPF datapath has to be doing the translation. Needs the host PID namespace
+pub fn detect_proxy_mode(interfaces: &[String], cni: Cni) -> ProxyMode {
+    if interfaces.iter().any(|itf| itf == "kube-ipvs0") {
+        return ProxyMode::Ipvs;
+    }
+    let kube_proxy_running = std::fs::read_dir("/proc")
+        .map(|entries| {
+            entries.filter_map(|entry| entry.ok()).any(|entry| {
+                std::fs::read_to_string(entry.path().join("comm"))
+                    .map(|comm| comm.trim() == "kube-proxy")
+                    .unwrap_or(false)
+            })
+        })
+        .unwrap_or(true);
+    if !kube_proxy_running && matches!(cni, Cni::Cilium | Cni::CalicoEbpf) {
+        return ProxyMode::Ebpf;
+    }
+    ProxyMode::Iptables
+}
+
+// Guess the CNI from the interfaces it creates and the maps its eBPF datapath pins
+pub fn detect_cni(interfaces: &[String]) -> Cni {
+    let pinned = |prefix: &str| {
+        std::fs::read_dir(BPF_PINS)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .any(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+            })
+            .unwrap_or(false)
+    };
+    let has_interface = |prefix: &str| interfaces.iter().any(|itf| itf.starts_with(prefix));
+
+    if has_interface("cilium_") || pinned("cilium_") {
+        Cni::Cilium
+    } else if pinned("cali_") {
+        Cni::CalicoEbpf
+    } else if has_interface("cali") || has_interface("vxlan.calico") {
+        Cni::Calico
+    } else if has_interface("flannel") || has_interface("cni0") {
+        Cni::Flannel
+    } else {
+        Cni::Unknown
+    }
+}
+
+// The loaded eBPF programs, attached to interfaces as they show up
+pub struct Datapath {
+    bpf: Bpf,
+    cni: Cni,
+    tc_loaded: bool,
+    tc_programs: ProgramArray<MapData>,
+    egress_loaded: bool,
+    // Interfaces with the egress program attached
+    egress: HashSet<String>,
+}
+
+impl Datapath {
+    // Attach the datapath to every interface, with XDP unless the interface is owned by another XDP
+    // program or the CNI runs its own eBPF datapath, then TC ingress is used so both programs run.
+    // The cgroup connect hook reports connects when the kube-proxy mode hides ClusterIPs from the
+    // interfaces, and redirects the connects of services with gated-action redirect, whose
+    // translated packets get their replies translated back on TC egress. The outcome
+    // for each interface is logged and kept in ATTACH_STATUS
+    pub fn attach(mut bpf: Bpf) -> anyhow::Result<Datapath> {
+        let interfaces = interface_names()?;
+        let cni = detect_cni(&interfaces);
+        info!(target: "attach", "Detected CNI: {}", cni);
+
+        let proxy_mode = config::get()
+            .proxy_mode
+            .unwrap_or_else(|| detect_proxy_mode(&interfaces, cni));
+        info!(target: "attach", "kube-proxy mode: {}", proxy_mode);
+        // ClusterIPs are still on the wire with iptables and IPVS, the ingress hooks see them
+        attach_connect_hook(&mut bpf, proxy_mode == ProxyMode::Ebpf);
+
+        // the dispatcher passes every packet until its protocol programs are in place
+        let mut xdp_programs = ProgramArray::try_from(bpf.take_map("XDP_PROGRAMS").unwrap())?;
+        let tc_programs = ProgramArray::try_from(bpf.take_map("TC_PROGRAMS").unwrap())?;
+        for (index, name) in XDP_PROTOCOL_PROGRAMS {
+            let program: &mut Xdp = protocol_program(&mut bpf, name)?.try_into()?;
+            program
+                .load()
+                .map_err(|err| diagnostics::load_failed(name, err.into()))?;
+            xdp_programs.set(index, program.fd()?, 0)?;
+        }
+
+        let xdp: &mut Xdp = bpf
+            .program_mut(utils::PROGRAM_NAME)
+            .unwrap()
+            .try_into()?;
+        xdp.load()
+            .map_err(|err| diagnostics::load_failed(utils::PROGRAM_NAME, err.into()))?;
+        selftest::init(xdp.fd()?.as_fd().try_clone_to_owned()?);
+
+        let egress_loaded = load_egress_program(&mut bpf);
+        let mut datapath = Datapath {
+            bpf,
+            cni,
+            tc_loaded: false,
+            tc_programs,
+            egress_loaded,
+            egress: HashSet::new(),
+        };
+        let layer = config::get().attach_layer;
+        info!(target: "attach", "Attach layer: {}", layer);
+        let stacked = stacked_interfaces(layer);
+        for itf in interfaces.iter() {
+            match stacked.get(itf) {
+                Some(status) => set_status(itf, status.clone()),
+                None => datapath.attach_interface(itf),
+            }
+        }
+        // interfaces left out for their bond or bridge only count through it
+        let attached = {
+            let attach_status = utils::ATTACH_STATUS.lock().unwrap();
+            interfaces
+                .iter()
+                .filter(|itf| !stacked.contains_key(*itf))
+                .filter_map(|itf| attach_status.get(itf))
+                .any(|status| is_attached(status))
+        };
+        if !attached {
+            report();
+            anyhow::bail!("the datapath could not be attached to any interface");
+        }
+        report();
+        Ok(datapath)
+    }
+
+    // The maps are taken out of the attached object once the programs are in place, the programs
+    // stay with the interface watcher
+    pub fn take_maps(&mut self) -> HashMap<String, Map> {
+        let names: Vec<String> = self.bpf.maps().map(|(name, _)| name.to_string()).collect();
+        names
+            .into_iter()
+            .map(|name| {
+                let map = self.bpf.take_map(&name).unwrap();
+                (name, map)
+            })
+            .collect()
+    }
+
+    pub fn program_fd(&self) -> anyhow::Result<OwnedFd> {
+        let xdp: &Xdp = self.bpf.program(utils::PROGRAM_NAME).unwrap().try_into()?;
+        Ok(xdp.fd()?.as_fd().try_clone_to_owned()?)
+    }
+
+    // The egress program only goes where the ingress datapath is, the redirected packets it
+    // translates the replies of come in there
+    fn attach_interface(&mut self, itf: &str) {
+        let mut status = match self.try_attach_interface(itf) {
+            Ok(status) => status,
+            Err(err) => format!("failed: {}", err),
+        };
+        if self.egress_loaded && is_attached(&status) {
+            if !self.egress.contains(itf) {
+                match self.attach_egress(itf) {
+                    Ok(_) => {
+                        self.egress.insert(itf.to_string());
+                    }
+                    Err(err) => {
+                        warn!(target: "attach", "Failed to attach the egress program to {}, replies of redirected packets go out unchanged: {}", itf, err);
+                        status = format!("{}, tc egress failed: {}", status, err);
+                    }
+                }
+            }
+            if self.egress.contains(itf) {
+                status.push_str(EGRESS_STATUS);
+            }
+        }
+        set_status(itf, status);
+    }
+
+    // A netlink TC attachment can't be pinned like the XDP links, the one left by an agent that
+    // didn't shut down cleanly is replaced
+    fn attach_egress(&mut self, itf: &str) -> anyhow::Result<()> {
+        let program: &mut SchedClassifier = self
+            .bpf
+            .program_mut(EGRESS_PROGRAM_NAME)
+            .unwrap()
+            .try_into()?;
+        let _ = tc::qdisc_add_clsact(itf);
+        let _ = tc::qdisc_detach_program(itf, TcAttachType::Egress, EGRESS_PROGRAM_NAME);
+        program.attach(itf, TcAttachType::Egress)?;
+        Ok(())
+    }
+
+    fn detach_egress(&mut self, itf: &str) {
+        if !self.egress.remove(itf) {
+            return;
+        }
+        if let Err(err) = tc::qdisc_detach_program(itf, TcAttachType::Egress, EGRESS_PROGRAM_NAME) {
+            warn!(target: "attach", "Failed to detach the egress program from {}: {}", itf, err);
+        }
+    }
+
+    fn try_attach_interface(&mut self, itf: &str) -> anyhow::Result<String> {
+        let reason = if matches!(self.cni, Cni::Cilium | Cni::CalicoEbpf) {
+            format!("{} runs an eBPF datapath", self.cni)
+        } else {
+            let xdp: &mut Xdp = self
+                .bpf
+                .program_mut(utils::PROGRAM_NAME)
+                .unwrap()
+                .try_into()?;
+            // the link pinned by the previous agent keeps running its program until the swap
+            let link_pin = link_pin_path(itf);
+            if link_pin.exists() && replace_pinned_program(xdp, &link_pin) {
+                return Ok(xdp_status(itf, "replaced the pinned program"));
+            }
+            // don't replace an XDP program attached by someone else
+            match xdp.attach(itf, xdp_flags()) {
+                Ok(link_id) => {
+                    let pinned = pin_link(xdp, link_id, itf, &link_pin)?;
+                    return Ok(xdp_status(itf, &pinned));
+                }
+                Err(err) if is_busy(&err) => "another XDP program is attached".to_string(),
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        if self.bpf.program(TC_PROGRAM_NAME).is_none() {
+            return Ok(format!(
+                "skipped: {}, no TC program in the eBPF object",
+                reason
+            ));
+        }
+        if !self.tc_loaded {
+            self.load_tc_programs()?;
+            self.tc_loaded = true;
+        }
+        let tc_program: &mut SchedClassifier =
+            self.bpf.program_mut(TC_PROGRAM_NAME).unwrap().try_into()?;
+        // the clsact qdisc may already be there, e.g. added by the CNI
+        let _ = tc::qdisc_add_clsact(itf);
+        tc_program.attach(itf, TcAttachType::Ingress)?;
+        Ok(format!("tc ingress ({})", reason))
+    }
+
+    // The TC dispatcher and its protocol programs are only loaded for the first interface that needs
+    // them. A partial load is undone, so the next interface loads them all again
+    fn load_tc_programs(&mut self) -> anyhow::Result<()> {
+        let result = self.try_load_tc_programs();
+        if result.is_err() {
+            let names = TC_PROTOCOL_PROGRAMS.iter().map(|(_, name)| *name);
+            for name in names.chain([TC_PROGRAM_NAME]) {
+                let program: Option<&mut SchedClassifier> =
+                    self.bpf.program_mut(name).and_then(|p| p.try_into().ok());
+                if let Some(program) = program {
+                    // not loaded yet
+                    let _ = program.unload();
+                }
+            }
+        }
+        result
+    }
+
+    fn try_load_tc_programs(&mut self) -> anyhow::Result<()> {
+        for (index, name) in TC_PROTOCOL_PROGRAMS {
+            let program: &mut SchedClassifier =
+                protocol_program(&mut self.bpf, name)?.try_into()?;
+            program
+                .load()
+                .map_err(|err| diagnostics::load_failed(name, err.into()))?;
+            self.tc_programs.set(index, program.fd()?, 0)?;
+        }
+        let tc_program: &mut SchedClassifier =
+            self.bpf.program_mut(TC_PROGRAM_NAME).unwrap().try_into()?;
+        tc_program
+            .load()
+            .map_err(|err| diagnostics::load_failed(TC_PROGRAM_NAME, err.into()))?;
+        Ok(())
+    }
+
+    // Pod to pod traffic on a node never crosses the physical interfaces, so the veths the CNI
+    // creates for new pods get the datapath too. Link events only trigger a rescan of the
+    // interfaces. The XDP attachments are checked every --attach-check-interval seconds as well.
+    // On SIGTERM the egress program is detached before the agent exits, the pinned XDP links stay
+    pub async fn watch_interfaces(mut self) {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(err) => {
+                warn!(target: "attach", "Not detaching the egress program on SIGTERM: {}", err);
+                return std::future::pending().await;
+            }
+        };
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        thread::spawn(move || {
+            if let Err(err) = link_events(events_tx) {
+                warn!(target: "attach", "Not watching for new interfaces: {}", err);
+            }
+        });
+
+        let check_interval = config::get().attach_check_interval;
+        let mut checks = tokio::time::interval(Duration::from_secs(check_interval.max(1)));
+        let mut watching = true;
+        // the programs have to stay loaded even without link events or checks
+        loop {
+            tokio::select! {
+                event = events.recv(), if watching => {
+                    if event.is_none() {
+                        watching = false;
+                        continue;
+                    }
+                    // a new pod brings several link events, wait for them to settle
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    while events.try_recv().is_ok() {}
+                    self.sync_interfaces();
+                }
+                _ = checks.tick(), if check_interval > 0 => self.check_attachments(),
+                _ = terminate.recv() => self.shutdown(),
+                _ = tokio::signal::ctrl_c() => self.shutdown(),
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        let interfaces: Vec<String> = self.egress.iter().cloned().collect();
+        for itf in interfaces {
+            self.detach_egress(&itf);
+        }
+        info!(target: "attach", "Egress program detached, exiting");
+        std::process::exit(0);
+    }
+
+    // A NIC driver reset, an MTU change or another tool can remove our XDP program from an
+    // interface without a link event. It is attached again where no program is left, an interface
+    // taken over by another XDP program is only reported
+    fn check_attachments(&mut self) {
+        let attached = match xdp_program_ids() {
+            Ok(attached) => attached,
+            Err(err) => {
+                warn!(target: "attach", "Failed to query the XDP programs of the interfaces: {}", err);
+                return;
+            }
+        };
+        let ours = match self.xdp_program_id() {
+            Ok(id) => id,
+            Err(err) => {
+                warn!(target: "attach", "Failed to get the id of the XDP program: {}", err);
+                return;
+            }
+        };
+        let checked: Vec<String> = utils::ATTACH_STATUS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, status)| status.starts_with("xdp") || status.starts_with(DETACHED_STATUS))
+            .map(|(itf, _)| itf.clone())
+            .collect();
+        for itf in checked {
+            let status = status_of(&itf);
+            match attached.get(&itf) {
+                // deleted, the next rescan forgets it
+                None => {}
+                Some((id, _)) if *id == ours && status.starts_with("xdp") => {}
+                Some((id, mode)) if *id == ours => {
+                    info!(target: "attach", "XDP program is back on {}", itf);
+                    let egress = if self.egress.contains(&itf) {
+                        EGRESS_STATUS
+                    } else {
+                        ""
+                    };
+                    set_status(&itf, format!("xdp ({}){}", mode, egress));
+                }
+                Some((0, _)) => {
+                    warn!(target: "attach", "XDP program was detached from {}, attaching it again", itf);
+                    metrics::XDP_REATTACHES.inc();
+                    // the pinned link went with the program, a new one is pinned
+                    let _ = std::fs::remove_file(link_pin_path(&itf));
+                    self.attach_interface(&itf);
+                    info!(target: "attach", "{}: {}", itf, status_of(&itf));
+                }
+                Some((id, _)) => {
+                    let status = format!("{}: replaced by XDP program {}", DETACHED_STATUS, id);
+                    if status_of(&itf) != status {
+                        warn!(target: "attach", "XDP program of {} was replaced by program {}", itf, id);
+                        set_status(&itf, status);
+                    }
+                }
+            }
+        }
+    }
+
+    // Take the datapath off an interface that became the upper or lower interface of another one.
+    // Only a pinned XDP link can be detached, a netlink attachment stays until the next restart
+    fn detach_interface(&mut self, itf: &str) {
+        self.detach_egress(itf);
+        let link_pin = link_pin_path(itf);
+        let detached = if link_pin.exists() {
+            // the link goes away with its last file descriptor
+            PinnedLink::from_pin(&link_pin)
+                .map_err(anyhow::Error::from)
+                .and_then(|link| Ok(link.unpin()?))
+                .map(drop)
+        } else {
+            tc::qdisc_detach_program(itf, TcAttachType::Ingress, TC_PROGRAM_NAME)
+                .map_err(anyhow::Error::from)
+        };
+        if let Err(err) = detached {
+            warn!(target: "attach", "Failed to detach the datapath from {}, its packets may be counted twice until the agent restarts: {}", itf, err);
+        }
+    }
+
+    fn xdp_program_id(&self) -> anyhow::Result<u32> {
+        let xdp: &Xdp = self.bpf.program(utils::PROGRAM_NAME).unwrap().try_into()?;
+        Ok(xdp.info()?.id())
+    }
+
+    fn sync_interfaces(&mut self) {
+        let interfaces = match interface_names() {
+            Ok(interfaces) => interfaces,
+            Err(err) => {
+                warn!(target: "attach", "Failed to list interfaces: {}", err);
+                return;
+            }
+        };
+
+        let known = utils::ATTACH_STATUS.lock().unwrap().clone();
+        // an interface is often enslaved to its bond or bridge right after it shows up
+        let stacked = stacked_interfaces(config::get().attach_layer);
+        for itf in interfaces.iter() {
+            let status = known.get(itf);
+            match (stacked.get(itf), status) {
+                (Some(stacked), Some(status)) if stacked == status => {}
+                (Some(stacked), Some(status)) if !status.starts_with(STACKED_STATUS) => {
+                    self.detach_interface(itf);
+                    set_status(itf, stacked.clone());
+                    info!(target: "attach", "Interface {} was stacked: {}", itf, stacked);
+                }
+                (Some(stacked), _) => set_status(itf, stacked.clone()),
+                (None, Some(status)) if !status.starts_with(STACKED_STATUS) => {}
+                (None, _) => {
+                    self.attach_interface(itf);
+                    info!(target: "attach", "New interface {}: {}", itf, status_of(itf));
+                }
+            }
+        }
+        // links of deleted interfaces are gone with them, only their pins are left
+        utils::ATTACH_STATUS.lock().unwrap().retain(|itf, _| {
+            let exists = itf == CGROUP_STATUS || interfaces.contains(itf);
+            if !exists {
+                self.egress.remove(itf);
+                let _ = std::fs::remove_file(link_pin_path(itf));
+                let _ = metrics::INTERFACE_ATTACHED.remove_label_values(&[itf]);
+            }
+            exists
+        });
+    }
+}
+
+fn xdp_flags() -> XdpFlags {
+    XdpFlags::SKB_MODE | XdpFlags::UPDATE_IF_NOEXIST
+}
+
+fn link_pin_path(itf: &str) -> PathBuf {
+    config::get().pin_path.join("links").join(itf)
+}
+
+// Swap the program of a pinned link for ours in one update, so the interface is never left without
+// a program. A pin that can't be reused (e.g. its interface was recreated) is removed
+fn replace_pinned_program(xdp: &mut Xdp, link_pin: &Path) -> bool {
+    let mut replace = || -> anyhow::Result<()> {
+        let link = PinnedLink::from_pin(link_pin)?;
+        xdp.attach_to_link(XdpLink::try_from(FdLink::from(link))?)?;
+        Ok(())
+    };
+    match replace() {
+        Ok(_) => true,
+        Err(err) => {
+            warn!(target: "attach", "Not reusing pinned link {}: {:#}", link_pin.display(), err);
+            let _ = std::fs::remove_file(link_pin);
+            false
+        }
+    }
+}
+
+// Pin the link so the program stays attached while the agent restarts. Only bpf_link based XDP
+// attachments (kernel 5.9+, which aya uses there) can be pinned. Taking the link from the program
+// and failing to pin it would detach the program, so the link is only taken once nothing but the
+// pin syscall itself can fail; otherwise the program keeps it until the agent exits
+fn pin_link(
+    xdp: &mut Xdp,
+    link_id: XdpLinkId,
+    itf: &str,
+    link_pin: &Path,
+) -> anyhow::Result<String> {
+    if !KernelVersion::current().is_ok_and(|version| version >= KernelVersion::new(5, 9, 0)) {
+        return Ok("not pinned: links need kernel 5.9+".to_string());
+    }
+    if let Err(err) = prepare_link_pin(link_pin) {
+        return Ok(format!("not pinned: {:#}", err));
+    }
+    let link = FdLink::try_from(xdp.take_link(link_id)?)?;
+    match link.pin(link_pin) {
+        Ok(_) => Ok("pinned".to_string()),
+        Err(err) => {
+            // the link was closed with the failed pin, the gate is back right away
+            warn!(target: "attach", "Failed to pin the link of {}, attaching again: {}", itf, err);
+            xdp.attach(itf, xdp_flags())?;
+            Ok(format!("not pinned: {}", err))
+        }
+    }
+}
+
+// A pin needs its directory on bpffs and no file at its path
+fn prepare_link_pin(link_pin: &Path) -> anyhow::Result<()> {
+    let dir = link_pin
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("{} has no directory", link_pin.display()))?;
+    std::fs::create_dir_all(dir)?;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let mut stat: libc::statfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if stat.f_type as u64 != BPF_FS_MAGIC {
+        anyhow::bail!("{} is not on bpffs", dir.display());
+    }
+    match std::fs::remove_file(link_pin) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+fn interface_names() -> anyhow::Result<Vec<String>> {
+    Ok(NetworkInterface::show()?
+        .into_iter()
+        .map(|itf| itf.name)
+        .collect())
+}
+
+// Block on a netlink socket subscribed to link changes, sending a notification for each message
+fn link_events(events: mpsc::UnboundedSender<()>) -> std::io::Result<()> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // closes the socket on return
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    addr.nl_groups = libc::RTMGRP_LINK as u32;
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut buf = [0u8; 8192];
+    loop {
+        let len = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            let err = std::io::Error::last_os_error();
+            match err.raw_os_error() {
+                // events were lost, a rescan catches up anyway
+                Some(libc::ENOBUFS) => {}
+                Some(libc::EINTR) => continue,
+                _ => return Err(err),
+            }
+        }
+        if events.send(()).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+// XDP program id of each interface, 0 without one
+fn xdp_program_ids() -> std::io::Result<HashMap<String, (u32, &'static str)>> {
+    Ok(links()?
+        .into_iter()
+        .map(|link| (link.name, (link.xdp_program, link.xdp_mode)))
+        .collect())
+}
+
+// XDP_ATTACHED_* value of IFLA_XDP_ATTACHED. With programs in several modes, the SKB one is the
+// one reported as the program of the interface
+fn xdp_mode(attached: u8) -> &'static str {
+    match attached {
+        0 => "",
+        1 => "native mode",
+        3 => "offloaded",
+        _ => "SKB mode",
+    }
+}
+
+// Status of an interface our XDP program was just attached to, with the mode the kernel reports
+fn xdp_status(itf: &str, detail: &str) -> String {
+    let mode = match links() {
+        Ok(links) => links
+            .into_iter()
+            .find(|link| link.name == itf)
+            .map_or("", |link| link.xdp_mode),
+        Err(err) => {
+            warn!(target: "attach", "Failed to query the XDP mode of {}: {}", itf, err);
+            ""
+        }
+    };
+    match mode {
+        "" => format!("xdp, {}", detail),
+        mode => format!("xdp ({}), {}", mode, detail),
+    }
+}
+
+// Every interface of the node, from a netlink dump of the links
+fn links() -> std::io::Result<Vec<Link>> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    #[repr(C)]
+    struct Request {
+        header: libc::nlmsghdr,
+        info: libc::ifinfomsg,
+    }
+    let mut request: Request = unsafe { mem::zeroed() };
+    request.header.nlmsg_len = mem::size_of::<Request>() as u32;
+    request.header.nlmsg_type = libc::RTM_GETLINK;
+    request.header.nlmsg_flags = (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16;
+    request.header.nlmsg_seq = 1;
+    request.info.ifi_family = libc::AF_UNSPEC as u8;
+    let ret = unsafe {
+        libc::send(
+            socket.as_raw_fd(),
+            &request as *const Request as *const libc::c_void,
+            mem::size_of::<Request>(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let header_len = mem::size_of::<libc::nlmsghdr>();
+    let info_len = mem::size_of::<libc::ifinfomsg>();
+    let mut links = Vec::new();
+    let mut buf = vec![0u8; 32768];
+    loop {
+        let len = unsafe {
+            libc::recv(
+                socket.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut messages = &buf[..len as usize];
+        while messages.len() >= header_len {
+            let header = unsafe { (messages.as_ptr() as *const libc::nlmsghdr).read_unaligned() };
+            let message_len = header.nlmsg_len as usize;
+            if message_len < header_len || message_len > messages.len() {
+                break;
+            }
+            match header.nlmsg_type as i32 {
+                libc::NLMSG_DONE => return Ok(links),
+                libc::NLMSG_ERROR => {
+                    let code = messages
+                        .get(header_len..header_len + 4)
+                        .map(|code| i32::from_ne_bytes(code.try_into().unwrap()))
+                        .unwrap_or(0);
+                    return Err(std::io::Error::from_raw_os_error(-code));
+                }
+                _ if header.nlmsg_type == libc::RTM_NEWLINK => {
+                    if let Some(attributes) = messages.get(header_len + info_len..message_len) {
+                        let info = unsafe {
+                            (messages[header_len..].as_ptr() as *const libc::ifinfomsg)
+                                .read_unaligned()
+                        };
+                        let mut link = Link {
+                            index: info.ifi_index as u32,
+                            ..Default::default()
+                        };
+                        for (kind, value) in netlink_attributes(attributes) {
+                            match kind {
+                                IFLA_IFNAME => link.name = netlink_string(value),
+                                IFLA_MASTER if value.len() >= 4 => {
+                                    link.master = u32::from_ne_bytes(value[..4].try_into().unwrap())
+                                }
+                                IFLA_LINKINFO => {
+                                    for (kind, value) in netlink_attributes(value) {
+                                        if kind == IFLA_INFO_KIND {
+                                            link.kind = netlink_string(value);
+                                        }
+                                    }
+                                }
+                                IFLA_XDP => {
+                                    for (kind, value) in netlink_attributes(value) {
+                                        if kind == IFLA_XDP_ATTACHED && !value.is_empty() {
+                                            link.xdp_mode = xdp_mode(value[0]);
+                                        }
+                                        if (kind == IFLA_XDP_PROG_ID
+                                            || (kind == IFLA_XDP_SKB_PROG_ID
+                                                && link.xdp_program == 0))
+                                            && value.len() >= 4
+                                        {
+                                            link.xdp_program =
+                                                u32::from_ne_bytes(value[..4].try_into().unwrap());
+                                        }
+                                    }
+                                }
+                                _ => {}
+                            }
+                        }
+                        if !link.name.is_empty() {
+                            links.push(link);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            // messages are aligned to 4 bytes
+            let aligned = (message_len + 3) & !3;
+            messages = messages.get(aligned..).unwrap_or_default();
+        }
+    }
+}
+
+fn netlink_string(value: &[u8]) -> String {
+    String::from_utf8_lossy(value)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+// Type (without the nested and byte order flags) and value of each attribute of a netlink message
+fn netlink_attributes(mut attributes: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut parsed = Vec::new();
+    while attributes.len() >= 4 {
+        let len = u16::from_ne_bytes([attributes[0], attributes[1]]) as usize;
+        let kind = u16::from_ne_bytes([attributes[2], attributes[3]]) & NLA_TYPE_MASK;
+        if len < 4 || len > attributes.len() {
+            break;
+        }
+        parsed.push((kind, &attributes[4..len]));
+        let aligned = (len + 3) & !3;
+        attributes = attributes.get(aligned..).unwrap_or_default();
+    }
+    parsed
+}
+
+fn protocol_program<'a>(
+    bpf: &'a mut Bpf,
+    name: &str,
+) -> anyhow::Result<&'a mut aya::programs::Program> {
+    bpf.program_mut(name)
+        .ok_or_else(|| anyhow::anyhow!("eBPF object has no {} program", name))
+}
+
+// The egress program is only needed by gated-action redirect, an object without it or a failed
+// load leaves the replies of redirected packets unchanged
+fn load_egress_program(bpf: &mut Bpf) -> bool {
+    let program = match bpf.program_mut(EGRESS_PROGRAM_NAME) {
+        Some(program) => program,
+        None => return false,
+    };
+    let mut load = || -> anyhow::Result<()> {
+        let program: &mut SchedClassifier = program.try_into()?;
+        program
+            .load()
+            .map_err(|err| diagnostics::load_failed(EGRESS_PROGRAM_NAME, err.into()))?;
+        Ok(())
+    };
+    match load() {
+        Ok(_) => true,
+        Err(err) => {
+            warn!(target: "attach", "Not loading the egress program, replies of redirected packets go out unchanged: {:#}", err);
+            false
+        }
+    }
+}
+
+fn attach_connect_hook(bpf: &mut Bpf, reports: bool) {
+    let cgroup_path = &config::get().cgroup_path;
+    if reports {
+        let enabled = bpf
+            .map_mut("CONNECT_REPORTS")
+            .ok_or_else(|| anyhow::anyhow!("eBPF object has no CONNECT_REPORTS map"))
+            .and_then(|map| Ok(Array::<&mut MapData, u32>::try_from(map)?.set(0, 1, 0)?));
+        if let Err(err) = enabled {
+            warn!(target: "attach", "Failed to enable the reports of the connect hook: {:#}", err);
+        }
+    }
+    let status = match bpf.program_mut(CONNECT_PROGRAM_NAME) {
+        None => "skipped: no connect program in the eBPF object".to_string(),
+        Some(program) => {
+            let mut attach = || -> anyhow::Result<()> {
+                let program: &mut CgroupSockAddr = program.try_into()?;
+                program
+                    .load()
+                    .map_err(|err| diagnostics::load_failed(CONNECT_PROGRAM_NAME, err.into()))?;
+                program.attach(File::open(cgroup_path)?)?;
+                Ok(())
+            };
+            match attach() {
+                Ok(_) => format!("connect4 on {}", cgroup_path.display()),
+                Err(err) if reports => format!("failed: {}", err),
+                // only gated-action redirect needs it
+                Err(err) => format!("skipped: no redirects, {}", err),
+            }
+        }
+    };
+    set_status(CGROUP_STATUS, status);
+}
+
+fn is_attached(status: &str) -> bool {
+    !["failed", "skipped", DETACHED_STATUS]
+        .iter()
+        .any(|prefix| status.starts_with(prefix))
+}
+
+// Empty for an interface without a status, e.g. deleted meanwhile
+fn status_of(itf: &str) -> String {
+    utils::ATTACH_STATUS
+        .lock()
+        .unwrap()
+        .get(itf)
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn set_status(itf: &str, status: String) {
+    metrics::INTERFACE_ATTACHED
+        .with_label_values(&[itf])
+        .set(is_attached(&status) as i64);
+    utils::ATTACH_STATUS
+        .lock()
+        .unwrap()
+        .insert(itf.to_string(), status);
+}
+
+// Attach statuses sent by the loader to the agent it started, whenever they change
+#[derive(Serialize, Deserialize, PartialEq)]
+struct StatusUpdate {
+    interfaces: BTreeMap<String, String>,
+    reattaches: u64,
+}
+
+// Sends the attach statuses of the loader to its agent, which serves them on the admin API
+pub async fn publish_status(mut agent: pipe::Sender) {
+    let mut checks = tokio::time::interval(Duration::from_secs(1));
+    let mut sent = None;
+    loop {
+        checks.tick().await;
+        let update = StatusUpdate {
+            interfaces: utils::ATTACH_STATUS.lock().unwrap().clone(),
+            reattaches: metrics::XDP_REATTACHES.get(),
+        };
+        if sent.as_ref() == Some(&update) {
+            continue;
+        }
+        let mut line = serde_json::to_vec(&update).unwrap();
+        line.push(b'\n');
+        if let Err(err) = agent.write_all(&line).await {
+            warn!(target: "attach", "Failed to send the attach statuses to the agent: {}", err);
+            return;
+        }
+        sent = Some(update);
+    }
+}
+
+// Keeps ATTACH_STATUS and the attach metrics of the agent in line with its loader
+pub async fn follow_status(loader: pipe::Receiver) {
+    let mut lines = BufReader::new(loader).lines();
+    let mut reattaches = 0;
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                warn!(target: "attach", "Failed to read the attach statuses of the loader: {}", err);
+                break;
+            }
+        };
+        let update: StatusUpdate = match serde_json::from_str(&line) {
+            Ok(update) => update,
+            Err(err) => {
+                warn!(target: "attach", "Invalid attach statuses from the loader: {}", err);
+                continue;
+            }
+        };
+        let mut attach_status = utils::ATTACH_STATUS.lock().unwrap();
+        for itf in attach_status.keys() {
+            if !update.interfaces.contains_key(itf) {
+                let _ = metrics::INTERFACE_ATTACHED.remove_label_values(&[itf]);
+            }
+        }
+        for (itf, status) in update.interfaces.iter() {
+            metrics::INTERFACE_ATTACHED
+                .with_label_values(&[itf])
+                .set(is_attached(status) as i64);
+        }
+        metrics::XDP_REATTACHES.inc_by(update.reattaches.saturating_sub(reattaches));
+        reattaches = update.reattaches;
+        *attach_status = update.interfaces;
+    }
+    warn!(target: "attach", "The loader stopped sending attach statuses");
+}
+
+fn report() {
+    info!(target: "attach", "Attach report:");
+    for (itf, status) in utils::ATTACH_STATUS.lock().unwrap().iter() {
+        // interfaces left out for their bond or bridge are covered by it
+        if status.starts_with(STACKED_STATUS) {
+            info!(target: "attach", "  {}: {}", itf, status);
+        } else if status.starts_with("failed") || status.starts_with("skipped") {
+            warn!(target: "attach", "  {}: {}", itf, status);
+        } else {
+            info!(target: "attach", "  {}: {}", itf, status);
+        }
+    }
+}
+
+// EBUSY means another XDP program is attached and UPDATE_IF_NOEXIST kept it
+fn is_busy(err: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(io_error) = err.downcast_ref::<std::io::Error>() {
+            if io_error.raw_os_error() == Some(libc::EBUSY) {
+                return true;
+            }
+        }
+        source = err.source();
+    }
+    false
+}
@@ -0,0 +1,213 @@
+use anyhow::Context;
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_server::tls_rustls::RustlsConfig;
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec, UserInfo};
+use k8s_openapi::api::authorization::v1::{
+    NonResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec,
+};
+use kube::api::{Api, PostParams};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::kubernetes;
+
+// TokenReview and SubjectAccessReview results are reused for this long, so a scraper doesn't cost
+// a review per request
+const TOKEN_CACHE_TTL: Duration = Duration::from_secs(60);
+
+type ReviewedUser = (Instant, Option<UserInfo>);
+type AccessReviewKey = (Vec<u8>, String, String);
+
+// This contains the user of each token (by sha256) the TokenReview authenticated, None when it
+// didn't, and when it was reviewed
+static REVIEWED_TOKENS: Lazy<Mutex<HashMap<Vec<u8>, ReviewedUser>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// This contains the SubjectAccessReview result of each token (by sha256), verb and path, and when
+// it was reviewed
+static REVIEWED_ACCESS: Lazy<Mutex<HashMap<AccessReviewKey, (Instant, bool)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// TLS settings of the admin API, None when it serves plain HTTP
+pub fn tls_config(opts: &config::Options) -> anyhow::Result<Option<RustlsConfig>> {
+    let (cert, key) = match (opts.admin_tls_cert.as_ref(), opts.admin_tls_key.as_ref()) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Ok(None),
+    };
+    let certs = load_certs(cert)?;
+    let key = load_key(key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let server_config = match opts.admin_client_ca.as_ref() {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots.add(&cert)?;
+            }
+            builder
+                .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+                .with_single_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth().with_single_cert(certs, key)?,
+    };
+    Ok(Some(RustlsConfig::from_config(Arc::new(server_config))))
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    let certs = rustls_pemfile::certs(&mut reader)?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate in {}", path.display());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    for item in rustls_pemfile::read_all(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    anyhow::bail!("No private key in {}", path.display())
+}
+
+// Middleware rejecting requests without a bearer token the API server authenticates for the
+// audience of the agent, and whose user may not use the path with the verb (the lowercase HTTP
+// method) as a non-resource URL
+pub async fn require_token(request: Request, next: Next) -> Response {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let token = match token {
+        Some(token) => token,
+        None => return (StatusCode::UNAUTHORIZED, "Missing bearer token").into_response(),
+    };
+
+    let key = Sha256::digest(token.as_bytes()).to_vec();
+    let user = match review(&key, &token).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return (StatusCode::UNAUTHORIZED, "Invalid bearer token").into_response(),
+        Err(err) => {
+            warn!(target: "admin", "TokenReview failed: {:#}", err);
+            return (StatusCode::SERVICE_UNAVAILABLE, "Token can't be reviewed").into_response();
+        }
+    };
+    let verb = request.method().as_str().to_lowercase();
+    let path = request.uri().path().to_string();
+    match authorize(key, &user, verb, path).await {
+        Ok(true) => next.run(request).await,
+        Ok(false) => (StatusCode::FORBIDDEN, "Not allowed").into_response(),
+        Err(err) => {
+            warn!(target: "admin", "SubjectAccessReview failed: {:#}", err);
+            (StatusCode::SERVICE_UNAVAILABLE, "Access can't be reviewed").into_response()
+        }
+    }
+}
+
+async fn review(key: &[u8], token: &str) -> anyhow::Result<Option<UserInfo>> {
+    if let Some((reviewed, user)) = REVIEWED_TOKENS.lock().unwrap().get(key) {
+        if reviewed.elapsed() < TOKEN_CACHE_TTL {
+            return Ok(user.clone());
+        }
+    }
+
+    let audience = config::get().admin_token_audience.clone();
+    let reviews: Api<TokenReview> = Api::all(kubernetes::client().await?);
+    let review = TokenReview {
+        spec: TokenReviewSpec {
+            token: Some(token.to_string()),
+            audiences: Some(vec![audience.clone()]),
+        },
+        ..Default::default()
+    };
+    let status = reviews
+        .create(&PostParams::default(), &review)
+        .await?
+        .status
+        .unwrap_or_default();
+    // the API server only authenticates a token for one of the audiences it was asked about
+    let for_agent = status
+        .audiences
+        .as_ref()
+        .is_some_and(|audiences| audiences.contains(&audience));
+    let user = status
+        .user
+        .filter(|_| status.authenticated == Some(true) && for_agent);
+    if let Some(user) = user.as_ref() {
+        info!(target: "admin", "Authenticated admin API client {}", user.username.as_deref().unwrap_or_default());
+    }
+
+    let mut reviewed = REVIEWED_TOKENS.lock().unwrap();
+    reviewed.retain(|_, (time, _)| time.elapsed() < TOKEN_CACHE_TTL);
+    reviewed.insert(key.to_vec(), (Instant::now(), user.clone()));
+    Ok(user)
+}
+
+async fn authorize(
+    key: Vec<u8>,
+    user: &UserInfo,
+    verb: String,
+    path: String,
+) -> anyhow::Result<bool> {
+    let key = (key, verb, path);
+    if let Some((reviewed, allowed)) = REVIEWED_ACCESS.lock().unwrap().get(&key) {
+        if reviewed.elapsed() < TOKEN_CACHE_TTL {
+            return Ok(*allowed);
+        }
+    }
+
+    let reviews: Api<SubjectAccessReview> = Api::all(kubernetes::client().await?);
+    let review = SubjectAccessReview {
+        spec: SubjectAccessReviewSpec {
+            user: user.username.clone(),
+            uid: user.uid.clone(),
+            groups: user.groups.clone(),
+            extra: user.extra.clone(),
+            non_resource_attributes: Some(NonResourceAttributes {
+                verb: Some(key.1.clone()),
+                path: Some(key.2.clone()),
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let allowed = reviews
+        .create(&PostParams::default(), &review)
+        .await?
+        .status
+        .is_some_and(|status| status.allowed);
+    if !allowed {
+        info!(target: "admin", "Admin API client {} may not {} {}", user.username.as_deref().unwrap_or_default(), key.1, key.2);
+    }
+
+    let mut reviewed = REVIEWED_ACCESS.lock().unwrap();
+    reviewed.retain(|_, (time, _)| time.elapsed() < TOKEN_CACHE_TTL);
+    reviewed.insert(key, (Instant::now(), allowed));
+    Ok(allowed)
+}
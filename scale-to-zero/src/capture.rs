@@ -0,0 +1,223 @@
+use aya::{
+    maps::{perf::AsyncPerfEventArray, HashMap, MapData},
+    util::online_cpus,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use k8s_openapi::serde_json::{json, Value};
+use log::warn;
+use once_cell::sync::Lazy;
+use scale_to_zero_common::CaptureHeader;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio::task;
+
+use crate::activity;
+
+const ETH_HDR_LEN: usize = 14;
+
+// Services with a running capture, mapped to the snapshot length and the number of running captures
+static CAPTURE_REQUESTS: Lazy<Mutex<std::collections::HashMap<u32, (u32, usize)>>> =
+    Lazy::new(|| Mutex::new(std::collections::HashMap::new()));
+
+// Packets received from the eBPF program, fanned out to every running capture
+static CAPTURED: Lazy<broadcast::Sender<CapturedPacket>> =
+    Lazy::new(|| broadcast::channel(1024).0);
+
+#[derive(Clone)]
+pub struct CapturedPacket {
+    pub timestamp: SystemTime,
+    pub ipv4_address: u32,
+    pub packet_len: u32,
+    pub data: Bytes,
+}
+
+// A capture of the packets dropped for a service, the capture stops when this is dropped
+pub struct Capture {
+    ipv4_address: u32,
+    receiver: broadcast::Receiver<CapturedPacket>,
+}
+
+impl Capture {
+    pub fn start(ipv4_address: u32, snaplen: u32) -> Capture {
+        let mut requests = CAPTURE_REQUESTS.lock().unwrap();
+        let request = requests.entry(ipv4_address).or_insert((snaplen, 0));
+        request.0 = request.0.max(snaplen);
+        request.1 += 1;
+
+        Capture {
+            ipv4_address,
+            receiver: CAPTURED.subscribe(),
+        }
+    }
+
+    pub async fn next(&mut self) -> Option<CapturedPacket> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(packet) if packet.ipv4_address == self.ipv4_address => return Some(packet),
+                Ok(_) => continue,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!(target: "capture", "Capture of {} skipped {} packets", Ipv4Addr::from(self.ipv4_address), skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let mut requests = CAPTURE_REQUESTS.lock().unwrap();
+        if let Some(request) = requests.get_mut(&self.ipv4_address) {
+            request.1 -= 1;
+            if request.1 == 0 {
+                requests.remove(&self.ipv4_address);
+            }
+        }
+    }
+}
+
+impl CapturedPacket {
+    // Decode the IPv4 and TCP/UDP headers, captured data always starts at the ethernet header
+    pub fn to_json(&self) -> Value {
+        let timestamp = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut packet = json!({
+            "timestamp": timestamp,
+            "service_ip": Ipv4Addr::from(self.ipv4_address).to_string(),
+            "packet_len": self.packet_len,
+        });
+
+        if self.data.len() < ETH_HDR_LEN + 20 {
+            return packet;
+        }
+        let ip = &self.data[ETH_HDR_LEN..];
+        let ihl = ((ip[0] & 0x0f) as usize * 4).min(ip.len());
+        let protocol = ip[9];
+        packet["src_ip"] = json!(Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]).to_string());
+        packet["dst_ip"] = json!(Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]).to_string());
+        packet["protocol"] = json!(match protocol {
+            1 => "icmp",
+            6 => "tcp",
+            17 => "udp",
+            _ => "other",
+        });
+
+        let l4 = &ip[ihl..];
+        if (protocol == 6 || protocol == 17) && l4.len() >= 4 {
+            packet["src_port"] = json!(u16::from_be_bytes([l4[0], l4[1]]));
+            packet["dst_port"] = json!(u16::from_be_bytes([l4[2], l4[3]]));
+        }
+        if protocol == 6 && l4.len() >= 14 {
+            packet["tcp_flags"] = json!(tcp_flags(l4[13]));
+        }
+        packet
+    }
+}
+
+fn tcp_flags(flags: u8) -> String {
+    let names = ["FIN", "SYN", "RST", "PSH", "ACK", "URG", "ECE", "CWR"];
+    names
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| flags & (1 << bit) != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Write the packets in the classic pcap format (microsecond timestamps, ethernet link type)
+pub fn to_pcap(packets: &[CapturedPacket], snaplen: u32) -> Bytes {
+    let mut pcap = BytesMut::new();
+    pcap.put_u32_le(0xa1b2c3d4);
+    pcap.put_u16_le(2);
+    pcap.put_u16_le(4);
+    pcap.put_i32_le(0);
+    pcap.put_u32_le(0);
+    pcap.put_u32_le(snaplen);
+    pcap.put_u32_le(1);
+
+    for packet in packets {
+        let ts = packet
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        pcap.put_u32_le(ts.as_secs() as u32);
+        pcap.put_u32_le(ts.subsec_micros());
+        pcap.put_u32_le(packet.data.len() as u32);
+        pcap.put_u32_le(packet.packet_len);
+        pcap.put_slice(&packet.data);
+    }
+    pcap.freeze()
+}
+
+pub fn start_readers(mut perf_array: AsyncPerfEventArray<MapData>) -> anyhow::Result<()> {
+    for cpu_id in online_cpus()? {
+        let mut buf = perf_array.open(cpu_id, None)?;
+
+        task::spawn(async move {
+            let mut buffers = (0..10)
+                .map(|_| BytesMut::with_capacity(1024))
+                .collect::<Vec<_>>();
+
+            loop {
+                let events = match buf.read_events(&mut buffers).await {
+                    Ok(events) => events,
+                    Err(err) => {
+                        warn!(target: "capture", "Failed to read the captured packets of CPU {}: {}", cpu_id, err);
+                        tokio::time::sleep(activity::READ_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                };
+                for buf in buffers.iter_mut().take(events.read) {
+                    let ptr = buf.as_ptr() as *const CaptureHeader;
+                    let header = unsafe { ptr.read_unaligned() };
+                    let start = mem::size_of::<CaptureHeader>();
+                    let end = (start + header.captured_len as usize).min(buf.len());
+
+                    // no receivers means no capture is running anymore
+                    let _ = CAPTURED.send(CapturedPacket {
+                        timestamp: SystemTime::now(),
+                        ipv4_address: header.ipv4_address,
+                        packet_len: header.packet_len,
+                        data: Bytes::copy_from_slice(&buf[start..end]),
+                    });
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+// sync the kernel capture list with the running captures
+pub async fn sync_capture_list(mut capture_map: HashMap<MapData, u32, u32>) {
+    loop {
+        let requests: std::collections::HashMap<u32, u32> = CAPTURE_REQUESTS
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ip, (snaplen, _))| (*ip, *snaplen))
+            .collect();
+
+        for (ip, snaplen) in requests.iter() {
+            if capture_map.get(ip, 0).ok() != Some(*snaplen) {
+                if let Err(err) = capture_map.insert(ip, snaplen, 0) {
+                    warn!(target: "capture", "Failed to start capture of {}: {}", Ipv4Addr::from(*ip), err);
+                }
+            }
+        }
+
+        let keys: Vec<u32> = capture_map.keys().filter_map(|k| k.ok()).collect();
+        for ip in keys {
+            if !requests.contains_key(&ip) {
+                let _ = capture_map.remove(&ip);
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
@@ -0,0 +1,286 @@
+use clap::Parser;
+use once_cell::sync::OnceCell;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+use crate::attach::{AttachLayer, ProxyMode};
+use crate::kubernetes::models::{parse_duration, DEFAULT_ANNOTATION_PREFIX};
+use crate::logging::LogFormat;
+
+#[derive(Debug, Clone, Parser)]
+pub struct Options {
+    /// Check the configuration, the annotations of the watched services, the RBAC permissions and
+    /// the kernel, then exit without attaching anything. Exits with an error when a check fails
+    #[clap(long)]
+    pub validate: bool,
+    /// Kubeconfig used to reach the cluster from outside of it, instead of the in-cluster config
+    #[clap(long)]
+    pub kubeconfig: Option<PathBuf>,
+    /// Kubeconfig context to use, defaults to the current context
+    #[clap(long)]
+    pub context: Option<String>,
+    /// User to impersonate for the kubernetes API requests
+    #[clap(long = "as")]
+    pub impersonate: Option<String>,
+    /// Group to impersonate for the kubernetes API requests, can be repeated
+    #[clap(long = "as-group", requires = "impersonate")]
+    pub impersonate_groups: Vec<String>,
+    /// Namespaces to watch (comma separated), each through namespaced API objects so a Role in each
+    /// of them is enough. Defaults to the namespace of the service account or kubeconfig context
+    #[clap(long, value_delimiter = ',')]
+    pub namespaces: Vec<String>,
+    /// Name of the node the agent runs on, identifies it in the scale-up leases. Defaults to the
+    /// hostname
+    #[clap(long, env = "NODE_NAME")]
+    pub node_name: Option<String>,
+    /// kube-proxy mode of the node, detected when not set. The eBPF mode adds a cgroup connect
+    /// hook since ClusterIPs never reach the interfaces
+    #[clap(long, value_enum)]
+    pub proxy_mode: Option<ProxyMode>,
+    /// Root of the host cgroup v2 hierarchy, the connect hook is attached to it
+    #[clap(long, default_value = "/sys/fs/cgroup")]
+    pub cgroup_path: PathBuf,
+    /// Interface whose gated packets of latency-critical services are held through AF_XDP
+    /// sockets and reinjected once the service is awake, instead of being dropped. Without it
+    /// latency-critical and buffer-udp have no effect
+    #[clap(long)]
+    pub hold_interface: Option<String>,
+    /// Maximum number of packets held per service
+    #[clap(long, default_value = "64")]
+    pub hold_max_frames: usize,
+    /// IPs shared by several services, e.g. the load balancer of an ingress controller (comma
+    /// separated). TLS connections to them wake the service of their server name
+    #[clap(long, value_delimiter = ',')]
+    pub sni_entrypoints: Vec<Ipv4Addr>,
+    /// Address the admin API listens on
+    #[clap(long, default_value = "127.0.0.1:9090")]
+    pub admin_addr: SocketAddr,
+    /// Address of the built-in page telling redirected clients that the service is starting up,
+    /// the target of gated-action redirect without an address. Not served when not set
+    #[clap(long)]
+    pub waking_page_addr: Option<SocketAddr>,
+    /// HTML of the waking page, with {host} and {retry_after} replaced. A built-in page when not
+    /// set
+    #[clap(long)]
+    pub waking_page: Option<PathBuf>,
+    /// Retry-After (seconds) of the waking page responses
+    #[clap(long, default_value = "5")]
+    pub waking_page_retry_after: u64,
+    /// Address of the node, the redirects to the waking page go to it when --waking-page-addr
+    /// listens on all addresses
+    #[clap(long, env = "NODE_IP")]
+    pub node_ip: Option<Ipv4Addr>,
+    /// Directory of bpffs where the maps and XDP links are pinned, an agent started later (e.g. an
+    /// upgrade) takes them over and swaps its program in without detaching it
+    #[clap(long, default_value = "/sys/fs/bpf/scale-to-zero")]
+    pub pin_path: PathBuf,
+    /// Certificate chain (PEM) of the admin API, which serves HTTPS when it is set
+    #[clap(long, requires = "admin_tls_key")]
+    pub admin_tls_cert: Option<PathBuf>,
+    /// Private key (PEM) of --admin-tls-cert
+    #[clap(long, requires = "admin_tls_cert")]
+    pub admin_tls_key: Option<PathBuf>,
+    /// CA bundle (PEM) the client certificates of the admin API are verified against, clients
+    /// without a valid certificate are rejected
+    #[clap(long, requires = "admin_tls_cert")]
+    pub admin_client_ca: Option<PathBuf>,
+    /// Require a bearer token on the admin API, validated with a Kubernetes TokenReview
+    #[clap(long)]
+    pub admin_token_auth: bool,
+    /// Audience the bearer tokens of the admin API have to be issued for, e.g. with `kubectl
+    /// create token --audience`
+    #[clap(long, default_value = "scale-to-zero")]
+    pub admin_token_audience: String,
+    /// Load, pin and attach the datapath in a loader process and run the agent as its child
+    /// without the capabilities of the setup, only CAP_PERFMON (CAP_NET_RAW to hold packets, and
+    /// CAP_BPF on kernels that check it on map operations) are kept
+    #[clap(long)]
+    pub drop_privileges: bool,
+    /// Seconds between the checks that the XDP program is still attached to each interface, 0
+    /// disables them
+    #[clap(long, default_value = "30")]
+    pub attach_check_interval: u64,
+    /// Which interfaces of bonds and bridges get the datapath: auto (the bond, not its slaves, and
+    /// the ports of a bridge, not the bridge), upper (bonds and bridges), lower (their slaves and
+    /// ports) or all
+    #[clap(long, value_enum, default_value = "auto")]
+    pub attach_layer: AttachLayer,
+    /// Load the eBPF object from this file instead of the one embedded at build time
+    #[clap(long)]
+    pub bpf_object: Option<PathBuf>,
+    /// Expected sha256 checksum (hex) of the eBPF object, embedded or given with --bpf-object
+    #[clap(long)]
+    pub bpf_object_sha256: Option<String>,
+    /// Detached ed25519 signature (64 raw bytes) of the eBPF object, embedded or given with
+    /// --bpf-object. The agent refuses to start when it doesn't verify
+    #[clap(long, requires = "bpf_object_public_key")]
+    pub bpf_object_signature: Option<PathBuf>,
+    /// Public key (PEM) the signature of the eBPF object is verified against
+    #[clap(long, requires = "bpf_object_signature")]
+    pub bpf_object_public_key: Option<PathBuf>,
+    /// Watch traffic from a packet socket instead of the eBPF program, for kernels that can't run
+    /// it. Services are still scaled down and woken up, but gated traffic is not dropped
+    #[clap(long)]
+    pub userspace_datapath: bool,
+    /// Replay a recording (pcap or JSON lines) instead of attaching the eBPF program
+    #[clap(long)]
+    pub simulate: Option<PathBuf>,
+    /// Speed factor applied to the recording replayed with --simulate
+    #[clap(long, default_value = "1.0")]
+    pub simulate_speed: f64,
+    /// Static config (JSON) of the IPs to gate and the commands or HTTP requests waking them up and
+    /// putting them to sleep, the agent then runs without Kubernetes
+    #[clap(long)]
+    pub standalone: Option<PathBuf>,
+    /// Label selector (e.g. tier=preview) of services that get enrolled without annotations
+    #[clap(long)]
+    pub auto_enroll_selector: Option<String>,
+    /// scale-down-time applied to services enrolled through --auto-enroll-selector
+    #[clap(long, default_value = "600", value_parser = duration)]
+    pub auto_enroll_scale_down_time: String,
+    /// Domain of the annotations read from services, annotations with the default domain are
+    /// still read while services are migrated
+    #[clap(long, env = "SCALE_TO_ZERO_ANNOTATION_PREFIX", default_value = DEFAULT_ANNOTATION_PREFIX)]
+    pub annotation_prefix: String,
+    /// Node conditions (comma separated) putting the cluster under pressure when true on any node
+    #[clap(
+        long,
+        value_delimiter = ',',
+        default_value = "MemoryPressure,DiskPressure,PIDPressure"
+    )]
+    pub pressure_conditions: Vec<String>,
+    /// Factor applied to the scale-down-time of low priority services under pressure
+    #[clap(long, default_value = "0.25")]
+    pub low_priority_pressure_factor: f64,
+    /// Factor applied to the scale-down-time of normal priority services under pressure, high
+    /// priority services keep theirs
+    #[clap(long, default_value = "0.5")]
+    pub normal_priority_pressure_factor: f64,
+    /// Wakes within an hour past which a service is flagged with a warning event, 0 disables it
+    #[clap(long, default_value = "10")]
+    pub excessive_wakes: usize,
+    /// Factor applied to the scale-down-time of a service each time it is flagged for excessive
+    /// wakes, left unchanged when not set
+    #[clap(long)]
+    pub excessive_wake_extension: Option<f64>,
+    /// Pages (a power of two) of the perf buffer of each CPU for the activity events, sized from the
+    /// number of watched services once the controller synced them when not set
+    #[clap(long)]
+    pub perf_buffer_pages: Option<usize>,
+    /// Activity events read from a perf buffer at once, adapts to the load when not set
+    #[clap(long)]
+    pub perf_batch_size: Option<usize>,
+    /// Seconds between the checks of the kernel service list against the watched services, and of
+    /// the gates against the ready endpoints of the services, 0 disables them
+    #[clap(long, default_value = "60")]
+    pub consistency_check_interval: u64,
+    /// Wakes patching their workload at the same time, the others wait in priority order (high
+    /// first), 0 for no limit
+    #[clap(long, default_value = "4")]
+    pub wake_concurrency: usize,
+    /// Tasks processing the activity events of each CPU
+    #[clap(long, default_value = "1")]
+    pub perf_consumers: usize,
+    /// WebAssembly module (needs the wasm-policy feature) asked whether to scale down and wake
+    /// services and with how many replicas, see the README for its exports
+    #[clap(long)]
+    pub policy_plugin: Option<PathBuf>,
+    /// Instructions (wasmtime fuel) a call of the policy plugin may run before it is stopped
+    #[clap(long, default_value = "10000000")]
+    pub policy_fuel: u64,
+    /// Start in maintenance mode, until the maintenance ConfigMap exists
+    #[clap(long)]
+    pub maintenance: bool,
+    /// ConfigMap in the namespace of the agent holding the maintenance mode of every agent, with
+    /// its `enabled` and `wake-all` keys
+    #[clap(long, default_value = "scale-to-zero-maintenance")]
+    pub maintenance_configmap: String,
+    /// Seconds after the start during which no service is scaled down while the state of the agent
+    /// is rebuilt, 0 disables the warm-up
+    #[clap(long, default_value = "60")]
+    pub warm_up: u64,
+    /// Port of the metrics of the mesh sidecars, read for the services with mesh activity
+    #[clap(long, default_value = "15090")]
+    pub mesh_metrics_port: u16,
+    /// Path of the metrics of the mesh sidecars
+    #[clap(long, default_value = "/stats/prometheus")]
+    pub mesh_metrics_path: String,
+    /// Counter of the requests received by a sidecar, e.g. envoy_http_downstream_rq_total without
+    /// Istio
+    #[clap(long, default_value = "istio_requests_total")]
+    pub mesh_request_metric: String,
+    /// Prometheus server (e.g. http://prometheus.monitoring:9090) the queue-depth-query of services
+    /// is run against
+    #[clap(long)]
+    pub prometheus_url: Option<String>,
+    /// Observe traffic to every ClusterIP and report scale-to-zero candidates
+    #[clap(long)]
+    pub learning_mode: bool,
+    /// Minimum idle period (seconds) for a service to be reported as a candidate
+    #[clap(long, default_value = "1800")]
+    pub learning_min_idle: u64,
+    /// How often (seconds) the learning mode report is logged
+    #[clap(long, default_value = "600")]
+    pub learning_report_interval: u64,
+    /// Share the last activity of each service with the agents of the other nodes through a Lease
+    /// per service, so a service busy on another node isn't scaled down
+    #[clap(long)]
+    pub activity_gossip: bool,
+    /// Source ports the connections of the agent are made from. Packets from them are never
+    /// activity on any node, use the same range on every agent and keep it out of the ephemeral
+    /// ports of the clients
+    #[clap(long, default_value = "61000-61099", value_parser = port_range)]
+    pub agent_source_ports: (u16, u16),
+    /// Change replicas through the scale subresource and keep the awake replicas in an annotation,
+    /// for workloads synced by Argo CD or Flux
+    #[clap(long)]
+    pub gitops: bool,
+    /// Number of activity and wake events recorded per service for the admin API, 0 disables the
+    /// recording
+    #[clap(long, default_value = "256")]
+    pub event_recording_len: usize,
+    /// Services watched at most, the services annotated past it are not gated and reported with a
+    /// warning event until others are no longer watched
+    #[clap(long, default_value = "1024")]
+    pub max_watched_services: usize,
+    /// Format of the log records, json for log pipelines. The fields of the eBPF records (program,
+    /// interface, CPU) are keys of the JSON objects
+    #[clap(long, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+}
+
+static OPTIONS: OnceCell<Options> = OnceCell::new();
+
+// A duration option copied into annotations as given, checked like the annotations are
+fn duration(value: &str) -> Result<String, String> {
+    parse_duration(value)
+        .map(|_| value.to_string())
+        .map_err(|err| err.to_string())
+}
+
+// First and last port of a range, e.g. 61000-61099
+fn port_range(value: &str) -> Result<(u16, u16), String> {
+    let (first, last) = value
+        .split_once('-')
+        .ok_or_else(|| format!("{} is not a port range (first-last)", value))?;
+    let first = first.trim().parse::<u16>().map_err(|err| err.to_string())?;
+    let last = last.trim().parse::<u16>().map_err(|err| err.to_string())?;
+    if first == 0 || first > last {
+        return Err(format!("{} is not a port range (first-last)", value));
+    }
+    Ok((first, last))
+}
+
+// Parse the command line once at startup, every other module reads it through `get`
+pub fn init() -> &'static Options {
+    OPTIONS.get_or_init(Options::parse)
+}
+
+#[cfg(all(test, feature = "e2e"))]
+pub fn init_default() -> &'static Options {
+    OPTIONS.get_or_init(|| Options::parse_from(["scale-to-zero"]))
+}
+
+pub fn get() -> &'static Options {
+    OPTIONS.get().expect("options are not initialized")
+}
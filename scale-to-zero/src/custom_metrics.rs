@@ -0,0 +1,350 @@
+use axum::{
+    extract::{Path, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use k8s_openapi::api::coordination::v1::Lease;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::chrono::{self, SecondsFormat};
+use k8s_openapi::serde_json::{self, json, Value};
+use kube::api::{Api, DeleteParams, ListParams, Patch, PatchParams};
+use kube::ResourceExt;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config;
+use crate::kubernetes;
+use crate::kubernetes::lease;
+use crate::kubernetes::models::{annotation, annotation_key, ServiceData, WATCHED_SERVICES};
+use crate::kubernetes::scaler::FIELD_MANAGER;
+
+// The adapter serves the custom.metrics.k8s.io API through the admin API, an APIService pointing
+// at the agent lets HPAs scale on the network activity of a service
+const GROUP_VERSION: &str = "custom.metrics.k8s.io/v1beta2";
+const PACKETS_PER_SECOND: &str = "packets_per_second";
+const SECONDS_SINCE_LAST_ACTIVITY: &str = "seconds_since_last_activity";
+
+// Packets per second are averaged over this window
+const WINDOW_SECONDS: i64 = 60;
+
+// Each agent publishes the packets of its node this often, the Lease of an agent that stopped
+// publishing for twice as long is left out
+const SHARE_INTERVAL: Duration = Duration::from_secs(15);
+
+// Label of the packet count Leases, with the identity of their agent, and their annotation with
+// the packets of each service within the window
+const PACKETS_LABEL: &str = "packets";
+const PACKETS_ANNOTATION: &str = "packets";
+
+type PacketsPerSecond = VecDeque<(i64, u64)>;
+
+// This contains the packets of each service IP per second within the window
+static PACKETS: Lazy<Mutex<HashMap<String, PacketsPerSecond>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Namespaces where this agent published packet counts, its Lease is deleted from a namespace once
+// it has none left there
+static PUBLISHED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// The metrics are only served to authenticated clients, by their certificate or their token
+pub fn served() -> bool {
+    let opts = config::get();
+    opts.admin_client_ca.is_some() || opts.admin_token_auth
+}
+
+// Forget the services no longer watched or without packets within the window, returns the number
+// of services left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    let now = chrono::Utc::now().timestamp();
+    let mut packets = PACKETS.lock().unwrap();
+    packets.retain(|ip, seconds| {
+        watched.contains(ip)
+            && seconds
+                .back()
+                .is_some_and(|(second, _)| now - second < WINDOW_SECONDS)
+    });
+    packets.len()
+}
+
+// Count a packet of a watched service
+pub fn record_packet(service_ip: &str) {
+    let now = chrono::Utc::now().timestamp();
+    let mut packets = PACKETS.lock().unwrap();
+    let seconds = packets.entry(service_ip.to_string()).or_default();
+    match seconds.back_mut() {
+        Some((second, count)) if *second == now => *count += 1,
+        _ => seconds.push_back((now, 1)),
+    }
+    while matches!(seconds.front(), Some((second, _)) if now - second >= WINDOW_SECONDS) {
+        seconds.pop_front();
+    }
+}
+
+// Packets of the service seen by this node within the window
+fn packets(service_ip: &str, now: i64) -> u64 {
+    PACKETS
+        .lock()
+        .unwrap()
+        .get(service_ip)
+        .map(|seconds| {
+            seconds
+                .iter()
+                .filter(|(second, _)| now - second < WINDOW_SECONDS)
+                .map(|(_, count)| count)
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+fn lease_name() -> String {
+    format!("scale-to-zero-packets-{}", lease::identity())
+}
+
+// Each agent only sees the packets of its own node, while the APIService sends a request to any of
+// them. Every 15s the agents publish the packets of their node in a Lease per namespace, the agent
+// answering a request adds those of the others to its own
+pub async fn share_packets() {
+    info!(target: "custom_metrics", "Sharing the packets of the services through Leases every {}s", SHARE_INTERVAL.as_secs());
+    loop {
+        let now = chrono::Utc::now().timestamp();
+        let mut counts: HashMap<String, BTreeMap<String, u64>> = HashMap::new();
+        for (ip, service) in WATCHED_SERVICES.lock().unwrap().iter() {
+            let packets = packets(ip, now);
+            if packets > 0 {
+                counts
+                    .entry(service.namespace.clone())
+                    .or_default()
+                    .insert(service.service_name.clone(), packets);
+            }
+        }
+        let stale: Vec<String> = PUBLISHED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|namespace| !counts.contains_key(*namespace))
+            .cloned()
+            .collect();
+        for namespace in stale {
+            match withdraw(&namespace).await {
+                Ok(()) => {
+                    PUBLISHED.lock().unwrap().remove(&namespace);
+                }
+                Err(err) => {
+                    warn!(target: "custom_metrics", "Failed to delete the packet counts of namespace {}: {:#}", namespace, err)
+                }
+            }
+        }
+        for (namespace, counts) in counts {
+            match publish(&namespace, &counts).await {
+                Ok(()) => {
+                    PUBLISHED.lock().unwrap().insert(namespace);
+                }
+                Err(err) => {
+                    warn!(target: "custom_metrics", "Failed to publish the packet counts of namespace {}: {:#}", namespace, err)
+                }
+            }
+        }
+        tokio::time::sleep(SHARE_INTERVAL).await;
+    }
+}
+
+async fn publish(namespace: &str, counts: &BTreeMap<String, u64>) -> anyhow::Result<()> {
+    let leases: Api<Lease> = Api::namespaced(kubernetes::client().await?, namespace);
+    let lease = json!({
+        "apiVersion": "coordination.k8s.io/v1",
+        "kind": "Lease",
+        "metadata": {
+            "name": lease_name(),
+            "labels": { annotation_key(PACKETS_LABEL): lease::identity() },
+            "annotations": { annotation_key(PACKETS_ANNOTATION): json!(counts).to_string() },
+        },
+        "spec": {
+            "holderIdentity": lease::identity(),
+            "renewTime": MicroTime(chrono::Utc::now()),
+        },
+    });
+    leases
+        .patch(
+            &lease_name(),
+            &PatchParams::apply(FIELD_MANAGER).force(),
+            &Patch::Apply(lease),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn withdraw(namespace: &str) -> anyhow::Result<()> {
+    let leases: Api<Lease> = Api::namespaced(kubernetes::client().await?, namespace);
+    match leases.delete(&lease_name(), &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(err)) if err.code == 404 => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Packets of the services of the namespace within the window published by the other agents
+async fn shared_packets(namespace: &str) -> anyhow::Result<HashMap<String, u64>> {
+    let leases: Api<Lease> = Api::namespaced(kubernetes::client().await?, namespace);
+    let label = annotation_key(PACKETS_LABEL);
+    let now = chrono::Utc::now();
+    let mut packets: HashMap<String, u64> = HashMap::new();
+    for lease in leases.list(&ListParams::default().labels(&label)).await? {
+        if lease.labels().get(&label).map(String::as_str) == Some(lease::identity()) {
+            continue;
+        }
+        let renewed = lease
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.renew_time.as_ref())
+            .is_some_and(|MicroTime(renew_time)| {
+                now.signed_duration_since(*renew_time).num_seconds()
+                    < 2 * SHARE_INTERVAL.as_secs() as i64
+            });
+        let counts = match annotation(lease.annotations(), PACKETS_ANNOTATION) {
+            Some(counts) if renewed => counts,
+            _ => continue,
+        };
+        let counts: HashMap<String, u64> = serde_json::from_str(counts)?;
+        for (service_name, count) in counts {
+            *packets.entry(service_name).or_default() += count;
+        }
+    }
+    Ok(packets)
+}
+
+// Only mounted when `served`, the API server authenticates with the client certificate of
+// --admin-client-ca and other clients with a token under --admin-token-auth
+pub fn routes() -> Router {
+    if !served() {
+        info!(target: "custom_metrics", "Custom metrics are not served without --admin-client-ca or --admin-token-auth");
+        return Router::new();
+    }
+    Router::new()
+        .route(&format!("/apis/{}", GROUP_VERSION), get(resources))
+        .route(
+            &format!(
+                "/apis/{}/namespaces/:namespace/services/:name/:metric",
+                GROUP_VERSION
+            ),
+            get(service_metric),
+        )
+}
+
+// Discovery of the metrics, the API server asks for it before forwarding any request
+async fn resources() -> Json<Value> {
+    let resources: Vec<Value> = [PACKETS_PER_SECOND, SECONDS_SINCE_LAST_ACTIVITY]
+        .iter()
+        .map(|metric| {
+            json!({
+                "name": format!("services/{}", metric),
+                "singularName": "",
+                "namespaced": true,
+                "kind": "MetricValueList",
+                "verbs": ["get"],
+            })
+        })
+        .collect();
+    Json(json!({
+        "kind": "APIResourceList",
+        "apiVersion": "v1",
+        "groupVersion": GROUP_VERSION,
+        "resources": resources,
+    }))
+}
+
+#[derive(Deserialize)]
+struct MetricQuery {
+    #[serde(rename = "labelSelector")]
+    label_selector: Option<String>,
+}
+
+// Value of a metric for a service, or for every watched service of the namespace with `*`
+async fn service_metric(
+    Path((namespace, name, metric)): Path<(String, String, String)>,
+    Query(query): Query<MetricQuery>,
+) -> Response {
+    if metric != PACKETS_PER_SECOND && metric != SECONDS_SINCE_LAST_ACTIVITY {
+        return (StatusCode::NOT_FOUND, format!("Unknown metric {}", metric)).into_response();
+    }
+    if query.label_selector.is_some() {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Label selectors are not supported, select the service by name",
+        )
+            .into_response();
+    }
+    let services: Vec<(String, ServiceData)> = WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, service)| {
+            service.namespace == namespace && (name == "*" || service.service_name == name)
+        })
+        .map(|(ip, service)| (ip.clone(), service.clone()))
+        .collect();
+    if services.is_empty() && name != "*" {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Service {}/{} is not watched", namespace, name),
+        )
+            .into_response();
+    }
+
+    // packets seen by the other nodes
+    let shared = if metric == PACKETS_PER_SECOND {
+        match shared_packets(&namespace).await {
+            Ok(shared) => shared,
+            Err(err) => {
+                warn!(target: "custom_metrics", "Failed to read the packet counts of namespace {}: {:#}", namespace, err);
+                return (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Packet counts of the other agents can't be read",
+                )
+                    .into_response();
+            }
+        }
+    } else {
+        HashMap::new()
+    };
+
+    let now = chrono::Utc::now();
+    let timestamp = now.to_rfc3339_opts(SecondsFormat::Secs, true);
+    let items: Vec<Value> = services
+        .iter()
+        .map(|(ip, service)| {
+            // quantities in milli units
+            let value = if metric == PACKETS_PER_SECOND {
+                let packets = packets(ip, now.timestamp())
+                    + shared.get(&service.service_name).copied().unwrap_or(0);
+                (packets as f64 * 1000.0 / WINDOW_SECONDS as f64).round() as i64
+            } else {
+                (now.timestamp() - service.last_packet_time).max(0) * 1000
+            };
+            json!({
+                "describedObject": {
+                    "kind": "Service",
+                    "namespace": service.namespace,
+                    "name": service.service_name,
+                    "apiVersion": "/v1",
+                },
+                "metric": { "name": metric },
+                "timestamp": timestamp,
+                "windowSeconds": WINDOW_SECONDS,
+                "value": format!("{}m", value),
+            })
+        })
+        .collect();
+    Json(json!({
+        "kind": "MetricValueList",
+        "apiVersion": GROUP_VERSION,
+        "metadata": {},
+        "items": items,
+    }))
+    .into_response()
+}
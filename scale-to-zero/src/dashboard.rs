@@ -0,0 +1,50 @@
+use k8s_openapi::chrono;
+use k8s_openapi::serde_json::{json, Value};
+
+use crate::kubernetes::models::{RECENT_WAKES, WATCHED_SERVICES};
+use crate::utils;
+
+// Read-only page served on the root of the admin API, it polls `data` as /dashboard.json
+pub const PAGE: &str = include_str!("dashboard.html");
+
+pub fn data() -> Value {
+    let now = chrono::Utc::now().timestamp();
+    let recent_wakes = RECENT_WAKES.lock().unwrap().clone();
+
+    let mut services: Vec<Value> = WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(ip, service)| {
+            let idle = (now - service.last_packet_time).max(0);
+            let state = match (&service.unmanageable, &service.wake_failure) {
+                (Some(reason), _) => format!("unmanageable ({})", reason),
+                (None, Some(failure)) => format!("not starting ({})", failure),
+                (None, None) if service.backend_available => "running".to_string(),
+                (None, None) => "scaled to zero".to_string(),
+            };
+            // only a running workload has a scale-down coming up
+            let remaining = if service.backend_available && service.unmanageable.is_none() {
+                Some((service.scale_down_time - idle).max(0))
+            } else {
+                None
+            };
+            json!({
+                "service_ip": ip,
+                "namespace": service.namespace,
+                "workload": format!("{}/{}", service.kind, service.name),
+                "state": state,
+                "idle_seconds": idle,
+                "scale_down_in_seconds": remaining,
+                "recent_wakes": recent_wakes.get(ip).cloned().unwrap_or_default(),
+            })
+        })
+        .collect();
+    services.sort_by(|a, b| a["workload"].as_str().cmp(&b["workload"].as_str()));
+
+    json!({
+        "now": now,
+        "services": services,
+        "interfaces": *utils::ATTACH_STATUS.lock().unwrap(),
+    })
+}
@@ -0,0 +1,144 @@
+use aya::maps::{Array, MapData};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use scale_to_zero_common::{destination_filter_bits, DESTINATION_FILTER_WORDS};
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config;
+use crate::kubernetes::models::{HOST_PORTS, OBSERVED_SERVICES, POD_TO_SERVICE, WATCHED_SERVICES};
+use crate::utils;
+
+// Bits of destinations that are gone are only cleared by a rebuild
+const REBUILD_INTERVAL: Duration = Duration::from_secs(10);
+
+struct DestinationFilter {
+    array: Array<MapData, u64>,
+    // the words as written to the kernel
+    words: Vec<u64>,
+    // destinations inserted since the last rebuild, kept by it even if their source doesn't list
+    // them yet
+    recent: HashSet<u32>,
+    ready: bool,
+}
+
+impl DestinationFilter {
+    fn set_bit(&mut self, bit: u32) -> anyhow::Result<()> {
+        let index = (bit / 64) as usize;
+        let word = self.words[index] | 1 << (bit % 64);
+        if word != self.words[index] {
+            self.array.set(index as u32, word, 0)?;
+            self.words[index] = word;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, words: Vec<u64>) -> anyhow::Result<()> {
+        for (index, word) in words.iter().enumerate() {
+            if *word != self.words[index] {
+                self.array.set(index as u32, word, 0)?;
+                self.words[index] = *word;
+            }
+        }
+        if !self.ready {
+            self.array.set(DESTINATION_FILTER_WORDS, 1, 0)?;
+            self.ready = true;
+            info!(target: "destination_filter", "Destination filter is ready");
+        }
+        Ok(())
+    }
+
+    // A filter missing a bit would let the packets of a gated service through, the datapath goes
+    // back to the lookups until the next rebuild
+    fn disable(&mut self, err: anyhow::Error) {
+        warn!(target: "destination_filter", "Disabling the destination filter: {:#}", err);
+        if let Err(err) = self.array.set(DESTINATION_FILTER_WORDS, 0, 0) {
+            warn!(target: "destination_filter", "Failed to disable the destination filter: {}", err);
+        }
+        self.ready = false;
+    }
+}
+
+static FILTER: Lazy<Mutex<Option<DestinationFilter>>> = Lazy::new(|| Mutex::new(None));
+
+pub fn init(array: Array<MapData, u64>) {
+    *FILTER.lock().unwrap() = Some(DestinationFilter {
+        array,
+        words: vec![0; DESTINATION_FILTER_WORDS as usize],
+        recent: HashSet::new(),
+        ready: false,
+    });
+}
+
+// Set the bits of a destination, before its entry is written to one of the maps behind the filter
+pub fn insert(address: u32) {
+    let mut filter = FILTER.lock().unwrap();
+    let filter = match filter.as_mut() {
+        Some(filter) => filter,
+        None => return,
+    };
+    filter.recent.insert(address);
+    for bit in destination_filter_bits(address) {
+        if let Err(err) = filter.set_bit(bit) {
+            filter.disable(err);
+            return;
+        }
+    }
+}
+
+// Rebuild the filter from the destinations the agent writes to the maps, which clears the bits of
+// the ones that are gone. The first rebuild enables the filter in the datapath
+pub async fn maintain() {
+    loop {
+        rebuild();
+        tokio::time::sleep(REBUILD_INTERVAL).await;
+    }
+}
+
+fn rebuild() {
+    let destinations = destinations();
+    let mut filter = FILTER.lock().unwrap();
+    let filter = match filter.as_mut() {
+        Some(filter) => filter,
+        None => return,
+    };
+    let mut words = vec![0u64; DESTINATION_FILTER_WORDS as usize];
+    for address in destinations.iter().chain(filter.recent.iter()) {
+        for bit in destination_filter_bits(*address) {
+            words[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+    filter.recent.clear();
+    if let Err(err) = filter.write(words) {
+        filter.disable(err);
+    }
+}
+
+// Everything in, or about to be in, SERVICE_LIST, LOAD_BALANCER_IPS, POD_TO_SERVICE, HOST_PORTS,
+// OBSERVED_SERVICES and SNI_ENTRYPOINTS
+fn destinations() -> HashSet<u32> {
+    let parse = |ip: &String| ip.parse::<Ipv4Addr>().ok().map(u32::from);
+    let mut destinations: HashSet<u32> = utils::service_list_keys();
+    for (service_ip, service) in WATCHED_SERVICES.lock().unwrap().iter() {
+        destinations.extend(parse(service_ip));
+        destinations.extend(service.load_balancer_ips.iter().map(|ip| u32::from(*ip)));
+    }
+    destinations.extend(POD_TO_SERVICE.lock().unwrap().keys().filter_map(parse));
+    destinations.extend(
+        HOST_PORTS
+            .lock()
+            .unwrap()
+            .keys()
+            .filter_map(|(node_ip, _, _)| parse(node_ip)),
+    );
+    destinations.extend(OBSERVED_SERVICES.lock().unwrap().keys().filter_map(parse));
+    destinations.extend(
+        config::get()
+            .sni_entrypoints
+            .iter()
+            .map(|ip| u32::from(*ip)),
+    );
+    destinations
+}
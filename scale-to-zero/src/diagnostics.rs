@@ -0,0 +1,152 @@
+use aya::programs::ProgramError;
+use log::{error, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::sync::Mutex;
+
+// Lines kept from the end of a verifier log in the error, the full log is on the health endpoint
+const VERIFIER_LOG_TAIL: usize = 20;
+
+// Load failures kept for the health endpoint, the oldest ones are dropped first
+const LOAD_FAILURES_LEN: usize = 16;
+
+// Capabilities checked in CapEff, loading without CAP_SYS_ADMIN needs the finer grained ones
+pub const CAP_NET_ADMIN: u32 = 12;
+pub const CAP_NET_RAW: u32 = 13;
+pub const CAP_SYS_ADMIN: u32 = 21;
+pub const CAP_PERFMON: u32 = 38;
+pub const CAP_BPF: u32 = 39;
+
+// A failed load of the eBPF object or one of its programs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadFailure {
+    pub stage: String,
+    pub error: String,
+    pub verifier_log: Option<String>,
+}
+
+// This contains the latest load failures since the agent started, reported by the health endpoint
+pub static LOAD_FAILURES: Lazy<Mutex<VecDeque<LoadFailure>>> =
+    Lazy::new(|| Mutex::new(VecDeque::new()));
+
+// Turn a failed load into an error that says what the kernel refused and what the node lacks,
+// instead of the bare aya error. The verifier log is cut to its end, where the rejected instruction
+// is, and kept whole for the health endpoint
+pub fn load_failed(stage: &str, err: anyhow::Error) -> anyhow::Error {
+    let verifier_log = err
+        .chain()
+        .find_map(|source| match source.downcast_ref::<ProgramError>() {
+            Some(ProgramError::LoadError { verifier_log, .. }) => Some(verifier_log.to_string()),
+            _ => None,
+        })
+        .filter(|log| !log.trim().is_empty());
+    let missing = missing_features();
+
+    let mut message = format!("Failed to load {} on kernel {}", stage, kernel_release());
+    if !missing.is_empty() {
+        message.push_str(&format!(", missing: {}", missing.join(", ")));
+    }
+    if let Some(log) = verifier_log.as_ref() {
+        let lines: Vec<&str> = log.lines().collect();
+        let tail = &lines[lines.len().saturating_sub(VERIFIER_LOG_TAIL)..];
+        message.push_str(&format!(
+            "\nverifier log (last {} of {} lines):\n  {}",
+            tail.len(),
+            lines.len(),
+            tail.join("\n  ")
+        ));
+    }
+    error!(target: "diagnostics", "{}: {:#}", message, err);
+
+    let mut load_failures = LOAD_FAILURES.lock().unwrap();
+    if load_failures.len() == LOAD_FAILURES_LEN {
+        load_failures.pop_front();
+    }
+    load_failures.push_back(LoadFailure {
+        stage: stage.to_string(),
+        error: format!("{:#}", err),
+        verifier_log,
+    });
+    err.context(message)
+}
+
+// Release of the running kernel, e.g. 5.15.0-91-generic
+pub fn kernel_release() -> String {
+    let mut uname: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut uname) } != 0 {
+        return "unknown".to_string();
+    }
+    unsafe { CStr::from_ptr(uname.release.as_ptr()) }
+        .to_string_lossy()
+        .to_string()
+}
+
+// (major, minor) of the running kernel
+pub fn kernel_version() -> Option<(u32, u32)> {
+    let release = kernel_release();
+    let mut parts = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|part| part.parse::<u32>());
+    Some((parts.next()?.ok()?, parts.next()?.ok()?))
+}
+
+// What the node lacks to run the datapath: BTF, bpffs, capabilities and the memlock limit
+pub fn missing_features() -> Vec<String> {
+    let mut missing = Vec::new();
+    if !std::path::Path::new("/sys/kernel/btf/vmlinux").exists() {
+        missing.push("kernel BTF (/sys/kernel/btf/vmlinux)".to_string());
+    }
+    match std::fs::read_to_string("/proc/mounts") {
+        Ok(mounts)
+            if mounts
+                .lines()
+                .any(|mount| mount.split(' ').nth(2) == Some("bpf")) => {}
+        Ok(_) => missing.push("bpffs mount (/sys/fs/bpf)".to_string()),
+        Err(err) => warn!(target: "diagnostics", "Failed to read /proc/mounts: {}", err),
+    }
+
+    match effective_capabilities() {
+        Some(caps) => {
+            let has = |cap: u32| caps & (1 << cap) != 0;
+            if !has(CAP_SYS_ADMIN) {
+                for (name, cap) in [
+                    ("CAP_BPF", CAP_BPF),
+                    ("CAP_PERFMON", CAP_PERFMON),
+                    ("CAP_NET_ADMIN", CAP_NET_ADMIN),
+                ] {
+                    if !has(cap) {
+                        missing.push(format!("{} (or CAP_SYS_ADMIN)", name));
+                    }
+                }
+            }
+        }
+        None => warn!(target: "diagnostics", "Failed to read the capabilities of the agent"),
+    }
+
+    // maps are charged to the memlock limit before 5.11
+    if matches!(kernel_version(), Some(version) if version < (5, 11)) {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        let limited = unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } == 0
+            && limit.rlim_cur != libc::RLIM_INFINITY;
+        if limited {
+            missing.push(format!(
+                "unlimited RLIMIT_MEMLOCK (is {} bytes)",
+                limit.rlim_cur
+            ));
+        }
+    }
+    missing
+}
+
+fn effective_capabilities() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(caps.trim(), 16).ok()
+}
@@ -0,0 +1,423 @@
+// End-to-end tests running the controller and the scaler against a fake kubernetes API server.
+// Run with `cargo test -p scale-to-zero --features e2e` once the eBPF program is built.
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use futures::stream;
+use k8s_openapi::serde_json::{self, json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::config;
+use crate::kubernetes::{self, controller, models::WATCHED_SERVICES, scaler};
+
+const SERVICE_IP: &str = "10.96.0.10";
+
+struct FakeCluster {
+    // Objects by (resource, name)
+    objects: HashMap<(String, String), Value>,
+    // Patches received, as (resource, name, body)
+    patches: Vec<(String, String, Value)>,
+    // Modified objects, as (resource, object), streamed to the watches
+    events: broadcast::Sender<(String, Value)>,
+    resource_version: u64,
+}
+
+type SharedCluster = Arc<Mutex<FakeCluster>>;
+
+impl FakeCluster {
+    fn shared() -> SharedCluster {
+        Arc::new(Mutex::new(FakeCluster {
+            objects: HashMap::new(),
+            patches: Vec::new(),
+            events: broadcast::channel(64).0,
+            resource_version: 1,
+        }))
+    }
+
+    fn add(&mut self, resource: &str, object: Value) {
+        let name = object["metadata"]["name"].as_str().unwrap().to_string();
+        self.objects.insert((resource.to_string(), name), object);
+    }
+
+    fn object(&self, resource: &str, name: &str) -> Value {
+        self.objects[&(resource.to_string(), name.to_string())].clone()
+    }
+
+    fn replica_patches(&self, replicas: i64) -> usize {
+        self.patches
+            .iter()
+            .filter(|(_, _, patch)| {
+                patch.pointer("/spec/replicas").and_then(Value::as_i64) == Some(replicas)
+            })
+            .count()
+    }
+}
+
+// Optional APIs (OpenKruise, canaries, Gateway API) aren't installed, their discovery and any other
+// request not routed here gets a NotFound
+fn router(cluster: SharedCluster) -> Router {
+    Router::new()
+        .route("/api/v1/:resource", get(list_or_watch_cluster))
+        .route("/api/v1/namespaces/:name", get(get_namespace))
+        .route("/api/v1/namespaces/:namespace/:resource", get(list_or_watch))
+        .route(
+            "/api/v1/namespaces/:namespace/:resource/:name",
+            get(get_object).patch(patch_object),
+        )
+        .route(
+            "/apis/apps/v1/namespaces/:namespace/:resource",
+            get(list_or_watch),
+        )
+        .route(
+            "/apis/apps/v1/namespaces/:namespace/:resource/:name",
+            get(get_object).patch(patch_object),
+        )
+        .route(
+            "/apis/discovery.k8s.io/v1/namespaces/:namespace/:resource",
+            get(list_or_watch),
+        )
+        .route(
+            "/apis/coordination.k8s.io/v1/namespaces/:namespace/:resource",
+            get(list_or_watch).post(create_object),
+        )
+        .route(
+            "/apis/coordination.k8s.io/v1/namespaces/:namespace/:resource/:name",
+            get(get_object).put(replace_object).patch(patch_object),
+        )
+        .route(
+            "/apis/networking.k8s.io/v1/namespaces/:namespace/:resource",
+            get(list_or_watch),
+        )
+        .route(
+            "/apis/scale-to-zero.isala.me/v1alpha1/:resource",
+            get(list_or_watch_cluster),
+        )
+        .route(
+            "/apis/scale-to-zero.isala.me/v1alpha1/namespaces/:namespace/:resource",
+            get(list_or_watch),
+        )
+        .fallback(not_installed)
+        .with_state(cluster)
+}
+
+fn conflict() -> Response {
+    (
+        StatusCode::CONFLICT,
+        Json(json!({
+            "apiVersion": "v1",
+            "kind": "Status",
+            "status": "Failure",
+            "reason": "Conflict",
+            "message": "conflict",
+            "code": 409,
+        })),
+    )
+        .into_response()
+}
+
+fn not_found() -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        Json(json!({
+            "apiVersion": "v1",
+            "kind": "Status",
+            "status": "Failure",
+            "reason": "NotFound",
+            "message": "not found",
+            "code": 404,
+        })),
+    )
+        .into_response()
+}
+
+async fn list_or_watch(
+    State(cluster): State<SharedCluster>,
+    Path((_namespace, resource)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let cluster = cluster.lock().unwrap();
+
+    // Objects only change through patches, watches stream the patched objects
+    if params.contains_key("watch") {
+        let events = stream::unfold(cluster.events.subscribe(), move |mut events| {
+            let resource = resource.clone();
+            async move {
+                loop {
+                    match events.recv().await {
+                        Ok((kind, object)) if kind == resource => {
+                            let event = json!({ "type": "MODIFIED", "object": object });
+                            let mut line = serde_json::to_vec(&event).unwrap();
+                            line.push(b'\n');
+                            return Some((Ok::<_, Infallible>(Bytes::from(line)), events));
+                        }
+                        Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+        return Body::from_stream(events).into_response();
+    }
+
+    let items: Vec<Value> = cluster
+        .objects
+        .iter()
+        .filter(|((kind, _), _)| *kind == resource)
+        .map(|(_, object)| object.clone())
+        .collect();
+    Json(json!({ "metadata": { "resourceVersion": "1" }, "items": items })).into_response()
+}
+
+async fn list_or_watch_cluster(
+    state: State<SharedCluster>,
+    Path(resource): Path<String>,
+    query: Query<HashMap<String, String>>,
+) -> Response {
+    list_or_watch(state, Path((String::new(), resource)), query).await
+}
+
+async fn not_installed() -> Response {
+    not_found()
+}
+
+async fn get_namespace(state: State<SharedCluster>, Path(name): Path<String>) -> Response {
+    get_object(state, Path((name.clone(), "namespaces".to_string(), name))).await
+}
+
+async fn get_object(
+    State(cluster): State<SharedCluster>,
+    Path((_namespace, resource, name)): Path<(String, String, String)>,
+) -> Response {
+    let cluster = cluster.lock().unwrap();
+    match cluster.objects.get(&(resource, name)) {
+        Some(object) => Json(object.clone()).into_response(),
+        None => not_found(),
+    }
+}
+
+async fn patch_object(
+    State(cluster): State<SharedCluster>,
+    Path((_namespace, resource, name)): Path<(String, String, String)>,
+    body: Bytes,
+) -> Response {
+    let patch: Value = serde_json::from_slice(&body).unwrap();
+    let mut cluster = cluster.lock().unwrap();
+    cluster
+        .patches
+        .push((resource.clone(), name.clone(), patch.clone()));
+    cluster.resource_version += 1;
+    let resource_version = cluster.resource_version.to_string();
+
+    let object = match cluster.objects.get_mut(&(resource.clone(), name)) {
+        Some(object) => object,
+        None => return not_found(),
+    };
+    match patch.as_array() {
+        // JSON patches are only used for the finalizers, apply and merge patches for the rest
+        Some(operations) => operations.iter().for_each(|op| json_patch(object, op)),
+        None => {
+            if let Some(replicas) = patch.pointer("/spec/replicas") {
+                object["spec"]["replicas"] = replicas.clone();
+            }
+            if let Some(finalizers) = patch.pointer("/metadata/finalizers") {
+                object["metadata"]["finalizers"] = finalizers.clone();
+            }
+            if let Some(Value::Object(annotations)) = patch.pointer("/metadata/annotations") {
+                for (key, value) in annotations {
+                    object["metadata"]["annotations"][key] = value.clone();
+                }
+            }
+        }
+    }
+    object["metadata"]["resourceVersion"] = json!(resource_version);
+
+    let object = object.clone();
+    let _ = cluster.events.send((resource, object.clone()));
+    Json(object).into_response()
+}
+
+async fn create_object(
+    State(cluster): State<SharedCluster>,
+    Path((_namespace, resource)): Path<(String, String)>,
+    Json(mut object): Json<Value>,
+) -> Response {
+    let mut cluster = cluster.lock().unwrap();
+    let name = object["metadata"]["name"].as_str().unwrap().to_string();
+    if cluster
+        .objects
+        .contains_key(&(resource.clone(), name.clone()))
+    {
+        return conflict();
+    }
+    cluster.resource_version += 1;
+    object["metadata"]["resourceVersion"] = json!(cluster.resource_version.to_string());
+    cluster.objects.insert((resource, name), object.clone());
+    (StatusCode::CREATED, Json(object)).into_response()
+}
+
+// Replaces are only accepted on the latest resource version, like the API server does
+async fn replace_object(
+    State(cluster): State<SharedCluster>,
+    Path((_namespace, resource, name)): Path<(String, String, String)>,
+    Json(mut object): Json<Value>,
+) -> Response {
+    let mut cluster = cluster.lock().unwrap();
+    let current = match cluster.objects.get(&(resource.clone(), name.clone())) {
+        Some(current) => current,
+        None => return not_found(),
+    };
+    if current["metadata"]["resourceVersion"] != object["metadata"]["resourceVersion"] {
+        return conflict();
+    }
+    cluster.resource_version += 1;
+    object["metadata"]["resourceVersion"] = json!(cluster.resource_version.to_string());
+    cluster.objects.insert((resource, name), object.clone());
+    Json(object).into_response()
+}
+
+fn json_patch(object: &mut Value, op: &Value) {
+    let path = op["path"].as_str().unwrap();
+    let (parent, key) = path.rsplit_once('/').unwrap();
+    let value = op["value"].clone();
+    match op["op"].as_str().unwrap() {
+        "add" | "replace" => match object.pointer_mut(parent).unwrap() {
+            Value::Array(items) if key == "-" => items.push(value),
+            Value::Array(items) => items[key.parse::<usize>().unwrap()] = value,
+            parent => parent[key] = value,
+        },
+        "remove" => match object.pointer_mut(parent).unwrap() {
+            Value::Array(items) => {
+                items.remove(key.parse::<usize>().unwrap());
+            }
+            Value::Object(fields) => {
+                fields.remove(key);
+            }
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+fn deployment(name: &str, replicas: i32) -> Value {
+    json!({
+        "apiVersion": "apps/v1",
+        "kind": "Deployment",
+        "metadata": {
+            "name": name,
+            "namespace": "default",
+            "resourceVersion": "1",
+            "generation": 1,
+        },
+        "spec": {
+            "replicas": replicas,
+            "selector": { "matchLabels": { "app": name } },
+            "template": { "metadata": { "labels": { "app": name } } },
+        },
+        "status": {
+            "observedGeneration": 1,
+            "replicas": replicas,
+            "updatedReplicas": replicas,
+            "readyReplicas": replicas,
+            "availableReplicas": replicas,
+            "conditions": [{
+                "type": "Available",
+                "status": if replicas > 0 { "True" } else { "False" },
+            }],
+        },
+    })
+}
+
+fn service(name: &str, cluster_ip: &str, reference: &str, scale_down_time: &str) -> Value {
+    json!({
+        "apiVersion": "v1",
+        "kind": "Service",
+        "metadata": {
+            "name": name,
+            "namespace": "default",
+            "resourceVersion": "1",
+            "annotations": {
+                "scale-to-zero.isala.me/reference": reference,
+                "scale-to-zero.isala.me/scale-down-time": scale_down_time,
+            },
+        },
+        "spec": { "clusterIP": cluster_ip },
+    })
+}
+
+// Start the fake API server and point the shared kubernetes client at it
+async fn start_fake_cluster(cluster: SharedCluster) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, router(cluster)).await.unwrap();
+    });
+
+    let config = kube::Config::new(format!("http://{}", addr).parse().unwrap());
+    kubernetes::set_client(kube::Client::try_from(config).unwrap());
+}
+
+async fn wait_for<F: Fn() -> bool>(what: &str, condition: F) {
+    for _ in 0..100 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("Timed out waiting for {}", what);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn scales_idle_deployment_down_and_wakes_it_up() {
+    config::init_default();
+    let cluster = FakeCluster::shared();
+    {
+        let mut cluster = cluster.lock().unwrap();
+        cluster.add("deployments", deployment("nginx", 1));
+        cluster.add(
+            "services",
+            service("nginx", SERVICE_IP, "deployment/nginx", "1"),
+        );
+    }
+    start_fake_cluster(cluster.clone()).await;
+
+    tokio::spawn(controller::kube_event_watcher());
+    tokio::spawn(scaler::scale_down());
+
+    wait_for("the service to be watched", || {
+        WATCHED_SERVICES.lock().unwrap().contains_key(SERVICE_IP)
+    })
+    .await;
+    assert_eq!(
+        cluster.lock().unwrap().object("services", "nginx")["metadata"]["finalizers"],
+        json!(["scale-to-zero.isala.me/cleanup"])
+    );
+
+    // No packets are sent, so the deployment is scaled down once scale-down-time elapses
+    wait_for("the deployment to be scaled down", || {
+        cluster.lock().unwrap().replica_patches(0) == 1
+    })
+    .await;
+    assert!(!WATCHED_SERVICES.lock().unwrap()[SERVICE_IP].backend_available);
+
+    scaler::scale_up(SERVICE_IP.to_string()).await.unwrap();
+    assert_eq!(cluster.lock().unwrap().replica_patches(1), 1);
+    let lease = cluster
+        .lock()
+        .unwrap()
+        .object("leases", "scale-to-zero-deployment-nginx");
+    assert!(lease["spec"]["holderIdentity"].is_string());
+
+    // A second wake within the rate limit window doesn't patch again
+    assert!(scaler::scale_up(SERVICE_IP.to_string()).await.is_err());
+    assert_eq!(cluster.lock().unwrap().replica_patches(1), 1);
+}
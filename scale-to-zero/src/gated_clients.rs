@@ -0,0 +1,38 @@
+use aya::maps::{HashMap, MapData};
+use log::warn;
+use once_cell::sync::Lazy;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+// Clients whose packets were gated while their service was scaled down, counted by the datapath
+static GATED_CLIENTS: Lazy<Mutex<Option<HashMap<MapData, u64, u64>>>> =
+    Lazy::new(|| Mutex::new(None));
+
+pub fn init(map: HashMap<MapData, u64, u64>) {
+    *GATED_CLIENTS.lock().unwrap() = Some(map);
+}
+
+// Distinct clients gated for a service since it was scaled down, on this node. Their entries are
+// removed so the next scale-down starts from zero
+pub fn take(service_ip: &str) -> usize {
+    let service = match service_ip.parse::<Ipv4Addr>() {
+        Ok(address) => u32::from(address),
+        Err(_) => return 0,
+    };
+    let mut gated_clients = GATED_CLIENTS.lock().unwrap();
+    let map = match gated_clients.as_mut() {
+        Some(map) => map,
+        None => return 0,
+    };
+    let keys: Vec<u64> = map
+        .keys()
+        .filter_map(Result::ok)
+        .filter(|key| (key >> 32) as u32 == service)
+        .collect();
+    for key in keys.iter() {
+        if let Err(err) = map.remove(key) {
+            warn!(target: "gated_clients", "Failed to remove a gated client of {}: {}", service_ip, err);
+        }
+    }
+    keys.len()
+}
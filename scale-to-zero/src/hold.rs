@@ -0,0 +1,357 @@
+use aya::maps::{Array, MapData, XskMap};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::kubernetes::endpoints;
+use crate::kubernetes::models::WATCHED_SERVICES;
+use crate::metrics;
+
+const ETH_HDR_LEN: usize = 14;
+const FRAME_SIZE: u32 = 2048;
+// Number of frames of each socket, all of them are given to the fill ring
+const RING_SIZE: u32 = 1024;
+// Held packets are dropped after this long, the client has retransmitted them by then anyway
+const HOLD_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct HeldPacket {
+    received: Instant,
+    // starts at the IPv4 header
+    ip_packet: Vec<u8>,
+}
+
+// This contains the packets held for each gated service IP, until its backends are ready
+static HELD: Lazy<Mutex<HashMap<u32, VecDeque<HeldPacket>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Bind an AF_XDP socket to every RX queue of the interface and register them in HELD_PACKETS, the
+// eBPF program redirects the gated packets of latency-critical services received on it to them.
+// Packets are reinjected into the host network stack once a backend is ready, so kube-proxy routes
+// them
+pub fn start(
+    mut sockets: XskMap<MapData>,
+    mut hold_interface: Array<MapData, u32>,
+    interface: &str,
+    max_frames: usize,
+) -> anyhow::Result<()> {
+    let name = CString::new(interface)?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        anyhow::bail!("Unknown interface {}", interface);
+    }
+
+    for queue in 0..rx_queues(interface)? {
+        let socket = XskSocket::bind(ifindex, queue)?;
+        sockets.set(queue, socket.fd.as_raw_fd(), 0)?;
+        thread::spawn(move || socket.receive(max_frames));
+    }
+    hold_interface.set(0, ifindex, 0)?;
+    info!(target: "hold", "Holding gated packets of latency-critical services received on {}", interface);
+
+    tokio::spawn(reinject(sockets));
+    Ok(())
+}
+
+fn rx_queues(interface: &str) -> anyhow::Result<u32> {
+    let queues = std::fs::read_dir(format!("/sys/class/net/{}/queues", interface))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("rx-"))
+        .count();
+    Ok(queues.max(1) as u32)
+}
+
+fn hold(frame: &[u8], max_frames: usize) {
+    if frame.len() < ETH_HDR_LEN + 20 {
+        return;
+    }
+    let ip_packet = &frame[ETH_HDR_LEN..];
+    let dst = u32::from_be_bytes([ip_packet[16], ip_packet[17], ip_packet[18], ip_packet[19]]);
+
+    let mut held = HELD.lock().unwrap();
+    let packets = held.entry(dst).or_default();
+    if packets.len() >= max_frames {
+        metrics::HELD_PACKETS_DROPPED.inc();
+        return;
+    }
+    metrics::HELD_PACKETS.inc();
+    packets.push_back(HeldPacket {
+        received: Instant::now(),
+        ip_packet: ip_packet.to_vec(),
+    });
+}
+
+// Send the held packets of awake services through a raw socket, they go through the OUTPUT chain
+// where kube-proxy translates the ClusterIP like it would have on the way in. A replica is not
+// enough, packets sent before a pod listens would be refused, so they wait for a ready endpoint
+async fn reinject(_sockets: XskMap<MapData>) {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_RAW, libc::IPPROTO_RAW) };
+    if fd < 0 {
+        warn!(target: "hold", "Held packets can't be reinjected: {}", io::Error::last_os_error());
+        return;
+    }
+    let raw_socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    loop {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let available: HashSet<u32> = WATCHED_SERVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(ip, service)| service.backend_available && endpoints::ready_endpoints(ip) > 0)
+            .filter_map(|(ip, _)| ip.parse::<Ipv4Addr>().ok())
+            .map(u32::from)
+            .collect();
+
+        let mut ready = Vec::new();
+        {
+            let mut held = HELD.lock().unwrap();
+            for packets in held.values_mut() {
+                let before = packets.len();
+                packets.retain(|packet| packet.received.elapsed() < HOLD_TIMEOUT);
+                metrics::HELD_PACKETS_DROPPED.inc_by((before - packets.len()) as u64);
+            }
+            held.retain(|ip, packets| {
+                if available.contains(ip) {
+                    ready.push((*ip, mem::take(packets)));
+                }
+                !packets.is_empty()
+            });
+        }
+
+        for (ip, packets) in ready {
+            info!(target: "hold", "Reinjecting {} held packets of {}", packets.len(), Ipv4Addr::from(ip));
+            for packet in packets {
+                if let Err(err) = send_raw(&raw_socket, ip, &packet.ip_packet) {
+                    warn!(target: "hold", "Failed to reinject a packet of {}: {}", Ipv4Addr::from(ip), err);
+                }
+            }
+        }
+    }
+}
+
+fn send_raw(socket: &OwnedFd, dst: u32, ip_packet: &[u8]) -> io::Result<()> {
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_addr.s_addr = dst.to_be();
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            ip_packet.as_ptr() as *const libc::c_void,
+            ip_packet.len(),
+            0,
+            &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Producer/consumer ring shared with the kernel
+struct Ring<T> {
+    // only kept to unmap the ring with it
+    _mapping: Mapping,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    descs: *mut T,
+}
+
+impl<T> Ring<T> {
+    unsafe fn map(
+        fd: &OwnedFd,
+        offsets: &libc::xdp_ring_offset,
+        pgoff: i64,
+    ) -> io::Result<Ring<T>> {
+        let len = offsets.desc as usize + RING_SIZE as usize * mem::size_of::<T>();
+        let base = libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd.as_raw_fd(),
+            pgoff as libc::off_t,
+        );
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let mapping = Mapping { base, len };
+        let base = base as *mut u8;
+        Ok(Ring {
+            _mapping: mapping,
+            producer: base.add(offsets.producer as usize) as *const AtomicU32,
+            consumer: base.add(offsets.consumer as usize) as *const AtomicU32,
+            descs: base.add(offsets.desc as usize) as *mut T,
+        })
+    }
+
+    unsafe fn desc(&self, index: u32) -> *mut T {
+        self.descs.add((index & (RING_SIZE - 1)) as usize)
+    }
+}
+
+// Memory mapped by the agent, unmapped when dropped
+struct Mapping {
+    base: *mut libc::c_void,
+    len: usize,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base, self.len);
+        }
+    }
+}
+
+// AF_XDP socket in copy mode (the XDP program runs in SKB mode) with only a RX ring. The rings are
+// declared before the UMEM so they are unmapped first, the socket is closed last
+struct XskSocket {
+    fill: Ring<u64>,
+    rx: Ring<libc::xdp_desc>,
+    umem: Mapping,
+    fd: OwnedFd,
+}
+
+// the rings and the UMEM are only used by the thread receiving from the socket
+unsafe impl Send for XskSocket {}
+
+impl XskSocket {
+    fn bind(ifindex: u32, queue: u32) -> io::Result<XskSocket> {
+        unsafe {
+            let fd = libc::socket(libc::AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = OwnedFd::from_raw_fd(fd);
+
+            let umem_len = (RING_SIZE * FRAME_SIZE) as usize;
+            let umem = libc::mmap(
+                ptr::null_mut(),
+                umem_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if umem == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            // unmapped on the error paths below as well
+            let umem = Mapping {
+                base: umem,
+                len: umem_len,
+            };
+
+            let mut reg: libc::xdp_umem_reg = mem::zeroed();
+            reg.addr = umem.base as u64;
+            reg.len = umem_len as u64;
+            reg.chunk_size = FRAME_SIZE;
+            setsockopt(&fd, libc::XDP_UMEM_REG, &reg)?;
+            setsockopt(&fd, libc::XDP_UMEM_FILL_RING, &RING_SIZE)?;
+            setsockopt(&fd, libc::XDP_UMEM_COMPLETION_RING, &RING_SIZE)?;
+            setsockopt(&fd, libc::XDP_RX_RING, &RING_SIZE)?;
+
+            let mut offsets: libc::xdp_mmap_offsets = mem::zeroed();
+            let mut optlen = mem::size_of::<libc::xdp_mmap_offsets>() as libc::socklen_t;
+            if libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_XDP,
+                libc::XDP_MMAP_OFFSETS,
+                &mut offsets as *mut libc::xdp_mmap_offsets as *mut libc::c_void,
+                &mut optlen,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            let fill: Ring<u64> =
+                Ring::map(&fd, &offsets.fr, libc::XDP_UMEM_PGOFF_FILL_RING as i64)?;
+            let rx: Ring<libc::xdp_desc> = Ring::map(&fd, &offsets.rx, libc::XDP_PGOFF_RX_RING)?;
+
+            // every frame is available to the kernel to start with
+            for frame in 0..RING_SIZE {
+                *fill.desc(frame) = (frame * FRAME_SIZE) as u64;
+            }
+            (*fill.producer).store(RING_SIZE, Ordering::Release);
+
+            let mut addr: libc::sockaddr_xdp = mem::zeroed();
+            addr.sxdp_family = libc::AF_XDP as u16;
+            addr.sxdp_flags = libc::XDP_COPY;
+            addr.sxdp_ifindex = ifindex;
+            addr.sxdp_queue_id = queue;
+            if libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_xdp as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_xdp>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(XskSocket { fill, rx, umem, fd })
+        }
+    }
+
+    fn receive(self, max_frames: usize) {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            if unsafe { libc::poll(&mut pollfd, 1, 1000) } <= 0 {
+                continue;
+            }
+            unsafe {
+                let consumer = (*self.rx.consumer).load(Ordering::Relaxed);
+                let available = (*self.rx.producer)
+                    .load(Ordering::Acquire)
+                    .wrapping_sub(consumer);
+                let mut fill_producer = (*self.fill.producer).load(Ordering::Relaxed);
+
+                for i in 0..available {
+                    let desc = *self.rx.desc(consumer.wrapping_add(i));
+                    let frame = std::slice::from_raw_parts(
+                        (self.umem.base as *const u8).add(desc.addr as usize),
+                        desc.len as usize,
+                    );
+                    hold(frame, max_frames);
+
+                    // the frame is copied, give it back to the kernel
+                    *self.fill.desc(fill_producer) = desc.addr - desc.addr % FRAME_SIZE as u64;
+                    fill_producer = fill_producer.wrapping_add(1);
+                }
+                (*self.fill.producer).store(fill_producer, Ordering::Release);
+                (*self.rx.consumer).store(consumer.wrapping_add(available), Ordering::Release);
+            }
+        }
+    }
+}
+
+fn setsockopt<T>(fd: &OwnedFd, option: libc::c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_XDP,
+            option,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use k8s_openapi::api::coordination::v1::Lease;
+use k8s_openapi::serde_json::{self, json};
+use kube::api::{Api, DynamicObject, ListParams, Patch, PatchParams};
+use kube::core::GroupVersionKind;
+use kube::{discovery, Client, ResourceExt};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::lease;
+use super::models::{annotation, annotation_key, ServiceData};
+use super::scaler::FIELD_MANAGER;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// A kind that isn't installed is looked up again this often
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+// Lease of the agent listing the canaries of a namespace, held for three polls
+const LEASE_NAME: &str = "scale-to-zero-canaries";
+const LEASE_SECONDS: i32 = 30;
+
+// Annotation of the Lease with the canaries in progress, for the agents not holding it
+const CANARIES_ANNOTATION: &str = "canaries";
+
+// Phases of a Flagger Canary while its analysis runs
+const FLAGGER_ACTIVE_PHASES: [&str; 5] = [
+    "Progressing",
+    "Promoting",
+    "Finalising",
+    "Waiting",
+    "WaitingPromotion",
+];
+
+// Phases of an Argo Rollout while it moves to a new revision
+const ROLLOUT_ACTIVE_PHASES: [&str; 2] = ["Progressing", "Paused"];
+
+// A canary analysis or rollout in progress, with the workloads and services it shifts traffic
+// between. Scaling any of them to zero breaks it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ActiveCanary {
+    description: String,
+    workloads: Vec<String>,
+    services: Vec<String>,
+}
+
+// This contains the canaries in progress of each watched namespace
+static ACTIVE: Lazy<Mutex<HashMap<String, Vec<ActiveCanary>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Why a service must not be scaled down, if it is part of a canary in progress
+pub fn active(service: &ServiceData) -> Option<String> {
+    ACTIVE
+        .lock()
+        .unwrap()
+        .get(&service.namespace)?
+        .iter()
+        .find(|canary| {
+            canary.workloads.contains(&service.name)
+                || canary.services.contains(&service.service_name)
+        })
+        .map(|canary| canary.description.clone())
+}
+
+// An optional kind of canary, discovered again until it is installed
+struct CanaryKind {
+    group: &'static str,
+    kind: &'static str,
+    description: &'static str,
+    parse: fn(&DynamicObject) -> Option<ActiveCanary>,
+    api: Option<Api<DynamicObject>>,
+    discovered_at: Option<Instant>,
+    // lacking RBAC for the kind disables it
+    forbidden: bool,
+}
+
+impl CanaryKind {
+    fn new(
+        group: &'static str,
+        kind: &'static str,
+        description: &'static str,
+        parse: fn(&DynamicObject) -> Option<ActiveCanary>,
+    ) -> Self {
+        CanaryKind {
+            group,
+            kind,
+            description,
+            parse,
+            api: None,
+            discovered_at: None,
+            forbidden: false,
+        }
+    }
+
+    async fn list(&mut self, client: &Client, namespace: &str, active: &mut Vec<ActiveCanary>) {
+        if self.forbidden {
+            return;
+        }
+        if self.api.is_none() {
+            if self
+                .discovered_at
+                .is_some_and(|discovered_at| discovered_at.elapsed() < DISCOVERY_INTERVAL)
+            {
+                return;
+            }
+            self.discovered_at = Some(Instant::now());
+            self.api = api(client, namespace, self.group, self.kind).await;
+        }
+        let api = match self.api.as_ref() {
+            Some(api) => api,
+            None => return,
+        };
+        match api.list(&ListParams::default()).await {
+            Ok(list) => active.extend(list.iter().filter_map(self.parse)),
+            Err(kube::Error::Api(err)) if err.code == 403 => {
+                warn!(target: "canary", "Not allowed to list {} in {}, scale-downs ignore them: {}", self.description, namespace, err.message);
+                self.forbidden = true;
+            }
+            // uninstalled, looked up again later
+            Err(kube::Error::Api(err)) if err.code == 404 => self.api = None,
+            Err(err) => {
+                warn!(target: "canary", "Failed to list {} in {}: {}", self.description, namespace, err)
+            }
+        }
+    }
+}
+
+// Refresh the canaries of a namespace every 10s. Only the agent holding the canaries Lease of the
+// namespace lists them and publishes them in the Lease, the other agents read them from there.
+// Flagger and Argo Rollouts are optional, and lacking RBAC for either only disables that kind
+pub async fn track_canaries(client: Client, namespace: String) {
+    let mut kinds = [
+        CanaryKind::new("flagger.app", "Canary", "Flagger canaries", flagger_canary),
+        CanaryKind::new("argoproj.io", "Rollout", "Argo Rollouts", argo_rollout),
+    ];
+    loop {
+        let active = match lease::hold(&namespace, LEASE_NAME, LEASE_SECONDS).await {
+            Ok(true) => {
+                let mut active = Vec::new();
+                for kind in kinds.iter_mut() {
+                    kind.list(&client, &namespace, &mut active).await;
+                }
+                if let Err(err) = publish(&client, &namespace, &active).await {
+                    warn!(target: "canary", "Failed to publish the canaries of {}: {}", namespace, err);
+                }
+                Some(active)
+            }
+            Ok(false) => match shared(&client, &namespace).await {
+                Ok(active) => active,
+                Err(err) => {
+                    warn!(target: "canary", "Failed to read the canaries of {}: {:#}", namespace, err);
+                    None
+                }
+            },
+            Err(err) => {
+                warn!(target: "canary", "Failed to take the canaries lease of {}: {:#}", namespace, err);
+                None
+            }
+        };
+        if let Some(active) = active {
+            ACTIVE.lock().unwrap().insert(namespace.clone(), active);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn publish(client: &Client, namespace: &str, active: &[ActiveCanary]) -> kube::Result<()> {
+    let leases: Api<Lease> = Api::namespaced(client.clone(), namespace);
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                annotation_key(CANARIES_ANNOTATION): json!(active).to_string()
+            }
+        }
+    }));
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    };
+    leases.patch(LEASE_NAME, &params, &patch).await?;
+    Ok(())
+}
+
+// The canaries published by the holder of the Lease, none before it published them
+async fn shared(client: &Client, namespace: &str) -> anyhow::Result<Option<Vec<ActiveCanary>>> {
+    let leases: Api<Lease> = Api::namespaced(client.clone(), namespace);
+    let lease = match leases.get_opt(LEASE_NAME).await? {
+        Some(lease) => lease,
+        None => return Ok(None),
+    };
+    match annotation(lease.annotations(), CANARIES_ANNOTATION) {
+        Some(active) => Ok(Some(serde_json::from_str(active)?)),
+        None => Ok(None),
+    }
+}
+
+async fn api(
+    client: &Client,
+    namespace: &str,
+    group: &str,
+    kind: &str,
+) -> Option<Api<DynamicObject>> {
+    for version in ["v1beta1", "v1alpha1"] {
+        let gvk = GroupVersionKind::gvk(group, version, kind);
+        if let Ok((resource, _)) = discovery::pinned_kind(client, &gvk).await {
+            info!(target: "canary", "{} {}/{} is installed", kind, group, version);
+            return Some(Api::namespaced_with(client.clone(), namespace, &resource));
+        }
+    }
+    None
+}
+
+fn text(object: &DynamicObject, pointer: &str) -> Option<String> {
+    Some(object.data.pointer(pointer)?.as_str()?.to_string())
+}
+
+// Flagger copies the target to <target>-primary and scales the target itself between analyses,
+// traffic goes through the <service>, <service>-primary and <service>-canary services
+fn flagger_canary(canary: &DynamicObject) -> Option<ActiveCanary> {
+    let phase = text(canary, "/status/phase")?;
+    if !FLAGGER_ACTIVE_PHASES.contains(&phase.as_str()) {
+        return None;
+    }
+    let target = text(canary, "/spec/targetRef/name")?;
+    let service = text(canary, "/spec/service/name").unwrap_or_else(|| target.clone());
+    Some(ActiveCanary {
+        description: format!("Flagger canary {} is {}", canary.name_any(), phase),
+        workloads: vec![format!("{}-primary", target), target],
+        services: vec![
+            format!("{}-primary", service),
+            format!("{}-canary", service),
+            service,
+        ],
+    })
+}
+
+// A Rollout either runs its own pods or takes over a Deployment through its workloadRef, traffic
+// goes through the stable and canary (or active and preview) services of its strategy
+fn argo_rollout(rollout: &DynamicObject) -> Option<ActiveCanary> {
+    let phase = text(rollout, "/status/phase")?;
+    if !ROLLOUT_ACTIVE_PHASES.contains(&phase.as_str()) {
+        return None;
+    }
+    let services = [
+        "/spec/strategy/canary/stableService",
+        "/spec/strategy/canary/canaryService",
+        "/spec/strategy/blueGreen/activeService",
+        "/spec/strategy/blueGreen/previewService",
+    ]
+    .iter()
+    .filter_map(|pointer| text(rollout, pointer))
+    .collect();
+    Some(ActiveCanary {
+        description: format!("Argo rollout {} is {}", rollout.name_any(), phase),
+        workloads: text(rollout, "/spec/workloadRef/name")
+            .into_iter()
+            .collect(),
+        services,
+    })
+}
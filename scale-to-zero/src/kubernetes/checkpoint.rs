@@ -0,0 +1,351 @@
+use anyhow::Context;
+use hyper::http;
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use k8s_openapi::api::core::v1::{Pod, PodTemplateSpec, Service};
+use k8s_openapi::serde_json::{self, json, Value};
+use kube::api::{Api, ListParams, Patch, PatchParams};
+use kube::runtime::events::EventType;
+use kube::ResourceExt;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::events;
+use super::lease;
+use super::models::{annotation, annotation_key, ServiceData};
+use super::scaler::FIELD_MANAGER;
+use super::startup;
+
+// Set on the service after a checkpoint, the archives written by the kubelets as JSON by node
+const ARCHIVES_ANNOTATION: &str = "checkpoint-archives";
+// Set on the service by the hook that built checkpoint images out of the archives, as JSON by
+// container. Used by the next wake, then removed
+const IMAGES_ANNOTATION: &str = "checkpoint-images";
+// Images of the containers before they were restored from checkpoint images, put back on the next
+// scale-down
+const ORIGINAL_IMAGES_ANNOTATION: &str = "checkpoint-original-images";
+// Time a kubelet gets to checkpoint a container
+const CHECKPOINT_TIMEOUT_SECONDS: u64 = 60;
+// The agent checkpointing a workload keeps its lease until the workload is scaled down
+const LEASE_SECONDS: i32 = 300;
+// An agent that didn't get the lease leaves the scale-down to the holder for this long
+const RETRY_AFTER: Duration = Duration::from_secs(10);
+
+// This contains the services being checkpointed, by this agent or another one, and until when the
+// scale-down loop leaves them alone
+static BUSY: Lazy<Mutex<HashMap<String, Instant>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn lease_name(service: &ServiceData) -> String {
+    format!("scale-to-zero-{}-{}-checkpoint", service.kind, service.name)
+}
+
+// Forget the services no longer watched, and the ones left alone long enough, returns the number
+// of services left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    let now = Instant::now();
+    let mut busy = BUSY.lock().unwrap();
+    busy.retain(|ip, until| watched.contains(ip) && now < *until);
+    busy.len()
+}
+
+// The service is being checkpointed and scaled down
+pub fn busy(service_ip: &str) -> bool {
+    BUSY.lock()
+        .unwrap()
+        .get(service_ip)
+        .is_some_and(|until| Instant::now() < *until)
+}
+
+// Checkpoint the running containers of a workload through the kubelet checkpoint API (the
+// ContainerCheckpoint feature gate, with CRI-O or containerd 2) right before it is scaled down.
+// The archives stay on the nodes and are listed on the service, for a hook to turn them into
+// checkpoint images the pods are restored from on the next wake. Only the agent holding the lease
+// checkpoints and then scales the workload down, returns whether this agent got it. Experimental,
+// a failed checkpoint doesn't stop the scale-down
+pub async fn create(service_ip: &str, service: &ServiceData) -> bool {
+    let lease = lease_name(service);
+    let acquired = match lease::try_acquire_for(&service.namespace, &lease, LEASE_SECONDS).await {
+        Ok(acquired) => acquired,
+        Err(err) => {
+            warn!(target: "checkpoint", "Failed to take lease {}: {}", lease, err);
+            false
+        }
+    };
+    let until = if acquired {
+        Instant::now() + Duration::from_secs(LEASE_SECONDS as u64)
+    } else {
+        Instant::now() + RETRY_AFTER
+    };
+    BUSY.lock().unwrap().insert(service_ip.to_string(), until);
+    if !acquired {
+        return false;
+    }
+
+    let (type_, reason, note) = match try_create(service).await {
+        Ok(archives) => {
+            info!(target: "checkpoint", "Checkpointed {} containers of {} {}", archives, service.kind, service.name);
+            (
+                EventType::Normal,
+                "Checkpointed",
+                format!("{} containers checkpointed before the scale-down", archives),
+            )
+        }
+        Err(err) => {
+            warn!(target: "checkpoint", "Failed to checkpoint {} {}: {:#}", service.kind, service.name, err);
+            (EventType::Warning, "CheckpointFailed", format!("{:#}", err))
+        }
+    };
+    let published = events::publish(
+        &service.namespace,
+        &service.service_name,
+        type_,
+        reason.to_string(),
+        note,
+        "Checkpoint",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "checkpoint", "Failed to record the checkpoint of {}: {}", service.service_name, err);
+    }
+    true
+}
+
+// The workload was scaled down after its checkpoint: the original images are put back while it has
+// no pod, and the lease is released
+pub async fn done(service_ip: &str, service: &ServiceData, scaled_down: bool) {
+    if scaled_down {
+        if let Err(err) = reset_images(service).await {
+            warn!(target: "checkpoint", "Failed to put back the images of {} {}: {:#}", service.kind, service.name, err);
+        }
+    }
+    if let Err(err) = lease::release(&service.namespace, &lease_name(service)).await {
+        warn!(target: "checkpoint", "Failed to release lease {}: {}", lease_name(service), err);
+    }
+    BUSY.lock().unwrap().remove(service_ip);
+}
+
+// Point the containers of the workload at the checkpoint images listed on the service before it is
+// scaled up, so its pods are restored from them. Without images the workload starts as usual, and
+// so it does when they can't be set
+pub async fn restore(service: &ServiceData) {
+    let (type_, reason, note) = match try_restore(service).await {
+        Ok(0) => return,
+        Ok(containers) => {
+            info!(target: "checkpoint", "Restoring {} containers of {} {} from checkpoint images", containers, service.kind, service.name);
+            (
+                EventType::Normal,
+                "Restored",
+                format!("{} containers restored from checkpoint images", containers),
+            )
+        }
+        Err(err) => {
+            warn!(target: "checkpoint", "Failed to restore {} {}: {:#}", service.kind, service.name, err);
+            (EventType::Warning, "RestoreFailed", format!("{:#}", err))
+        }
+    };
+    let published = events::publish(
+        &service.namespace,
+        &service.service_name,
+        type_,
+        reason.to_string(),
+        note,
+        "Checkpoint",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "checkpoint", "Failed to record the restore of {}: {}", service.service_name, err);
+    }
+}
+
+async fn try_restore(service: &ServiceData) -> anyhow::Result<usize> {
+    let client = super::client().await?;
+    let services: Api<Service> = Api::namespaced(client, &service.namespace);
+    let s = services.get(&service.service_name).await?;
+    let images: BTreeMap<String, String> = match annotation(s.annotations(), IMAGES_ANNOTATION) {
+        Some(images) => serde_json::from_str(images).context("Invalid checkpoint-images")?,
+        None => return Ok(0),
+    };
+    // the images of the workload before its first restore, kept before they are replaced
+    let originals = match annotation(s.annotations(), ORIGINAL_IMAGES_ANNOTATION) {
+        Some(originals) => originals.clone(),
+        None => serde_json::to_string(&template_images(service).await?)?,
+    };
+    annotate(&services, service, &originals, None).await?;
+    set_images(service, &images).await?;
+    // an image is restored from once, the next wake needs a newer checkpoint
+    annotate(&services, service, &originals, Some(Value::Null)).await?;
+    Ok(images.len())
+}
+
+async fn reset_images(service: &ServiceData) -> anyhow::Result<()> {
+    let client = super::client().await?;
+    let services: Api<Service> = Api::namespaced(client, &service.namespace);
+    let s = services.get(&service.service_name).await?;
+    let originals: BTreeMap<String, String> =
+        match annotation(s.annotations(), ORIGINAL_IMAGES_ANNOTATION) {
+            Some(originals) => serde_json::from_str(originals)?,
+            None => return Ok(()),
+        };
+    set_images(service, &originals).await?;
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                annotation_key(ORIGINAL_IMAGES_ANNOTATION): null
+            }
+        }
+    }));
+    services
+        .patch(&service.service_name, &params(), &patch)
+        .await?;
+    Ok(())
+}
+
+async fn annotate(
+    services: &Api<Service>,
+    service: &ServiceData,
+    originals: &str,
+    images: Option<Value>,
+) -> anyhow::Result<()> {
+    let mut annotations = json!({ annotation_key(ORIGINAL_IMAGES_ANNOTATION): originals });
+    if let Some(images) = images {
+        annotations[annotation_key(IMAGES_ANNOTATION)] = images;
+    }
+    let patch = Patch::Merge(json!({ "metadata": { "annotations": annotations } }));
+    services
+        .patch(&service.service_name, &params(), &patch)
+        .await?;
+    Ok(())
+}
+
+fn params() -> PatchParams {
+    PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    }
+}
+
+// Images of the containers in the pod template of the workload
+async fn template_images(service: &ServiceData) -> anyhow::Result<BTreeMap<String, String>> {
+    let client = super::client().await?;
+    let template = match service.kind.as_str() {
+        "deployment" => Api::<Deployment>::namespaced(client, &service.namespace)
+            .get(&service.name)
+            .await?
+            .spec
+            .map(|spec| spec.template),
+        "statefulset" => Api::<StatefulSet>::namespaced(client, &service.namespace)
+            .get(&service.name)
+            .await?
+            .spec
+            .map(|spec| spec.template),
+        kind => anyhow::bail!("Checkpoints can't be restored into a {}", kind),
+    };
+    Ok(template
+        .and_then(|template: PodTemplateSpec| template.spec)
+        .map(|spec| spec.containers)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|container| Some((container.name, container.image?)))
+        .collect())
+}
+
+async fn set_images(
+    service: &ServiceData,
+    images: &BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+    let client = super::client().await?;
+    let containers: Vec<Value> = images
+        .iter()
+        .map(|(name, image)| json!({ "name": name, "image": image }))
+        .collect();
+    let patch = Patch::Strategic(json!({
+        "spec": {
+            "template": {
+                "spec": {
+                    "containers": containers
+                }
+            }
+        }
+    }));
+    match service.kind.as_str() {
+        "deployment" => {
+            Api::<Deployment>::namespaced(client, &service.namespace)
+                .patch(&service.name, &params(), &patch)
+                .await?;
+        }
+        "statefulset" => {
+            Api::<StatefulSet>::namespaced(client, &service.namespace)
+                .patch(&service.name, &params(), &patch)
+                .await?;
+        }
+        kind => anyhow::bail!("Checkpoints can't be restored into a {}", kind),
+    }
+    Ok(())
+}
+
+async fn try_create(service: &ServiceData) -> anyhow::Result<usize> {
+    let client = super::client().await?;
+    let pods: Api<Pod> = Api::namespaced(client.clone(), &service.namespace);
+    let params = ListParams::default().labels(&startup::pod_selector(service).await?);
+
+    let mut archives: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for pod in pods.list(&params).await? {
+        let node = match pod.spec.as_ref().and_then(|spec| spec.node_name.clone()) {
+            Some(node) => node,
+            None => continue,
+        };
+        let running: Vec<String> = pod
+            .status
+            .as_ref()
+            .and_then(|status| status.container_statuses.as_ref())
+            .into_iter()
+            .flatten()
+            .filter(
+                |status| matches!(status.state.as_ref(), Some(state) if state.running.is_some()),
+            )
+            .map(|status| status.name.clone())
+            .collect();
+        for container in running {
+            // through the node proxy of the API server, the agent has no kubelet credentials
+            let path = format!(
+                "/api/v1/nodes/{}/proxy/checkpoint/{}/{}/{}?timeout={}",
+                node,
+                service.namespace,
+                pod.name_any(),
+                container,
+                CHECKPOINT_TIMEOUT_SECONDS
+            );
+            let response: Value = client
+                .request(http::Request::post(path).body(Vec::new())?)
+                .await?;
+            let items = response
+                .get("items")
+                .and_then(|items| items.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|item| item.as_str().map(str::to_string));
+            archives.entry(node.clone()).or_default().extend(items);
+        }
+    }
+    let count = archives.values().map(Vec::len).sum();
+    if count == 0 {
+        anyhow::bail!("No running container to checkpoint");
+    }
+
+    let services: Api<Service> = Api::namespaced(client, &service.namespace);
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                annotation_key(ARCHIVES_ANNOTATION): serde_json::to_string(&archives)?
+            }
+        }
+    }));
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    };
+    services
+        .patch(&service.service_name, &params, &patch)
+        .await?;
+    Ok(count)
+}
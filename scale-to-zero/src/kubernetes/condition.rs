@@ -0,0 +1,66 @@
+use cel_interpreter::{Context, Program, Value};
+use k8s_openapi::chrono::{self, Datelike, Timelike};
+use k8s_openapi::serde_json;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use crate::policy::PolicyContext;
+
+// Service IPs whose condition failed to evaluate, warned about once until it evaluates again
+static FAILING: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Forget the services no longer watched
+pub fn retain(watched: &HashSet<String>) {
+    FAILING.lock().unwrap().retain(|ip| watched.contains(ip));
+}
+
+// Parse a scale-down-condition, a typo is reported when the service is reconciled
+pub fn compile(expression: &str) -> anyhow::Result<Program> {
+    Program::compile(expression).map_err(|err| anyhow::anyhow!("{}", err))
+}
+
+// Whether the scale-down-condition of an idle service lets it be scaled down. A condition that
+// can't be evaluated keeps the service up
+pub fn allows_scale_down(service_ip: &str, expression: &str, context: &PolicyContext) -> bool {
+    match evaluate(expression, context) {
+        Ok(allowed) => {
+            FAILING.lock().unwrap().remove(service_ip);
+            allowed
+        }
+        Err(err) => {
+            if FAILING.lock().unwrap().insert(service_ip.to_string()) {
+                warn!(target: "condition", "Keeping {} {} up, its scale-down-condition failed: {:#}", context.kind, context.name, err);
+            }
+            false
+        }
+    }
+}
+
+// The fields of the policy context are the variables of the expression, with the current time
+// (UTC) as `now`, `hour`, `minute` and `weekday` (Mon to Sun)
+fn evaluate(expression: &str, policy_context: &PolicyContext) -> anyhow::Result<bool> {
+    let program = compile(expression)?;
+    let mut context = Context::default();
+    if let serde_json::Value::Object(fields) = serde_json::to_value(policy_context)? {
+        for (name, value) in fields {
+            context
+                .add_variable(name, value)
+                .map_err(|err| anyhow::anyhow!("{}", err))?;
+        }
+    }
+    let now = chrono::Utc::now();
+    context.add_variable_from_value("now", now.timestamp());
+    context.add_variable_from_value("hour", i64::from(now.hour()));
+    context.add_variable_from_value("minute", i64::from(now.minute()));
+    context.add_variable_from_value("weekday", now.weekday().to_string());
+
+    let result = program
+        .execute(&context)
+        .map_err(|err| anyhow::anyhow!("{}", err))?;
+    match result {
+        Value::Bool(allowed) => Ok(allowed),
+        value => anyhow::bail!("the condition returned {:?} instead of a boolean", value),
+    }
+}
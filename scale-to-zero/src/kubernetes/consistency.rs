@@ -0,0 +1,159 @@
+use futures::Stream;
+use kube::runtime::events::EventType;
+use log::warn;
+use once_cell::sync::Lazy;
+use scale_to_zero_common::ServicePolicy;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use super::endpoints;
+use super::events;
+use super::models::WATCHED_SERVICES;
+use crate::config;
+use crate::metrics;
+use crate::utils;
+
+// Sent to the controller of every namespace to reconcile all of their services again
+static RESYNC: Lazy<broadcast::Sender<()>> = Lazy::new(|| broadcast::channel(1).0);
+
+// Trigger of Controller::reconcile_all_on
+pub fn resync_requests() -> impl Stream<Item = ()> {
+    futures::stream::unfold(RESYNC.subscribe(), |mut requests| async move {
+        match requests.recv().await {
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => Some(((), requests)),
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    })
+}
+
+// Reconcile every service of every namespace again
+pub fn resync() {
+    let _ = RESYNC.send(());
+}
+
+// Compare the kernel service list with the desired state of the watched services, and that desired
+// state with the cluster, every --consistency-check-interval seconds. An inconsistency found by
+// two checks in a row is repaired, a single one can be a change on its way:
+// - service_list: an entry of SERVICE_LIST differs from the policy of its service, is missing or is
+//   a leftover of a previous agent. The whole list is written again
+// - gate: the gate of a service is closed while its EndpointSlices have ready endpoints, e.g. a
+//   lost watch event. All services are reconciled again, then the gate is opened
+pub async fn check() {
+    let interval = config::get().consistency_check_interval;
+    if interval == 0 {
+        return;
+    }
+    let mut suspects: HashSet<(String, &'static str)> = HashSet::new();
+    loop {
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+        let found = inconsistencies();
+
+        let mut gates_closed = false;
+        for (service_ip, kind) in found.iter() {
+            if !suspects.contains(&(service_ip.clone(), kind)) {
+                // checked again after a resync of the services
+                gates_closed |= *kind == "gate";
+                continue;
+            }
+            metrics::CONSISTENCY_REPAIRS
+                .with_label_values(&[kind])
+                .inc();
+            match *kind {
+                "gate" => open_gate(service_ip).await,
+                _ => {
+                    warn!(target: "consistency", "SERVICE_LIST entry of {} still differs from its service, writing the list again", service_ip);
+                    utils::request_full_sync();
+                }
+            }
+        }
+        if gates_closed {
+            warn!(target: "consistency", "Gates closed on services with ready endpoints, reconciling every service again");
+            resync();
+        }
+        suspects = found.into_iter().collect();
+    }
+}
+
+fn inconsistencies() -> Vec<(String, &'static str)> {
+    let desired: HashMap<u32, ServicePolicy> = WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(ip, service)| {
+            Some((
+                ip.parse::<Ipv4Addr>().ok()?.into(),
+                utils::service_policy(service),
+            ))
+        })
+        .collect();
+    let kernel = utils::SERVICE_LIST_SNAPSHOT.lock().unwrap().clone();
+
+    let mut found = Vec::new();
+    for (ip, value) in kernel.iter() {
+        let stale = match desired.get(ip) {
+            Some(desired) => desired != value,
+            None => super::controller::services_synced(),
+        };
+        if stale {
+            found.push((Ipv4Addr::from(*ip).to_string(), "service_list"));
+        }
+    }
+    found.extend(
+        desired
+            .keys()
+            .filter(|ip| !kernel.contains_key(ip))
+            .map(|ip| (Ipv4Addr::from(*ip).to_string(), "service_list")),
+    );
+
+    let gated: Vec<String> = WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, service)| {
+            !service.backend_available && !service.monitor && service.unmanageable.is_none()
+        })
+        .map(|(ip, _)| ip.clone())
+        .collect();
+    // the controller keeps the gate of a waiting or waking service closed on purpose
+    found.extend(
+        gated
+            .into_iter()
+            .filter(|ip| !super::controller::waiting(ip) && !super::scaler::waking(ip))
+            .filter(|ip| endpoints::ready_endpoints(ip) > 0)
+            .map(|ip| (ip, "gate")),
+    );
+    found
+}
+
+// The resync left the gate closed although the service is served, open it rather than black-hole
+// the service until its next change
+async fn open_gate(service_ip: &str) {
+    if super::controller::waiting(service_ip) || super::scaler::waking(service_ip) {
+        return;
+    }
+    let service = {
+        let mut watched_services = WATCHED_SERVICES.lock().unwrap();
+        match watched_services.get_mut(service_ip) {
+            Some(service) if !service.backend_available => {
+                service.backend_available = true;
+                service.clone()
+            }
+            _ => return,
+        }
+    };
+    warn!(target: "consistency", "Opening the gate of {} {}, its service has ready endpoints", service.kind, service.name);
+    let published = events::publish(
+        &service.namespace,
+        &service.service_name,
+        EventType::Warning,
+        "GateForcedOpen".to_string(),
+        "Gate was closed while the service had ready endpoints, opened by the consistency check"
+            .to_string(),
+        "Reconcile",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "consistency", "Failed to record forced gate of service {}: {}", service.service_name, err);
+    }
+}
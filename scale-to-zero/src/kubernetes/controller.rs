@@ -1,207 +1,868 @@
-use anyhow::{Context, Ok};
-use futures::{stream, StreamExt, TryStreamExt};
+use anyhow::Context as _;
+use futures::StreamExt;
 use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
 use k8s_openapi::api::core::v1::Service;
 use k8s_openapi::chrono;
+use k8s_openapi::serde_json::json;
 use kube::Resource;
 use kube::{
-    api::Api,
-    runtime::{watcher, WatchStreamExt},
+    api::{Api, DynamicObject, Patch, PatchParams},
+    runtime::{
+        controller::Action,
+        events::EventType,
+        finalizer::{finalizer, Event as Finalizer},
+        reflector::{ObjectRef, Store},
+        watcher, Controller,
+    },
     Client, ResourceExt,
 };
-use log::{info, warn};
-use std::collections::HashMap;
-use std::thread;
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use scale_to_zero_common::PROTOCOL_ALL;
+use std::collections::HashSet;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::custom_metrics;
+use crate::kubernetes;
+use crate::kubernetes::canary;
+use crate::kubernetes::condition;
+use crate::kubernetes::consistency;
+use crate::kubernetes::endpoints;
+use crate::kubernetes::enroll::EnrollPolicy;
+use crate::kubernetes::events;
+use crate::kubernetes::gitops;
+use crate::kubernetes::gossip;
+use crate::kubernetes::groups;
+use crate::kubernetes::ingress;
+use crate::kubernetes::kruise;
+use crate::kubernetes::maintenance;
+use crate::kubernetes::models::{
+    self, annotation, parse_duration, ActiveHours, ClientsPerReplica, GatedAction, Hook,
+    LogActivity, ObservedService, PatchTemplates, Priority, QueueDepth, ServiceData, WakeQuota,
+    WakeThreshold, WorkloadReference, ACTIVE_HOURS_ANNOTATION,
+    ACTIVE_HOURS_SCALE_DOWN_TIME_ANNOTATION, ACTIVE_HOURS_TIMEZONE_ANNOTATION,
+    ACTIVITY_PORTS_ANNOTATION, ACTIVITY_PROTOCOLS_ANNOTATION, ACTIVITY_SOURCES_ANNOTATION,
+    BUFFER_UDP_ANNOTATION, CHECKPOINT_ANNOTATION, CLIENTS_PER_REPLICA_ANNOTATION,
+    GATED_ACTION_ANNOTATION, HPA_ANNOTATION, LATENCY_CRITICAL_ANNOTATION, LOG_ACTIVITY_ANNOTATION,
+    MODE_ANNOTATION, OBSERVED_SERVICES, OBSERVED_SERVICES_MAX, PATCH_TARGET_ANNOTATION,
+    PLACEHOLDER_PRIORITY_CLASS_ANNOTATION, POST_SCALE_DOWN_HOOK_ANNOTATION,
+    PRE_WAKE_HOOK_ANNOTATION, PRIORITY_ANNOTATION, QUEUE_DEPTH_QUERY_ANNOTATION,
+    QUEUE_DEPTH_THRESHOLD_ANNOTATION, QUEUE_DEPTH_URL_ANNOTATION, REFERENCE_ANNOTATION,
+    SCALE_DOWN_CONDITION_ANNOTATION, SCALE_DOWN_TIME_ANNOTATION, SERVER_NAMES_ANNOTATION,
+    SLEEP_PATCH_ANNOTATION, WAKE_GROUP_ANNOTATION, WAKE_PATCH_ANNOTATION, WAKE_QUOTA_ANNOTATION,
+    WAKE_QUOTA_POLICY_ANNOTATION, WAKE_THRESHOLD_ANNOTATION, WATCHED_SERVICES,
+};
+use crate::kubernetes::placeholder;
+use crate::kubernetes::policies;
+use crate::kubernetes::pressure;
+use crate::kubernetes::queue_depth;
+use crate::kubernetes::statefulset::{self, Readiness};
+use crate::kubernetes::wake_group;
+use crate::kubernetes::wake_trace;
+use crate::utils;
+use crate::waking_page;
+
+// Removes the service from the kernel map before the service is deleted
+const FINALIZER: &str = "scale-to-zero.isala.me/cleanup";
+
+// Services are reconciled again after this long even if nothing changed
+const REQUEUE_INTERVAL: Duration = Duration::from_secs(300);
+
+// Services whose workload is scaled up but can't serve yet are reconciled again after this long
+const WAKE_REQUEUE_INTERVAL: Duration = Duration::from_secs(2);
+
+// A namespace counts as synced after this long even if some annotated services are not watched,
+// e.g. because their reference is invalid
+const SYNC_TIMEOUT: Duration = Duration::from_secs(60);
+
+// IPs requested for a LoadBalancer service from MetalLB and kube-vip, announced before the status
+// of the service has them
+const LOAD_BALANCER_IPS_ANNOTATIONS: [&str; 2] = [
+    "metallb.universe.tf/loadBalancerIPs",
+    "kube-vip.io/loadbalancerIPs",
+];
+
+// Namespaces whose annotated services are not all watched yet since the agent started
+static UNSYNCED_NAMESPACES: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// This contains the services whose workload is scaled up but can't serve yet, their gate is kept
+// closed on purpose until it can
+static WAITING: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+    #[error("Finalizer error: {0}")]
+    Finalizer(#[source] Box<kube::runtime::finalizer::Error<Error>>),
+}
 
-use crate::kubernetes::models::{ServiceData, WorkloadReference, WATCHED_SERVICES};
+struct Context {
+    services: Api<Service>,
+    deployments: Api<Deployment>,
+    statefulsets: Api<StatefulSet>,
+    enroll_policy: Option<EnrollPolicy>,
+}
 
 pub async fn kube_event_watcher() -> anyhow::Result<()> {
-    // Workload (deploy/statefulset) to service mapper
-    let mut workload_service: HashMap<WorkloadReference, Service> = HashMap::new();
-
-    let client = Client::try_default().await?;
-
-    let services: Api<Service> = Api::default_namespaced(client.clone());
-    let deployments: Api<Deployment> = Api::default_namespaced(client.clone());
-    let statefulsets: Api<StatefulSet> = Api::default_namespaced(client.clone());
-
-    let svc_watcher = watcher(services, watcher::Config::default());
-    let deployment_watcher = watcher(deployments.clone(), watcher::Config::default());
-    let statefulset_watcher = watcher(statefulsets.clone(), watcher::Config::default());
-
-    // select on applied events from all watchers
-    let mut combo_stream = stream::select_all(vec![
-        svc_watcher
-            .applied_objects()
-            .map_ok(Watched::Service)
-            .boxed(),
-        deployment_watcher
-            .applied_objects()
-            .map_ok(Watched::Deployment)
-            .boxed(),
-        statefulset_watcher
-            .applied_objects()
-            .map_ok(Watched::StatefulSet)
-            .boxed(),
-    ]);
-    // SelectAll Stream elements must have the same Item, so all packed in this:
-    #[allow(clippy::large_enum_variant)]
-    enum Watched {
-        Service(Service),
-        Deployment(Deployment),
-        StatefulSet(StatefulSet),
-    }
-    while let Some(o) = combo_stream.try_next().await? {
-        match o {
-            Watched::Service(s) => {
-                // ignore services that don't have the annotation
-                if !s
-                    .annotations()
-                    .contains_key("scale-to-zero.isala.me/reference")
-                    && !s
-                        .annotations()
-                        .contains_key("scale-to-zero.isala.me/scale-down-time")
-                {
-                    info!(target: "kube_event_watcher", "Service {} is not annotated, skipping", s.name_any());
-                    continue;
-                }
+    let client = kubernetes::client().await?;
+
+    // One controller per namespace, so no cluster-wide list or watch is needed
+    tokio::spawn(pressure::track_pressure(client.clone()));
+    tokio::spawn(consistency::check());
+    tokio::spawn(queue_depth::track_queue_depths());
+    if config::get().activity_gossip {
+        tokio::spawn(gossip::share_activity(client.clone()));
+    }
+    if custom_metrics::served() {
+        tokio::spawn(custom_metrics::share_packets());
+    }
+    if config::get().gitops {
+        info!(target: "gitops", "Replicas are changed through the scale subresource by field manager {}, the awake replicas are kept in the {} annotation", kubernetes::scaler::FIELD_MANAGER, models::annotation_key(models::AWAKE_REPLICAS_ANNOTATION));
+    }
+    tokio::spawn(policies::track_cluster_policies(client.clone()));
+    maintenance::init(client.clone()).await;
+
+    let namespaces = kubernetes::namespaces(&client);
+    UNSYNCED_NAMESPACES.store(namespaces.len(), Ordering::Relaxed);
+    let controllers = namespaces
+        .into_iter()
+        .map(|namespace| watch_namespace(client.clone(), namespace));
+    futures::future::join_all(controllers).await;
+    Ok(())
+}
 
-                // Get the workload reference from the annotation
-                let workload_ref = s
-                    .annotations()
-                    .get("scale-to-zero.isala.me/reference")
-                    .unwrap()
-                    .clone();
-                let workload_ref_split: Vec<&str> = workload_ref.split('/').collect();
-
-                if workload_ref_split.len() != 2 {
-                    warn!(
-                        target: "kube_event_watcher",
-                        "Service {} has invalid reference annotation: {}",
-                        s.name_any(),
-                        workload_ref
-                    );
-                    continue;
+async fn watch_namespace(client: Client, namespace: String) {
+    info!(target: "kube_event_watcher", "Watching services in namespace {}", namespace);
+    let services: Api<Service> = Api::namespaced(client.clone(), &namespace);
+    let deployments: Api<Deployment> = Api::namespaced(client.clone(), &namespace);
+    let statefulsets: Api<StatefulSet> = Api::namespaced(client.clone(), &namespace);
+
+    let controller = Controller::new(services.clone(), watcher::Config::default());
+    let store = controller.store();
+    tokio::spawn(prune(store.clone(), namespace.clone()));
+    tokio::spawn(mark_synced(store.clone()));
+    tokio::spawn(groups::track_group(client.clone(), namespace.clone()));
+    tokio::spawn(ingress::track_routes(client.clone(), namespace.clone()));
+    tokio::spawn(canary::track_canaries(client.clone(), namespace.clone()));
+    tokio::spawn(policies::track_policies(client.clone(), namespace.clone()));
+    tokio::spawn(endpoints::track_pod_ips(
+        client.clone(),
+        store.clone(),
+        namespace.clone(),
+    ));
+
+    let ctx = Arc::new(Context {
+        services,
+        deployments: deployments.clone(),
+        statefulsets: statefulsets.clone(),
+        enroll_policy: EnrollPolicy::from_config(),
+    });
+
+    // A change of a workload reconciles the services referencing it
+    let deployment_store = store.clone();
+    let statefulset_store = store.clone();
+    let mut controller = controller
+        .watches(deployments, watcher::Config::default(), move |d| {
+            services_referencing(&deployment_store, d)
+        })
+        .watches(statefulsets, watcher::Config::default(), move |sts| {
+            services_referencing(&statefulset_store, sts)
+        });
+    for (kind, resource) in kruise::discover(&client).await {
+        let kruise_store = store.clone();
+        let api: Api<DynamicObject> = Api::namespaced_with(client.clone(), &namespace, &resource);
+        controller =
+            controller.watches_with(api, resource, watcher::Config::default(), move |obj| {
+                let reference = WorkloadReference {
+                    kind: kind.to_string(),
+                    name: obj.name_any(),
+                    namespace: obj.namespace().unwrap_or_default(),
+                };
+                services_referencing_workload(&kruise_store, &reference)
+            });
+    }
+    controller
+        .reconcile_all_on(consistency::resync_requests())
+        .reconcile_on(policies::requeue_requests(store.clone(), namespace.clone()))
+        .run(reconcile, error_policy, ctx)
+        .for_each(|res| async move {
+            match res {
+                Ok((service, _)) => {
+                    debug!(target: "kube_event_watcher", "Reconciled service {}", service.name)
                 }
-                let workload_type = workload_ref_split[0];
-                let workload_name = workload_ref_split[1];
+                Err(e) => warn!(target: "kube_event_watcher", "Reconcile failed: {}", e),
+            }
+        })
+        .await;
+}
 
-                // Get the idle minutes from the annotation
-                let scale_down_time = s
-                    .annotations()
-                    .get("scale-to-zero.isala.me/scale-down-time")
-                    .unwrap()
-                    .parse::<i64>()
-                    .context("Failed to parse scale-down-time")?;
-
-                let service_ip = s
-                    .spec
-                    .as_ref()
-                    .ok_or_else(|| {
-                        anyhow::anyhow!("Failed to get service spec for {}", s.name_any())
-                    })?
-                    .cluster_ip
-                    .as_ref()
-                    .ok_or_else(|| {
-                        anyhow::anyhow!("Failed to get cluster IP for {}", s.name_any())
-                    })?;
-
-                info!(target: "kube_watcher", "service: {}, workload_type: {}, workload_name: {}, scale_down_time: {}, service_ip: {}", s.name_any(), workload_type, workload_name, scale_down_time, service_ip);
-
-                let workload = match workload_type {
-                    "deployment" => {
-                        let deployment = deployments
-                            .get(workload_name)
-                            .await
-                            .context("Failed to get deployment")?;
-
-                        let replicas = deployment
-                            .spec
-                            .as_ref()
-                            .ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "Failed to get deployment spec for {}",
-                                    deployment.name_any()
-                                )
-                            })?
-                            .replicas
-                            .ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "Failed to get replicas for {}",
-                                    deployment.name_any()
-                                )
-                            })?;
-
-                        update_workload_status(
-                            "deployment".to_string(),
-                            deployment.name_any(),
-                            deployment.namespace(),
-                            replicas,
-                            &mut workload_service,
-                            s.clone(),
-                            service_ip.to_string(),
-                            scale_down_time,
-                        )
-                        .await?;
+// Whether every watched namespace was synced, until then the kernel service list may still hold
+// services of a previous agent that the controller did not get to
+pub fn services_synced() -> bool {
+    UNSYNCED_NAMESPACES.load(Ordering::Relaxed) == 0
+}
 
-                        Ok(())
-                    }
-                    "statefulset" => {
-                        let statefulset = statefulsets
-                            .get(workload_name)
-                            .await
-                            .context("Failed to get statefulset")?;
-
-                        let replicas = statefulset
-                            .spec
-                            .as_ref()
-                            .ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "Failed to get deployment spec for {}",
-                                    statefulset.name_any()
-                                )
-                            })?
-                            .replicas
-                            .ok_or_else(|| {
-                                anyhow::anyhow!(
-                                    "Failed to get replicas for {}",
-                                    statefulset.name_any()
-                                )
-                            })?;
-
-                        update_workload_status(
-                            "statefulset".to_string(),
-                            statefulset.name_any(),
-                            statefulset.namespace(),
-                            replicas,
-                            &mut workload_service,
-                            s.clone(),
-                            service_ip.to_string(),
-                            scale_down_time,
-                        )
-                        .await?;
+// The services of the standalone mode come from its static config, there is nothing to wait for
+pub fn mark_synced_without_cluster() {
+    UNSYNCED_NAMESPACES.store(0, Ordering::Relaxed);
+}
 
-                        Ok(())
-                    }
-                    _ => Err(anyhow::anyhow!("Unknown workload type: {}", workload_type)),
-                };
+async fn mark_synced(store: Store<Service>) {
+    let deadline = Instant::now() + SYNC_TIMEOUT;
+    if store.wait_until_ready().await.is_ok() {
+        while Instant::now() < deadline {
+            let cluster_ips: Vec<String> = store
+                .state()
+                .iter()
+                .filter(|s| is_annotated(s))
+                .filter_map(|s| s.spec.as_ref()?.cluster_ip.clone())
+                .collect();
+            let synced = {
+                let watched = WATCHED_SERVICES.lock().unwrap();
+                cluster_ips.iter().all(|ip| watched.contains_key(ip))
+            };
+            if synced {
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+    UNSYNCED_NAMESPACES.fetch_sub(1, Ordering::Relaxed);
+}
+
+// Annotated itself, or selected by a policy
+pub fn is_annotated(s: &Service) -> bool {
+    annotation(s.annotations(), REFERENCE_ANNOTATION).is_some()
+        || annotation(s.annotations(), SCALE_DOWN_TIME_ANNOTATION).is_some()
+        || !policies::matching(&s.namespace().unwrap_or_default(), s.labels())
+            .0
+            .is_empty()
+}
+
+async fn reconcile(s: Arc<Service>, ctx: Arc<Context>) -> Result<Action, Error> {
+    // ignore services that don't have the annotation, forgetting them if they had it before
+    if !is_annotated(&s) {
+        cleanup(&s);
+        if s.finalizers().iter().any(|f| f == FINALIZER) {
+            remove_finalizer(&ctx.services, &s).await?;
+        }
+        if s.meta().deletion_timestamp.is_some() {
+            return Ok(Action::await_change());
+        }
+
+        if let Some(policy) = ctx.enroll_policy.as_ref().filter(|p| p.matches(&s)) {
+            policy
+                .enroll(&s, &ctx.services, &ctx.deployments, &ctx.statefulsets)
+                .await?;
+            return Ok(Action::await_change());
+        }
+        if config::get().learning_mode {
+            observe_service(&s);
+        }
+        debug!(target: "kube_event_watcher", "Service {} is not annotated, skipping", s.name_any());
+        return Ok(Action::await_change());
+    }
+
+    let services = ctx.services.clone();
+    finalizer(&services, FINALIZER, s, |event| async move {
+        match event {
+            Finalizer::Apply(s) => apply(&policies::apply(&s), &ctx).await.map_err(Error::from),
+            Finalizer::Cleanup(s) => {
+                cleanup(&s);
+                Ok(Action::await_change())
+            }
+        }
+    })
+    .await
+    .map_err(|e| Error::Finalizer(Box::new(e)))
+}
+
+fn error_policy(s: Arc<Service>, error: &Error, _ctx: Arc<Context>) -> Action {
+    warn!(target: "kube_event_watcher", "Failed to reconcile service {}: {}", s.name_any(), error);
+    Action::requeue(Duration::from_secs(10))
+}
+
+async fn apply(s: &Service, ctx: &Context) -> anyhow::Result<Action> {
+    // Get the workload reference from the annotation
+    let workload = match workload_reference(s) {
+        Some(workload) => workload,
+        None => {
+            warn!(
+                target: "kube_event_watcher",
+                "Service {} has invalid reference annotation: {:?}",
+                s.name_any(),
+                annotation(s.annotations(), REFERENCE_ANNOTATION)
+            );
+            return Ok(Action::await_change());
+        }
+    };
+
+    // Get the idle seconds from the annotation
+    let scale_down_time = match scale_down_time(s) {
+        Ok(scale_down_time) => scale_down_time,
+        Err(err) => return invalid_annotations(s, err).await,
+    };
 
-                if let Err(e) = workload {
-                    warn!(target: "kube_event_watcher", "Failed to get workload: {}", e);
-                    continue;
+    let service_ip = s
+        .spec
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get service spec for {}", s.name_any()))?
+        .cluster_ip
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get cluster IP for {}", s.name_any()))?;
+
+    info!(target: "kube_watcher", "service: {}, workload_type: {}, workload_name: {}, scale_down_time: {}, service_ip: {}", s.name_any(), workload.kind, workload.name, scale_down_time, service_ip);
+
+    let mut waking = false;
+    let replicas = match workload.kind.as_str() {
+        "deployment" => {
+            let deployment = match ctx
+                .deployments
+                .get_opt(&workload.name)
+                .await
+                .context("Failed to get deployment")?
+            {
+                Some(deployment) => deployment,
+                None => return workload_missing(s, &workload),
+            };
+            let replicas = replicas_of(deployment.clone())?;
+            let managed_fields = deployment.metadata.managed_fields.as_deref();
+            gitops::check(
+                &workload,
+                &s.name_any(),
+                replicas,
+                managed_fields.unwrap_or_default(),
+            )
+            .await;
+            // a replica that crash loops or is still pulling its image can't serve, the gate stays
+            // closed until one is ready
+            if replicas >= 1 {
+                let from_zero = WATCHED_SERVICES
+                    .lock()
+                    .unwrap()
+                    .get(service_ip)
+                    .is_some_and(|service| !service.backend_available);
+                if let Some(reason) = deployment_waiting(&deployment, from_zero) {
+                    debug!(target: "kube_event_watcher", "Deployment {} is waking up: {}", workload.name, reason);
+                    waking = true;
                 }
             }
-            Watched::Deployment(d) => {
-                process_resource(d, &workload_service)?;
+            replicas
+        }
+        "statefulset" => {
+            let statefulset = match ctx
+                .statefulsets
+                .get_opt(&workload.name)
+                .await
+                .context("Failed to get statefulset")?
+            {
+                Some(statefulset) => statefulset,
+                None => return workload_missing(s, &workload),
+            };
+            let replicas = replicas_of(statefulset.clone())?;
+            let managed_fields = statefulset.metadata.managed_fields.as_deref();
+            gitops::check(
+                &workload,
+                &s.name_any(),
+                replicas,
+                managed_fields.unwrap_or_default(),
+            )
+            .await;
+            // the gate stays closed until the pods can serve, checked again shortly
+            if replicas >= 1 {
+                match statefulset::readiness(&statefulset).await? {
+                    Readiness::Ready => {
+                        statefulset::clear_volume_failure(&workload.namespace, &s.name_any())
+                    }
+                    Readiness::Waiting(reason) => {
+                        debug!(target: "kube_event_watcher", "Statefulset {} is waking up: {}", workload.name, reason);
+                        waking = true;
+                    }
+                    Readiness::VolumeFailure(failure) => {
+                        warn!(target: "kube_event_watcher", "Statefulset {} can't attach its volumes: {}", workload.name, failure);
+                        statefulset::report_volume_failure(
+                            &workload.namespace,
+                            &s.name_any(),
+                            &failure,
+                        )
+                        .await;
+                        waking = true;
+                    }
+                }
             }
-            Watched::StatefulSet(sts) => {
-                process_resource(sts, &workload_service)?;
+            replicas
+        }
+        kind if kruise::is_kruise(kind) => {
+            let api = kruise::api(kubernetes::client().await?, &workload.namespace, kind).await?;
+            match kruise::replicas(&api, &workload.name).await {
+                Ok(replicas) => replicas,
+                Err(err) if is_not_found(&err) => return workload_missing(s, &workload),
+                Err(err) => return Err(err.context(format!("Failed to get {}", kind))),
             }
         }
+        _ => {
+            warn!(target: "kube_event_watcher", "Unknown workload type: {}", workload.kind);
+            return Ok(Action::await_change());
+        }
+    };
+
+    let service_data = match service_data(s, workload, scale_down_time, replicas >= 1 && !waking) {
+        Ok(service_data) => service_data,
+        Err(err) => return invalid_annotations(s, err).await,
+    };
+    let wake_group = (!service_data.wake_group.is_empty()).then(|| service_data.clone());
+    if !update_workload_status(service_ip.to_string(), service_data).await {
+        return too_many_services(s).await;
+    }
+    set_waiting(service_ip, waking);
+    if let Some(service) = wake_group {
+        wake_group::reconcile(&service, replicas).await;
+    }
+    if waking {
+        return Ok(Action::requeue(WAKE_REQUEUE_INTERVAL));
+    }
+    Ok(Action::requeue(REQUEUE_INTERVAL))
+}
+
+// A deleted workload would leave its service watched with a gate no wake can open, the service is
+// reconciled again by the watch of the workloads once it is created again
+fn workload_missing(s: &Service, workload: &WorkloadReference) -> anyhow::Result<Action> {
+    warn!(target: "kube_event_watcher", "{} {} of service {} doesn't exist", workload.kind, workload.name, s.name_any());
+    cleanup(s);
+    Ok(Action::await_change())
+}
+
+fn is_not_found(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<kube::Error>(), Some(kube::Error::Api(err)) if err.code == 404)
+}
+
+// The service isn't watched past --max-watched-services, it is reconciled again later in case
+// others are no longer watched by then
+async fn too_many_services(s: &Service) -> anyhow::Result<Action> {
+    let max = config::get().max_watched_services;
+    warn!(target: "kube_event_watcher", "Not watching service {}, {} services are watched already", s.name_any(), max);
+    let (namespace, name) = (s.namespace().unwrap_or_default(), s.name_any());
+    let published = events::publish(
+        &namespace,
+        &name,
+        EventType::Warning,
+        "TooManyServices".to_string(),
+        format!(
+            "{} services are watched already, see --max-watched-services",
+            max
+        ),
+        "Reconcile",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "kube_event_watcher", "Failed to record that service {} is not watched: {}", s.name_any(), err);
+    }
+    Ok(Action::requeue(REQUEUE_INTERVAL))
+}
+
+// Retrying doesn't fix a typo in an annotation, it is reported on the service and the service is
+// reconciled again once it changes. A service that was watched keeps its last valid state
+async fn invalid_annotations(s: &Service, err: anyhow::Error) -> anyhow::Result<Action> {
+    warn!(target: "kube_event_watcher", "Service {} has invalid annotations: {:#}", s.name_any(), err);
+    let (namespace, name) = (s.namespace().unwrap_or_default(), s.name_any());
+    let published = events::publish(
+        &namespace,
+        &name,
+        EventType::Warning,
+        "InvalidAnnotation".to_string(),
+        format!("{:#}", err),
+        "Reconcile",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "kube_event_watcher", "Failed to record invalid annotations of service {}: {}", s.name_any(), err);
+    }
+    Ok(Action::await_change())
+}
+
+// The state of an annotated service from its annotations, without looking its workload up. Shared
+// with --validate, which checks the annotations of every service
+pub fn service_data(
+    s: &Service,
+    workload: WorkloadReference,
+    scale_down_time: i64,
+    backend_available: bool,
+) -> anyhow::Result<ServiceData> {
+    let monitor = match annotation(s.annotations(), MODE_ANNOTATION).map(String::as_str) {
+        None | Some("enforce") => false,
+        Some("monitor") => true,
+        Some(mode) => anyhow::bail!("Unknown mode: {}, expected enforce or monitor", mode),
+    };
+    let hold_connections = annotation(s.annotations(), LATENCY_CRITICAL_ANNOTATION)
+        .map(String::as_str)
+        == Some("true");
+    let buffer_udp =
+        annotation(s.annotations(), BUFFER_UDP_ANNOTATION).map(String::as_str) == Some("true");
+    let priority = match annotation(s.annotations(), PRIORITY_ANNOTATION) {
+        Some(priority) => priority.parse::<Priority>()?,
+        None => Priority::default(),
+    };
+    let gated_action = match annotation(s.annotations(), GATED_ACTION_ANNOTATION) {
+        Some(action) => action
+            .parse::<GatedAction>()
+            .context("Failed to parse gated-action")?,
+        None => GatedAction::default(),
+    };
+    let http_port = http_port(s);
+    if matches!(gated_action, GatedAction::Redirect { address: None, .. }) {
+        if waking_page::target().is_none() {
+            anyhow::bail!("gated-action redirect without an address needs --waking-page-addr, and --node-ip when it listens on all addresses");
+        }
+        if http_port.is_none() {
+            anyhow::bail!("gated-action redirect without an address needs a plain HTTP port: appProtocol http, named http or http-*, or port 80");
+        }
+    }
+
+    let pre_wake_hook = annotation(s.annotations(), PRE_WAKE_HOOK_ANNOTATION)
+        .map(String::as_str)
+        .map(Hook::parse)
+        .transpose()?;
+    let post_scale_down_hook = annotation(s.annotations(), POST_SCALE_DOWN_HOOK_ANNOTATION)
+        .map(String::as_str)
+        .map(Hook::parse)
+        .transpose()?;
+    let activity_protocols = match annotation(s.annotations(), ACTIVITY_PROTOCOLS_ANNOTATION) {
+        Some(protocols) => {
+            models::activity_protocols(protocols).context("Failed to parse activity-protocols")?
+        }
+        None => PROTOCOL_ALL,
+    };
+    let activity_ports = match annotation(s.annotations(), ACTIVITY_PORTS_ANNOTATION) {
+        Some(ports) => models::activity_ports(ports).context("Failed to parse activity-ports")?,
+        None => Vec::new(),
+    };
+    let wake_quota = annotation(s.annotations(), WAKE_QUOTA_ANNOTATION)
+        .map(|quota| {
+            let policy = annotation(s.annotations(), WAKE_QUOTA_POLICY_ANNOTATION);
+            WakeQuota::parse(quota, policy.map(String::as_str))
+        })
+        .transpose()
+        .context("Failed to parse wake-quota")?;
+    let wake_threshold = match annotation(s.annotations(), WAKE_THRESHOLD_ANNOTATION) {
+        Some(threshold) => {
+            WakeThreshold::parse(threshold).context("Failed to parse wake-threshold")?
+        }
+        None => WakeThreshold::default(),
+    };
+    let clients_per_replica = annotation(s.annotations(), CLIENTS_PER_REPLICA_ANNOTATION)
+        .map(String::as_str)
+        .map(ClientsPerReplica::parse)
+        .transpose()
+        .context("Failed to parse clients-per-replica")?;
+    let log_activity = annotation(s.annotations(), LOG_ACTIVITY_ANNOTATION)
+        .map(String::as_str)
+        .map(LogActivity::parse)
+        .transpose()
+        .context("Failed to parse log-activity")?;
+    let activity_sources = models::activity_sources(
+        annotation(s.annotations(), ACTIVITY_SOURCES_ANNOTATION).map(String::as_str),
+        log_activity.is_some(),
+    )
+    .context("Failed to parse activity-sources")?;
+    let active_hours = annotation(s.annotations(), ACTIVE_HOURS_ANNOTATION)
+        .map(|hours| {
+            ActiveHours::parse(
+                hours,
+                annotation(s.annotations(), ACTIVE_HOURS_TIMEZONE_ANNOTATION).map(String::as_str),
+                annotation(s.annotations(), ACTIVE_HOURS_SCALE_DOWN_TIME_ANNOTATION)
+                    .map(String::as_str),
+            )
+        })
+        .transpose()
+        .context("Failed to parse active-hours")?;
+    let scale_down_condition =
+        annotation(s.annotations(), SCALE_DOWN_CONDITION_ANNOTATION).cloned();
+    if let Some(expression) = scale_down_condition.as_ref() {
+        condition::compile(expression).context("Failed to parse scale-down-condition")?;
+    }
+    let queue_depth = QueueDepth::parse(
+        annotation(s.annotations(), QUEUE_DEPTH_URL_ANNOTATION).map(String::as_str),
+        annotation(s.annotations(), QUEUE_DEPTH_QUERY_ANNOTATION).map(String::as_str),
+        annotation(s.annotations(), QUEUE_DEPTH_THRESHOLD_ANNOTATION).map(String::as_str),
+    )
+    .context("Failed to parse queue-depth-url, queue-depth-query or queue-depth-threshold")?;
+    let wake_group = match annotation(s.annotations(), WAKE_GROUP_ANNOTATION) {
+        Some(members) => models::wake_group(members).context("Failed to parse wake-group")?,
+        None => Vec::new(),
+    };
+    let patch_templates = PatchTemplates::parse(
+        annotation(s.annotations(), PATCH_TARGET_ANNOTATION).map(String::as_str),
+        annotation(s.annotations(), SLEEP_PATCH_ANNOTATION).map(String::as_str),
+        annotation(s.annotations(), WAKE_PATCH_ANNOTATION).map(String::as_str),
+    )
+    .context("Failed to parse sleep-patch, wake-patch or patch-target")?;
+
+    Ok(ServiceData {
+        scale_down_time,
+        last_packet_time: chrono::Utc::now().timestamp(),
+        kind: workload.kind,
+        name: workload.name,
+        namespace: workload.namespace,
+        service_name: s.name_any(),
+        backend_available,
+        monitor,
+        unmanageable: None,
+        hold_connections,
+        buffer_udp,
+        priority,
+        gated_action,
+        activity_protocols,
+        activity_ports,
+        pre_wake_hook,
+        post_scale_down_hook,
+        wake_quota,
+        wake_threshold,
+        scale_down_condition,
+        queue_depth,
+        wake_group,
+        patch_templates,
+        clients_per_replica,
+        log_activity,
+        activity_sources,
+        checkpoint: annotation(s.annotations(), CHECKPOINT_ANNOTATION).map(String::as_str)
+            == Some("true"),
+        placeholder_priority_class: annotation(
+            s.annotations(),
+            PLACEHOLDER_PRIORITY_CLASS_ANNOTATION,
+        )
+        .cloned(),
+        hpa: annotation(s.annotations(), HPA_ANNOTATION).cloned(),
+        server_names: annotation(s.annotations(), SERVER_NAMES_ANNOTATION)
+            .map(|names| {
+                names
+                    .split(',')
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default(),
+        hook_status: Default::default(),
+        wake_failure: None,
+        active_hours,
+        policies: policies::matching(&s.namespace().unwrap_or_default(), s.labels()).0,
+        load_balancer_ips: load_balancer_ips(s),
+        ipv6_addresses: ipv6_addresses(s),
+        http_port,
+    })
+}
+
+// The TCP port of the service that serves plain HTTP, the waking page can't answer TLS or UDP
+fn http_port(s: &Service) -> Option<u16> {
+    let ports = s.spec.as_ref()?.ports.as_ref()?;
+    let tcp = ports
+        .iter()
+        .filter(|port| port.protocol.as_deref().unwrap_or("TCP") == "TCP");
+    let named = tcp.clone().find(|port| {
+        let name = port.name.as_deref().unwrap_or_default();
+        port.app_protocol.as_deref() == Some("http") || name == "http" || name.starts_with("http-")
+    });
+    named
+        .or_else(|| tcp.clone().find(|port| port.port == 80))
+        .and_then(|port| u16::try_from(port.port).ok())
+}
+
+// Addresses a bare-metal load balancer (MetalLB, kube-vip) announces for a LoadBalancer service,
+// traffic to them arrives at the node addressed to them rather than to the ClusterIP
+fn load_balancer_ips<A: FromStr + Ord>(s: &Service) -> Vec<A> {
+    if s.spec.as_ref().and_then(|spec| spec.type_.as_deref()) != Some("LoadBalancer") {
+        return Vec::new();
     }
+    let status = s
+        .status
+        .iter()
+        .flat_map(|status| status.load_balancer.iter())
+        .flat_map(|load_balancer| load_balancer.ingress.iter().flatten())
+        .filter_map(|ingress| ingress.ip.clone());
+    let requested = LOAD_BALANCER_IPS_ANNOTATIONS
+        .iter()
+        .filter_map(|name| s.annotations().get(*name))
+        .flat_map(|ips| ips.split(','))
+        .map(|ip| ip.trim().to_string());
+    let mut ips: Vec<A> = status
+        .chain(requested)
+        .filter_map(|ip| ip.parse().ok())
+        .collect();
+    ips.sort();
+    ips.dedup();
+    ips
+}
+
+// IPv6 addresses of a dual-stack service, gated like its IPv4 ClusterIP which identifies it
+fn ipv6_addresses(s: &Service) -> Vec<Ipv6Addr> {
+    let cluster_ips = s
+        .spec
+        .iter()
+        .flat_map(|spec| spec.cluster_ips.iter().flatten())
+        .filter_map(|ip| ip.parse().ok());
+    let mut addresses: Vec<Ipv6Addr> = cluster_ips.chain(load_balancer_ips(s)).collect();
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+fn set_waiting(service_ip: &str, waiting: bool) {
+    let mut waiting_services = WAITING.lock().unwrap();
+    if waiting {
+        waiting_services.insert(service_ip.to_string());
+    } else {
+        waiting_services.remove(service_ip);
+    }
+}
+
+// The workload of the service is scaled up but can't serve yet
+pub fn waiting(service_ip: &str) -> bool {
+    WAITING.lock().unwrap().contains(service_ip)
+}
+
+// Forget a service, the next sync removes it from the kernel map
+fn cleanup(s: &Service) {
+    let service_ip = match s.spec.as_ref().and_then(|spec| spec.cluster_ip.as_ref()) {
+        Some(ip) => ip,
+        None => return,
+    };
+    set_waiting(service_ip, false);
+    if let Some(service) = WATCHED_SERVICES.lock().unwrap().remove(service_ip) {
+        info!(target: "kube_event_watcher", "Service {} is no longer watched", s.name_any());
+        forget_activity(service_ip.clone(), &service);
+        // nothing would resume the CronJobs suspended while it slept
+        if !service.wake_group.is_empty() {
+            tokio::spawn(async move { wake_group::resume(&service).await });
+        }
+    }
+}
+
+fn forget_activity(service_ip: String, service: &ServiceData) {
+    if config::get().activity_gossip {
+        tokio::spawn(gossip::forget(
+            service_ip,
+            service.namespace.clone(),
+            service.service_name.clone(),
+        ));
+    }
+}
+
+async fn remove_finalizer(services: &Api<Service>, s: &Service) -> anyhow::Result<()> {
+    let finalizers: Vec<&String> = s.finalizers().iter().filter(|f| *f != FINALIZER).collect();
+    services
+        .patch(
+            &s.name_any(),
+            &PatchParams::default(),
+            &Patch::Merge(json!({
+                "metadata": {
+                    "finalizers": finalizers
+                }
+            })),
+        )
+        .await?;
     Ok(())
 }
 
+// Forget services deleted while another agent removed the finalizer, or while this one was down
+async fn prune(store: Store<Service>, namespace: String) {
+    if store.wait_until_ready().await.is_err() {
+        return;
+    }
+    loop {
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        let cluster_ips: HashSet<String> = store
+            .state()
+            .iter()
+            .filter_map(|s| s.spec.as_ref()?.cluster_ip.clone())
+            .collect();
+
+        // the other namespaces are pruned by their own controller
+        WATCHED_SERVICES.lock().unwrap().retain(|ip, service| {
+            let exists = service.namespace != namespace || cluster_ips.contains(ip);
+            if !exists {
+                info!(target: "kube_event_watcher", "Service of {} {} is gone", service.kind, service.name);
+                forget_activity(ip.clone(), service);
+            }
+            exists
+        });
+        OBSERVED_SERVICES
+            .lock()
+            .unwrap()
+            .retain(|ip, service| service.namespace != namespace || cluster_ips.contains(ip));
+    }
+}
+
+pub fn scale_down_time(s: &Service) -> anyhow::Result<i64> {
+    annotation(s.annotations(), SCALE_DOWN_TIME_ANNOTATION)
+        .ok_or_else(|| anyhow::anyhow!("Service {} has no scale-down-time", s.name_any()))
+        .and_then(|value| parse_duration(value))
+        .context("Failed to parse scale-down-time")
+}
+
+pub fn workload_reference(s: &Service) -> Option<WorkloadReference> {
+    let workload_ref = annotation(s.annotations(), REFERENCE_ANNOTATION)?;
+    let (kind, name) = workload_ref.split_once('/')?;
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+    Some(WorkloadReference {
+        kind: kind.to_string(),
+        name: name.to_string(),
+        namespace: s.namespace()?,
+    })
+}
+
+fn services_referencing<T: K8sResource>(
+    store: &Store<Service>,
+    resource: T,
+) -> Vec<ObjectRef<Service>> {
+    let reference = WorkloadReference {
+        kind: resource.kind(),
+        name: resource.name(),
+        namespace: resource.namespace_().unwrap_or_default(),
+    };
+    services_referencing_workload(store, &reference)
+}
+
+fn services_referencing_workload(
+    store: &Store<Service>,
+    reference: &WorkloadReference,
+) -> Vec<ObjectRef<Service>> {
+    store
+        .state()
+        .iter()
+        .filter(|s| workload_reference(s).as_ref() == Some(reference))
+        .map(|s| ObjectRef::from_obj(s.as_ref()))
+        .collect()
+}
+
+// Register a service that is not annotated so the learning mode tracks its traffic
+fn observe_service(s: &Service) {
+    let service_ip = match s.spec.as_ref().and_then(|spec| spec.cluster_ip.clone()) {
+        Some(ip) if ip != "None" => ip,
+        _ => return,
+    };
+
+    let mut observed_services = OBSERVED_SERVICES.lock().unwrap();
+    if observed_services.len() >= OBSERVED_SERVICES_MAX
+        && !observed_services.contains_key(&service_ip)
+    {
+        debug!(target: "kube_event_watcher", "Not observing service {}, {} services are observed already", s.name_any(), OBSERVED_SERVICES_MAX);
+        return;
+    }
+    observed_services
+        .entry(service_ip)
+        .or_insert_with(|| ObservedService {
+            name: s.name_any(),
+            namespace: s.namespace().unwrap_or_default(),
+            last_seen_ns: utils::monotonic_now_ns(),
+            ..Default::default()
+        });
+}
+
 // Define the common interface
 trait K8sResource {
     fn name(&self) -> String;
@@ -256,88 +917,98 @@ impl K8sResource for StatefulSet {
     }
 }
 
-// Now we can define a function that works with any K8sResource
-fn process_resource<T: K8sResource>(
-    resource: T,
-    workload_service: &HashMap<WorkloadReference, Service>,
-) -> anyhow::Result<()> {
-    let service = workload_service.get(&WorkloadReference {
-        kind: resource.kind(),
-        name: resource.name(),
-        namespace: resource
-            .namespace_()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get namespace for {}", resource.kind()))?,
-    });
-    let service = match service {
-        Some(s) => s,
-        None => return Ok(()),
-    };
-
-    let replicas = resource
+fn replicas_of<T: K8sResource>(resource: T) -> anyhow::Result<i32> {
+    resource
         .replicas()
-        .ok_or_else(|| anyhow::anyhow!("Failed to get replicas for {}", resource.name()))?;
-
-    // TODO: Check if health check is passing before setting backend_available to true
-    thread::sleep(std::time::Duration::from_secs(2));
+        .ok_or_else(|| anyhow::anyhow!("Failed to get replicas for {}", resource.name()))
+}
 
-    let service_ip = service
-        .spec
-        .as_ref()
-        .ok_or_else(|| anyhow::anyhow!("Failed to get service spec for {}", service.name_any()))?
-        .cluster_ip
-        .as_ref()
-        .ok_or_else(|| anyhow::anyhow!("Failed to get cluster IP for {}", service.name_any()))?;
-    {
-        let mut watched_services = WATCHED_SERVICES.lock().unwrap();
-        let service_data = watched_services.get_mut(service_ip).unwrap();
-        service_data.backend_available = replicas >= 1;
+// Why a scaled up Deployment can't serve yet: no ready replica, or not Available while it wakes
+// from zero. A Deployment serving with ready replicas stays open when it is not Available (rolling
+// update, below minAvailable)
+fn deployment_waiting(deployment: &Deployment, from_zero: bool) -> Option<String> {
+    let status = match deployment.status.as_ref() {
+        Some(status) => status,
+        None => return Some("no status".to_string()),
+    };
+    if status.ready_replicas.unwrap_or(0) == 0 {
+        return Some("no ready replica".to_string());
+    }
+    if !from_zero {
+        return None;
+    }
+    let available = status
+        .conditions
+        .iter()
+        .flatten()
+        .find(|condition| condition.type_ == "Available");
+    match available {
+        Some(condition) if condition.status == "True" => None,
+        Some(condition) => Some(format!(
+            "not available: {}",
+            condition.reason.as_deref().unwrap_or("unknown reason")
+        )),
+        None => Some("no Available condition".to_string()),
     }
-    Ok(())
 }
 
-async fn update_workload_status(
-    kind: String,
-    name: String,
-    namespace: Option<String>,
-    replicas: i32,
-    workload_service: &mut HashMap<WorkloadReference, Service>,
-    service: Service,
-    service_ip: String,
-    scale_down_time: i64,
-) -> anyhow::Result<()> {
-    let namespace = match namespace {
-        Some(ns) => ns,
-        None => return Err(anyhow::anyhow!("Failed to get namespace for {}", name)),
-    };
+// Add or update a watched service, keeping what the agent tracks itself (last packet, manageability).
+// Returns false when the service is new and --max-watched-services are watched already
+async fn update_workload_status(service_ip: String, service: ServiceData) -> bool {
+    info!(target: "update_workload_status", "updating workload status for kind: {}, name: {}, namespace: {}, available: {}, service_ip: {}, scale_down_time: {}", service.kind, service.name, service.namespace, service.backend_available, service_ip, service.scale_down_time);
 
-    info!(target: "update_workload_status", "updating workload status for service: {}, kind: {}, name: {}, namespace: {}, replicas: {}, service_ip: {}, scale_down_time: {}", service.name_any(), kind, name, namespace, replicas, service_ip, scale_down_time);
+    let (was_available, was_monitored) = WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .get(&service_ip)
+        .map(|service| (Some(service.backend_available), service.monitor))
+        .unwrap_or((None, false));
 
-    // sleep for 1 second to allow the service to be created
-    thread::sleep(std::time::Duration::from_secs(2));
+    // TODO: Check if health check is passing before setting backend_available to true
+    let gate_opens = service.backend_available && was_available == Some(false);
+    if gate_opens {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+    let opened = gate_opens.then(|| service.clone());
+    let (service_monitored, service_available) = (service.monitor, service.backend_available);
 
-    workload_service.insert(
-        WorkloadReference {
-            kind: kind.clone(),
-            name: name.clone(),
-            namespace: namespace.clone(),
-        },
-        service.clone(),
-    );
     {
         let mut watched_services = WATCHED_SERVICES.lock().unwrap();
-
-        watched_services.insert(
-            service_ip.clone(),
-            ServiceData {
-                scale_down_time,
-                last_packet_time: chrono::Utc::now().timestamp(),
-                kind,
-                name,
-                namespace,
-                backend_available: replicas >= 1,
-            },
-        );
+        match watched_services.get_mut(&service_ip) {
+            Some(service_data) => {
+                *service_data = ServiceData {
+                    last_packet_time: service_data.last_packet_time,
+                    unmanageable: service_data.unmanageable.take(),
+                    hook_status: std::mem::take(&mut service_data.hook_status),
+                    wake_failure: service_data.wake_failure.take(),
+                    ..service
+                };
+            }
+            None => {
+                if watched_services.len() >= config::get().max_watched_services {
+                    return false;
+                }
+                watched_services.insert(service_ip.clone(), service);
+            }
+        }
     }
-
-    Ok(())
+    // the gate opens with the next sync of the service list
+    if let Some(service) = opened {
+        if service.placeholder_priority_class.is_some() {
+            let service = service.clone();
+            tokio::spawn(async move { placeholder::delete(&service).await });
+        }
+        wake_trace::gate_opened(&service_ip, &service).await;
+    }
+    // the gate of a monitored service is open, no packet would wake a service switched to monitor
+    // while it was scaled down
+    if service_monitored && !service_available && !was_monitored {
+        info!(target: "monitor", "Waking {}, monitored while scaled down", service_ip);
+        tokio::spawn(async move {
+            if let Err(err) = kubernetes::scaler::scale_up(service_ip.clone()).await {
+                warn!(target: "monitor", "Failed to wake {}: {:#}", service_ip, err);
+            }
+        });
+    }
+    true
 }
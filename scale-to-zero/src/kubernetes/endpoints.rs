@@ -0,0 +1,236 @@
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::{Pod, Service};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::runtime::reflector::{ObjectRef, Store};
+use kube::runtime::{reflector, watcher, WatchStreamExt};
+use kube::{Api, Client, ResourceExt};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::lease;
+use super::models::{HOST_PORTS, POD_TO_SERVICE, WATCHED_SERVICES};
+
+// Label linking an EndpointSlice to its service
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+// This contains the namespace and number of ready endpoints of each watched service IP
+static READY_ENDPOINTS: Lazy<Mutex<HashMap<String, (String, usize)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// This contains the pod or node of each address seen in the pods of the node and the endpoints of
+// the watched services, with the namespace it was seen in
+static CLIENTS: Lazy<Mutex<HashMap<String, (String, String)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The pod (pod/<namespace>/<name>) or node (node/<name>) an address belongs to, clients outside
+// the watched namespaces are unknown
+pub fn client(address: Ipv4Addr) -> Option<String> {
+    CLIENTS
+        .lock()
+        .unwrap()
+        .get(&address.to_string())
+        .map(|(_, client)| client.clone())
+}
+
+// Ready endpoints of the service in its EndpointSlices, as of the last scan (every 5 seconds)
+pub fn ready_endpoints(service_ip: &str) -> usize {
+    READY_ENDPOINTS
+        .lock()
+        .unwrap()
+        .get(service_ip)
+        .map_or(0, |(_, ready)| *ready)
+}
+
+// Keep POD_TO_SERVICE in sync with the EndpointSlices of the watched services of a namespace,
+// packets sent straight to a backend pod (after DNAT) then count as activity of the service.
+// Backend pods with a hostPort or on the host network are reached at the address of their node,
+// their ports go to HOST_PORTS instead. Packets to a pod arrive at its node, only the pods of this
+// node are watched and the agent of each node tracks its own
+pub async fn track_pod_ips(client: Client, services: Store<Service>, namespace: String) {
+    let node = lease::identity();
+    let slices: Api<EndpointSlice> = Api::namespaced(client.clone(), &namespace);
+    let (reader, writer) = reflector::store();
+    tokio::spawn(
+        reflector(writer, watcher(slices, watcher::Config::default()))
+            .default_backoff()
+            .touched_objects()
+            .for_each(|_| futures::future::ready(())),
+    );
+    let pods_api: Api<Pod> = Api::namespaced(client, &namespace);
+    let (pods, writer) = reflector::store();
+    let on_node = watcher::Config::default().fields(&format!("spec.nodeName={}", node));
+    tokio::spawn(
+        reflector(writer, watcher(pods_api, on_node))
+            .default_backoff()
+            .touched_objects()
+            .for_each(|_| futures::future::ready(())),
+    );
+    // pods not in the store yet (or without RBAC to watch them) count as regular pod IPs
+    if reader.wait_until_ready().await.is_err() {
+        return;
+    }
+
+    loop {
+        let watched: Vec<String> = WATCHED_SERVICES.lock().unwrap().keys().cloned().collect();
+        let cluster_ips: HashMap<String, String> = services
+            .state()
+            .iter()
+            .filter_map(|s| Some((s.name_any(), s.spec.as_ref()?.cluster_ip.clone()?)))
+            .filter(|(_, cluster_ip)| watched.contains(cluster_ip))
+            .collect();
+
+        let mut pod_ips = HashMap::new();
+        let mut host_ports = HashMap::new();
+        let mut with_endpoints = HashSet::new();
+        let mut backends = HashMap::new();
+        let mut ready: HashMap<String, (String, usize)> = HashMap::new();
+        for slice in reader.state() {
+            let service_ip = match slice
+                .labels()
+                .get(SERVICE_NAME_LABEL)
+                .and_then(|name| cluster_ips.get(name))
+            {
+                Some(service_ip) => service_ip,
+                None => continue,
+            };
+            if slice.address_type != "IPv4" {
+                continue;
+            }
+            for endpoint in slice.endpoints.iter() {
+                with_endpoints.insert(service_ip.clone());
+                let pod_name = endpoint
+                    .target_ref
+                    .as_ref()
+                    .filter(|target| target.kind.as_deref() == Some("Pod"))
+                    .and_then(|target| target.name.as_deref());
+                if let Some(pod_name) = pod_name {
+                    let client = format!("pod/{}/{}", namespace, pod_name);
+                    for address in endpoint.addresses.iter() {
+                        backends.insert(address.clone(), (namespace.clone(), client.clone()));
+                    }
+                }
+                // the packets to a pod of another node arrive at that node
+                if endpoint
+                    .node_name
+                    .as_deref()
+                    .is_some_and(|name| name != node)
+                {
+                    continue;
+                }
+                let pod =
+                    pod_name.and_then(|name| pods.get(&ObjectRef::new(name).within(&namespace)));
+                let host_network = pod.as_ref().is_some_and(|pod| is_host_network(pod));
+                if let Some(pod) = pod.as_ref() {
+                    for key in pod_host_ports(pod) {
+                        host_ports.insert(key, (namespace.clone(), service_ip.clone()));
+                    }
+                }
+                // the address is the one of the node, other traffic to it isn't the service's
+                if !host_network {
+                    for address in endpoint.addresses.iter() {
+                        pod_ips.insert(address.clone(), (namespace.clone(), service_ip.clone()));
+                    }
+                }
+                // an unknown condition counts as ready
+                let is_ready = endpoint
+                    .conditions
+                    .as_ref()
+                    .and_then(|conditions| conditions.ready)
+                    .unwrap_or(true);
+                if is_ready {
+                    ready
+                        .entry(service_ip.clone())
+                        .or_insert_with(|| (namespace.clone(), 0))
+                        .1 += 1;
+                }
+            }
+        }
+
+        {
+            let mut pod_to_service = POD_TO_SERVICE.lock().unwrap();
+            pod_to_service.retain(|_, (ns, _)| *ns != namespace);
+            pod_to_service.extend(pod_ips);
+        }
+        {
+            // the last ports of a scaled down service are kept so its clients still wake it, those
+            // of a service with endpoints are replaced so the ports of moved pods are forgotten
+            let mut known = HOST_PORTS.lock().unwrap();
+            known.retain(|_, (ns, service_ip)| {
+                *ns != namespace
+                    || (watched.contains(service_ip) && !with_endpoints.contains(service_ip))
+            });
+            known.extend(host_ports);
+        }
+        {
+            let mut clients = CLIENTS.lock().unwrap();
+            clients.retain(|_, (ns, _)| *ns != namespace);
+            // backends on the host network have the address of their node, set below when local
+            clients.extend(backends);
+            for pod in pods.state() {
+                let status = match pod.status.as_ref() {
+                    Some(status) => status,
+                    None => continue,
+                };
+                let node = pod.spec.as_ref().and_then(|spec| spec.node_name.clone());
+                if let (Some(host_ip), Some(node)) = (status.host_ip.clone(), node) {
+                    clients.insert(host_ip, (namespace.clone(), format!("node/{}", node)));
+                }
+                // the address is the one of the node
+                if is_host_network(&pod) {
+                    continue;
+                }
+                if let Some(pod_ip) = status.pod_ip.clone() {
+                    let client = format!("pod/{}/{}", namespace, pod.name_any());
+                    clients.insert(pod_ip, (namespace.clone(), client));
+                }
+            }
+        }
+        {
+            let mut ready_endpoints = READY_ENDPOINTS.lock().unwrap();
+            ready_endpoints.retain(|_, (ns, _)| *ns != namespace);
+            ready_endpoints.extend(ready);
+        }
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+fn is_host_network(pod: &Pod) -> bool {
+    pod.spec.as_ref().and_then(|spec| spec.host_network) == Some(true)
+}
+
+// (node IP, port, IP protocol) the pod is reached at on its node: the host ports of its
+// containers, or every container port on the host network
+fn pod_host_ports(pod: &Pod) -> Vec<(String, u16, u8)> {
+    let host_ip = match pod
+        .status
+        .as_ref()
+        .and_then(|status| status.host_ip.clone())
+    {
+        Some(host_ip) => host_ip,
+        None => return Vec::new(),
+    };
+    let host_network = is_host_network(pod);
+    pod.spec
+        .iter()
+        .flat_map(|spec| spec.containers.iter())
+        .flat_map(|container| container.ports.iter().flatten())
+        .filter_map(|port| {
+            let number = match port.host_port {
+                Some(host_port) => host_port,
+                None if host_network => port.container_port,
+                None => return None,
+            };
+            let protocol = match port.protocol.as_deref() {
+                None | Some("TCP") => 6,
+                Some("UDP") => 17,
+                // SCTP has no ports in the datapath
+                _ => return None,
+            };
+            Some((host_ip.clone(), u16::try_from(number).ok()?, protocol))
+        })
+        .collect()
+}
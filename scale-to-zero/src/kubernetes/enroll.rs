@@ -0,0 +1,132 @@
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::serde_json::json;
+use kube::api::{Api, ListParams, Patch, PatchParams};
+use kube::ResourceExt;
+use log::{info, warn};
+use std::collections::BTreeMap;
+
+use super::models::{annotation_key, REFERENCE_ANNOTATION, SCALE_DOWN_TIME_ANNOTATION};
+use super::scaler::FIELD_MANAGER;
+use crate::config;
+
+// Policy enrolling services that match a label selector without annotating each one of them
+pub struct EnrollPolicy {
+    // (key, value) pairs, a missing value only requires the label to exist
+    selector: Vec<(String, Option<String>)>,
+    scale_down_time: String,
+}
+
+impl EnrollPolicy {
+    pub fn from_config() -> Option<EnrollPolicy> {
+        let opts = config::get();
+        let selector = opts
+            .auto_enroll_selector
+            .as_ref()?
+            .split(',')
+            .map(str::trim)
+            .filter(|term| !term.is_empty())
+            .map(|term| match term.split_once('=') {
+                Some((key, value)) => (key.trim().to_string(), Some(value.trim().to_string())),
+                None => (term.to_string(), None),
+            })
+            .collect();
+
+        Some(EnrollPolicy {
+            selector,
+            scale_down_time: opts.auto_enroll_scale_down_time.clone(),
+        })
+    }
+
+    pub fn matches(&self, service: &Service) -> bool {
+        let labels = service.labels();
+        self.selector.iter().all(|(key, value)| match value {
+            Some(value) => labels.get(key) == Some(value),
+            None => labels.contains_key(key),
+        })
+    }
+
+    // Annotate the service with the default policy, the watcher then picks it up like any other
+    // annotated service. The workload is the only deployment or statefulset selected by the service
+    pub async fn enroll(
+        &self,
+        service: &Service,
+        services: &Api<Service>,
+        deployments: &Api<Deployment>,
+        statefulsets: &Api<StatefulSet>,
+    ) -> anyhow::Result<()> {
+        let selector = match service
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.selector.as_ref())
+        {
+            Some(selector) if !selector.is_empty() => selector,
+            _ => {
+                warn!(target: "enroll", "Service {} has no selector, can't enroll it", service.name_any());
+                return Ok(());
+            }
+        };
+
+        let mut workloads = Vec::new();
+        for deployment in deployments.list(&ListParams::default()).await? {
+            let labels = deployment
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.template.metadata.as_ref())
+                .and_then(|meta| meta.labels.as_ref());
+            if selects(selector, labels) {
+                workloads.push(format!("deployment/{}", deployment.name_any()));
+            }
+        }
+        for statefulset in statefulsets.list(&ListParams::default()).await? {
+            let labels = statefulset
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.template.metadata.as_ref())
+                .and_then(|meta| meta.labels.as_ref());
+            if selects(selector, labels) {
+                workloads.push(format!("statefulset/{}", statefulset.name_any()));
+            }
+        }
+
+        if workloads.len() != 1 {
+            warn!(
+                target: "enroll",
+                "Service {} selects {} workloads ({}), can't enroll it",
+                service.name_any(),
+                workloads.len(),
+                workloads.join(", ")
+            );
+            return Ok(());
+        }
+
+        info!(target: "enroll", "Enrolling service {} with {}", service.name_any(), workloads[0]);
+        services
+            .patch(
+                &service.name_any(),
+                &PatchParams::apply(FIELD_MANAGER),
+                &Patch::Apply(json!({
+                    "apiVersion": "v1",
+                    "kind": "Service",
+                    "metadata": {
+                        "name": service.name_any(),
+                        "annotations": {
+                            annotation_key(REFERENCE_ANNOTATION): workloads[0],
+                            annotation_key(SCALE_DOWN_TIME_ANNOTATION): self.scale_down_time,
+                        }
+                    }
+                })),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+fn selects(selector: &BTreeMap<String, String>, labels: Option<&BTreeMap<String, String>>) -> bool {
+    match labels {
+        Some(labels) => selector
+            .iter()
+            .all(|(key, value)| labels.get(key) == Some(value)),
+        None => false,
+    }
+}
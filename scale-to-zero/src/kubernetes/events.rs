@@ -0,0 +1,37 @@
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+
+use crate::config;
+
+// Record an event on the annotated service, reported by the agent of this node
+pub async fn publish(
+    namespace: &str,
+    service_name: &str,
+    type_: EventType,
+    reason: String,
+    note: String,
+    action: &str,
+) -> anyhow::Result<()> {
+    let reporter = Reporter {
+        controller: "scale-to-zero".to_string(),
+        instance: config::get().node_name.clone(),
+    };
+    let reference = ObjectReference {
+        api_version: Some("v1".to_string()),
+        kind: Some("Service".to_string()),
+        name: Some(service_name.to_string()),
+        namespace: Some(namespace.to_string()),
+        ..Default::default()
+    };
+    let recorder = Recorder::new(super::client().await?, reporter, reference);
+    recorder
+        .publish(Event {
+            type_,
+            reason,
+            note: Some(note),
+            action: action.to_string(),
+            secondary: None,
+        })
+        .await?;
+    Ok(())
+}
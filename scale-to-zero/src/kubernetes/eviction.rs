@@ -0,0 +1,78 @@
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::models::{
+    HOST_PORTS, IDLE_GAPS, LAST_CALLED, OBSERVED_SERVICES, POD_TO_SERVICE, RECENT_WAKES,
+    WATCHED_SERVICES,
+};
+use super::{
+    checkpoint, condition, explain, gitops, gossip, monitor, polling, queue_depth, quota,
+    resource_quota, retry, statefulset, wake_trace, wakes,
+};
+use crate::custom_metrics;
+use crate::metrics;
+use crate::recorder;
+use crate::talkers;
+
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+// The state kept per service outlives the service in a dynamic cluster (preview environments,
+// jobs), every minute the entries of the services no longer watched are evicted and the size of
+// each structure exported as scale_to_zero_state_entries
+pub async fn evict() {
+    loop {
+        tokio::time::sleep(EVICTION_INTERVAL).await;
+        let (watched, services, workloads) = {
+            let watched_services = WATCHED_SERVICES.lock().unwrap();
+            let watched: HashSet<String> = watched_services.keys().cloned().collect();
+            let services: HashSet<(String, String)> = watched_services
+                .values()
+                .map(|service| (service.namespace.clone(), service.service_name.clone()))
+                .collect();
+            let workloads: HashSet<(String, String, String)> =
+                watched_services.values().map(retry::workload).collect();
+            (watched, services, workloads)
+        };
+
+        condition::retain(&watched);
+        let sizes = [
+            ("watched_services", watched.len()),
+            ("last_called", retain(&LAST_CALLED, &watched)),
+            ("recent_wakes", retain(&RECENT_WAKES, &watched)),
+            ("idle_gaps", retain(&IDLE_GAPS, &watched)),
+            ("pod_to_service", POD_TO_SERVICE.lock().unwrap().len()),
+            ("host_ports", HOST_PORTS.lock().unwrap().len()),
+            ("observed_services", OBSERVED_SERVICES.lock().unwrap().len()),
+            ("wake_quotas", quota::retain(&watched)),
+            ("excessive_wakes", wakes::retain(&watched)),
+            ("quota_backoffs", resource_quota::retain(&watched)),
+            ("wake_traces", wake_trace::retain(&watched)),
+            ("decisions", explain::retain(&watched)),
+            ("monitored", monitor::retain(&watched)),
+            ("queue_depths", queue_depth::retain(&watched)),
+            ("retry_generations", retry::retain(&workloads)),
+            ("replica_changes", gitops::retain(&workloads)),
+            ("volume_failures", statefulset::retain(&services)),
+            ("recorded_events", recorder::retain(&watched)),
+            ("top_talkers", talkers::retain(&watched)),
+            ("seen_activity", gossip::retain(&watched)),
+            ("checkpoints", checkpoint::retain(&watched)),
+            ("polled_activity", polling::retain(&watched)),
+            ("packet_rates", custom_metrics::retain(&watched)),
+        ];
+        for (structure, entries) in sizes {
+            metrics::STATE_ENTRIES
+                .with_label_values(&[structure])
+                .set(entries as i64);
+        }
+        debug!(target: "eviction", "State entries: {:?}", sizes);
+    }
+}
+
+fn retain<T>(map: &Mutex<HashMap<String, T>>, watched: &HashSet<String>) -> usize {
+    let mut map = map.lock().unwrap();
+    map.retain(|ip, _| watched.contains(ip));
+    map.len()
+}
@@ -0,0 +1,109 @@
+use k8s_openapi::chrono;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+// The inputs of the last scale-down evaluation of a service, the loop evaluates every service
+// once a second
+#[derive(Debug, Clone, Serialize)]
+pub struct ScaleDownDecision {
+    pub evaluated_at: i64,
+    pub scale_down: bool,
+    pub reason: String,
+    // Unix seconds of the last activity, of the whole namespace for a group
+    pub last_activity: i64,
+    pub idle_seconds: i64,
+    // Annotated scale-down-time, the one of the namespace group if any, and the one in effect
+    // after the pressure factor, the excessive wake extension and the active hours
+    pub scale_down_time: i64,
+    pub group_scale_down_time: Option<i64>,
+    pub pressure_scale_down_time: i64,
+    pub effective_scale_down_time: i64,
+    pub active_hours: bool,
+    pub wake_quota_exceeded: Option<bool>,
+    pub condition: Option<ConditionInput>,
+    pub queue_depth: Option<QueueDepthInput>,
+    pub policy: Option<PolicyInput>,
+    pub unmanageable: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConditionInput {
+    pub expression: String,
+    pub allowed: bool,
+}
+
+// Pending messages of the queue of the service, unknown when they couldn't be read recently
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueDepthInput {
+    pub depth: Option<f64>,
+    pub threshold: u64,
+    pub allowed: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyInput {
+    pub name: String,
+    pub allowed: bool,
+}
+
+// The inputs of the last wake request of a service that wasn't rate limited
+#[derive(Debug, Clone, Serialize)]
+pub struct WakeDecision {
+    pub evaluated_at: i64,
+    pub woken: bool,
+    pub reason: String,
+    pub wake_quota_exceeded: Option<bool>,
+    pub policy: Option<PolicyInput>,
+    pub gated_clients: Option<usize>,
+}
+
+impl WakeDecision {
+    pub fn now() -> Self {
+        WakeDecision {
+            evaluated_at: chrono::Utc::now().timestamp(),
+            woken: false,
+            reason: String::new(),
+            wake_quota_exceeded: None,
+            policy: None,
+            gated_clients: None,
+        }
+    }
+}
+
+static SCALE_DOWNS: Lazy<Mutex<HashMap<String, ScaleDownDecision>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static WAKES: Lazy<Mutex<HashMap<String, WakeDecision>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn record_scale_down(service_ip: &str, decision: ScaleDownDecision) {
+    SCALE_DOWNS
+        .lock()
+        .unwrap()
+        .insert(service_ip.to_string(), decision);
+}
+
+pub fn record_wake(service_ip: &str, reason: impl Into<String>, mut decision: WakeDecision) {
+    decision.reason = reason.into();
+    WAKES
+        .lock()
+        .unwrap()
+        .insert(service_ip.to_string(), decision);
+}
+
+pub fn scale_down(service_ip: &str) -> Option<ScaleDownDecision> {
+    SCALE_DOWNS.lock().unwrap().get(service_ip).cloned()
+}
+
+pub fn wake(service_ip: &str) -> Option<WakeDecision> {
+    WAKES.lock().unwrap().get(service_ip).cloned()
+}
+
+// Forget the services no longer watched, returns the number of services left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    WAKES.lock().unwrap().retain(|ip, _| watched.contains(ip));
+    let mut scale_downs = SCALE_DOWNS.lock().unwrap();
+    scale_downs.retain(|ip, _| watched.contains(ip));
+    scale_downs.len()
+}
@@ -0,0 +1,128 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ManagedFieldsEntry;
+use k8s_openapi::chrono;
+use kube::runtime::events::EventType;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use super::events;
+use super::models::{ServiceData, WorkloadReference};
+use super::retry;
+use super::scaler::FIELD_MANAGER;
+use crate::metrics;
+
+// A replica change within this long (seconds) of ours undoing it is a revert
+const REVERT_WINDOW: i64 = 600;
+
+// Reverts within an hour before the workload is reported as fought over
+const REVERT_ALERT: usize = 3;
+
+type WorkloadKey = (String, String, String);
+
+// This contains the replicas last set on each (namespace, kind, name) and when (unix seconds)
+static LAST_SET: Lazy<Mutex<HashMap<WorkloadKey, (i32, i64)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// This contains the times (unix seconds) our replica changes were reverted within the last hour
+static REVERTS: Lazy<Mutex<HashMap<WorkloadKey, VecDeque<i64>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Forget the workloads no longer watched, by (namespace, kind, name), returns the number left
+pub fn retain(watched: &HashSet<(String, String, String)>) -> usize {
+    REVERTS
+        .lock()
+        .unwrap()
+        .retain(|workload, _| watched.contains(workload));
+    let mut last_set = LAST_SET.lock().unwrap();
+    last_set.retain(|workload, _| watched.contains(workload));
+    last_set.len()
+}
+
+pub fn record(service: &ServiceData, replicas: i32) {
+    LAST_SET.lock().unwrap().insert(
+        retry::workload(service),
+        (replicas, chrono::Utc::now().timestamp()),
+    );
+}
+
+// Compare the replicas of a workload with the ones we set shortly before. A workload we scaled
+// down that is up again, or the other way around, with spec.replicas last changed by another
+// manager than the agents was reverted, usually by the self-heal of a GitOps tool. The manager is
+// reported once the workload is reverted REVERT_ALERT times within an hour
+pub async fn check(
+    reference: &WorkloadReference,
+    service_name: &str,
+    replicas: i32,
+    managed_fields: &[ManagedFieldsEntry],
+) {
+    let manager = match replicas_manager(managed_fields) {
+        Some(manager) if manager != FIELD_MANAGER => manager,
+        _ => return,
+    };
+    let workload = (
+        reference.namespace.clone(),
+        reference.kind.clone(),
+        reference.name.clone(),
+    );
+    let now = chrono::Utc::now().timestamp();
+    let reverted = {
+        let mut last_set = LAST_SET.lock().unwrap();
+        match last_set.get(&workload) {
+            Some((set, time)) if now - time <= REVERT_WINDOW && (*set == 0) != (replicas == 0) => {
+                last_set.remove(&workload);
+                true
+            }
+            _ => false,
+        }
+    };
+    if !reverted {
+        return;
+    }
+    warn!(target: "gitops", "spec.replicas of {} {} was reverted to {} by {}", reference.kind, reference.name, replicas, manager);
+    metrics::REPLICA_REVERTS
+        .with_label_values(&[&reference.namespace, service_name])
+        .inc();
+
+    let count = {
+        let mut reverts = REVERTS.lock().unwrap();
+        let times = reverts.entry(workload).or_default();
+        times.push_back(now);
+        while times.front().is_some_and(|time| now - time > 3600) {
+            times.pop_front();
+        }
+        times.len()
+    };
+    if count != REVERT_ALERT {
+        return;
+    }
+    let note = format!(
+        "spec.replicas of {} {} was reverted {} times within an hour, last by {}. Make it ignore spec.replicas, see --gitops",
+        reference.kind, reference.name, count, manager
+    );
+    let published = events::publish(
+        &reference.namespace,
+        service_name,
+        EventType::Warning,
+        "ReplicasReverted".to_string(),
+        note,
+        "Scale",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "gitops", "Failed to record replica reverts of service {}: {}", service_name, err);
+    }
+}
+
+// The manager that changed spec.replicas last
+fn replicas_manager(entries: &[ManagedFieldsEntry]) -> Option<String> {
+    entries
+        .iter()
+        .filter(|entry| {
+            entry.fields_v1.as_ref().is_some_and(|fields| {
+                fields.0.pointer("/f:spec/f:replicas").is_some()
+                    || entry.subresource.as_deref() == Some("scale")
+            })
+        })
+        .max_by_key(|entry| entry.time.as_ref().map(|time| time.0))
+        .and_then(|entry| entry.manager.clone())
+}
@@ -0,0 +1,220 @@
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{self, TimeZone};
+use k8s_openapi::serde_json::json;
+use kube::api::{Api, DeleteParams, ListParams, Patch, PatchParams, PostParams};
+use kube::{Client, ResourceExt};
+use log::{debug, info, warn};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::lease;
+use super::models::{annotation_key, WATCHED_SERVICES};
+use super::scaler::FIELD_MANAGER;
+
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(10);
+
+// Label of the activity Leases, with the name of their service
+const ACTIVITY_LABEL: &str = "activity";
+
+// This contains the last packet this agent saw itself for each service IP. Only these are published,
+// the start time of the agent or the end of its warm-up is not activity of the service
+static SEEN: Lazy<Mutex<HashMap<String, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn lease_name(service_name: &str) -> String {
+    format!("scale-to-zero-activity-{}", service_name)
+}
+
+// Note a packet of the service seen on this node
+pub fn seen(service_ip: &str, timestamp: i64) {
+    SEEN.lock()
+        .unwrap()
+        .insert(service_ip.to_string(), timestamp);
+}
+
+// Forget the services no longer watched, returns the number of services left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    let mut seen = SEEN.lock().unwrap();
+    seen.retain(|ip, _| watched.contains(ip));
+    seen.len()
+}
+
+// Delete the Lease of a service no longer watched. Every agent tries, the ones that come after the
+// first get a 404
+pub async fn forget(service_ip: String, namespace: String, service_name: String) {
+    SEEN.lock().unwrap().remove(&service_ip);
+    let client = match super::client().await {
+        Ok(client) => client,
+        Err(err) => {
+            warn!(target: "gossip", "Failed to delete the activity Lease of {}: {:#}", service_name, err);
+            return;
+        }
+    };
+    let leases: Api<Lease> = Api::namespaced(client, &namespace);
+    match leases
+        .delete(&lease_name(&service_name), &DeleteParams::default())
+        .await
+    {
+        Ok(_) => {
+            debug!(target: "gossip", "Deleted the activity Lease of {}", service_name)
+        }
+        Err(kube::Error::Api(err)) if err.code == 404 => {}
+        Err(err) => {
+            warn!(target: "gossip", "Failed to delete the activity Lease of {}: {}", service_name, err)
+        }
+    }
+}
+
+// Each agent only sees the packets of its own node and would scale down a service busy on another
+// one. Every 10s the agents exchange the last activity of the services through a Lease per service:
+// the renewTime of the Lease is the last activity seen by any agent
+pub async fn share_activity(client: Client) {
+    info!(target: "gossip", "Sharing the activity of the services through Leases every {}s", GOSSIP_INTERVAL.as_secs());
+    loop {
+        let namespaces: HashSet<String> = WATCHED_SERVICES
+            .lock()
+            .unwrap()
+            .values()
+            .map(|service| service.namespace.clone())
+            .collect();
+        for namespace in namespaces {
+            if let Err(err) = exchange(&client, &namespace).await {
+                warn!(target: "gossip", "Failed to share the activity of namespace {}: {:#}", namespace, err);
+            }
+        }
+        tokio::time::sleep(GOSSIP_INTERVAL).await;
+    }
+}
+
+// Take the newer activity published by other agents first, then publish the packets seen here
+// where they are newer than the Lease
+async fn exchange(client: &Client, namespace: &str) -> anyhow::Result<()> {
+    let leases: Api<Lease> = Api::namespaced(client.clone(), namespace);
+    let label = annotation_key(ACTIVITY_LABEL);
+    // last activity (unix seconds) published for each service, with the resource version of its
+    // Lease
+    let published: HashMap<String, (i64, String)> = leases
+        .list(&ListParams::default().labels(&label))
+        .await?
+        .into_iter()
+        .filter_map(|lease| {
+            let service = lease.labels().get(&label)?.clone();
+            let renew_time = lease.spec.as_ref()?.renew_time.as_ref()?.0.timestamp();
+            Some((service, (renew_time, lease.resource_version()?)))
+        })
+        .collect();
+
+    let mut publish = Vec::new();
+    {
+        let seen = SEEN.lock().unwrap();
+        let mut watched_services = WATCHED_SERVICES.lock().unwrap();
+        for (service_ip, service) in watched_services
+            .iter_mut()
+            .filter(|(_, service)| service.namespace == namespace)
+        {
+            let published = published.get(&service.service_name);
+            if let Some((last_activity, _)) = published {
+                if *last_activity > service.last_packet_time {
+                    debug!(target: "gossip", "Activity of {} on another node at {}", service.service_name, last_activity);
+                    service.last_packet_time = *last_activity;
+                }
+            }
+            let last_seen = match seen.get(service_ip) {
+                Some(last_seen) => *last_seen,
+                None => continue,
+            };
+            match published {
+                Some((last_activity, _)) if *last_activity >= last_seen => {}
+                published => publish.push((
+                    service.service_name.clone(),
+                    last_seen,
+                    published.map(|(_, version)| version.clone()),
+                )),
+            }
+        }
+    }
+
+    for (service_name, last_activity, version) in publish {
+        let result = match version {
+            Some(version) => renew(&leases, &service_name, last_activity, version).await,
+            None => create(client, namespace, &service_name, last_activity).await,
+        };
+        match result {
+            Ok(()) => {}
+            // another agent published first, its activity is read on the next exchange
+            Err(kube::Error::Api(err)) if err.code == 409 => {
+                debug!(target: "gossip", "Activity of {} was published by another agent", service_name)
+            }
+            Err(err) => {
+                warn!(target: "gossip", "Failed to publish the activity of {}: {}", service_name, err)
+            }
+        }
+    }
+    Ok(())
+}
+
+fn micro_time(timestamp: i64) -> MicroTime {
+    MicroTime(
+        chrono::Utc
+            .timestamp_opt(timestamp, 0)
+            .single()
+            .unwrap_or_else(chrono::Utc::now),
+    )
+}
+
+// The resource version makes the patch fail if another agent published in between, a newer
+// activity is never overwritten with an older one
+async fn renew(
+    leases: &Api<Lease>,
+    service_name: &str,
+    last_activity: i64,
+    version: String,
+) -> Result<(), kube::Error> {
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    };
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "resourceVersion": version
+        },
+        "spec": {
+            "holderIdentity": lease::identity(),
+            "renewTime": micro_time(last_activity)
+        }
+    }));
+    leases
+        .patch(&lease_name(service_name), &params, &patch)
+        .await?;
+    Ok(())
+}
+
+async fn create(
+    client: &Client,
+    namespace: &str,
+    service_name: &str,
+    last_activity: i64,
+) -> Result<(), kube::Error> {
+    let leases: Api<Lease> = Api::namespaced(client.clone(), namespace);
+    let owner = super::service_owner(client.clone(), namespace, service_name).await?;
+    let lease = Lease {
+        metadata: ObjectMeta {
+            name: Some(lease_name(service_name)),
+            labels: Some(BTreeMap::from([(
+                annotation_key(ACTIVITY_LABEL),
+                service_name.to_string(),
+            )])),
+            owner_references: owner.map(|owner| vec![owner]),
+            ..Default::default()
+        },
+        spec: Some(LeaseSpec {
+            holder_identity: Some(lease::identity().to_string()),
+            renew_time: Some(micro_time(last_activity)),
+            ..Default::default()
+        }),
+    };
+    leases.create(&PostParams::default(), &lease).await?;
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{Api, Client, ResourceExt};
+use log::{info, warn};
+use std::time::Duration;
+
+use super::models::{
+    annotation, parse_duration, GROUP_SCALE_DOWN_TIME_ANNOTATION, NAMESPACE_GROUPS,
+    WATCHED_SERVICES,
+};
+
+// Keep NAMESPACE_GROUPS in sync with the annotation of a watched namespace. Reading namespaces
+// needs cluster-wide RBAC, without it the services of the namespace are scaled one by one
+pub async fn track_group(client: Client, namespace: String) {
+    let namespaces: Api<Namespace> = Api::all(client);
+    loop {
+        let scale_down_time = match namespaces.get_opt(&namespace).await {
+            Ok(ns) => ns.and_then(|ns| {
+                parse_duration(annotation(ns.annotations(), GROUP_SCALE_DOWN_TIME_ANNOTATION)?)
+                    .map_err(|err| {
+                        warn!(target: "groups", "Namespace {} has an invalid group-scale-down-time: {}", namespace, err)
+                    })
+                    .ok()
+            }),
+            Err(kube::Error::Api(err)) if err.code == 403 => {
+                warn!(target: "groups", "Not allowed to read namespace {}, groups are disabled: {}", namespace, err.message);
+                return;
+            }
+            Err(err) => {
+                warn!(target: "groups", "Failed to get namespace {}: {}", namespace, err);
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                continue;
+            }
+        };
+
+        let previous = {
+            let mut groups = NAMESPACE_GROUPS.lock().unwrap();
+            match scale_down_time {
+                Some(scale_down_time) => groups.insert(namespace.clone(), scale_down_time),
+                None => groups.remove(&namespace),
+            }
+        };
+        if previous != scale_down_time {
+            info!(target: "groups", "Group scale-down-time of namespace {}: {:?}", namespace, scale_down_time);
+        }
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+pub fn scale_down_time(namespace: &str) -> Option<i64> {
+    NAMESPACE_GROUPS.lock().unwrap().get(namespace).copied()
+}
+
+// Last packet to any service of the group, the group is idle only when all of them are
+pub fn last_packet_time(namespace: &str) -> i64 {
+    WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|service| service.namespace == namespace)
+        .map(|service| service.last_packet_time)
+        .max()
+        .unwrap_or(0)
+}
+
+// Service IPs of the scaled-down services of the group
+pub fn sleeping_members(namespace: &str) -> Vec<String> {
+    WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, service)| service.namespace == namespace && !service.backend_available)
+        .map(|(ip, _)| ip.clone())
+        .collect()
+}
@@ -0,0 +1,178 @@
+use k8s_openapi::api::batch::v1::{CronJob, Job};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::api::{Api, ListParams, PostParams};
+use kube::runtime::events::EventType;
+use kube::ResourceExt;
+use log::{info, warn};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::events;
+use super::models::{annotation_key, Hook, HookStatus, ServiceData, WATCHED_SERVICES};
+
+// Labels of the hook Jobs, a running Job of the same service and phase is waited on instead of
+// creating another one, so several agents waking the same service share it
+const HOOK_SERVICE_LABEL: &str = "hook-service";
+const HOOK_PHASE_LABEL: &str = "hook-phase";
+
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    PreWake,
+    PostScaleDown,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::PreWake => f.write_str("pre-wake"),
+            Phase::PostScaleDown => f.write_str("post-scale-down"),
+        }
+    }
+}
+
+impl Phase {
+    // Event reason prefix, e.g. PreWakeHookFailed
+    fn reason(&self) -> &'static str {
+        match self {
+            Phase::PreWake => "PreWakeHook",
+            Phase::PostScaleDown => "PostScaleDownHook",
+        }
+    }
+}
+
+// Run the hook of a service and wait for it, the outcome is kept in the state of the service and
+// recorded as an event on it
+pub async fn run(service: &ServiceData, hook: &Hook, phase: Phase) -> anyhow::Result<()> {
+    info!(target: "hooks", "Running {} hook {} of service {}", phase, hook.cronjob, service.service_name);
+    set_status(service, phase, HookStatus::Running);
+    let result = run_job(service, hook, phase).await;
+    let (type_, reason, note) = match result.as_ref() {
+        Ok(job) => {
+            set_status(service, phase, HookStatus::Succeeded { job: job.clone() });
+            (
+                EventType::Normal,
+                format!("{}Succeeded", phase.reason()),
+                format!("Job {} completed", job),
+            )
+        }
+        Err(err) => {
+            set_status(
+                service,
+                phase,
+                HookStatus::Failed {
+                    error: format!("{:#}", err),
+                },
+            );
+            (
+                EventType::Warning,
+                format!("{}Failed", phase.reason()),
+                format!("{:#}", err),
+            )
+        }
+    };
+    let published = events::publish(
+        &service.namespace,
+        &service.service_name,
+        type_,
+        reason,
+        note,
+        phase.reason(),
+    );
+    if let Err(err) = published.await {
+        warn!(target: "hooks", "Failed to record hook event of service {}: {}", service.service_name, err);
+    }
+    result.map(|_| ())
+}
+
+async fn run_job(service: &ServiceData, hook: &Hook, phase: Phase) -> anyhow::Result<String> {
+    let client = super::client().await?;
+    let jobs: Api<Job> = Api::namespaced(client.clone(), &service.namespace);
+    let selector = format!(
+        "{}={},{}={}",
+        annotation_key(HOOK_SERVICE_LABEL),
+        service.service_name,
+        annotation_key(HOOK_PHASE_LABEL),
+        phase
+    );
+
+    let running = jobs
+        .list(&ListParams::default().labels(&selector))
+        .await?
+        .into_iter()
+        .find(|job| finished(job).is_none());
+    let name = match running {
+        Some(job) => job.name_any(),
+        None => {
+            let cronjobs: Api<CronJob> = Api::namespaced(client.clone(), &service.namespace);
+            let template = cronjobs
+                .get(&hook.cronjob)
+                .await?
+                .spec
+                .ok_or_else(|| anyhow::anyhow!("CronJob {} has no spec", hook.cronjob))?
+                .job_template;
+
+            let mut labels = template
+                .metadata
+                .as_ref()
+                .and_then(|meta| meta.labels.clone())
+                .unwrap_or_default();
+            labels.insert(
+                annotation_key(HOOK_SERVICE_LABEL),
+                service.service_name.clone(),
+            );
+            labels.insert(annotation_key(HOOK_PHASE_LABEL), phase.to_string());
+            let owner =
+                super::service_owner(client, &service.namespace, &service.service_name).await?;
+            let job = Job {
+                metadata: ObjectMeta {
+                    generate_name: Some(format!("{}-{}-", hook.cronjob, phase)),
+                    labels: Some(labels),
+                    owner_references: owner.map(|owner| vec![owner]),
+                    ..Default::default()
+                },
+                spec: template.spec,
+                ..Default::default()
+            };
+            jobs.create(&PostParams::default(), &job).await?.name_any()
+        }
+    };
+
+    let deadline = Instant::now() + Duration::from_secs(hook.timeout);
+    loop {
+        if let Some(succeeded) = finished(&jobs.get(&name).await?) {
+            if succeeded {
+                return Ok(name);
+            }
+            anyhow::bail!("Job {} failed", name);
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!("Job {} did not complete within {}s", name, hook.timeout);
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
+
+fn set_status(service: &ServiceData, phase: Phase, status: HookStatus) {
+    let mut watched_services = WATCHED_SERVICES.lock().unwrap();
+    let watched = watched_services
+        .values_mut()
+        .filter(|s| s.namespace == service.namespace && s.service_name == service.service_name);
+    for watched in watched {
+        watched
+            .hook_status
+            .insert(phase.to_string(), status.clone());
+    }
+}
+
+// Whether the Job succeeded, None while it runs
+fn finished(job: &Job) -> Option<bool> {
+    let conditions = job.status.as_ref()?.conditions.as_ref()?;
+    conditions
+        .iter()
+        .filter(|c| c.status == "True")
+        .find_map(|c| match c.type_.as_str() {
+            "Complete" => Some(true),
+            "Failed" => Some(false),
+            _ => None,
+        })
+}
@@ -0,0 +1,98 @@
+use k8s_openapi::api::autoscaling::v2::HorizontalPodAutoscaler;
+use k8s_openapi::serde_json::json;
+use kube::api::{Api, Patch, PatchParams};
+use kube::ResourceExt;
+use log::{info, warn};
+
+use super::models::{annotation, annotation_key, ServiceData};
+use super::scaler::FIELD_MANAGER;
+
+// Set on the HPA while the workload is scaled down, the minReplicas to restore on the next wake
+const MIN_REPLICAS_ANNOTATION: &str = "hpa-min-replicas";
+
+fn api(client: kube::Client, service: &ServiceData) -> Api<HorizontalPodAutoscaler> {
+    Api::namespaced(client, &service.namespace)
+}
+
+fn params() -> PatchParams {
+    PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    }
+}
+
+// Lower the minReplicas of the HPA to 0 before the workload is scaled down, its minReplicas is
+// kept on the HPA for the next wake. Without the HPAScaleToZero feature gate the API server
+// rejects 0, the HPA is left as is since it doesn't act on a workload at 0 replicas anyway
+pub async fn scale_down(service: &ServiceData, name: &str) -> anyhow::Result<()> {
+    let hpas = api(super::client().await?, service);
+    let hpa = hpas.get(name).await?;
+    let min_replicas = match annotation(hpa.annotations(), MIN_REPLICAS_ANNOTATION) {
+        Some(min_replicas) => min_replicas.parse::<i32>()?,
+        None => hpa
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.min_replicas)
+            .unwrap_or(1),
+    };
+
+    let annotate = json!({
+        "metadata": {
+            "annotations": {
+                annotation_key(MIN_REPLICAS_ANNOTATION): min_replicas.to_string()
+            }
+        }
+    });
+    hpas.patch(name, &params(), &Patch::Merge(annotate)).await?;
+
+    let lower = Patch::Merge(json!({
+        "spec": {
+            "minReplicas": 0
+        }
+    }));
+    match hpas.patch(name, &params(), &lower).await {
+        Ok(_) => {
+            info!(target: "hpa", "Lowered minReplicas of HPA {} from {} to 0", name, min_replicas);
+            Ok(())
+        }
+        Err(kube::Error::Api(err)) if err.code == 422 => {
+            warn!(target: "hpa", "Keeping minReplicas of HPA {}, 0 is not allowed without the HPAScaleToZero feature gate", name);
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Restore the minReplicas of the HPA and return it, the workload is scaled up to it and the HPA
+// takes over from there
+pub async fn wake(service: &ServiceData, name: &str) -> anyhow::Result<i32> {
+    let hpas = api(super::client().await?, service);
+    let hpa = hpas.get(name).await?;
+    let stored = annotation(hpa.annotations(), MIN_REPLICAS_ANNOTATION)
+        .map(|min_replicas| min_replicas.parse::<i32>())
+        .transpose()?;
+    let min_replicas = match stored {
+        Some(min_replicas) => min_replicas,
+        None => hpa
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.min_replicas)
+            .unwrap_or(1),
+    };
+
+    if stored.is_some() {
+        let patch = Patch::Merge(json!({
+            "metadata": {
+                "annotations": {
+                    annotation_key(MIN_REPLICAS_ANNOTATION): null
+                }
+            },
+            "spec": {
+                "minReplicas": min_replicas
+            }
+        }));
+        hpas.patch(name, &params(), &patch).await?;
+        info!(target: "hpa", "Restored minReplicas of HPA {} to {}", name, min_replicas);
+    }
+    Ok(min_replicas.max(1))
+}
@@ -0,0 +1,197 @@
+use k8s_openapi::api::networking::v1::Ingress;
+use kube::api::{Api, DynamicObject, ListParams};
+use kube::core::GroupVersionKind;
+use kube::{discovery, Client, ResourceExt};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::models::WATCHED_SERVICES;
+
+// Traffic through an ingress controller reaches the pods of a service, never its ClusterIP, so a
+// scaled down backend can't be woken by the datapath. Routes map the hosts of the Ingresses and
+// HTTPRoutes of a namespace to the services they send to, for the wake endpoint of the admin API
+
+type Route = (String, String);
+
+// This contains the (host, service name) routes of each watched namespace
+static ROUTES: Lazy<Mutex<HashMap<String, Vec<Route>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Refresh the routes of a namespace every 30s. The Gateway API is optional, and lacking RBAC for
+// either kind only disables that kind
+pub async fn track_routes(client: Client, namespace: String) {
+    let ingresses: Api<Ingress> = Api::namespaced(client.clone(), &namespace);
+    let mut ingresses = Some(ingresses);
+    let mut http_routes = http_route_api(&client, &namespace).await;
+    loop {
+        let mut routes = Vec::new();
+        if let Some(api) = ingresses.as_ref() {
+            match api.list(&ListParams::default()).await {
+                Ok(list) => routes.extend(list.iter().flat_map(ingress_routes)),
+                Err(kube::Error::Api(err)) if err.code == 403 => {
+                    warn!(target: "ingress", "Not allowed to list ingresses in {}, host based wakes ignore them: {}", namespace, err.message);
+                    ingresses = None;
+                }
+                Err(err) => {
+                    warn!(target: "ingress", "Failed to list ingresses in {}: {}", namespace, err)
+                }
+            }
+        }
+        if let Some(api) = http_routes.as_ref() {
+            match api.list(&ListParams::default()).await {
+                Ok(list) => routes.extend(list.iter().flat_map(http_route_routes)),
+                Err(kube::Error::Api(err)) if err.code == 403 => {
+                    warn!(target: "ingress", "Not allowed to list HTTPRoutes in {}, host based wakes ignore them: {}", namespace, err.message);
+                    http_routes = None;
+                }
+                Err(err) => {
+                    warn!(target: "ingress", "Failed to list HTTPRoutes in {}: {}", namespace, err)
+                }
+            }
+        }
+        ROUTES.lock().unwrap().insert(namespace.clone(), routes);
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+async fn http_route_api(client: &Client, namespace: &str) -> Option<Api<DynamicObject>> {
+    for version in ["v1", "v1beta1"] {
+        let gvk = GroupVersionKind::gvk("gateway.networking.k8s.io", version, "HTTPRoute");
+        if let Ok((resource, _)) = discovery::pinned_kind(client, &gvk).await {
+            info!(target: "ingress", "Gateway API {} is installed", version);
+            return Some(Api::namespaced_with(client.clone(), namespace, &resource));
+        }
+    }
+    None
+}
+
+fn ingress_routes(ingress: &Ingress) -> Vec<(String, String)> {
+    let mut routes = Vec::new();
+    let spec = match ingress.spec.as_ref() {
+        Some(spec) => spec,
+        None => return routes,
+    };
+    for rule in spec.rules.iter().flatten() {
+        // a rule without a host matches any host
+        let host = rule.host.clone().unwrap_or_else(|| "*".to_string());
+        for path in rule.http.iter().flat_map(|http| http.paths.iter()) {
+            if let Some(service) = path.backend.service.as_ref() {
+                routes.push((host.clone(), service.name.clone()));
+            }
+        }
+    }
+    if let Some(service) = spec
+        .default_backend
+        .as_ref()
+        .and_then(|backend| backend.service.as_ref())
+    {
+        routes.push(("*".to_string(), service.name.clone()));
+    }
+    routes
+}
+
+fn http_route_routes(route: &DynamicObject) -> Vec<(String, String)> {
+    let namespace = route.namespace().unwrap_or_default();
+    let mut hosts: Vec<String> = route
+        .data
+        .pointer("/spec/hostnames")
+        .and_then(|hostnames| hostnames.as_array())
+        .map(|hostnames| {
+            hostnames
+                .iter()
+                .filter_map(|host| host.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    if hosts.is_empty() {
+        hosts.push("*".to_string());
+    }
+
+    // only Service backends in the namespace of the route
+    let backends: Vec<String> = route
+        .data
+        .pointer("/spec/rules")
+        .and_then(|rules| rules.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|rule| rule.get("backendRefs")?.as_array())
+        .flatten()
+        .filter(|backend| {
+            matches!(
+                backend.get("kind").and_then(|kind| kind.as_str()),
+                None | Some("Service")
+            ) && backend
+                .get("namespace")
+                .and_then(|ns| ns.as_str())
+                .unwrap_or(&namespace)
+                == namespace
+        })
+        .filter_map(|backend| Some(backend.get("name")?.as_str()?.to_string()))
+        .collect();
+
+    hosts
+        .iter()
+        .flat_map(|host| {
+            backends
+                .iter()
+                .map(move |backend| (host.clone(), backend.clone()))
+        })
+        .collect()
+}
+
+// Whether a route host (exact, `*.example.com` or `*`) matches the host of a request
+pub fn host_matches(route_host: &str, host: &str) -> bool {
+    match route_host.strip_prefix('*') {
+        Some("") => true,
+        Some(suffix) => host.ends_with(suffix) && host.len() > suffix.len(),
+        None => route_host.eq_ignore_ascii_case(host),
+    }
+}
+
+// Service IPs of the watched services routed to from a host. Exact hosts win over wildcards
+pub fn services_for_host(host: &str) -> Vec<String> {
+    // the port is not part of the routes
+    let host = host.rsplit_once(':').map(|(host, _)| host).unwrap_or(host);
+    let routes = ROUTES.lock().unwrap();
+    let mut matches: Vec<(String, String)> = routes
+        .iter()
+        .flat_map(|(namespace, routes)| {
+            routes
+                .iter()
+                .filter(|(route_host, _)| route_host.eq_ignore_ascii_case(host))
+                .map(move |(_, service)| (namespace.clone(), service.clone()))
+        })
+        .collect();
+    if matches.is_empty() {
+        matches = routes
+            .iter()
+            .flat_map(|(namespace, routes)| {
+                routes
+                    .iter()
+                    .filter(|(route_host, _)| host_matches(route_host, host))
+                    .map(move |(_, service)| (namespace.clone(), service.clone()))
+            })
+            .collect();
+    }
+    drop(routes);
+
+    matches
+        .iter()
+        .flat_map(|(namespace, service)| service_ips(namespace, service))
+        .collect()
+}
+
+// Service IP of a watched service, by namespace and name
+pub fn service_ips(namespace: &str, service_name: &str) -> Vec<String> {
+    WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, service)| {
+            service.namespace == namespace && service.service_name == service_name
+        })
+        .map(|(ip, _)| ip.clone())
+        .collect()
+}
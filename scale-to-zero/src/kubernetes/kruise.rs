@@ -0,0 +1,107 @@
+use k8s_openapi::serde_json::json;
+use kube::api::{Api, DynamicObject, Patch, PatchParams};
+use kube::core::{ApiResource, GroupVersionKind};
+use kube::{discovery, Client};
+use log::info;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::scaler::FIELD_MANAGER;
+
+// An OpenKruise workload kind, only used through the scale subresource so the CRDs are optional
+pub struct KruiseKind {
+    // kind in the reference annotation, e.g. cloneset/web
+    pub name: &'static str,
+    group: &'static str,
+    version: &'static str,
+    kind: &'static str,
+}
+
+pub const KINDS: [KruiseKind; 2] = [
+    KruiseKind {
+        name: "cloneset",
+        group: "apps.kruise.io",
+        version: "v1alpha1",
+        kind: "CloneSet",
+    },
+    KruiseKind {
+        name: "advancedstatefulset",
+        group: "apps.kruise.io",
+        version: "v1beta1",
+        kind: "StatefulSet",
+    },
+];
+
+// API resources of the kinds found in the cluster, by kind name
+static RESOURCES: Lazy<Mutex<HashMap<&'static str, ApiResource>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn is_kruise(kind: &str) -> bool {
+    KINDS.iter().any(|k| k.name == kind)
+}
+
+pub fn gvk(kind: &str) -> Option<GroupVersionKind> {
+    KINDS
+        .iter()
+        .find(|k| k.name == kind)
+        .map(|k| GroupVersionKind::gvk(k.group, k.version, k.kind))
+}
+
+// Look the OpenKruise APIs up through discovery, kinds that are not installed are skipped
+pub async fn discover(client: &Client) -> Vec<(&'static str, ApiResource)> {
+    let mut installed = Vec::new();
+    for kind in KINDS.iter() {
+        let gvk = GroupVersionKind::gvk(kind.group, kind.version, kind.kind);
+        if let Ok((resource, _)) = discovery::pinned_kind(client, &gvk).await {
+            info!(target: "kruise", "OpenKruise {} API is installed", kind.kind);
+            RESOURCES
+                .lock()
+                .unwrap()
+                .insert(kind.name, resource.clone());
+            installed.push((kind.name, resource));
+        }
+    }
+    installed
+}
+
+pub async fn api(
+    client: Client,
+    namespace: &str,
+    kind: &str,
+) -> anyhow::Result<Api<DynamicObject>> {
+    let cached = RESOURCES.lock().unwrap().get(kind).cloned();
+    let resource = match cached {
+        Some(resource) => resource,
+        None => discover(&client)
+            .await
+            .into_iter()
+            .find(|(name, _)| *name == kind)
+            .map(|(_, resource)| resource)
+            .ok_or_else(|| anyhow::anyhow!("OpenKruise API of {} is not installed", kind))?,
+    };
+    Ok(Api::namespaced_with(client, namespace, &resource))
+}
+
+pub async fn replicas(api: &Api<DynamicObject>, name: &str) -> anyhow::Result<i32> {
+    let scale = api.get_scale(name).await?;
+    Ok(scale.spec.and_then(|spec| spec.replicas).unwrap_or(0))
+}
+
+pub async fn set_replicas(
+    api: &Api<DynamicObject>,
+    name: &str,
+    replicas: i32,
+) -> anyhow::Result<()> {
+    let patch = Patch::Merge(json!({
+        "spec": {
+            "replicas": replicas
+        }
+    }));
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    };
+    api.patch_scale(name, &params, &patch).await?;
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono;
+use kube::api::{Api, DeleteParams, PostParams};
+use once_cell::sync::Lazy;
+
+use crate::config;
+
+// Longer than the scale-up rate limit, so a node can't take the lease over while the wake is running
+const LEASE_DURATION_SECONDS: i32 = 10;
+
+// Holder identity of the leases taken by this agent
+static IDENTITY: Lazy<String> = Lazy::new(|| {
+    config::get().node_name.clone().unwrap_or_else(|| {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|hostname| hostname.trim().to_string())
+            .unwrap_or_else(|_| "unknown".to_string())
+    })
+});
+
+pub fn identity() -> &'static str {
+    IDENTITY.as_str()
+}
+
+// Try to take the lease `name`, only the agent holding it scales the workload up. The lease is
+// created when missing and taken over once expired, both relying on the API server rejecting
+// concurrent writes so exactly one agent gets it
+pub async fn try_acquire(namespace: &str, name: &str) -> anyhow::Result<bool> {
+    try_acquire_for(namespace, name, LEASE_DURATION_SECONDS).await
+}
+
+// Same for a task longer than the default duration, `release` gives the lease up once it is done
+pub async fn try_acquire_for(
+    namespace: &str,
+    name: &str,
+    duration_seconds: i32,
+) -> anyhow::Result<bool> {
+    acquire(namespace, name, duration_seconds, false).await
+}
+
+// Take the lease `name` or renew it when this agent already holds it, for a task a single agent
+// runs for as long as it is up. Another agent takes it over once it expires
+pub async fn hold(namespace: &str, name: &str, duration_seconds: i32) -> anyhow::Result<bool> {
+    acquire(namespace, name, duration_seconds, true).await
+}
+
+async fn acquire(
+    namespace: &str,
+    name: &str,
+    duration_seconds: i32,
+    renew: bool,
+) -> anyhow::Result<bool> {
+    let leases: Api<Lease> = Api::namespaced(super::client().await?, namespace);
+    let now = chrono::Utc::now();
+
+    let result = match leases.get_opt(name).await? {
+        None => {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(name.to_string()),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(IDENTITY.clone()),
+                    lease_duration_seconds: Some(duration_seconds),
+                    acquire_time: Some(MicroTime(now)),
+                    renew_time: Some(MicroTime(now)),
+                    lease_transitions: Some(0),
+                }),
+            };
+            leases.create(&PostParams::default(), &lease).await
+        }
+        Some(mut lease) => {
+            let spec = lease.spec.get_or_insert_with(Default::default);
+            let duration = spec
+                .lease_duration_seconds
+                .unwrap_or(LEASE_DURATION_SECONDS);
+            let held = renew && spec.holder_identity.as_ref() == Some(&*IDENTITY);
+            if let Some(MicroTime(renew_time)) = spec.renew_time {
+                if !held && renew_time + chrono::Duration::seconds(duration as i64) > now {
+                    return Ok(false);
+                }
+            }
+
+            if !held {
+                spec.holder_identity = Some(IDENTITY.clone());
+                spec.acquire_time = Some(MicroTime(now));
+                spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+            }
+            spec.lease_duration_seconds = Some(duration_seconds);
+            spec.renew_time = Some(MicroTime(now));
+            // the resource version of the lease we read makes this fail if another agent won
+            leases.replace(name, &PostParams::default(), &lease).await
+        }
+    };
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Delete a lease held by this agent, so the next agent doesn't wait for it to expire
+pub async fn release(namespace: &str, name: &str) -> anyhow::Result<()> {
+    let leases: Api<Lease> = Api::namespaced(super::client().await?, namespace);
+    match leases.delete(name, &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(err)) if err.code == 404 => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
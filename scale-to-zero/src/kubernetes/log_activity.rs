@@ -0,0 +1,118 @@
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::{Api, ListParams, LogParams};
+use kube::ResourceExt;
+use log::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use tokio::task;
+
+use super::models::{ActivityKind, ServiceData, WATCHED_SERVICES};
+use super::{polling, startup};
+use crate::activity::ActivitySource;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Name of the Lease of the agent reading the logs of a namespace
+const SOURCE: &str = "log-activity";
+
+// Log output of the backend pods as activity, for workloads driven by pulled work (e.g. queue
+// consumers) that receive no packets. Only services with the log-activity annotation are read
+pub struct LogSource;
+
+impl ActivitySource for LogSource {
+    fn name(&self) -> &'static str {
+        "logs"
+    }
+
+    fn start(self: Box<Self>) -> anyhow::Result<()> {
+        task::spawn(poll());
+        Ok(())
+    }
+}
+
+// Read the logs of each opted in service once per window, enough lines within it count as a packet.
+// Only the agent holding the log-activity Lease of a namespace reads them
+async fn poll() {
+    let mut polled: HashMap<String, Instant> = HashMap::new();
+    loop {
+        let services: Vec<(String, ServiceData)> = WATCHED_SERVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, service)| {
+                service.log_activity.is_some()
+                    && service.activity_sources.contains(&ActivityKind::Logs)
+                    && service.backend_available
+            })
+            .map(|(ip, service)| (ip.clone(), service.clone()))
+            .collect();
+        polled.retain(|ip, _| services.iter().any(|(service_ip, _)| service_ip == ip));
+        let mut namespaces: BTreeMap<String, Vec<(String, ServiceData)>> = BTreeMap::new();
+        for (service_ip, service) in services {
+            namespaces
+                .entry(service.namespace.clone())
+                .or_default()
+                .push((service_ip, service));
+        }
+
+        for (namespace, services) in namespaces {
+            if !polling::lead(&namespace, SOURCE, POLL_INTERVAL, ActivityKind::Logs).await {
+                continue;
+            }
+            let mut active = Vec::new();
+            for (service_ip, service) in services {
+                let log_activity = match service.log_activity {
+                    Some(log_activity) => log_activity,
+                    None => continue,
+                };
+                let window = Duration::from_secs(log_activity.window);
+                if matches!(polled.get(&service_ip), Some(time) if time.elapsed() < window) {
+                    continue;
+                }
+                polled.insert(service_ip.clone(), Instant::now());
+
+                match log_lines(&service, log_activity.window).await {
+                    Ok(lines) if lines >= log_activity.lines => active.push(service_ip),
+                    Ok(lines) => {
+                        debug!(target: "log_activity", "{} {} logged {} lines, below {}", service.kind, service.name, lines, log_activity.lines);
+                    }
+                    Err(err) => {
+                        debug!(target: "log_activity", "Failed to read the logs of {} {}: {:#}", service.kind, service.name, err);
+                    }
+                }
+            }
+            polling::publish(&namespace, SOURCE, ActivityKind::Logs, active).await;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Lines logged by the containers of the pods of the workload within the last `window` seconds
+async fn log_lines(service: &ServiceData, window: u64) -> anyhow::Result<usize> {
+    let pods: Api<Pod> = Api::namespaced(super::client().await?, &service.namespace);
+    let params = ListParams::default().labels(&startup::pod_selector(service).await?);
+    let mut lines = 0;
+    for pod in pods.list(&params).await? {
+        let containers: Vec<String> = pod
+            .spec
+            .iter()
+            .flat_map(|spec| {
+                spec.containers
+                    .iter()
+                    .map(|container| container.name.clone())
+            })
+            .collect();
+        for container in containers {
+            let params = LogParams {
+                container: Some(container),
+                since_seconds: Some(window as i64),
+                ..Default::default()
+            };
+            // a container that is not running has no logs yet
+            if let Ok(logs) = pods.logs(&pod.name_any(), &params).await {
+                lines += logs.lines().count();
+            }
+        }
+    }
+    Ok(lines)
+}
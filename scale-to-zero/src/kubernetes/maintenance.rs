@@ -0,0 +1,146 @@
+use k8s_openapi::api::core::v1::ConfigMap;
+use k8s_openapi::chrono;
+use k8s_openapi::serde_json::json;
+use kube::api::{Api, Patch, PatchParams};
+use kube::Client;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::models::WATCHED_SERVICES;
+use super::scaler::{self, FIELD_MANAGER};
+use crate::config;
+use crate::metrics;
+
+// Keys of the maintenance ConfigMap, "true" turns them on
+const ENABLED_KEY: &str = "enabled";
+const WAKE_ALL_KEY: &str = "wake-all";
+
+// While the maintenance mode is on no service is scaled down, traffic still wakes them. It is kept
+// in the maintenance ConfigMap so every agent follows the same mode
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Maintenance {
+    pub enabled: bool,
+    // Whether every sleeping service was woken when it was turned on
+    pub wake_all: bool,
+    // Where it was last changed: flag, api or configmap
+    pub source: String,
+    // Unix seconds of the last change
+    pub since: Option<i64>,
+}
+
+static MAINTENANCE: Lazy<Mutex<Maintenance>> = Lazy::new(|| Mutex::new(Maintenance::default()));
+
+pub fn get() -> Maintenance {
+    MAINTENANCE.lock().unwrap().clone()
+}
+
+pub fn enabled() -> bool {
+    MAINTENANCE.lock().unwrap().enabled
+}
+
+// Turn the maintenance mode of every agent on or off through the ConfigMap, applied here right away
+// and by the other agents at their next read. Standalone agents have no ConfigMap, only their own
+// mode changes
+pub async fn change(enabled: bool, wake_all: bool) -> anyhow::Result<()> {
+    let wake_all = enabled && wake_all;
+    if config::get().standalone.is_none() {
+        let name = &config::get().maintenance_configmap;
+        let configmaps: Api<ConfigMap> = Api::default_namespaced(super::client().await?);
+        let configmap = json!({
+            "apiVersion": "v1",
+            "kind": "ConfigMap",
+            "metadata": {
+                "name": name
+            },
+            "data": {
+                ENABLED_KEY: enabled.to_string(),
+                WAKE_ALL_KEY: wake_all.to_string()
+            }
+        });
+        configmaps
+            .patch(
+                name,
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(&configmap),
+            )
+            .await?;
+    }
+    set(enabled, wake_all, "api").await;
+    Ok(())
+}
+
+// Turn the maintenance mode of this agent on or off. Turning it on with wake_all scales up every
+// sleeping service
+async fn set(enabled: bool, wake_all: bool, source: &str) {
+    let wake_all = enabled && wake_all;
+    {
+        let mut maintenance = MAINTENANCE.lock().unwrap();
+        if maintenance.enabled == enabled && maintenance.wake_all == wake_all {
+            return;
+        }
+        *maintenance = Maintenance {
+            enabled,
+            wake_all,
+            source: source.to_string(),
+            since: Some(chrono::Utc::now().timestamp()),
+        };
+    }
+    metrics::MAINTENANCE_MODE.set(enabled as i64);
+    if enabled {
+        warn!(target: "maintenance", "Maintenance mode turned on from the {}, services are not scaled down", source);
+    } else {
+        info!(target: "maintenance", "Maintenance mode turned off from the {}", source);
+    }
+
+    if wake_all {
+        let sleeping: Vec<String> = WATCHED_SERVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, service)| !service.backend_available)
+            .map(|(ip, _)| ip.clone())
+            .collect();
+        info!(target: "maintenance", "Waking {} sleeping services", sleeping.len());
+        // all at once, the wake queue lets the high priority ones through first
+        let wakes = sleeping.into_iter().map(|service_ip| async move {
+            if let Err(err) = scaler::scale_up(service_ip.clone()).await {
+                warn!(target: "maintenance", "Failed to wake {}: {:#}", service_ip, err);
+            }
+        });
+        futures::future::join_all(wakes).await;
+    }
+}
+
+// Follow the maintenance ConfigMap in the namespace of the agent every 10s, written by the admin
+// API of any agent or edited by hand. Until it exists the agent keeps the mode of --maintenance
+async fn track_configmap(client: Client, name: String) {
+    let configmaps: Api<ConfigMap> = Api::default_namespaced(client);
+    loop {
+        match configmaps.get_opt(&name).await {
+            Ok(Some(configmap)) => {
+                let data = configmap.data.unwrap_or_default();
+                let is_true = |key: &str| data.get(key).map(String::as_str) == Some("true");
+                set(is_true(ENABLED_KEY), is_true(WAKE_ALL_KEY), "configmap").await;
+            }
+            Ok(None) => {}
+            Err(kube::Error::Api(err)) if err.code == 403 => {
+                warn!(target: "maintenance", "Not allowed to get ConfigMap {}, maintenance mode is only set by --maintenance and the admin API of this agent: {}", name, err.message);
+                return;
+            }
+            Err(err) => warn!(target: "maintenance", "Failed to get ConfigMap {}: {}", name, err),
+        }
+        tokio::time::sleep(Duration::from_secs(10)).await;
+    }
+}
+
+// Start in maintenance mode when the flag is set, and follow the ConfigMap
+pub async fn init(client: Client) {
+    let opts = config::get();
+    if opts.maintenance {
+        set(true, false, "flag").await;
+    }
+    tokio::spawn(track_configmap(client, opts.maintenance_configmap.clone()));
+}
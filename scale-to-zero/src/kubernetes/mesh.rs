@@ -0,0 +1,138 @@
+use hyper::http;
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::{Api, ListParams};
+use kube::ResourceExt;
+use log::debug;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::task;
+
+use super::models::{ActivityKind, ServiceData, WATCHED_SERVICES};
+use super::{polling, startup};
+use crate::activity::ActivitySource;
+use crate::config;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+// Name of the Lease of the agent scraping the sidecars of a namespace
+const SOURCE: &str = "mesh-activity";
+
+// Requests counted by the sidecars of a service mesh (Envoy, Istio) as activity. With mTLS or
+// tunneled traffic the packets reaching the pods tell little about real requests, and health
+// checks of the mesh keep them busy. Only services with `mesh` in their activity sources are read
+pub struct MeshSource;
+
+impl ActivitySource for MeshSource {
+    fn name(&self) -> &'static str {
+        "mesh"
+    }
+
+    fn start(self: Box<Self>) -> anyhow::Result<()> {
+        task::spawn(poll());
+        Ok(())
+    }
+}
+
+// Scrape the sidecars of the pods of each service and count a growing request counter as activity.
+// Only the agent holding the mesh-activity Lease of a namespace scrapes them
+async fn poll() {
+    // last request count of each service IP
+    let mut requests: HashMap<String, f64> = HashMap::new();
+    loop {
+        let services: Vec<(String, ServiceData)> = WATCHED_SERVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, service)| {
+                service.activity_sources.contains(&ActivityKind::Mesh) && service.backend_available
+            })
+            .map(|(ip, service)| (ip.clone(), service.clone()))
+            .collect();
+        requests.retain(|ip, _| services.iter().any(|(service_ip, _)| service_ip == ip));
+        let mut namespaces: BTreeMap<String, Vec<(String, ServiceData)>> = BTreeMap::new();
+        for (service_ip, service) in services {
+            namespaces
+                .entry(service.namespace.clone())
+                .or_default()
+                .push((service_ip, service));
+        }
+
+        for (namespace, services) in namespaces {
+            if !polling::lead(&namespace, SOURCE, POLL_INTERVAL, ActivityKind::Mesh).await {
+                // the counts of a later turn as holder are compared with fresh ones
+                for (service_ip, _) in services {
+                    requests.remove(&service_ip);
+                }
+                continue;
+            }
+            let mut active = Vec::new();
+            for (service_ip, service) in services {
+                let count = match request_count(&service).await {
+                    Ok(count) => count,
+                    Err(err) => {
+                        debug!(target: "mesh", "Failed to scrape the sidecars of {} {}: {:#}", service.kind, service.name, err);
+                        continue;
+                    }
+                };
+                // a lower count is a restarted sidecar or a pod that went away, not activity
+                if matches!(requests.insert(service_ip.clone(), count), Some(last) if count > last)
+                {
+                    active.push(service_ip);
+                }
+            }
+            polling::publish(&namespace, SOURCE, ActivityKind::Mesh, active).await;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Requests received by the pods of the workload, summed over their sidecars
+async fn request_count(service: &ServiceData) -> anyhow::Result<f64> {
+    let opts = config::get();
+    let client = super::client().await?;
+    let pods: Api<Pod> = Api::namespaced(client.clone(), &service.namespace);
+    let params = ListParams::default().labels(&startup::pod_selector(service).await?);
+    let mut count = 0.0;
+    for pod in pods.list(&params).await? {
+        // through the pod proxy of the API server, the sidecars may only accept local scrapes. The
+        // datapath doesn't count the connections of the API server to the port as activity
+        let path = format!(
+            "/api/v1/namespaces/{}/pods/{}:{}/proxy{}",
+            service.namespace,
+            pod.name_any(),
+            opts.mesh_metrics_port,
+            opts.mesh_metrics_path
+        );
+        let metrics = client
+            .request_text(http::Request::get(path).body(Vec::new())?)
+            .await?;
+        count += inbound_requests(&metrics, &opts.mesh_request_metric);
+    }
+    Ok(count)
+}
+
+// Sum of the series of a counter in the Prometheus text format. Istio reports each request on both
+// ends, the series reported by the client side are left out
+fn inbound_requests(metrics: &str, metric: &str) -> f64 {
+    metrics
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            // `name{labels} value [timestamp]` or `name value [timestamp]`
+            let (name, labels, rest) = match line.split_once('{') {
+                Some((name, rest)) => {
+                    let (labels, rest) = rest.rsplit_once('}')?;
+                    (name, labels, rest)
+                }
+                None => {
+                    let (name, rest) = line.split_once(' ')?;
+                    (name, "", rest)
+                }
+            };
+            if name != metric || labels.contains("reporter=\"source\"") {
+                return None;
+            }
+            rest.split_whitespace().next()?.parse::<f64>().ok()
+        })
+        .sum()
+}
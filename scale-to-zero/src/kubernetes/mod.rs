@@ -1,3 +1,119 @@
+pub mod canary;
+pub mod checkpoint;
+pub mod condition;
+pub mod consistency;
 pub mod controller;
+pub mod endpoints;
+pub mod enroll;
+pub mod events;
+pub mod eviction;
+pub mod explain;
+pub mod gitops;
+pub mod gossip;
+pub mod groups;
+pub mod hooks;
+pub mod hpa;
+pub mod ingress;
+pub mod kruise;
+pub mod lease;
+pub mod log_activity;
+pub mod maintenance;
+pub mod mesh;
 pub mod models;
+pub mod monitor;
+pub mod patch_template;
+pub mod placeholder;
+pub mod policies;
+pub mod polling;
+pub mod pressure;
+pub mod queue_depth;
+pub mod quota;
+pub mod resource_quota;
+pub mod retry;
 pub mod scaler;
+pub mod startup;
+pub mod statefulset;
+pub mod wake_group;
+pub mod wake_queue;
+pub mod wake_trace;
+pub mod wakes;
+pub mod warm_up;
+
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use kube::api::Api;
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::{Client, Config, Resource};
+use once_cell::sync::OnceCell;
+
+use crate::config;
+
+static CLIENT: OnceCell<Client> = OnceCell::new();
+
+// Shared kubernetes client, built from the options unless one was set with `set_client`
+pub async fn client() -> anyhow::Result<Client> {
+    if let Some(client) = CLIENT.get() {
+        return Ok(client.clone());
+    }
+    let client = Client::try_from(kube_config().await?)?;
+    Ok(CLIENT.get_or_init(|| client).clone())
+}
+
+// In-cluster config, or a kubeconfig when running outside of the cluster during development
+async fn kube_config() -> anyhow::Result<Config> {
+    let opts = config::get();
+    let options = KubeConfigOptions {
+        context: opts.context.clone(),
+        ..Default::default()
+    };
+    let mut kube_config = match opts.kubeconfig.as_ref() {
+        Some(path) => {
+            Config::from_custom_kubeconfig(Kubeconfig::read_from(path)?, &options).await?
+        }
+        None if opts.context.is_some() => Config::from_kubeconfig(&options).await?,
+        None => Config::infer().await?,
+    };
+
+    if let Some(user) = opts.impersonate.as_ref() {
+        kube_config.auth_info.impersonate = Some(user.clone());
+    }
+    if !opts.impersonate_groups.is_empty() {
+        kube_config.auth_info.impersonate_groups = Some(opts.impersonate_groups.clone());
+    }
+    Ok(kube_config)
+}
+
+// Namespaces watched by the controller, the default namespace of the client unless configured
+pub fn namespaces(client: &Client) -> Vec<String> {
+    let namespaces = &config::get().namespaces;
+    if namespaces.is_empty() {
+        vec![client.default_namespace().to_string()]
+    } else {
+        namespaces.clone()
+    }
+}
+
+// Owner reference to the service, so the objects created for it are garbage collected with it.
+// It isn't a controller reference, the service doesn't manage these objects
+pub async fn service_owner(
+    client: Client,
+    namespace: &str,
+    name: &str,
+) -> Result<Option<OwnerReference>, kube::Error> {
+    let services: Api<Service> = Api::namespaced(client, namespace);
+    let owner = services
+        .get_opt(name)
+        .await?
+        .and_then(|service| service.controller_owner_ref(&()))
+        .map(|owner| OwnerReference {
+            controller: None,
+            block_owner_deletion: None,
+            ..owner
+        });
+    Ok(owner)
+}
+
+#[cfg(all(test, feature = "e2e"))]
+pub fn set_client(client: Client) {
+    let _ = CLIENT.set(client);
+}
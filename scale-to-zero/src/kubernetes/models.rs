@@ -1,8 +1,148 @@
+use chrono_tz::Tz;
+use k8s_openapi::chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use k8s_openapi::serde_json;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
+use crate::config;
+use scale_to_zero_common::{
+    PROTOCOL_ICMP, PROTOCOL_OTHER, PROTOCOL_TCP, PROTOCOL_UDP, SERVICE_POLICY_PORT_RANGES,
+};
+
+pub const DEFAULT_ANNOTATION_PREFIX: &str = "scale-to-zero.isala.me";
+
+// Annotation names, the domain in front of them is configured with --annotation-prefix
+pub const REFERENCE_ANNOTATION: &str = "reference";
+pub const SCALE_DOWN_TIME_ANNOTATION: &str = "scale-down-time";
+pub const LATENCY_CRITICAL_ANNOTATION: &str = "latency-critical";
+// Priority class of the service (low, normal or high), low ones go first under cluster pressure
+pub const PRIORITY_ANNOTATION: &str = "priority";
+// CronJob whose job template runs before the workload is scaled up, as `<cronjob>[,<timeout>]`
+pub const PRE_WAKE_HOOK_ANNOTATION: &str = "pre-wake-hook";
+// Same for a Job run after the workload was scaled down
+pub const POST_SCALE_DOWN_HOOK_ANNOTATION: &str = "post-scale-down-hook";
+// Maximum number of wakes of the service in a window, as `<wakes>[/<window seconds>]`
+pub const WAKE_QUOTA_ANNOTATION: &str = "wake-quota";
+// What happens past the wake quota, keep-down (default) or keep-up
+pub const WAKE_QUOTA_POLICY_ANNOTATION: &str = "wake-quota-policy";
+// L4 protocols counting as activity of the service (tcp, udp, icmp, other), all of them by default
+pub const ACTIVITY_PROTOCOLS_ANNOTATION: &str = "activity-protocols";
+pub const ACTIVITY_PORTS_ANNOTATION: &str = "activity-ports";
+// Hold the gated UDP datagrams of the service and replay them after the wake
+pub const BUFFER_UDP_ANNOTATION: &str = "buffer-udp";
+// Gated packets needed within a window before the service is woken, as `<packets>[/<window seconds>]`
+pub const WAKE_THRESHOLD_ANNOTATION: &str = "wake-threshold";
+// Low priority class of a placeholder pod keeping the capacity of the workload while it is down
+pub const PLACEHOLDER_PRIORITY_CLASS_ANNOTATION: &str = "placeholder-priority-class";
+// TLS server names (comma separated, `*.` wildcards allowed) of the service on the SNI entrypoints
+pub const SERVER_NAMES_ANNOTATION: &str = "server-names";
+// HorizontalPodAutoscaler of the workload, which chooses the replicas while the service is awake
+pub const HPA_ANNOTATION: &str = "hpa";
+// CEL expression deciding whether the service may be scaled down once it is idle
+pub const SCALE_DOWN_CONDITION_ANNOTATION: &str = "scale-down-condition";
+// Objects following the service to sleep and back, e.g. `cronjob/nightly-report`
+pub const WAKE_GROUP_ANNOTATION: &str = "wake-group";
+// Queue consumed by the service, checked before it is scaled down: a URL returning the number of
+// pending messages, or a PromQL query run against --prometheus-url
+pub const QUEUE_DEPTH_URL_ANNOTATION: &str = "queue-depth-url";
+pub const QUEUE_DEPTH_QUERY_ANNOTATION: &str = "queue-depth-query";
+// Pending messages up to which the service may still be scaled down, 0 by default
+pub const QUEUE_DEPTH_THRESHOLD_ANNOTATION: &str = "queue-depth-threshold";
+// JSON merge patches replacing the replica change when the service is scaled down (sleep-patch)
+// and woken (wake-patch), e.g. `{"spec":{"paused":true}}`. `{{replicas}}` is replaced by the
+// replicas of the change
+pub const SLEEP_PATCH_ANNOTATION: &str = "sleep-patch";
+pub const WAKE_PATCH_ANNOTATION: &str = "wake-patch";
+// Object in the namespace of the service the patches apply to, as `<apiVersion>/<Kind>/<name>`
+// (e.g. `example.com/v1/Database/orders`), the workload by default
+pub const PATCH_TARGET_ANNOTATION: &str = "patch-target";
+// On a patch-target other than the workload: the services allowed to patch it, comma separated
+pub const PATCHABLE_BY_ANNOTATION: &str = "patchable-by";
+// Distinct gated clients a replica is woken up for, as `<clients>[/<max replicas>]`
+pub const CLIENTS_PER_REPLICA_ANNOTATION: &str = "clients-per-replica";
+// Checkpoint the containers of the workload before it is scaled down (experimental)
+pub const CHECKPOINT_ANNOTATION: &str = "checkpoint";
+// Log lines of the backend pods within a window counting as activity, as `<lines>[/<window seconds>]`
+pub const LOG_ACTIVITY_ANNOTATION: &str = "log-activity";
+// Sources whose activity keeps the service up (packets, logs, mesh), comma separated. Packets, and
+// logs with log-activity, by default
+pub const ACTIVITY_SOURCES_ANNOTATION: &str = "activity-sources";
+// What happens to the packets of the service while it is scaled down: drop (default), pass
+// (observe-only) or redirect[=<ip>[:<port>]] to a placeholder for TCP and UDP, the built-in waking
+// page without an address
+pub const GATED_ACTION_ANNOTATION: &str = "gated-action";
+// Windows during which the service isn't scaled down, as `<days> <HH:MM>-<HH:MM>` separated by `;`
+// e.g. `Mon-Fri 08:00-18:00`
+pub const ACTIVE_HOURS_ANNOTATION: &str = "active-hours";
+// Time zone of the active hours, UTC by default
+pub const ACTIVE_HOURS_TIMEZONE_ANNOTATION: &str = "active-hours-timezone";
+// Scale-down-time within the active hours, instead of not scaling the service down at all
+pub const ACTIVE_HOURS_SCALE_DOWN_TIME_ANNOTATION: &str = "active-hours-scale-down-time";
+// What the agent does with the service: enforce (default) scales it down and gates it, monitor
+// only tracks its activity and reports what enforcing would do
+pub const MODE_ANNOTATION: &str = "mode";
+// On a workload, its replicas while awake, written with --gitops
+pub const AWAKE_REPLICAS_ANNOTATION: &str = "awake-replicas";
+// On a Namespace, its services are scaled down once all of them were idle this long (seconds)
+pub const GROUP_SCALE_DOWN_TIME_ANNOTATION: &str = "group-scale-down-time";
+
+pub fn annotation_key(name: &str) -> String {
+    format!("{}/{}", config::get().annotation_prefix, name)
+}
+
+// Read an annotation under the configured prefix, falling back to the default one
+pub fn annotation<'a>(annotations: &'a BTreeMap<String, String>, name: &str) -> Option<&'a String> {
+    annotations
+        .get(&annotation_key(name))
+        .or_else(|| annotations.get(&format!("{}/{}", DEFAULT_ANNOTATION_PREFIX, name)))
+}
+
+// Seconds of a duration annotation, either a bare number of seconds or numbers with a unit (s, m,
+// h or d) such as 30s, 5m or 1h30m
+pub fn parse_duration(value: &str) -> anyhow::Result<i64> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u32>() {
+        return Ok(seconds as i64);
+    }
+    if value.is_empty() {
+        anyhow::bail!("Empty duration");
+    }
+    let mut seconds: i64 = 0;
+    let mut number = String::new();
+    for c in value.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => anyhow::bail!("Unknown unit '{}' in duration {}", c, value),
+        };
+        if number.is_empty() {
+            anyhow::bail!("Missing number before '{}' in duration {}", c, value);
+        }
+        seconds = number
+            .parse::<i64>()
+            .ok()
+            .and_then(|n| n.checked_mul(unit))
+            .and_then(|n| n.checked_add(seconds))
+            .ok_or_else(|| anyhow::anyhow!("Duration {} is too long", value))?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        anyhow::bail!("Missing unit after {} in duration {}", number, value);
+    }
+    Ok(seconds)
+}
+
 // This contains a mapper of service IPs to availablity of it's backends
 // If pods are available, the value is true, if not, false
 pub static WATCHED_SERVICES: Lazy<Arc<Mutex<HashMap<String, ServiceData>>>> =
@@ -12,6 +152,43 @@ pub static WATCHED_SERVICES: Lazy<Arc<Mutex<HashMap<String, ServiceData>>>> =
 pub static LAST_CALLED: Lazy<Mutex<HashMap<String, SystemTime>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
+// Number of wakes kept per service for the dashboard
+pub const RECENT_WAKES_LEN: usize = 10;
+
+// This contains the times (unix seconds) of the last wakes of each service IP
+pub static RECENT_WAKES: Lazy<Mutex<HashMap<String, VecDeque<i64>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Number of idle gaps kept per service to recommend a scale-down-time
+pub const IDLE_GAPS_LEN: usize = 256;
+
+// This contains the last pauses (seconds) between packets of each service IP
+pub static IDLE_GAPS: Lazy<Mutex<HashMap<String, VecDeque<u64>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// This contains a mapper of backend pod IPs to the (namespace, ClusterIP) of their watched service
+pub static POD_TO_SERVICE: Lazy<Mutex<HashMap<String, (String, String)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub type HostPort = (String, u16, u8);
+
+// This contains a mapper of the (node IP, port, IP protocol) of backend pods with a hostPort or on
+// the host network to the (namespace, ClusterIP) of their watched service. Entries outlive the pods,
+// so a client of the node a pod last ran on wakes the service
+pub static HOST_PORTS: Lazy<Mutex<HashMap<HostPort, (String, String)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// This contains the group scale-down-time of the watched namespaces annotated as a group
+pub static NAMESPACE_GROUPS: Lazy<Mutex<HashMap<String, i64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Services observed by the learning mode at most, the capacity of the OBSERVED_SERVICES eBPF map
+pub const OBSERVED_SERVICES_MAX: usize = 4096;
+
+// This contains a mapper of ClusterIPs of services that are not annotated, used by the learning mode
+pub static OBSERVED_SERVICES: Lazy<Arc<Mutex<HashMap<String, ObservedService>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
 #[derive(Eq, Hash, PartialEq)]
 pub struct WorkloadReference {
     pub kind: String,
@@ -19,12 +196,747 @@ pub struct WorkloadReference {
     pub namespace: String,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct ServiceData {
     pub scale_down_time: i64,
     pub last_packet_time: i64,
     pub kind: String,
     pub name: String,
     pub namespace: String,
+    // Name of the annotated service, events about the service are attached to it
+    pub service_name: String,
     pub backend_available: bool,
+    // Tracked and reported, but never gated nor scaled
+    pub monitor: bool,
+    // Reason the workload must not be scaled down (paused, mid-rollout, foreign owner), if any
+    pub unmanageable: Option<String>,
+    // Latency-critical service, gated packets are held by the agent and reinjected after the wake
+    pub hold_connections: bool,
+    // Only the UDP datagrams are held, for services where TCP clients retransmit anyway
+    pub buffer_udp: bool,
+    pub priority: Priority,
+    pub gated_action: GatedAction,
+    // PROTOCOL_* bits of the protocols that are activity of the service
+    pub activity_protocols: u32,
+    // Destination port ranges of the TCP and UDP packets that are activity, all ports when empty
+    pub activity_ports: Vec<(u16, u16)>,
+    pub pre_wake_hook: Option<Hook>,
+    pub post_scale_down_hook: Option<Hook>,
+    pub wake_quota: Option<WakeQuota>,
+    pub wake_threshold: WakeThreshold,
+    pub scale_down_condition: Option<String>,
+    pub queue_depth: Option<QueueDepth>,
+    pub wake_group: Vec<WakeGroupMember>,
+    pub patch_templates: Option<PatchTemplates>,
+    pub clients_per_replica: Option<ClientsPerReplica>,
+    pub checkpoint: bool,
+    pub log_activity: Option<LogActivity>,
+    pub activity_sources: Vec<ActivityKind>,
+    pub placeholder_priority_class: Option<String>,
+    pub hpa: Option<String>,
+    pub server_names: Vec<String>,
+    // Last run of each hook, by phase
+    pub hook_status: BTreeMap<String, HookStatus>,
+    // Why the pods of the workload are not starting after the last wake, if they are stuck
+    pub wake_failure: Option<String>,
+    pub active_hours: Option<ActiveHours>,
+    // ScaleToZeroPolicies and ClusterScaleToZeroPolicies selecting the service
+    pub policies: Vec<String>,
+    // Addresses announced for the service by a bare-metal load balancer, gated like its ClusterIP
+    pub load_balancer_ips: Vec<Ipv4Addr>,
+    // IPv6 ClusterIPs and load balancer addresses of a dual-stack service
+    pub ipv6_addresses: Vec<Ipv6Addr>,
+    // Plain HTTP port of the service, the only one redirected to the waking page
+    pub http_port: Option<u16>,
+}
+
+// PROTOCOL_* bits of a comma separated list of protocols
+pub fn activity_protocols(activity_protocols: &str) -> anyhow::Result<u32> {
+    let mut protocols = 0;
+    for protocol in activity_protocols.split(',').map(str::trim) {
+        protocols |= match protocol {
+            "tcp" => PROTOCOL_TCP,
+            "udp" => PROTOCOL_UDP,
+            "icmp" => PROTOCOL_ICMP,
+            "other" => PROTOCOL_OTHER,
+            _ => anyhow::bail!("Unknown protocol: {}", protocol),
+        };
+    }
+    Ok(protocols)
+}
+
+// Port ranges of a comma separated list of ports and ranges, e.g. 80,8000-8100
+pub fn activity_ports(activity_ports: &str) -> anyhow::Result<Vec<(u16, u16)>> {
+    let mut ranges = Vec::new();
+    for range in activity_ports.split(',').map(str::trim) {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let (start, end) = (start.trim().parse::<u16>()?, end.trim().parse::<u16>()?);
+        if start > end {
+            anyhow::bail!("Port range {} ends before it starts", range);
+        }
+        ranges.push((start, end));
+    }
+    if ranges.len() > SERVICE_POLICY_PORT_RANGES {
+        anyhow::bail!(
+            "{} port ranges given, the datapath takes at most {}",
+            ranges.len(),
+            SERVICE_POLICY_PORT_RANGES
+        );
+    }
+    Ok(ranges)
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct QueueDepth {
+    pub source: QueueDepthSource,
+    pub threshold: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueDepthSource {
+    Url(String),
+    Query(String),
+}
+
+impl QueueDepth {
+    pub fn parse(
+        url: Option<&str>,
+        query: Option<&str>,
+        threshold: Option<&str>,
+    ) -> anyhow::Result<Option<QueueDepth>> {
+        let source = match (url, query) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("Only one of queue-depth-url and queue-depth-query can be set")
+            }
+            (Some(url), None) => QueueDepthSource::Url(in_cluster_url(url.trim())?),
+            (None, Some(query)) => QueueDepthSource::Query(query.trim().to_string()),
+            (None, None) if threshold.is_some() => {
+                anyhow::bail!("queue-depth-threshold needs queue-depth-url or queue-depth-query")
+            }
+            (None, None) => return Ok(None),
+        };
+        if matches!(source, QueueDepthSource::Query(_)) && config::get().prometheus_url.is_none() {
+            anyhow::bail!("queue-depth-query needs --prometheus-url");
+        }
+        let threshold = match threshold {
+            Some(threshold) => threshold.trim().parse()?,
+            None => 0,
+        };
+        Ok(Some(QueueDepth { source, threshold }))
+    }
+}
+
+// A queue-depth-url is fetched by the agents from the node network, where anyone annotating a
+// service could otherwise reach the cloud metadata API or the node's own ports. Only the services
+// of the cluster are allowed, by their `<service>.<namespace>.svc` name
+fn in_cluster_url(url: &str) -> anyhow::Result<String> {
+    let parsed =
+        reqwest::Url::parse(url).map_err(|err| anyhow::anyhow!("Invalid URL {}: {}", url, err))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        anyhow::bail!("{} is not an HTTP URL", url);
+    }
+    let host = parsed.host_str().unwrap_or_default().trim_end_matches('.');
+    let name = host
+        .strip_suffix(".svc.cluster.local")
+        .or_else(|| host.strip_suffix(".svc"))
+        .unwrap_or_default();
+    match name.split_once('.') {
+        Some((service, namespace))
+            if !service.is_empty() && !namespace.is_empty() && !namespace.contains('.') =>
+        {
+            Ok(url.to_string())
+        }
+        _ => anyhow::bail!("{} has to name a service as <service>.<namespace>.svc", url),
+    }
+}
+
+// Patches applied to sleep and wake the service instead of changing the replicas of its workload,
+// each operation without one changes the replicas as usual
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct PatchTemplates {
+    pub target: Option<PatchTarget>,
+    pub sleep: Option<String>,
+    pub wake: Option<String>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct PatchTarget {
+    // group/version, or version for the core group
+    pub api_version: String,
+    pub kind: String,
+    pub name: String,
+}
+
+impl PatchTemplates {
+    pub fn parse(
+        target: Option<&str>,
+        sleep: Option<&str>,
+        wake: Option<&str>,
+    ) -> anyhow::Result<Option<PatchTemplates>> {
+        if sleep.is_none() && wake.is_none() {
+            if target.is_some() {
+                anyhow::bail!("patch-target needs sleep-patch or wake-patch");
+            }
+            return Ok(None);
+        }
+        for template in [sleep, wake].into_iter().flatten() {
+            render_patch(template, 1)?;
+        }
+        let target = target.map(PatchTarget::parse).transpose()?;
+        Ok(Some(PatchTemplates {
+            target,
+            sleep: sleep.map(|sleep| sleep.trim().to_string()),
+            wake: wake.map(|wake| wake.trim().to_string()),
+        }))
+    }
+
+    // The template of a change to `replicas`: sleep-patch for 0, wake-patch otherwise
+    pub fn template(&self, replicas: i32) -> Option<&str> {
+        if replicas == 0 {
+            self.sleep.as_deref()
+        } else {
+            self.wake.as_deref()
+        }
+    }
+}
+
+impl PatchTarget {
+    fn parse(target: &str) -> anyhow::Result<PatchTarget> {
+        let mut parts = target.trim().rsplitn(3, '/');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(kind), Some(api_version))
+                if !name.is_empty() && !kind.is_empty() && !api_version.is_empty() =>
+            {
+                Ok(PatchTarget {
+                    api_version: api_version.to_string(),
+                    kind: kind.to_string(),
+                    name: name.to_string(),
+                })
+            }
+            _ => anyhow::bail!(
+                "Invalid patch target {}, expected <apiVersion>/<Kind>/<name>",
+                target
+            ),
+        }
+    }
+}
+
+// A patch template with its replicas, it has to be a JSON object
+pub fn render_patch(template: &str, replicas: i32) -> anyhow::Result<serde_json::Value> {
+    let patch: serde_json::Value =
+        serde_json::from_str(&template.replace("{{replicas}}", &replicas.to_string()))?;
+    if !patch.is_object() {
+        anyhow::bail!("A patch has to be a JSON object, not {}", patch);
+    }
+    Ok(patch)
+}
+
+// Objects scaled down and woken with the service, comma separated as `<kind>/<name>` in its
+// namespace. CronJobs are suspended while the service is down
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WakeGroupMember {
+    CronJob(String),
+}
+
+impl fmt::Display for WakeGroupMember {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WakeGroupMember::CronJob(name) => write!(f, "CronJob {}", name),
+        }
+    }
+}
+
+pub fn wake_group(wake_group: &str) -> anyhow::Result<Vec<WakeGroupMember>> {
+    wake_group
+        .split(',')
+        .map(str::trim)
+        .filter(|member| !member.is_empty())
+        .map(|member| match member.split_once('/') {
+            Some(("cronjob", name)) if !name.is_empty() => {
+                Ok(WakeGroupMember::CronJob(name.to_string()))
+            }
+            _ => anyhow::bail!(
+                "Unknown wake group member {}, expected cronjob/<name>",
+                member
+            ),
+        })
+        .collect()
+}
+
+// Default window (seconds) of a wake quota
+pub const DEFAULT_WAKE_QUOTA_WINDOW: i64 = 3600;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct WakeQuota {
+    pub max_wakes: usize,
+    pub window: i64,
+    pub policy: WakeQuotaPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WakeQuotaPolicy {
+    // Traffic past the quota doesn't wake the service anymore
+    #[default]
+    KeepDown,
+    // The service is woken once more and then not scaled down until the window passes
+    KeepUp,
+}
+
+impl WakeQuota {
+    pub fn parse(value: &str, policy: Option<&str>) -> anyhow::Result<WakeQuota> {
+        let (max_wakes, window) = match value.split_once('/') {
+            Some((max_wakes, window)) => (max_wakes, parse_duration(window)?),
+            None => (value, DEFAULT_WAKE_QUOTA_WINDOW),
+        };
+        let policy = match policy {
+            None | Some("keep-down") => WakeQuotaPolicy::KeepDown,
+            Some("keep-up") => WakeQuotaPolicy::KeepUp,
+            Some(policy) => anyhow::bail!("Unknown wake quota policy: {}", policy),
+        };
+        Ok(WakeQuota {
+            max_wakes: max_wakes.trim().parse()?,
+            window,
+            policy,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ActiveHours {
+    pub windows: Vec<ActiveWindow>,
+    #[serde(serialize_with = "serialize_timezone")]
+    pub timezone: Tz,
+    // Scale-down-time within the windows, the service isn't scaled down then without one
+    pub scale_down_time: Option<i64>,
+}
+
+// A window ending before it starts ends on the next day, e.g. `Fri 22:00-06:00`
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct ActiveWindow {
+    // Days the window starts on
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+fn serialize_timezone<S: Serializer>(timezone: &Tz, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(timezone.name())
+}
+
+impl ActiveHours {
+    pub fn parse(
+        value: &str,
+        timezone: Option<&str>,
+        scale_down_time: Option<&str>,
+    ) -> anyhow::Result<ActiveHours> {
+        let windows = value
+            .split(';')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .map(ActiveWindow::parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if windows.is_empty() {
+            anyhow::bail!("No active hours in {}", value);
+        }
+        let timezone = match timezone {
+            Some(timezone) => timezone
+                .trim()
+                .parse::<Tz>()
+                .map_err(|err| anyhow::anyhow!("Unknown time zone {}: {}", timezone, err))?,
+            None => Tz::UTC,
+        };
+        Ok(ActiveHours {
+            windows,
+            timezone,
+            scale_down_time: scale_down_time.map(parse_duration).transpose()?,
+        })
+    }
+
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        let now = now.with_timezone(&self.timezone);
+        let (day, time) = (now.weekday(), now.time());
+        self.windows.iter().any(|window| {
+            if window.start < window.end {
+                window.days.contains(&day) && time >= window.start && time < window.end
+            } else {
+                (window.days.contains(&day) && time >= window.start)
+                    || (window.days.contains(&day.pred()) && time < window.end)
+            }
+        })
+    }
+}
+
+impl ActiveWindow {
+    fn parse(value: &str) -> anyhow::Result<ActiveWindow> {
+        let (days, hours) = value
+            .split_once(' ')
+            .ok_or_else(|| anyhow::anyhow!("Expected <days> <HH:MM>-<HH:MM>, got {}", value))?;
+        let (start, end) = hours
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("Expected <HH:MM>-<HH:MM>, got {}", hours))?;
+        Ok(ActiveWindow {
+            days: parse_days(days)?,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+}
+
+// Comma separated days or ranges of days, e.g. `Mon-Fri` or `Mon,Wed,Sat-Sun`
+fn parse_days(value: &str) -> anyhow::Result<Vec<Weekday>> {
+    let parse_day = |day: &str| {
+        day.trim()
+            .parse::<Weekday>()
+            .map_err(|_| anyhow::anyhow!("Unknown day: {}", day))
+    };
+    let mut days = Vec::new();
+    for term in value.split(',') {
+        match term.split_once('-') {
+            Some((first, last)) => {
+                let (mut day, last) = (parse_day(first)?, parse_day(last)?);
+                days.push(day);
+                while day != last {
+                    day = day.succ();
+                    days.push(day);
+                }
+            }
+            None => days.push(parse_day(term)?),
+        }
+    }
+    Ok(days)
+}
+
+// 24:00 ends a window at midnight
+fn parse_time(value: &str) -> anyhow::Result<NaiveTime> {
+    let value = value.trim();
+    if value == "24:00" {
+        return Ok(NaiveTime::MIN);
+    }
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .map_err(|err| anyhow::anyhow!("Invalid time {}: {}", value, err))
+}
+
+// Where the activity of a service was seen
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivityKind {
+    // Packets from the datapath
+    Packets,
+    // Log output of the backend pods
+    Logs,
+    // Requests counted by the sidecars of a service mesh
+    Mesh,
+}
+
+pub fn activity_sources(
+    value: Option<&str>,
+    log_activity: bool,
+) -> anyhow::Result<Vec<ActivityKind>> {
+    let value = match value {
+        Some(value) => value,
+        None if log_activity => return Ok(vec![ActivityKind::Packets, ActivityKind::Logs]),
+        None => return Ok(vec![ActivityKind::Packets]),
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .map(|source| match source {
+            "packets" => Ok(ActivityKind::Packets),
+            "logs" => Ok(ActivityKind::Logs),
+            "mesh" => Ok(ActivityKind::Mesh),
+            _ => anyhow::bail!("Unknown activity source: {}", source),
+        })
+        .collect()
+}
+
+// Default window (seconds) of the log activity of a service
+pub const DEFAULT_LOG_ACTIVITY_WINDOW: u64 = 60;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct LogActivity {
+    pub lines: usize,
+    pub window: u64,
+}
+
+impl LogActivity {
+    pub fn parse(value: &str) -> anyhow::Result<LogActivity> {
+        let (lines, window) = match value.split_once('/') {
+            Some((lines, window)) => (lines, window.trim().parse::<u64>()?),
+            None => (value, DEFAULT_LOG_ACTIVITY_WINDOW),
+        };
+        let lines = lines.trim().parse::<usize>()?;
+        if lines == 0 || window == 0 {
+            anyhow::bail!(
+                "Log activity {:?} must be at least one line in one second",
+                value
+            );
+        }
+        Ok(LogActivity { lines, window })
+    }
+}
+
+// Default cap of the replicas a wake sizes from its gated clients
+pub const DEFAULT_MAX_WAKE_REPLICAS: i32 = 10;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct ClientsPerReplica {
+    pub clients: usize,
+    pub max_replicas: i32,
+}
+
+impl ClientsPerReplica {
+    pub fn parse(value: &str) -> anyhow::Result<ClientsPerReplica> {
+        let (clients, max_replicas) = match value.split_once('/') {
+            Some((clients, max_replicas)) => (clients, max_replicas.trim().parse::<i32>()?),
+            None => (value, DEFAULT_MAX_WAKE_REPLICAS),
+        };
+        let clients = clients.trim().parse::<usize>()?;
+        if clients == 0 || max_replicas < 1 {
+            anyhow::bail!(
+                "Clients per replica {:?} must be at least one client and one replica",
+                value
+            );
+        }
+        Ok(ClientsPerReplica {
+            clients,
+            max_replicas,
+        })
+    }
+
+    // Replicas for the clients that were gated, never fewer than `replicas`
+    pub fn replicas(&self, gated_clients: usize, replicas: i32) -> i32 {
+        let needed = gated_clients
+            .div_ceil(self.clients)
+            .min(self.max_replicas as usize) as i32;
+        replicas.max(needed)
+    }
+}
+
+// Default window (seconds) of a wake threshold
+pub const DEFAULT_WAKE_THRESHOLD_WINDOW: u32 = 10;
+
+// A single packet wakes the service unless more are required, so stray packets (e.g. a scanner's
+// SYN) don't scale it up
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub struct WakeThreshold {
+    pub packets: u32,
+    pub window: u32,
+}
+
+impl Default for WakeThreshold {
+    fn default() -> Self {
+        WakeThreshold {
+            packets: 1,
+            window: DEFAULT_WAKE_THRESHOLD_WINDOW,
+        }
+    }
+}
+
+impl WakeThreshold {
+    pub fn parse(value: &str) -> anyhow::Result<WakeThreshold> {
+        let (packets, window) = match value.split_once('/') {
+            Some((packets, window)) => (packets, window.trim().parse::<u32>()?),
+            None => (value, DEFAULT_WAKE_THRESHOLD_WINDOW),
+        };
+        let packets = packets.trim().parse::<u32>()?;
+        if packets == 0 || window == 0 {
+            anyhow::bail!(
+                "Wake threshold {:?} must be at least one packet in one second",
+                value
+            );
+        }
+        Ok(WakeThreshold { packets, window })
+    }
+}
+
+// Default time (seconds) a hook Job gets to complete
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 300;
+
+// A Job created from the job template of a CronJob (usually suspended) at some point of the
+// lifecycle of a service
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Hook {
+    pub cronjob: String,
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase", tag = "state")]
+pub enum HookStatus {
+    Running,
+    Succeeded { job: String },
+    Failed { error: String },
+}
+
+impl Hook {
+    pub fn parse(value: &str) -> anyhow::Result<Hook> {
+        let (cronjob, timeout) = match value.split_once(',') {
+            Some((cronjob, timeout)) => (cronjob, timeout.trim().parse::<u64>()?),
+            None => (value, DEFAULT_HOOK_TIMEOUT),
+        };
+        if cronjob.trim().is_empty() {
+            anyhow::bail!("Hook {:?} has no CronJob", value);
+        }
+        Ok(Hook {
+            cronjob: cronjob.trim().to_string(),
+            timeout,
+        })
+    }
+}
+
+// How early a service is scaled down while the cluster is under pressure, and how early it wakes
+// when many services wake at once
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl std::str::FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(anyhow::anyhow!("Unknown priority: {}", s)),
+        }
+    }
+}
+
+// Enforcement of the gate of a scaled down service
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GatedAction {
+    #[default]
+    Drop,
+    Pass,
+    // The port is the one connected to when not set, the address the built-in waking page
+    Redirect {
+        address: Option<Ipv4Addr>,
+        port: Option<u16>,
+    },
+}
+
+impl std::str::FromStr for GatedAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "drop" => return Ok(GatedAction::Drop),
+            "pass" => return Ok(GatedAction::Pass),
+            "redirect" => {
+                return Ok(GatedAction::Redirect {
+                    address: None,
+                    port: None,
+                })
+            }
+            _ => {}
+        }
+        let target = s
+            .trim()
+            .strip_prefix("redirect=")
+            .ok_or_else(|| anyhow::anyhow!("Unknown gated action: {}", s))?;
+        let (address, port) = match target.split_once(':') {
+            Some((address, port)) => (address, Some(port.parse::<u16>()?)),
+            None => (target, None),
+        };
+        Ok(GatedAction::Redirect {
+            address: Some(address.parse()?),
+            port,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ObservedService {
+    pub name: String,
+    pub namespace: String,
+    // Last time a packet was seen, in nanoseconds since boot (0 if never seen)
+    pub last_seen_ns: u64,
+    pub longest_idle: u64,
+    // Recent gaps (seconds) between observed packets
+    pub gaps: VecDeque<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        let valid = [
+            ("300", 300),
+            ("5m", 300),
+            ("1h30m", 5400),
+            ("2d", 172800),
+            (" 45s ", 45),
+        ];
+        for (value, seconds) in valid {
+            assert_eq!(parse_duration(value).unwrap(), seconds, "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_invalid_durations() {
+        let invalid = [
+            "",
+            "5x",
+            "m",
+            "1h30",
+            "-5m",
+            // overflows of the number, of its unit and of the sum
+            "9223372036854775808s",
+            "200000000000000d",
+            "9223372036854775807s1s",
+        ];
+        for value in invalid {
+            assert!(parse_duration(value).is_err(), "{}", value);
+        }
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn overnight_window() {
+        let hours = ActiveHours::parse("Fri 22:00-06:00", None, None).unwrap();
+        // 2024-03-01 is a Friday
+        assert!(hours.is_active(at("2024-03-01T23:00:00Z")));
+        assert!(hours.is_active(at("2024-03-02T05:59:00Z")));
+        assert!(!hours.is_active(at("2024-03-01T21:59:00Z")));
+        assert!(!hours.is_active(at("2024-03-02T06:00:00Z")));
+        // the nights after Thursday and Saturday aren't listed
+        assert!(!hours.is_active(at("2024-02-29T23:00:00Z")));
+        assert!(!hours.is_active(at("2024-03-03T05:00:00Z")));
+    }
+
+    #[test]
+    fn window_across_a_dst_change() {
+        // Berlin moves from UTC+1 to UTC+2 on 2024-03-31
+        let hours = ActiveHours::parse("Mon-Sun 08:00-18:00", Some("Europe/Berlin"), None).unwrap();
+        assert!(!hours.is_active(at("2024-03-30T06:30:00Z")));
+        assert!(hours.is_active(at("2024-03-30T07:30:00Z")));
+        assert!(hours.is_active(at("2024-03-30T16:30:00Z")));
+        assert!(hours.is_active(at("2024-03-31T06:30:00Z")));
+        assert!(!hours.is_active(at("2024-03-31T05:30:00Z")));
+        assert!(!hours.is_active(at("2024-03-31T16:30:00Z")));
+    }
+
+    #[test]
+    fn invalid_time_zone() {
+        assert!(ActiveHours::parse("Mon 08:00-18:00", Some("Mars/Olympus_Mons"), None).is_err());
+        assert!(ActiveHours::parse("Mon 08:00-18:00", Some("Europe/Berlin"), None).is_ok());
+    }
 }
@@ -0,0 +1,44 @@
+use log::info;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::explain::ScaleDownDecision;
+use super::models::ServiceData;
+use crate::metrics;
+
+// This contains the monitored services that would be scaled down by now and since when (unix
+// seconds), by service IP
+static WOULD_BE_DOWN: Lazy<Mutex<HashMap<String, i64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Forget the services no longer watched, returns the number of services left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    let mut would_be_down = WOULD_BE_DOWN.lock().unwrap();
+    would_be_down.retain(|ip, _| watched.contains(ip));
+    would_be_down.len()
+}
+
+// A monitored service is evaluated like any other but never scaled down. The scale-downs it would
+// have had, and the wakes that would have followed once its traffic came back, are logged and
+// counted instead
+pub fn observe(service_ip: &str, service: &ServiceData, decision: &mut ScaleDownDecision) {
+    let labels = [service.namespace.as_str(), service.service_name.as_str()];
+    let mut would_be_down = WOULD_BE_DOWN.lock().unwrap();
+    if decision.scale_down {
+        if !would_be_down.contains_key(service_ip) {
+            would_be_down.insert(service_ip.to_string(), decision.evaluated_at);
+            info!(target: "monitor", "{} {} would be scaled down: {}", service.kind, service.name, decision.reason);
+            metrics::MONITOR_SCALE_DOWNS
+                .with_label_values(&labels)
+                .inc();
+        }
+        decision.scale_down = false;
+        decision.reason = format!("Monitored, would be scaled down: {}", decision.reason);
+    } else if decision.idle_seconds <= decision.effective_scale_down_time {
+        // traffic came back
+        if let Some(since) = would_be_down.remove(service_ip) {
+            info!(target: "monitor", "{} {} would be woken after {}s down", service.kind, service.name, decision.evaluated_at - since);
+            metrics::MONITOR_WAKES.with_label_values(&labels).inc();
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use kube::api::{Api, DynamicObject, Patch, PatchParams};
+use kube::core::GroupVersionKind;
+use kube::{discovery, ResourceExt};
+use log::info;
+
+use super::kruise;
+use super::models::{annotation, render_patch, ServiceData, PATCHABLE_BY_ANNOTATION};
+use super::scaler::FIELD_MANAGER;
+
+// Scale the service to `replicas` with its patch template through the dynamic client, for the
+// operators that need more than replicas to change (paused or suspended fields of a custom
+// resource). Returns false when the service has no template for this change
+pub async fn apply(service: &ServiceData, replicas: i32) -> anyhow::Result<bool> {
+    let templates = match service.patch_templates.as_ref() {
+        Some(templates) => templates,
+        None => return Ok(false),
+    };
+    let template = match templates.template(replicas) {
+        Some(template) => template,
+        None => return Ok(false),
+    };
+    let (gvk, name) = match templates.target.as_ref() {
+        Some(target) => {
+            let (group, version) = target
+                .api_version
+                .rsplit_once('/')
+                .unwrap_or(("", target.api_version.as_str()));
+            (
+                GroupVersionKind::gvk(group, version, &target.kind),
+                target.name.clone(),
+            )
+        }
+        None => (workload_gvk(&service.kind)?, service.name.clone()),
+    };
+
+    let client = super::client().await?;
+    let (resource, _) = discovery::pinned_kind(&client, &gvk)
+        .await
+        .map_err(|err| anyhow::anyhow!("{} is not served by the cluster: {}", gvk.kind, err))?;
+    let api: Api<DynamicObject> = Api::namespaced_with(client, &service.namespace, &resource);
+    let mut patch = render_patch(template, replicas)?;
+    // anyone who can annotate a service could otherwise rewrite any object of its namespace with
+    // the permissions of the agent. An object other than the workload opts in, and the resource
+    // version fails the patch if the object changed since it was checked
+    let workload = gvk == workload_gvk(&service.kind)? && name == service.name;
+    if !workload {
+        let object = api.get(&name).await?;
+        let allowed =
+            annotation(object.annotations(), PATCHABLE_BY_ANNOTATION).is_some_and(|services| {
+                services
+                    .split(',')
+                    .any(|allowed| allowed.trim() == service.service_name)
+            });
+        if !allowed {
+            anyhow::bail!(
+                "{} {} doesn't allow patches from service {}, it needs the {} annotation",
+                gvk.kind,
+                name,
+                service.service_name,
+                PATCHABLE_BY_ANNOTATION
+            );
+        }
+        patch["metadata"]["resourceVersion"] = object.resource_version().into();
+    }
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    };
+    api.patch(&name, &params, &Patch::Merge(patch)).await?;
+    info!(target: "scaler", "Patched {} {} for {} replicas of {} {}", gvk.kind, name, replicas, service.kind, service.name);
+    Ok(true)
+}
+
+fn workload_gvk(kind: &str) -> anyhow::Result<GroupVersionKind> {
+    match kind {
+        "deployment" => Ok(GroupVersionKind::gvk("apps", "v1", "Deployment")),
+        "statefulset" => Ok(GroupVersionKind::gvk("apps", "v1", "StatefulSet")),
+        kind => kruise::gvk(kind).ok_or_else(|| anyhow::anyhow!("Unknown workload type: {}", kind)),
+    }
+}
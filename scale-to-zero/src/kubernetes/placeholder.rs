@@ -0,0 +1,155 @@
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use k8s_openapi::api::core::v1::{Container, Pod, PodSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
+use k8s_openapi::serde_json;
+use kube::api::{Api, DeleteParams, PostParams};
+use kube::{Resource, ResourceExt};
+use log::{info, warn};
+use std::collections::BTreeMap;
+
+use super::kruise;
+use super::models::{annotation_key, ServiceData};
+
+// Label of the placeholder pods
+const PLACEHOLDER_LABEL: &str = "placeholder";
+const PAUSE_IMAGE: &str = "registry.k8s.io/pause:3.9";
+
+fn name(service: &ServiceData) -> String {
+    format!(
+        "scale-to-zero-placeholder-{}-{}",
+        service.kind, service.name
+    )
+}
+
+// Keep the capacity of a scaled down workload with a pod of the same size at a low priority class.
+// If its node is removed, the pending placeholder makes the cluster autoscaler provision another
+// one before the next wake instead of during it. Every agent scaling down tries, the first wins.
+// The pods of the workload preempt it when they need its capacity, and it goes with the workload
+// when that is deleted
+pub async fn create(service: &ServiceData, priority_class: &str) {
+    if let Err(err) = try_create(service, priority_class).await {
+        warn!(target: "placeholder", "Failed to create the placeholder of {} {}: {:#}", service.kind, service.name, err);
+    }
+}
+
+async fn try_create(service: &ServiceData, priority_class: &str) -> anyhow::Result<()> {
+    let (owner, template) = workload(service).await?;
+    // one pause container per container of the workload, with the same resources
+    let containers = template
+        .containers
+        .iter()
+        .map(|container| Container {
+            name: container.name.clone(),
+            image: Some(PAUSE_IMAGE.to_string()),
+            resources: container.resources.clone(),
+            ..Default::default()
+        })
+        .collect();
+    let pod = Pod {
+        metadata: ObjectMeta {
+            name: Some(name(service)),
+            labels: Some(BTreeMap::from([(
+                annotation_key(PLACEHOLDER_LABEL),
+                service.service_name.clone(),
+            )])),
+            owner_references: Some(vec![owner]),
+            ..Default::default()
+        },
+        spec: Some(PodSpec {
+            containers,
+            priority_class_name: Some(priority_class.to_string()),
+            node_selector: template.node_selector,
+            affinity: template.affinity,
+            tolerations: template.tolerations,
+            termination_grace_period_seconds: Some(0),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+
+    let pods: Api<Pod> = Api::namespaced(super::client().await?, &service.namespace);
+    match pods.create(&PostParams::default(), &pod).await {
+        Ok(_) => {
+            info!(target: "placeholder", "Created placeholder of {} {}", service.kind, service.name);
+            Ok(())
+        }
+        Err(kube::Error::Api(err)) if err.code == 409 => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Remove the placeholder once the workload is ready, when its pods no longer need the capacity
+pub async fn delete(service: &ServiceData) {
+    if let Err(err) = try_delete(service).await {
+        warn!(target: "placeholder", "Failed to delete the placeholder of {} {}: {:#}", service.kind, service.name, err);
+    }
+}
+
+async fn try_delete(service: &ServiceData) -> anyhow::Result<()> {
+    let pods: Api<Pod> = Api::namespaced(super::client().await?, &service.namespace);
+    match pods.delete(&name(service), &DeleteParams::default()).await {
+        Ok(_) => {
+            info!(target: "placeholder", "Deleted placeholder of {} {}", service.kind, service.name);
+            Ok(())
+        }
+        Err(kube::Error::Api(err)) if err.code == 404 => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Reference to the workload, owning the placeholder, and the pod spec of its template
+async fn workload(service: &ServiceData) -> anyhow::Result<(OwnerReference, PodSpec)> {
+    let client = super::client().await?;
+    let (owner, spec) = match service.kind.as_str() {
+        "deployment" => {
+            let deployments: Api<Deployment> = Api::namespaced(client, &service.namespace);
+            let deployment = deployments.get(&service.name).await?;
+            let owner = deployment.controller_owner_ref(&()).map(not_controller);
+            let spec = deployment.spec.and_then(|spec| spec.template.spec);
+            (owner, spec)
+        }
+        "statefulset" => {
+            let statefulsets: Api<StatefulSet> = Api::namespaced(client, &service.namespace);
+            let statefulset = statefulsets.get(&service.name).await?;
+            let owner = statefulset.controller_owner_ref(&()).map(not_controller);
+            let spec = statefulset.spec.and_then(|spec| spec.template.spec);
+            (owner, spec)
+        }
+        kind if kruise::is_kruise(kind) => {
+            let api = kruise::api(client, &service.namespace, kind).await?;
+            let workload = api.get(&service.name).await?;
+            let owner = workload.types.as_ref().map(|types| OwnerReference {
+                api_version: types.api_version.clone(),
+                kind: types.kind.clone(),
+                name: workload.name_any(),
+                uid: workload.uid().unwrap_or_default(),
+                ..Default::default()
+            });
+            let spec = match workload.data.pointer("/spec/template/spec") {
+                Some(spec) => Some(serde_json::from_value(spec.clone())?),
+                None => None,
+            };
+            (owner, spec)
+        }
+        _ => anyhow::bail!("Unknown workload type: {}", service.kind),
+    };
+    let owner = owner.ok_or_else(|| {
+        anyhow::anyhow!(
+            "{} {} has no uid to own the placeholder",
+            service.kind,
+            service.name
+        )
+    })?;
+    let spec = spec
+        .ok_or_else(|| anyhow::anyhow!("{} {} has no pod template", service.kind, service.name))?;
+    Ok((owner, spec))
+}
+
+// The placeholder isn't a pod the workload manages, its controller would adopt or fight over it
+fn not_controller(owner: OwnerReference) -> OwnerReference {
+    OwnerReference {
+        controller: None,
+        block_owner_deletion: None,
+        ..owner
+    }
+}
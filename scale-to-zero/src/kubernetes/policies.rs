@@ -0,0 +1,271 @@
+use futures::{Stream, StreamExt};
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
+use kube::api::ListParams;
+use kube::runtime::reflector::{ObjectRef, Store};
+use kube::runtime::{reflector, watcher, WatchStreamExt};
+use kube::{Api, Client, CustomResource, ResourceExt};
+use log::{info, warn};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use super::models::{annotation, annotation_key, REFERENCE_ANNOTATION, WATCHED_SERVICES};
+
+// Backoff of the first list of the policies, doubled on every failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// Settings of the annotated services selected by the policy in its namespace, as annotation names
+// (without their domain) and values. The annotations of a service override them
+#[derive(CustomResource, Debug, Clone, Default, Deserialize, Serialize)]
+#[kube(
+    group = "scale-to-zero.isala.me",
+    version = "v1alpha1",
+    kind = "ScaleToZeroPolicy",
+    namespaced,
+    schema = "disabled",
+    crates(serde_json = "k8s_openapi::serde_json")
+)]
+pub struct ScaleToZeroPolicySpec {
+    #[serde(default)]
+    pub selector: LabelSelector,
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+}
+
+// Same for the services of every namespace, or of the listed ones. Namespaced policies override it
+#[derive(CustomResource, Debug, Clone, Default, Deserialize, Serialize)]
+#[kube(
+    group = "scale-to-zero.isala.me",
+    version = "v1alpha1",
+    kind = "ClusterScaleToZeroPolicy",
+    schema = "disabled",
+    crates(serde_json = "k8s_openapi::serde_json")
+)]
+pub struct ClusterScaleToZeroPolicySpec {
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    #[serde(default)]
+    pub selector: LabelSelector,
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+}
+
+static POLICIES: Lazy<Mutex<HashMap<String, Store<ScaleToZeroPolicy>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static CLUSTER_POLICIES: OnceCell<Store<ClusterScaleToZeroPolicy>> = OnceCell::new();
+
+// A policy was added, changed or deleted
+#[derive(Debug, Clone)]
+struct PolicyChange {
+    name: String,
+    // every namespace when empty
+    namespaces: Vec<String>,
+    selector: LabelSelector,
+}
+
+// Sent to the controller of every namespace to reconcile the services a policy change affects
+static CHANGES: Lazy<broadcast::Sender<PolicyChange>> = Lazy::new(|| broadcast::channel(64).0);
+
+// Keep the policies of a watched namespace, a change reconciles the services it affects again
+pub async fn track_policies(client: Client, namespace: String) {
+    let api: Api<ScaleToZeroPolicy> = Api::namespaced(client, &namespace);
+    let mut backoff = INITIAL_BACKOFF;
+    while let Err(err) = api.list(&ListParams::default().limit(1)).await {
+        warn!(target: "policies", "Failed to list the policies of namespace {}, retrying in {}s: {}", namespace, backoff.as_secs(), err);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    let (reader, writer) = reflector::store();
+    POLICIES.lock().unwrap().insert(namespace.clone(), reader);
+    reflector(writer, watcher(api, watcher::Config::default()))
+        .default_backoff()
+        .for_each(|event| {
+            let namespace = namespace.clone();
+            async move {
+                publish(event, |policy: &ScaleToZeroPolicy| PolicyChange {
+                    name: policy.name_any(),
+                    namespaces: vec![namespace.clone()],
+                    selector: policy.spec.selector.clone(),
+                })
+            }
+        })
+        .await;
+}
+
+// Cluster policies need a cluster-wide list and watch, without them only namespaced policies apply
+pub async fn track_cluster_policies(client: Client) {
+    let api: Api<ClusterScaleToZeroPolicy> = Api::all(client);
+    let mut backoff = INITIAL_BACKOFF;
+    while let Err(err) = api.list(&ListParams::default().limit(1)).await {
+        warn!(target: "policies", "Failed to list the cluster policies, retrying in {}s: {}", backoff.as_secs(), err);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    info!(target: "policies", "Watching cluster policies");
+    let (reader, writer) = reflector::store();
+    let _ = CLUSTER_POLICIES.set(reader);
+    reflector(writer, watcher(api, watcher::Config::default()))
+        .default_backoff()
+        .for_each(|event| async move {
+            publish(event, |policy: &ClusterScaleToZeroPolicy| PolicyChange {
+                name: policy.name_any(),
+                namespaces: policy.spec.namespaces.clone(),
+                selector: policy.spec.selector.clone(),
+            })
+        })
+        .await;
+}
+
+fn publish<K>(
+    event: Result<watcher::Event<K>, watcher::Error>,
+    change: impl Fn(&K) -> PolicyChange,
+) {
+    let policies = match event {
+        Ok(watcher::Event::Applied(policy)) | Ok(watcher::Event::Deleted(policy)) => vec![policy],
+        Ok(watcher::Event::Restarted(policies)) => policies,
+        Err(err) => {
+            warn!(target: "policies", "Failed to watch policies: {}", err);
+            return;
+        }
+    };
+    for policy in policies.iter() {
+        let _ = CHANGES.send(change(policy));
+    }
+}
+
+// Trigger of Controller::reconcile_on: the services of the namespace a policy selects, and the ones
+// it selected before its change. Every service of the namespace when changes were missed
+pub fn requeue_requests(
+    store: Store<Service>,
+    namespace: String,
+) -> impl Stream<Item = ObjectRef<Service>> {
+    futures::stream::unfold(CHANGES.subscribe(), |mut changes| async move {
+        match changes.recv().await {
+            Ok(change) => Some((Some(change), changes)),
+            Err(broadcast::error::RecvError::Lagged(_)) => Some((None, changes)),
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    })
+    .flat_map(move |change| futures::stream::iter(affected(&store, &namespace, change.as_ref())))
+}
+
+fn affected(
+    store: &Store<Service>,
+    namespace: &str,
+    change: Option<&PolicyChange>,
+) -> Vec<ObjectRef<Service>> {
+    if let Some(change) = change {
+        if !change.namespaces.is_empty() && !change.namespaces.iter().any(|ns| ns == namespace) {
+            return Vec::new();
+        }
+    }
+    let selected_before: HashSet<String> = WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|service| service.namespace == namespace)
+        .filter(|service| change.is_none_or(|change| service.policies.contains(&change.name)))
+        .map(|service| service.service_name.clone())
+        .collect();
+    store
+        .state()
+        .iter()
+        .filter(|s| {
+            change.is_none_or(|change| {
+                selects(&change.selector, s.labels()) || selected_before.contains(&s.name_any())
+            })
+        })
+        .map(|s| ObjectRef::from_obj(s.as_ref()))
+        .collect()
+}
+
+// Names of the policies selecting a service and the settings they give it. Namespaced policies come
+// first, and policies of the same scope in name order
+pub fn matching(
+    namespace: &str,
+    labels: &BTreeMap<String, String>,
+) -> (Vec<String>, BTreeMap<String, String>) {
+    let mut namespaced: Vec<(String, BTreeMap<String, String>)> = POLICIES
+        .lock()
+        .unwrap()
+        .get(namespace)
+        .map(|store| store.state())
+        .unwrap_or_default()
+        .iter()
+        .filter(|policy| selects(&policy.spec.selector, labels))
+        .map(|policy| (policy.name_any(), policy.spec.settings.clone()))
+        .collect();
+    namespaced.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut cluster: Vec<(String, BTreeMap<String, String>)> = CLUSTER_POLICIES
+        .get()
+        .map(|store| store.state())
+        .unwrap_or_default()
+        .iter()
+        .filter(|policy| {
+            policy.spec.namespaces.is_empty()
+                || policy.spec.namespaces.iter().any(|ns| ns == namespace)
+        })
+        .filter(|policy| selects(&policy.spec.selector, labels))
+        .map(|policy| (policy.name_any(), policy.spec.settings.clone()))
+        .collect();
+    cluster.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut names = Vec::new();
+    let mut merged = BTreeMap::new();
+    for (name, settings) in namespaced.into_iter().chain(cluster) {
+        for (setting, value) in settings {
+            // the workload is specific to each service
+            if setting != REFERENCE_ANNOTATION {
+                merged.entry(setting).or_insert(value);
+            }
+        }
+        names.push(name);
+    }
+    (names, merged)
+}
+
+// The service with the settings of its policies added to the annotations it doesn't have itself
+pub fn apply(s: &Service) -> Service {
+    let (_, settings) = matching(&s.namespace().unwrap_or_default(), s.labels());
+    let mut s = s.clone();
+    if settings.is_empty() {
+        return s;
+    }
+    let annotations = s.metadata.annotations.get_or_insert_with(BTreeMap::new);
+    for (name, value) in settings {
+        if annotation(annotations, &name).is_none() {
+            annotations.insert(annotation_key(&name), value);
+        }
+    }
+    s
+}
+
+fn selects(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool {
+    let match_labels = selector
+        .match_labels
+        .iter()
+        .flatten()
+        .all(|(key, value)| labels.get(key) == Some(value));
+    let match_expressions = selector
+        .match_expressions
+        .iter()
+        .flatten()
+        .all(|expression| {
+            let value = labels.get(&expression.key);
+            let values = expression.values.as_deref().unwrap_or_default();
+            match expression.operator.as_str() {
+                "In" => value.is_some_and(|value| values.contains(value)),
+                "NotIn" => !value.is_some_and(|value| values.contains(value)),
+                "Exists" => value.is_some(),
+                "DoesNotExist" => value.is_none(),
+                _ => false,
+            }
+        });
+    match_labels && match_expressions
+}
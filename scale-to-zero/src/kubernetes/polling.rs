@@ -0,0 +1,164 @@
+use k8s_openapi::api::coordination::v1::Lease;
+use k8s_openapi::chrono;
+use k8s_openapi::serde_json::{self, json};
+use kube::api::{Api, Patch, PatchParams};
+use kube::ResourceExt;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::lease;
+use super::models::{annotation, annotation_key, ActivityKind, WATCHED_SERVICES};
+use super::scaler::FIELD_MANAGER;
+use crate::utils;
+
+// The Lease of a source is held for this many polls, another agent takes over after its holder
+// went away
+const HELD_POLLS: u64 = 3;
+
+// Annotation of the Lease of a source with the last activity of each service it found active
+const ACTIVITY_ANNOTATION: &str = "activity";
+
+// This contains the last activity (unix seconds) of each source applied to each service IP, by this
+// agent polling or taken from the Lease of the source
+static APPLIED: Lazy<Mutex<HashMap<(&'static str, String), i64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn lease_name(source: &str) -> String {
+    format!("scale-to-zero-{}", source)
+}
+
+// Forget the services no longer watched, returns the number of entries left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    let mut applied = APPLIED.lock().unwrap();
+    applied.retain(|(_, ip), _| watched.contains(ip));
+    applied.len()
+}
+
+// Activity read from the API server (logs, sidecar metrics) is polled by a single agent per
+// namespace, the one holding the Lease of the source, so the load on the API server doesn't grow
+// with the nodes. Returns whether this agent polls the namespace this round, the others apply the
+// activity published by the holder instead
+pub async fn lead(
+    namespace: &str,
+    source: &'static str,
+    interval: Duration,
+    kind: ActivityKind,
+) -> bool {
+    let duration = (interval.as_secs() * HELD_POLLS) as i32;
+    match lease::hold(namespace, &lease_name(source), duration).await {
+        Ok(true) => true,
+        Ok(false) => {
+            if let Err(err) = follow(namespace, source, kind).await {
+                warn!(target: "polling", "Failed to read the {} activity of namespace {}: {:#}", source, namespace, err);
+            }
+            false
+        }
+        Err(err) => {
+            warn!(target: "polling", "Failed to take the {} lease of namespace {}: {:#}", source, namespace, err);
+            false
+        }
+    }
+}
+
+// Apply the activity the holder found since it was last read
+async fn follow(namespace: &str, source: &'static str, kind: ActivityKind) -> anyhow::Result<()> {
+    let leases: Api<Lease> = Api::namespaced(super::client().await?, namespace);
+    let published: HashMap<String, i64> = match leases.get_opt(&lease_name(source)).await? {
+        Some(lease) => match annotation(lease.annotations(), ACTIVITY_ANNOTATION) {
+            Some(activity) => serde_json::from_str(activity)?,
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+    let active: Vec<String> = {
+        let watched_services = WATCHED_SERVICES.lock().unwrap();
+        let mut applied = APPLIED.lock().unwrap();
+        watched_services
+            .iter()
+            .filter(|(_, service)| service.namespace == namespace)
+            .filter_map(|(ip, service)| {
+                let time = *published.get(&service.service_name)?;
+                let last = applied.insert((source, ip.clone()), time).unwrap_or(0);
+                // activity older than the last one of the service changes nothing
+                if time <= last || time <= service.last_packet_time {
+                    return None;
+                }
+                Some(ip.clone())
+            })
+            .collect()
+    };
+    for service_ip in active {
+        debug!(target: "polling", "{} activity of {} found by another agent", source, service_ip);
+        if let Ok(address) = service_ip.parse::<Ipv4Addr>() {
+            utils::process_activity(address, kind).await;
+        }
+    }
+    Ok(())
+}
+
+// Count the services the holder found active as activity, and publish it for the other agents
+pub async fn publish(
+    namespace: &str,
+    source: &'static str,
+    kind: ActivityKind,
+    active: Vec<String>,
+) {
+    if active.is_empty() {
+        return;
+    }
+    let now = chrono::Utc::now().timestamp();
+    {
+        let mut applied = APPLIED.lock().unwrap();
+        for service_ip in active.iter() {
+            applied.insert((source, service_ip.clone()), now);
+        }
+    }
+    for service_ip in active.iter() {
+        if let Ok(address) = service_ip.parse::<Ipv4Addr>() {
+            utils::process_activity(address, kind).await;
+        }
+    }
+
+    // the whole activity known for the namespace, so an agent that missed a round catches up
+    let activity: BTreeMap<String, i64> = {
+        let watched_services = WATCHED_SERVICES.lock().unwrap();
+        let applied = APPLIED.lock().unwrap();
+        watched_services
+            .iter()
+            .filter(|(_, service)| service.namespace == namespace)
+            .filter_map(|(ip, service)| {
+                let time = applied.get(&(source, ip.clone()))?;
+                Some((service.service_name.clone(), *time))
+            })
+            .collect()
+    };
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                annotation_key(ACTIVITY_ANNOTATION): json!(activity).to_string()
+            }
+        }
+    }));
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    };
+    let published = match super::client().await {
+        Ok(client) => {
+            Api::<Lease>::namespaced(client, namespace)
+                .patch(&lease_name(source), &params, &patch)
+                .await
+        }
+        Err(err) => {
+            warn!(target: "polling", "Failed to publish the {} activity of namespace {}: {:#}", source, namespace, err);
+            return;
+        }
+    };
+    if let Err(err) = published {
+        warn!(target: "polling", "Failed to publish the {} activity of namespace {}: {}", source, namespace, err);
+    }
+}
@@ -0,0 +1,71 @@
+use k8s_openapi::api::core::v1::Node;
+use kube::api::{Api, ListParams};
+use kube::{Client, ResourceExt};
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use super::models::Priority;
+use crate::config;
+use crate::metrics;
+
+static UNDER_PRESSURE: AtomicBool = AtomicBool::new(false);
+
+// Check the conditions of the nodes every 30s, the cluster is under pressure as long as one of
+// the configured conditions is true on any node
+pub async fn track_pressure(client: Client) {
+    let nodes: Api<Node> = Api::all(client);
+    let conditions = &config::get().pressure_conditions;
+    loop {
+        match nodes.list(&ListParams::default()).await {
+            Ok(nodes) => {
+                let pressured: Vec<String> = nodes
+                    .iter()
+                    .filter(|node| has_condition(node, conditions))
+                    .map(|node| node.name_any())
+                    .collect();
+                let under_pressure = !pressured.is_empty();
+                if UNDER_PRESSURE.swap(under_pressure, Ordering::Relaxed) != under_pressure {
+                    if under_pressure {
+                        warn!(target: "pressure", "Cluster is under pressure (nodes: {}), scaling down low priority services earlier", pressured.join(", "));
+                    } else {
+                        info!(target: "pressure", "Cluster is no longer under pressure");
+                    }
+                }
+                metrics::CLUSTER_UNDER_PRESSURE.set(under_pressure as i64);
+            }
+            Err(kube::Error::Api(err)) if err.code == 403 => {
+                warn!(target: "pressure", "Not allowed to list nodes, priorities are disabled: {}", err.message);
+                return;
+            }
+            Err(err) => warn!(target: "pressure", "Failed to list nodes: {}", err),
+        }
+        tokio::time::sleep(Duration::from_secs(30)).await;
+    }
+}
+
+fn has_condition(node: &Node, conditions: &[String]) -> bool {
+    node.status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .map(|node_conditions| {
+            node_conditions
+                .iter()
+                .any(|c| c.status == "True" && conditions.contains(&c.type_))
+        })
+        .unwrap_or(false)
+}
+
+// Scale-down-time of a service given its priority and the current pressure
+pub fn scale_down_time(scale_down_time: i64, priority: Priority) -> i64 {
+    if !UNDER_PRESSURE.load(Ordering::Relaxed) {
+        return scale_down_time;
+    }
+    let opts = config::get();
+    let factor = match priority {
+        Priority::Low => opts.low_priority_pressure_factor,
+        Priority::Normal => opts.normal_priority_pressure_factor,
+        Priority::High => 1.0,
+    };
+    (scale_down_time as f64 * factor) as i64
+}
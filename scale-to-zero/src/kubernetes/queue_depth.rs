@@ -0,0 +1,218 @@
+use k8s_openapi::api::coordination::v1::Lease;
+use k8s_openapi::chrono;
+use k8s_openapi::serde_json::{self, json, Value};
+use kube::api::{Api, Patch, PatchParams};
+use kube::ResourceExt;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::lease;
+use super::models::{annotation, annotation_key, QueueDepth, QueueDepthSource, WATCHED_SERVICES};
+use super::scaler::FIELD_MANAGER;
+use crate::agent_traffic;
+use crate::config;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(15);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A depth read longer ago than this (seconds) is unknown, the service is kept up
+const MAX_AGE: i64 = 60;
+
+// Annotation of the queue depth Lease of a service with the last reading, shared by the agents
+const READING_ANNOTATION: &str = "queue-depth";
+
+type DepthRead = (Result<f64, String>, i64);
+
+// This contains the last queue depth read for each service IP and when (unix seconds), or why it
+// couldn't be read
+static DEPTHS: Lazy<Mutex<HashMap<String, DepthRead>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Forget the services no longer watched, returns the number of services left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    let mut depths = DEPTHS.lock().unwrap();
+    depths.retain(|ip, _| watched.contains(ip));
+    depths.len()
+}
+
+// Read the queue of every awake service consuming one every 15s, a sleeping service isn't scaled
+// down anyway. The queues are read concurrently, so unreachable sources don't delay the others
+pub async fn track_queue_depths() {
+    loop {
+        let services: Vec<(String, String, String, QueueDepth)> = WATCHED_SERVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, service)| service.backend_available)
+            .filter_map(|(ip, service)| {
+                let queue = service.queue_depth.clone()?;
+                Some((
+                    ip.clone(),
+                    service.namespace.clone(),
+                    service.service_name.clone(),
+                    queue,
+                ))
+            })
+            .collect();
+
+        futures::future::join_all(services.into_iter().map(
+            |(service_ip, namespace, service_name, queue)| async move {
+                let reading = match refresh(&namespace, &service_name, &queue).await {
+                    Ok(Some(reading)) => reading,
+                    // another agent reads it in this round
+                    Ok(None) => return,
+                    Err(err) => {
+                        warn!(target: "queue_depth", "Failed to share the queue depth of {}: {:#}", service_name, err);
+                        return;
+                    }
+                };
+                record(service_ip, &service_name, reading);
+            },
+        ))
+        .await;
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// The queue is read by a single agent per round, the one taking the Lease of the service, which
+// publishes the reading in the Lease for the others
+async fn refresh(
+    namespace: &str,
+    service_name: &str,
+    queue: &QueueDepth,
+) -> anyhow::Result<Option<(Result<f64, String>, i64)>> {
+    let leases: Api<Lease> = Api::namespaced(super::client().await?, namespace);
+    let name = format!("scale-to-zero-queue-depth-{}", service_name);
+    let now = chrono::Utc::now().timestamp();
+    if let Some(reading) = leases
+        .get_opt(&name)
+        .await?
+        .and_then(|lease| shared(&lease))
+    {
+        if now - reading.1 < POLL_INTERVAL.as_secs() as i64 {
+            return Ok(Some(reading));
+        }
+    }
+    if !lease::try_acquire(namespace, &name).await? {
+        return Ok(None);
+    }
+    let depth = read(&queue.source)
+        .await
+        .map_err(|err| format!("{:#}", err));
+    let reading = match &depth {
+        Ok(depth) => json!({ "depth": depth, "read_at": now }),
+        Err(err) => json!({ "error": err, "read_at": now }),
+    };
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    };
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                annotation_key(READING_ANNOTATION): reading.to_string()
+            }
+        }
+    }));
+    leases.patch(&name, &params, &patch).await?;
+    Ok(Some((depth, now)))
+}
+
+fn shared(lease: &Lease) -> Option<(Result<f64, String>, i64)> {
+    let reading: Value =
+        serde_json::from_str(annotation(lease.annotations(), READING_ANNOTATION)?).ok()?;
+    let read_at = reading["read_at"].as_i64()?;
+    match reading["depth"].as_f64() {
+        Some(depth) => Some((Ok(depth), read_at)),
+        None => Some((Err(reading["error"].as_str()?.to_string()), read_at)),
+    }
+}
+
+fn record(service_ip: String, name: &str, (depth, read_at): (Result<f64, String>, i64)) {
+    let previous = DEPTHS
+        .lock()
+        .unwrap()
+        .insert(service_ip, (depth.clone(), read_at));
+    // warned about once until it is read again
+    match (previous.map(|(depth, _)| depth), depth) {
+        (None | Some(Ok(_)), Err(err)) => {
+            warn!(target: "queue_depth", "Keeping {} up, its queue depth can't be read: {}", name, err)
+        }
+        (Some(Err(_)), Ok(depth)) => {
+            info!(target: "queue_depth", "Queue depth of {} is read again: {}", name, depth)
+        }
+        _ => {}
+    }
+}
+
+// The last depth of the queue of an idle service and whether it lets the service be scaled down.
+// A depth that is unknown or too old keeps the service up
+pub fn allows_scale_down(service_ip: &str, queue: &QueueDepth) -> (Option<f64>, bool) {
+    let now = chrono::Utc::now().timestamp();
+    let depth = match DEPTHS.lock().unwrap().get(service_ip) {
+        Some((Ok(depth), read_at)) if now - read_at <= MAX_AGE => Some(*depth),
+        _ => None,
+    };
+    (
+        depth,
+        depth.is_some_and(|depth| depth <= queue.threshold as f64),
+    )
+}
+
+// Requests go through the reserved source ports, their packets would otherwise be activity of the
+// service they reach on the other nodes
+async fn read(source: &QueueDepthSource) -> anyhow::Result<f64> {
+    match source {
+        QueueDepthSource::Url(url) => {
+            let (status, body) =
+                agent_traffic::request("GET", url, String::new(), REQUEST_TIMEOUT).await?;
+            if !status.is_success() {
+                anyhow::bail!("{} returned {}", url, status);
+            }
+            body.trim()
+                .parse::<f64>()
+                .map_err(|_| anyhow::anyhow!("{} returned {:?} instead of a number", url, body))
+        }
+        QueueDepthSource::Query(query) => {
+            let prometheus = config::get()
+                .prometheus_url
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("queue-depth-query needs --prometheus-url"))?;
+            let url = reqwest::Url::parse_with_params(
+                &format!("{}/api/v1/query", prometheus.trim_end_matches('/')),
+                &[("query", query)],
+            )?;
+            let (_, body) =
+                agent_traffic::request("GET", url.as_str(), String::new(), REQUEST_TIMEOUT).await?;
+            let body: Value = serde_json::from_str(&body)?;
+            if body["status"] != "success" {
+                anyhow::bail!(
+                    "{} failed: {}",
+                    query,
+                    body["error"].as_str().unwrap_or("unknown error")
+                );
+            }
+            query_result(&body["data"])
+                .ok_or_else(|| anyhow::anyhow!("{} returned no sample", query))
+        }
+    }
+}
+
+// The value of a scalar result, or the sum of the samples of a vector. A vector without samples is
+// no result, a queue metric that goes away when it is empty needs `or vector(0)`
+fn query_result(data: &Value) -> Option<f64> {
+    let sample = |value: &Value| value.get(1)?.as_str()?.parse::<f64>().ok();
+    match data["resultType"].as_str()? {
+        "scalar" => sample(&data["result"]),
+        "vector" => {
+            let samples = data["result"].as_array()?;
+            if samples.is_empty() {
+                return None;
+            }
+            samples.iter().map(|series| sample(&series["value"])).sum()
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,78 @@
+use k8s_openapi::chrono;
+use kube::runtime::events::EventType;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use super::events;
+use super::models::{ServiceData, WakeQuota, WakeQuotaPolicy};
+use crate::metrics;
+
+// This contains the times (unix seconds) of the wakes of each service IP within its quota window
+static WAKES: Lazy<Mutex<HashMap<String, VecDeque<i64>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Service IPs over their quota that were already alerted on
+static ALERTED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Whether the service used up its wakes of the current window. Each agent counts the wakes it
+// triggered itself
+pub fn exceeded(service_ip: &str, quota: &WakeQuota) -> bool {
+    let now = chrono::Utc::now().timestamp();
+    let mut wakes = WAKES.lock().unwrap();
+    let wakes = wakes.entry(service_ip.to_string()).or_default();
+    while matches!(wakes.front(), Some(time) if now - time >= quota.window) {
+        wakes.pop_front();
+    }
+    let exceeded = wakes.len() >= quota.max_wakes;
+    if !exceeded {
+        ALERTED.lock().unwrap().remove(service_ip);
+    }
+    exceeded
+}
+
+// Forget the services no longer watched, returns the number of services left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    ALERTED.lock().unwrap().retain(|ip| watched.contains(ip));
+    let mut wakes = WAKES.lock().unwrap();
+    wakes.retain(|ip, _| watched.contains(ip));
+    wakes.len()
+}
+
+pub fn record(service_ip: &str) {
+    WAKES
+        .lock()
+        .unwrap()
+        .entry(service_ip.to_string())
+        .or_default()
+        .push_back(chrono::Utc::now().timestamp());
+}
+
+// Alert once per exceeded window with an event on the service
+pub async fn alert(service_ip: &str, service: &ServiceData, quota: &WakeQuota) {
+    if !ALERTED.lock().unwrap().insert(service_ip.to_string()) {
+        return;
+    }
+    metrics::WAKE_QUOTA_EXCEEDED.inc();
+    let consequence = match quota.policy {
+        WakeQuotaPolicy::KeepDown => "it is kept scaled down",
+        WakeQuotaPolicy::KeepUp => "it is kept up",
+    };
+    let note = format!(
+        "{} wakes within {}s, {} until the window passes",
+        quota.max_wakes, quota.window, consequence
+    );
+    warn!(target: "quota", "Service {} exceeded its wake quota: {}", service.service_name, note);
+    let published = events::publish(
+        &service.namespace,
+        &service.service_name,
+        EventType::Warning,
+        "WakeQuotaExceeded".to_string(),
+        note,
+        "Wake",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "quota", "Failed to record wake quota event of service {}: {}", service.service_name, err);
+    }
+}
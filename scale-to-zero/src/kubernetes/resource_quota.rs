@@ -0,0 +1,294 @@
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use k8s_openapi::api::core::v1::{Container, PodSpec, ResourceQuota};
+use k8s_openapi::serde_json;
+use kube::api::{Api, ListParams};
+use kube::runtime::events::EventType;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::events;
+use super::kruise;
+use super::models::ServiceData;
+use crate::metrics;
+
+// Backoff of a service whose wake was blocked, doubled on every blocked attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// Resources of a pod counted by a quota, by their names in the quota
+const POD_RESOURCES: [(&str, &str, bool); 9] = [
+    ("cpu", "cpu", false),
+    ("requests.cpu", "cpu", false),
+    ("limits.cpu", "cpu", true),
+    ("memory", "memory", false),
+    ("requests.memory", "memory", false),
+    ("limits.memory", "memory", true),
+    ("ephemeral-storage", "ephemeral-storage", false),
+    ("requests.ephemeral-storage", "ephemeral-storage", false),
+    ("limits.ephemeral-storage", "ephemeral-storage", true),
+];
+
+struct Blocked {
+    until: Instant,
+    backoff: Duration,
+}
+
+// This contains the services whose last wake was blocked by a quota, they aren't checked again
+// before their backoff passes
+static BLOCKED: Lazy<Mutex<HashMap<String, Blocked>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Set once listing the quotas is forbidden, the check is skipped from then on
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+// Forget the services no longer watched, and the backoffs long expired, returns the number left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    let now = Instant::now();
+    let mut blocked = BLOCKED.lock().unwrap();
+    blocked.retain(|ip, blocked| watched.contains(ip) && blocked.until + MAX_BACKOFF > now);
+    blocked.len()
+}
+
+// Whether the ResourceQuotas of the namespace would reject the pods of the wake. A blocked wake is
+// reported with an event on the service and not tried again before its backoff passes, instead of
+// leaving a workload with pods that can't be created. Fails open when the quotas can't be read
+pub async fn blocks(service_ip: &str, service: &ServiceData, replicas: i32) -> bool {
+    if let Some(blocked) = BLOCKED.lock().unwrap().get(service_ip) {
+        if blocked.until > Instant::now() {
+            return true;
+        }
+    }
+    if DISABLED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let exceeded = match exceeded_quota(service, replicas).await {
+        Ok(exceeded) => exceeded,
+        Err(err) => {
+            if let Some(kube::Error::Api(response)) = err.downcast_ref::<kube::Error>() {
+                if response.code == 403 {
+                    warn!(target: "resource_quota", "Not allowed to list resource quotas, wakes are not checked against them: {}", response.message);
+                    DISABLED.store(true, Ordering::Relaxed);
+                    return false;
+                }
+            }
+            warn!(target: "resource_quota", "Failed to check the resource quotas of {} {}: {:#}", service.kind, service.name, err);
+            return false;
+        }
+    };
+
+    let reason = match exceeded {
+        Some(reason) => reason,
+        None => {
+            if BLOCKED.lock().unwrap().remove(service_ip).is_some() {
+                info!(target: "resource_quota", "Wake of {} {} is no longer blocked by a quota", service.kind, service.name);
+            }
+            return false;
+        }
+    };
+    let backoff = {
+        let mut blocked = BLOCKED.lock().unwrap();
+        let backoff = blocked
+            .get(service_ip)
+            .map(|blocked| (blocked.backoff * 2).min(MAX_BACKOFF))
+            .unwrap_or(INITIAL_BACKOFF);
+        blocked.insert(
+            service_ip.to_string(),
+            Blocked {
+                until: Instant::now() + backoff,
+                backoff,
+            },
+        );
+        backoff
+    };
+    warn!(target: "resource_quota", "Not scaling up {} {}, wake blocked by quota: {}", service.kind, service.name, reason);
+    metrics::WAKES_BLOCKED_BY_QUOTA.inc();
+    let published = events::publish(
+        &service.namespace,
+        &service.service_name,
+        EventType::Warning,
+        "WakeBlockedByQuota".to_string(),
+        format!(
+            "Wake blocked by quota {}, checked again after {}s",
+            reason,
+            backoff.as_secs()
+        ),
+        "Wake",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "resource_quota", "Failed to record blocked wake of service {}: {}", service.service_name, err);
+    }
+    true
+}
+
+// The first quota the pods of the wake would exceed, as "<quota>: <resource> ...". Quotas with
+// scopes only count some pods (priority classes, terminating, best effort) and are skipped
+async fn exceeded_quota(service: &ServiceData, replicas: i32) -> anyhow::Result<Option<String>> {
+    let client = super::client().await?;
+    let quotas: Api<ResourceQuota> = Api::namespaced(client, &service.namespace);
+    let quotas = quotas.list(&ListParams::default()).await?;
+    if quotas.items.is_empty() {
+        return Ok(None);
+    }
+    let pod = match pod_spec(service).await? {
+        Some(pod) => pod,
+        None => return Ok(None),
+    };
+
+    for quota in quotas.iter() {
+        let scoped = quota.spec.as_ref().is_some_and(|spec| {
+            spec.scopes
+                .as_ref()
+                .is_some_and(|scopes| !scopes.is_empty())
+                || spec.scope_selector.is_some()
+        });
+        let status = match quota.status.as_ref() {
+            Some(status) if !scoped => status,
+            _ => continue,
+        };
+        for (resource, hard) in status.hard.iter().flatten() {
+            let needed = match resource.as_str() {
+                "pods" | "count/pods" => replicas as f64,
+                name => match POD_RESOURCES
+                    .iter()
+                    .find(|(quota_name, _, _)| *quota_name == name)
+                {
+                    Some((_, pod_resource, limits)) => {
+                        pod_resources(&pod, pod_resource, *limits) * replicas as f64
+                    }
+                    None => continue,
+                },
+            };
+            let hard = parse_quantity(&hard.0).unwrap_or(f64::MAX);
+            let used = status
+                .used
+                .as_ref()
+                .and_then(|used| used.get(resource))
+                .and_then(|used| parse_quantity(&used.0))
+                .unwrap_or(0.0);
+            if needed > 0.0 && used + needed > hard {
+                return Ok(Some(format!(
+                    "{}: {} needs {} more, {} of {} used",
+                    quota.metadata.name.as_deref().unwrap_or_default(),
+                    resource,
+                    format_quantity(resource, needed),
+                    format_quantity(resource, used),
+                    format_quantity(resource, hard)
+                )));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// The pod template of the workload, the pods of a wake are created from it
+async fn pod_spec(service: &ServiceData) -> anyhow::Result<Option<PodSpec>> {
+    let client = super::client().await?;
+    let spec = match service.kind.as_str() {
+        "deployment" => {
+            let deployments: Api<Deployment> = Api::namespaced(client, &service.namespace);
+            deployments
+                .get(&service.name)
+                .await?
+                .spec
+                .and_then(|spec| spec.template.spec)
+        }
+        "statefulset" => {
+            let statefulsets: Api<StatefulSet> = Api::namespaced(client, &service.namespace);
+            statefulsets
+                .get(&service.name)
+                .await?
+                .spec
+                .and_then(|spec| spec.template.spec)
+        }
+        kind if kruise::is_kruise(kind) => {
+            let api = kruise::api(client, &service.namespace, kind).await?;
+            let workload = api.get(&service.name).await?;
+            match workload.data.pointer("/spec/template/spec") {
+                Some(spec) => Some(serde_json::from_value(spec.clone())?),
+                None => None,
+            }
+        }
+        _ => None,
+    };
+    Ok(spec)
+}
+
+// Requests (or limits) of a pod for a resource: its containers together, or its largest init
+// container if that is more. A container with only a limit requests as much
+fn pod_resources(pod: &PodSpec, resource: &str, limits: bool) -> f64 {
+    let containers: f64 = pod
+        .containers
+        .iter()
+        .map(|container| container_resources(container, resource, limits))
+        .sum();
+    let init_containers = pod
+        .init_containers
+        .iter()
+        .flatten()
+        .map(|container| container_resources(container, resource, limits))
+        .fold(0.0, f64::max);
+    containers.max(init_containers)
+}
+
+fn container_resources(container: &Container, resource: &str, limits: bool) -> f64 {
+    let resources = match container.resources.as_ref() {
+        Some(resources) => resources,
+        None => return 0.0,
+    };
+    let limit = resources
+        .limits
+        .as_ref()
+        .and_then(|limits| limits.get(resource))
+        .and_then(|limit| parse_quantity(&limit.0));
+    if limits {
+        return limit.unwrap_or(0.0);
+    }
+    resources
+        .requests
+        .as_ref()
+        .and_then(|requests| requests.get(resource))
+        .and_then(|request| parse_quantity(&request.0))
+        .or(limit)
+        .unwrap_or(0.0)
+}
+
+// A Kubernetes quantity ("500m", "2Gi", "1.5", "1e3") as a number of units
+fn parse_quantity(quantity: &str) -> Option<f64> {
+    let quantity = quantity.trim();
+    let suffixes: [(&str, f64); 15] = [
+        ("Ki", 1024.0),
+        ("Mi", 1024.0 * 1024.0),
+        ("Gi", 1024.0 * 1024.0 * 1024.0),
+        ("Ti", 1024.0 * 1024.0 * 1024.0 * 1024.0),
+        ("Pi", 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0),
+        ("Ei", 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0 * 1024.0),
+        ("n", 1e-9),
+        ("u", 1e-6),
+        ("m", 1e-3),
+        ("k", 1e3),
+        ("M", 1e6),
+        ("G", 1e9),
+        ("T", 1e12),
+        ("P", 1e15),
+        ("E", 1e18),
+    ];
+    for (suffix, multiplier) in suffixes {
+        if let Some(number) = quantity.strip_suffix(suffix) {
+            return number.parse::<f64>().ok().map(|number| number * multiplier);
+        }
+    }
+    quantity.parse::<f64>().ok()
+}
+
+fn format_quantity(resource: &str, value: f64) -> String {
+    if resource.contains("memory") || resource.contains("storage") {
+        return format!("{:.0}Mi", value / (1024.0 * 1024.0));
+    }
+    if resource.contains("cpu") {
+        return format!("{:.0}m", value * 1000.0);
+    }
+    format!("{}", value)
+}
@@ -0,0 +1,122 @@
+use kube::runtime::events::EventType;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::events;
+use super::models::ServiceData;
+use super::scaler;
+use crate::metrics;
+
+// Attempts of a scale patch, the first one included
+const MAX_ATTEMPTS: u32 = 6;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+type WorkloadKey = (String, String, String);
+
+// This contains the generation of the last scale patch requested for each (namespace, kind, name),
+// a retry is abandoned once a newer patch of its workload was requested
+static GENERATIONS: Lazy<Mutex<HashMap<WorkloadKey, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn workload(service: &ServiceData) -> (String, String, String) {
+    (
+        service.namespace.clone(),
+        service.kind.clone(),
+        service.name.clone(),
+    )
+}
+
+// Forget the workloads no longer watched, by (namespace, kind, name), returns the number left
+pub fn retain(watched: &HashSet<(String, String, String)>) -> usize {
+    let mut generations = GENERATIONS.lock().unwrap();
+    generations.retain(|workload, _| watched.contains(workload));
+    generations.len()
+}
+
+fn next_generation(service: &ServiceData) -> u64 {
+    let mut generations = GENERATIONS.lock().unwrap();
+    let generation = generations.entry(workload(service)).or_default();
+    *generation += 1;
+    *generation
+}
+
+fn is_current(service: &ServiceData, generation: u64) -> bool {
+    GENERATIONS.lock().unwrap().get(&workload(service)) == Some(&generation)
+}
+
+// Throttling, server errors and connection failures are worth another attempt, anything else
+// (e.g. a missing workload or RBAC) fails the same way every time
+fn is_retryable(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<kube::Error>() {
+        Some(kube::Error::Api(response)) => response.code == 429 || response.code >= 500,
+        Some(kube::Error::HyperError(_)) | Some(kube::Error::Service(_)) => true,
+        _ => false,
+    }
+}
+
+// Scale the workload of a service, retrying in the background with an exponential backoff when the
+// API server can't take the patch right now. Only errors that are not worth retrying are returned
+pub async fn set_replicas(service: &ServiceData, replicas: i32) -> anyhow::Result<()> {
+    let generation = next_generation(service);
+    match scaler::set_replicas(service, replicas).await {
+        Err(err) if is_retryable(&err) => {
+            warn!(target: "retry", "Failed to scale {} {} to {}, retrying: {:#}", service.kind, service.name, replicas, err);
+            metrics::SCALE_RETRIES.inc();
+            tokio::spawn(retry(service.clone(), replicas, generation));
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+async fn retry(service: ServiceData, replicas: i32, generation: u64) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 2..=MAX_ATTEMPTS {
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        if !is_current(&service, generation) {
+            info!(target: "retry", "Scale patch of {} {} to {} was superseded", service.kind, service.name, replicas);
+            return;
+        }
+        match scaler::set_replicas(&service, replicas).await {
+            Ok(()) => {
+                info!(target: "retry", "Scaled {} {} to {} after {} attempts", service.kind, service.name, replicas, attempt);
+                return;
+            }
+            Err(err) if attempt < MAX_ATTEMPTS && is_retryable(&err) => {
+                warn!(target: "retry", "Attempt {} to scale {} {} to {} failed: {:#}", attempt, service.kind, service.name, replicas, err);
+                metrics::SCALE_RETRIES.inc();
+            }
+            Err(err) => {
+                fail(&service, replicas, attempt, err).await;
+                return;
+            }
+        }
+    }
+}
+
+// Give up on a scale patch and record it on the service, its traffic stays gated (or the workload
+// up) until the next wake or scale-down
+async fn fail(service: &ServiceData, replicas: i32, attempts: u32, err: anyhow::Error) {
+    metrics::SCALE_FAILURES.inc();
+    let note = format!(
+        "Failed to scale {} {} to {} after {} attempts: {:#}",
+        service.kind, service.name, replicas, attempts, err
+    );
+    warn!(target: "retry", "{}", note);
+    let published = events::publish(
+        &service.namespace,
+        &service.service_name,
+        EventType::Warning,
+        "ScaleFailed".to_string(),
+        note,
+        "Scale",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "retry", "Failed to record scale failure of service {}: {}", service.service_name, err);
+    }
+}
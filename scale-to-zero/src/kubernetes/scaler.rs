@@ -1,122 +1,823 @@
-use super::models::{ServiceData, WATCHED_SERVICES};
-use crate::kubernetes::models::LAST_CALLED;
-use anyhow::Ok;
+use super::canary;
+use super::checkpoint;
+use super::condition;
+use super::explain::{
+    self, ConditionInput, PolicyInput, QueueDepthInput, ScaleDownDecision, WakeDecision,
+};
+use super::gitops;
+use super::groups;
+use super::hooks;
+use super::hpa;
+use super::kruise;
+use super::lease;
+use super::maintenance;
+use super::models::{
+    annotation_key, Hook, ServiceData, WakeQuotaPolicy, AWAKE_REPLICAS_ANNOTATION, WATCHED_SERVICES,
+};
+use super::monitor;
+use super::patch_template;
+use super::placeholder;
+use super::pressure;
+use super::queue_depth;
+use super::quota;
+use super::resource_quota;
+use super::retry;
+use super::startup;
+use super::wake_queue;
+use super::wake_trace::{self, Phase};
+use super::wakes;
+use super::warm_up;
+use crate::config;
+use crate::gated_clients;
+use crate::kubernetes::models::{LAST_CALLED, RECENT_WAKES, RECENT_WAKES_LEN};
+use crate::metrics;
+use crate::policy::{self, PolicyContext};
+use crate::standalone;
+use crate::utils;
+use futures::future::{BoxFuture, FutureExt, Shared};
 use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use k8s_openapi::chrono;
 use k8s_openapi::serde_json::json;
 use kube::api::Api;
 use kube::api::{Patch, PatchParams};
-use kube::Client;
-use log::info;
+use kube::Resource;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Mutex;
 use std::time::{Duration, SystemTime};
 
+// Field manager owning spec.replicas of the scaled workloads
+pub const FIELD_MANAGER: &str = "scale-to-zero";
+
+// Controllers from these API groups are expected to own workloads, any other one is an operator
+const CORE_API_GROUPS: [&str; 3] = ["", "apps", "batch"];
+
+// How often the active hours of the services are checked for windows that just opened
+const ACTIVE_HOURS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// A wake that didn't happen because another one covers it, not worth an error log
+#[derive(Debug, thiserror::Error)]
+pub enum WakeError {
+    #[error("Rate limited: {0} was woken less than 5 seconds ago")]
+    RateLimited(String),
+    #[error("Joined the wake in progress of {service_ip}, which failed: {failure}")]
+    Joined { service_ip: String, failure: String },
+}
+
+type InFlightWake = Shared<BoxFuture<'static, Result<(), String>>>;
+
+// The wake in progress of each service IP. Every CPU reading the ring buffer can ask for the same
+// service within milliseconds, they all wait for the first wake instead of starting their own
+static IN_FLIGHT: Lazy<Mutex<HashMap<String, InFlightWake>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Wake the sleeping services whose active hours just started, so the first request of the window
+// doesn't wait for a cold start. Windows already open when the agent starts are left alone, the
+// service may have been scaled down within them by active-hours-scale-down-time
+pub async fn wake_for_active_hours() {
+    // whether the active hours of each service applied at the previous check
+    let mut active: HashMap<String, bool> = HashMap::new();
+    let mut checks = tokio::time::interval(ACTIVE_HOURS_CHECK_INTERVAL);
+    loop {
+        checks.tick().await;
+        let now = chrono::Utc::now();
+        let starting: Vec<String> = {
+            let watched_services = WATCHED_SERVICES.lock().unwrap();
+            let starting = watched_services
+                .iter()
+                .filter_map(|(ip, service)| {
+                    let is_active = service.active_hours.as_ref()?.is_active(now);
+                    let was_active = active.insert(ip.clone(), is_active);
+                    let opened = is_active && was_active == Some(false);
+                    (opened && !service.backend_available).then(|| ip.clone())
+                })
+                .collect();
+            active.retain(|ip, _| watched_services.contains_key(ip));
+            starting
+        };
+        let wakes = starting.into_iter().map(|service_ip| async move {
+            info!(target: "scale_up", "Waking {} as its active hours start", service_ip);
+            if let Err(err) = scale_up(service_ip.clone()).await {
+                warn!(target: "scale_up", "Failed to wake {} for its active hours: {:#}", service_ip, err);
+            }
+        });
+        futures::future::join_all(wakes).await;
+    }
+}
+
 pub async fn scale_down() -> anyhow::Result<()> {
-    let client = Client::try_default().await?;
-    let deployments: Api<Deployment> = Api::default_namespaced(client.clone());
-    let statefulsets: Api<StatefulSet> = Api::default_namespaced(client.clone());
     loop {
+        if maintenance::enabled() || warm_up::active() {
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            continue;
+        }
         let keys: Vec<_>;
         {
             let watched_services = WATCHED_SERVICES.lock().unwrap();
             keys = watched_services.keys().cloned().collect();
         }
         for key in keys {
-            let mut service: ServiceData;
+            // deleted since the keys were collected
+            let Some(mut service) = WATCHED_SERVICES.lock().unwrap().get(&key).cloned() else {
+                continue;
+            };
+            // nothing to decide while the backends are down, the decision that scaled them down
+            // stays the one explained
+            if !service.backend_available {
+                continue;
+            }
+            // left to the agent checkpointing it
+            if service.checkpoint && checkpoint::busy(&key) {
+                continue;
+            }
+            let mut decision = evaluate_scale_down(&key, &service);
+            // a service kept up past its quota is alerted on like one kept down
+            if let Some(quota) = service
+                .wake_quota
+                .filter(|_| decision.wake_quota_exceeded == Some(true))
             {
-                let mut watched_services = WATCHED_SERVICES.lock().unwrap();
-                service = watched_services.get_mut(&key).unwrap().clone();
+                let (key, service) = (key.clone(), service.clone());
+                tokio::spawn(async move { quota::alert(&key, &service, &quota).await });
+            }
+            if service.monitor {
+                monitor::observe(&key, &service, &mut decision);
+                explain::record_scale_down(&key, decision);
+                continue;
             }
-            let idle_minutes = service.scale_down_time;
-            let last_packet_time = service.last_packet_time;
-            let now = chrono::Utc::now().timestamp();
-            if now - last_packet_time > idle_minutes as i64 && service.backend_available {
+            if decision.scale_down {
+                let reason = match unmanageable_reason(&service).await {
+                    Ok(reason) => reason,
+                    Err(err) => {
+                        warn!(target: "scale_down", "Failed to check whether {} {} can be scaled down: {:#}", service.kind, service.name, err);
+                        continue;
+                    }
+                };
+                let unmanageable = reason.is_some();
+                decision.unmanageable = reason.clone();
+                set_unmanageable(&key, &service, reason);
+                if unmanageable {
+                    decision.scale_down = false;
+                    decision.reason = "The workload is unmanageable".to_string();
+                    explain::record_scale_down(&key, decision);
+                    continue;
+                }
+                explain::record_scale_down(&key, decision);
+
                 service.backend_available = false;
+                service.unmanageable = None;
+                service.wake_failure = None;
                 info!(target: "scale_down", "Scaling down backends of {}", service.name);
-                if service.kind == "deployment" {
-                    deployments
-                        .patch(
-                            service.name.as_str(),
-                            &PatchParams::default(),
-                            &Patch::Merge(json!({
-                                "spec": {
-                                    "replicas": 0
-                                }
-                            })),
-                        )
-                        .await?;
-                } else if service.kind == "statefulset" {
-                    statefulsets
-                        .patch(
-                            service.name.as_str(),
-                            &PatchParams::default(),
-                            &Patch::Merge(json!({
-                                "spec": {
-                                    "replicas": 0
-                                }
-                            })),
-                        )
-                        .await?;
+                if let Some(name) = service.hpa.as_ref() {
+                    if let Err(err) = hpa::scale_down(&service, name).await {
+                        warn!(target: "scale_down", "Failed to lower minReplicas of HPA {}: {:#}", name, err);
+                    }
                 }
-                {
-                    let mut watched_services = WATCHED_SERVICES.lock().unwrap();
-                    let service_to_update = watched_services.get_mut(&key).unwrap();
-                    *service_to_update = service;
+                // the checkpoint takes up to a minute per container, the other services aren't
+                // held up by it
+                if service.checkpoint {
+                    tokio::spawn(async move {
+                        if checkpoint::create(&key, &service).await {
+                            let scaled_down = finish_scale_down(&key, service.clone()).await;
+                            checkpoint::done(&key, &service, scaled_down).await;
+                        }
+                    });
+                    continue;
                 }
+                finish_scale_down(&key, service).await;
+            } else {
+                explain::record_scale_down(&key, decision);
             }
         }
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
     }
 }
 
+// Scale the workload of an idle service down, returns whether it was
+async fn finish_scale_down(key: &str, service: ServiceData) -> bool {
+    if let Err(err) = retry::set_replicas(&service, 0).await {
+        warn!(target: "scale_down", "Failed to scale down {} {}: {:#}", service.kind, service.name, err);
+        return false;
+    }
+    metrics::SERVICE_SCALE_DOWNS
+        .with_label_values(&[&service.namespace, &service.service_name])
+        .inc();
+    wakes::reset(key);
+    if let Some(priority_class) = service.placeholder_priority_class.clone() {
+        let service = service.clone();
+        tokio::spawn(async move { placeholder::create(&service, &priority_class).await });
+    }
+    let post_scale_down_hook = service
+        .post_scale_down_hook
+        .clone()
+        .map(|hook| (service.clone(), hook));
+    if let Some(service_to_update) = WATCHED_SERVICES.lock().unwrap().get_mut(key) {
+        *service_to_update = service;
+    }
+    if let Some((service, hook)) = post_scale_down_hook {
+        tokio::spawn(run_post_scale_down_hook(service, hook));
+    }
+    true
+}
+
+// Whether a service is idle long enough to be scaled down, with the inputs of the decision
+fn evaluate_scale_down(key: &str, service: &ServiceData) -> ScaleDownDecision {
+    // services of a group namespace are idle only as long as the whole group is
+    let group_scale_down_time = groups::scale_down_time(&service.namespace);
+    let (scale_down_time, last_packet_time) = match group_scale_down_time {
+        Some(scale_down_time) => (
+            scale_down_time,
+            groups::last_packet_time(&service.namespace),
+        ),
+        None => (service.scale_down_time, service.last_packet_time),
+    };
+    let pressure_scale_down_time = pressure::scale_down_time(scale_down_time, service.priority);
+    let mut scale_down_time = wakes::scale_down_time(key, pressure_scale_down_time);
+    // within its active hours a service is kept up, or idles for longer
+    let active_hours = service
+        .active_hours
+        .as_ref()
+        .filter(|hours| hours.is_active(chrono::Utc::now()));
+    if let Some(active_scale_down_time) = active_hours.and_then(|hours| hours.scale_down_time) {
+        scale_down_time = scale_down_time.max(active_scale_down_time);
+    }
+    let now = chrono::Utc::now().timestamp();
+    let mut decision = ScaleDownDecision {
+        evaluated_at: now,
+        scale_down: false,
+        reason: String::new(),
+        last_activity: last_packet_time,
+        idle_seconds: now - last_packet_time,
+        scale_down_time: service.scale_down_time,
+        group_scale_down_time,
+        pressure_scale_down_time,
+        effective_scale_down_time: scale_down_time,
+        active_hours: active_hours.is_some(),
+        wake_quota_exceeded: None,
+        condition: None,
+        queue_depth: None,
+        policy: None,
+        unmanageable: None,
+    };
+    if active_hours.is_some_and(|hours| hours.scale_down_time.is_none()) {
+        decision.reason = "Within the active hours of the service".to_string();
+        return decision;
+    }
+    if decision.idle_seconds <= scale_down_time {
+        decision.reason = format!(
+            "Idle for {}s of the {}s scale-down-time",
+            decision.idle_seconds, scale_down_time
+        );
+        return decision;
+    }
+    // a service bouncing past its quota stays up until the window passes
+    if let Some(quota) = service.wake_quota.as_ref() {
+        let exceeded = quota::exceeded(key, quota);
+        decision.wake_quota_exceeded = Some(exceeded);
+        if quota.policy == WakeQuotaPolicy::KeepUp && exceeded {
+            decision.reason =
+                "The wake quota is exceeded, kept up until its window passes".to_string();
+            return decision;
+        }
+    }
+    let context = PolicyContext::new(key, service, scale_down_time);
+    if let Some(expression) = service.scale_down_condition.as_ref() {
+        let allowed = condition::allows_scale_down(key, expression, &context);
+        decision.condition = Some(ConditionInput {
+            expression: expression.clone(),
+            allowed,
+        });
+        if !allowed {
+            decision.reason = "The scale-down condition is false".to_string();
+            return decision;
+        }
+    }
+    // a worker is idle on the network while it drains its queue
+    if let Some(queue) = service.queue_depth.as_ref() {
+        let (depth, allowed) = queue_depth::allows_scale_down(key, queue);
+        decision.queue_depth = Some(QueueDepthInput {
+            depth,
+            threshold: queue.threshold,
+            allowed,
+        });
+        if !allowed {
+            decision.reason = match depth {
+                Some(depth) => format!(
+                    "{} messages are pending in its queue, above the threshold of {}",
+                    depth, queue.threshold
+                ),
+                None => "The depth of its queue is unknown".to_string(),
+            };
+            return decision;
+        }
+    }
+    let allowed = policy::should_scale_down(&context);
+    decision.policy = policy::name().map(|name| PolicyInput { name, allowed });
+    if !allowed {
+        decision.reason = "The scale policy keeps the service up".to_string();
+        return decision;
+    }
+    decision.scale_down = true;
+    decision.reason = format!(
+        "Idle for {}s, past the {}s scale-down-time",
+        decision.idle_seconds, scale_down_time
+    );
+    decision
+}
+
+// A wake of the service is in progress
+pub fn waking(service_ip: &str) -> bool {
+    IN_FLIGHT.lock().unwrap().contains_key(service_ip)
+}
+
+// Wake the backends of a service, or join the wake of the service already in progress and share
+// its result
 pub async fn scale_up(service_ip: String) -> anyhow::Result<()> {
     let now = SystemTime::now();
-    {
+    let (wake, joined) = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        match in_flight.get(&service_ip) {
+            Some(wake) => (wake.clone(), true),
+            None => {
+                {
+                    let mut last_called = LAST_CALLED.lock().unwrap();
+                    if let Some(time) = last_called.get(&service_ip) {
+                        if now.duration_since(*time)? < Duration::from_secs(5) {
+                            return Err(WakeError::RateLimited(service_ip).into());
+                        }
+                    }
+                    last_called.insert(service_ip.clone(), now);
+                }
+                let ip = service_ip.clone();
+                // removed by the wake itself, whichever caller still waits for it drives it to the
+                // end
+                let wake = async move {
+                    let result = scale_up_once(ip.clone(), now).await;
+                    IN_FLIGHT.lock().unwrap().remove(&ip);
+                    result.map_err(|err| format!("{:#}", err))
+                }
+                .boxed()
+                .shared();
+                in_flight.insert(service_ip.clone(), wake.clone());
+                (wake, false)
+            }
+        }
+    };
+    if !joined {
+        return wake.await.map_err(anyhow::Error::msg);
+    }
+    metrics::WAKES_COALESCED.inc();
+    wake.await.map_err(|failure| {
+        WakeError::Joined {
+            service_ip,
+            failure,
+        }
+        .into()
+    })
+}
+
+async fn scale_up_once(service_ip: String, now: SystemTime) -> anyhow::Result<()> {
+    // without a cluster, the target is woken by its executor
+    if config::get().standalone.is_some() {
+        return standalone::wake(&service_ip).await;
+    }
+
+    let namespace = WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .get(&service_ip)
+        .map(|service| service.namespace.clone())
+        .ok_or_else(|| anyhow::anyhow!("Service {} is not watched", service_ip))?;
+    let mut service_ips = vec![service_ip.clone()];
+    // a single wake restores the whole group, the other members are rate limited like the first
+    if groups::scale_down_time(&namespace).is_some() {
+        let members = groups::sleeping_members(&namespace);
         let mut last_called = LAST_CALLED.lock().unwrap();
-        if let Some(time) = last_called.get(&service_ip) {
-            if now.duration_since(*time)? < Duration::from_secs(5) {
-                return Err(anyhow::anyhow!(
-                    "Rate Limited: Function can only be called once every 5 seconds per service_ip"
-                ));
+        for member in members.into_iter().filter(|ip| *ip != service_ip) {
+            last_called.insert(member.clone(), now);
+            service_ips.push(member);
+        }
+    }
+    // a member failing to wake doesn't keep the others asleep, only the failure of the service
+    // asked for is returned
+    let wakes = service_ips.into_iter().map(|member| async move {
+        let woken = wake(member.clone(), now).await;
+        (member, woken)
+    });
+    let mut result = Ok(());
+    for (member, woken) in futures::future::join_all(wakes).await {
+        match woken {
+            Ok(_) => {}
+            Err(err) if member == service_ip => result = Err(err),
+            Err(err) => {
+                warn!(target: "scale_up", "Failed to wake {} with {}: {:#}", member, service_ip, err)
             }
         }
-        last_called.insert(service_ip.clone(), now);
     }
-    info!(target: "scale_up", "Scaling up backends of {}", service_ip);
+    result
+}
 
-    let client = Client::try_default().await?;
-    let deployments: Api<Deployment> = Api::default_namespaced(client.clone());
-    let statefulsets: Api<StatefulSet> = Api::default_namespaced(client.clone());
-    let mut service: ServiceData;
-    {
-        let mut watched_services = WATCHED_SERVICES.lock().unwrap();
-        service = watched_services.get_mut(&service_ip).unwrap().clone();
+async fn wake(service_ip: String, received: SystemTime) -> anyhow::Result<()> {
+    let Some(mut service) = WATCHED_SERVICES.lock().unwrap().get(&service_ip).cloned() else {
+        return Err(anyhow::anyhow!("{} is no longer watched", service_ip));
+    };
+    let mut decision = WakeDecision::now();
+    // its gate is open, a wake can only come from the admin API. A monitored service at zero replicas
+    // is woken, nothing else would bring its backends back
+    if service.monitor && service.backend_available {
+        explain::record_wake(&service_ip, "Monitored services are not woken", decision);
+        return Ok(());
+    }
+    // the gate pinned by the previous agent is stale, patching the replicas could scale the
+    // workload in
+    if warm_up::active() && service.backend_available {
+        utils::request_full_sync();
+        explain::record_wake(
+            &service_ip,
+            "Warming up, the service is already up and its gate reopened",
+            decision,
+        );
+        return Ok(());
+    }
+    let allowed = policy::should_wake(&PolicyContext::new(
+        &service_ip,
+        &service,
+        service.scale_down_time,
+    ));
+    decision.policy = policy::name().map(|name| PolicyInput { name, allowed });
+    if !allowed {
+        explain::record_wake(
+            &service_ip,
+            "The scale policy keeps the service down",
+            decision,
+        );
+        return Ok(());
     }
     service.backend_available = true;
 
-    if service.kind == "deployment" {
-        deployments
-            .patch(
-                service.name.as_str(),
-                &PatchParams::default(),
-                &Patch::Merge(json!({
-                    "spec": {
-                        "replicas": 1
-                    }
-                })),
-            )
-            .await?;
-    } else if service.kind == "statefulset" {
-        statefulsets
-            .patch(
-                service.name.as_str(),
-                &PatchParams::default(),
-                &Patch::Merge(json!({
-                    "spec": {
-                        "replicas": 1
-                    }
-                })),
-            )
-            .await?;
+    if let Some(quota) = service.wake_quota {
+        let exceeded = quota::exceeded(&service_ip, &quota);
+        decision.wake_quota_exceeded = Some(exceeded);
+        if exceeded {
+            quota::alert(&service_ip, &service, &quota).await;
+            if quota.policy == WakeQuotaPolicy::KeepDown {
+                explain::record_wake(&service_ip, "The wake quota is exceeded", decision);
+                return Ok(());
+            }
+        }
+        quota::record(&service_ip);
+    }
+
+    info!(target: "scale_up", "Scaling up backends of {}", service_ip);
+    {
+        let mut recent_wakes = RECENT_WAKES.lock().unwrap();
+        let wakes = recent_wakes.entry(service_ip.clone()).or_default();
+        if wakes.len() == RECENT_WAKES_LEN {
+            wakes.pop_front();
+        }
+        wakes.push_back(chrono::Utc::now().timestamp());
+    }
+    wakes::record(&service_ip, &service);
+    // taken on every agent, so the next scale-down of the service starts from zero everywhere
+    let gated_clients = gated_clients::take(&service_ip);
+    decision.gated_clients = Some(gated_clients);
+    metrics::SERVICE_PENT_UP_CLIENTS
+        .with_label_values(&[&service.namespace, &service.service_name])
+        .set(gated_clients as i64);
+
+    // held until the workload is patched
+    let slot = wake_queue::acquire(&service.service_name, service.priority).await;
+
+    // Every node receiving traffic for the service gets here, only the one holding the lease patches
+    let lease = format!("scale-to-zero-{}-{}", service.kind, service.name);
+    if !lease::try_acquire(&service.namespace, &lease).await? {
+        info!(target: "scale_up", "{} {} is being scaled up by another agent", service.kind, service.name);
+        metrics::SCALE_UPS_DEDUPLICATED.inc();
+        decision.woken = true;
+        explain::record_wake(
+            &service_ip,
+            "Woken by another agent holding the lease",
+            decision,
+        );
+        return Ok(());
+    }
+    let trace_id = wake_trace::start(&service_ip, &service, received);
+    decision.woken = true;
+    explain::record_wake(&service_ip, "Woken", decision);
+    info!(target: "scale_up", trace_id = trace_id.as_str(); "Scaling up {} {}", service.kind, service.name);
+    metrics::SERVICE_WAKES
+        .with_label_values(&[&service.namespace, &service.service_name])
+        .inc();
+
+    // The hook can take minutes, the gate opens once the workload is scaled up after it. The slot
+    // isn't held while the hook runs
+    if let Some(hook) = service.pre_wake_hook.clone() {
+        drop(slot);
+        tokio::spawn(async move {
+            if let Err(err) = hooks::run(&service, &hook, hooks::Phase::PreWake).await {
+                warn!(target: "scale_up", "Not scaling up {} {}, pre-wake hook failed: {:#}", service.kind, service.name, err);
+                return;
+            }
+            let slot = wake_queue::acquire(&service.service_name, service.priority).await;
+            if service.checkpoint {
+                checkpoint::restore(&service).await;
+            }
+            let replicas = wake_replicas(&service_ip, &service, gated_clients).await;
+            if resource_quota::blocks(&service_ip, &service, replicas).await {
+                return;
+            }
+            let scaled_up = retry::set_replicas(&service, replicas).await;
+            drop(slot);
+            match scaled_up {
+                Ok(()) => {
+                    wake_trace::mark(&service_ip, Phase::PatchApplied);
+                    startup::follow(service_ip, service).await
+                }
+                Err(err) => {
+                    warn!(target: "scale_up", "Failed to scale up {} {}: {}", service.kind, service.name, err)
+                }
+            }
+        });
+        return Ok(());
+    }
+
+    if service.checkpoint {
+        checkpoint::restore(&service).await;
+    }
+    let replicas = wake_replicas(&service_ip, &service, gated_clients).await;
+    // pods the quota rejects would leave the workload scaled up without a single one created
+    if resource_quota::blocks(&service_ip, &service, replicas).await {
+        return Ok(());
+    }
+    retry::set_replicas(&service, replicas).await?;
+    wake_trace::mark(&service_ip, Phase::PatchApplied);
+    tokio::spawn(startup::follow(service_ip, service));
+    Ok(())
+}
+
+// Replicas a workload is woken up with, the minReplicas of its HPA which takes over from there,
+// or more for the clients that were gated meanwhile, unless the scale policy asks for another number.
+// A failed HPA lookup doesn't keep the service down, it is woken with a single replica
+async fn wake_replicas(service_ip: &str, service: &ServiceData, gated_clients: usize) -> i32 {
+    let mut replicas = match service.hpa.as_ref() {
+        Some(name) => match hpa::wake(service, name).await {
+            Ok(replicas) => replicas,
+            Err(err) => {
+                warn!(target: "scale_up", "Failed to read minReplicas of HPA {} of {} {}, waking with 1 replica: {:#}", name, service.kind, service.name, err);
+                1
+            }
+        },
+        None => 1,
+    };
+    if let Some(clients_per_replica) = service.clients_per_replica.as_ref() {
+        replicas = clients_per_replica.replicas(gated_clients, replicas);
+    }
+    let mut context = PolicyContext::new(service_ip, service, service.scale_down_time);
+    context.replicas = replicas;
+    context.gated_clients = gated_clients;
+    policy::target_replicas(&context)
+}
+
+// Every agent scales idle workloads down, only the one holding the lease runs the hook
+async fn run_post_scale_down_hook(service: ServiceData, hook: Hook) {
+    let lease = format!(
+        "scale-to-zero-{}-{}-{}",
+        service.kind,
+        service.name,
+        hooks::Phase::PostScaleDown
+    );
+    match lease::try_acquire(&service.namespace, &lease).await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(err) => {
+            warn!(target: "scale_down", "Failed to take lease {}: {}", lease, err);
+            return;
+        }
+    }
+    if let Err(err) = hooks::run(&service, &hook, hooks::Phase::PostScaleDown).await {
+        warn!(target: "scale_down", "Post-scale-down hook of {} {} failed: {:#}", service.kind, service.name, err);
     }
+}
+
+// Set the replicas of a workload with server-side apply, so spec.replicas is owned by FIELD_MANAGER,
+// or through the scale subresource with --gitops, or apply the patch template of the service. Goes
+// through `retry::set_replicas` to be retried on transient errors
+pub async fn set_replicas(service: &ServiceData, replicas: i32) -> anyhow::Result<()> {
+    // the replicas are changed by the operator of the patched object, not recorded as the agent's
+    if patch_template::apply(service, replicas).await? {
+        return Ok(());
+    }
+    let client = super::client().await?;
+    match service.kind.as_str() {
+        "deployment" => {
+            let deployments: Api<Deployment> = Api::namespaced(client, &service.namespace);
+            patch_replicas(&deployments, &service.name, replicas).await?
+        }
+        "statefulset" => {
+            let statefulsets: Api<StatefulSet> = Api::namespaced(client, &service.namespace);
+            patch_replicas(&statefulsets, &service.name, replicas).await?
+        }
+        kind if kruise::is_kruise(kind) => {
+            let api = kruise::api(client, &service.namespace, kind).await?;
+            kruise::set_replicas(&api, &service.name, replicas).await?;
+            if config::get().gitops {
+                annotate_awake_replicas(&api, &service.name, replicas).await?;
+            }
+        }
+        _ => anyhow::bail!("Unknown workload type: {}", service.kind),
+    }
+    gitops::record(service, replicas);
     Ok(())
 }
+
+// GitOps tools diff the spec of the workload against git: with --gitops the spec is left alone,
+// replicas go through the scale subresource and the replicas of the awake workload are written to
+// the awake-replicas annotation, so both can be ignored by the sync
+async fn patch_replicas<K>(api: &Api<K>, name: &str, replicas: i32) -> anyhow::Result<()>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug,
+{
+    if !config::get().gitops {
+        return apply_replicas(api, name, replicas).await;
+    }
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    };
+    let patch = Patch::Merge(json!({
+        "spec": {
+            "replicas": replicas
+        }
+    }));
+    api.patch_scale(name, &params, &patch).await?;
+    annotate_awake_replicas(api, name, replicas).await
+}
+
+async fn annotate_awake_replicas<K>(api: &Api<K>, name: &str, replicas: i32) -> anyhow::Result<()>
+where
+    K: Clone + DeserializeOwned + Debug,
+{
+    // the awake replicas are kept while the workload is down
+    if replicas == 0 {
+        return Ok(());
+    }
+    let params = PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    };
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                annotation_key(AWAKE_REPLICAS_ANNOTATION): replicas.to_string()
+            }
+        }
+    }));
+    api.patch(name, &params, &patch).await?;
+    Ok(())
+}
+
+async fn apply_replicas<K>(api: &Api<K>, name: &str, replicas: i32) -> anyhow::Result<()>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug,
+{
+    let patch = Patch::Apply(json!({
+        "apiVersion": K::api_version(&()),
+        "kind": K::kind(&()),
+        "metadata": {
+            "name": name
+        },
+        "spec": {
+            "replicas": replicas
+        }
+    }));
+
+    match api
+        .patch(name, &PatchParams::apply(FIELD_MANAGER), &patch)
+        .await
+    {
+        Ok(_) => Ok(()),
+        // Another manager owns spec.replicas, make the conflict visible and take the field over
+        Err(kube::Error::Api(err)) if err.code == 409 => {
+            warn!(target: "scaler", "spec.replicas of {} {} is also managed by another field manager: {}", K::kind(&()), name, err.message);
+            metrics::REPLICA_CONFLICTS.inc();
+            api.patch(name, &PatchParams::apply(FIELD_MANAGER).force(), &patch)
+                .await?;
+            Ok(())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Reason the workload of a service must not be scaled down, if any. A wake is never blocked since
+// leaving the traffic gated is always worse than waking a workload someone else manages
+async fn unmanageable_reason(service: &ServiceData) -> anyhow::Result<Option<String>> {
+    // scaling down resumes once the analysis is over
+    if let Some(reason) = canary::active(service) {
+        return Ok(Some(reason));
+    }
+    let client = super::client().await?;
+    match service.kind.as_str() {
+        "deployment" => {
+            let deployments: Api<Deployment> = Api::namespaced(client, &service.namespace);
+            Ok(deployment_unmanageable(
+                &deployments.get(&service.name).await?,
+            ))
+        }
+        "statefulset" => {
+            let statefulsets: Api<StatefulSet> = Api::namespaced(client, &service.namespace);
+            Ok(statefulset_unmanageable(
+                &statefulsets.get(&service.name).await?,
+            ))
+        }
+        kind if kruise::is_kruise(kind) => {
+            let api = kruise::api(client, &service.namespace, kind).await?;
+            let workload = api.get(&service.name).await?;
+            let observed_generation = workload
+                .data
+                .pointer("/status/observedGeneration")
+                .and_then(|generation| generation.as_i64());
+            if let Some(reason) = foreign_owner(&workload.metadata) {
+                return Ok(Some(reason));
+            }
+            if is_generation_pending(&workload.metadata, observed_generation) {
+                return Ok(Some("rollout is in progress".to_string()));
+            }
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+fn deployment_unmanageable(deployment: &Deployment) -> Option<String> {
+    if let Some(reason) = foreign_owner(&deployment.metadata) {
+        return Some(reason);
+    }
+    if deployment.spec.as_ref().and_then(|spec| spec.paused) == Some(true) {
+        return Some("rollout is paused".to_string());
+    }
+    let status = deployment.status.as_ref()?;
+    if is_generation_pending(&deployment.metadata, status.observed_generation)
+        || status.updated_replicas.unwrap_or(0) < status.replicas.unwrap_or(0)
+    {
+        return Some("rollout is in progress".to_string());
+    }
+    None
+}
+
+fn statefulset_unmanageable(statefulset: &StatefulSet) -> Option<String> {
+    if let Some(reason) = foreign_owner(&statefulset.metadata) {
+        return Some(reason);
+    }
+    let status = statefulset.status.as_ref()?;
+    if is_generation_pending(&statefulset.metadata, status.observed_generation)
+        || (status.update_revision.is_some() && status.current_revision != status.update_revision)
+    {
+        return Some("rollout is in progress".to_string());
+    }
+    None
+}
+
+fn is_generation_pending(meta: &ObjectMeta, observed_generation: Option<i64>) -> bool {
+    matches!((meta.generation, observed_generation), (Some(generation), Some(observed)) if observed < generation)
+}
+
+fn foreign_owner(meta: &ObjectMeta) -> Option<String> {
+    meta.owner_references
+        .as_ref()?
+        .iter()
+        .find(|owner| {
+            let group = owner
+                .api_version
+                .rsplit_once('/')
+                .map(|(group, _)| group)
+                .unwrap_or("");
+            owner.controller == Some(true) && !CORE_API_GROUPS.contains(&group)
+        })
+        .map(|owner| format!("owned by {} {}", owner.kind, owner.name))
+}
+
+// Record whether the workload of a service can be managed, logging only when it changes
+fn set_unmanageable(key: &str, service: &ServiceData, reason: Option<String>) {
+    if service.unmanageable == reason {
+        return;
+    }
+    match reason.as_ref() {
+        Some(reason) => {
+            warn!(target: "scale_down", "Not scaling down {} {}: {}", service.kind, service.name, reason)
+        }
+        None => {
+            info!(target: "scale_down", "{} {} can be scaled again", service.kind, service.name)
+        }
+    }
+    metrics::WORKLOAD_UNMANAGEABLE
+        .with_label_values(&[&service.namespace, &service.kind, &service.name])
+        .set(reason.is_some() as i64);
+
+    let mut watched_services = WATCHED_SERVICES.lock().unwrap();
+    if let Some(service) = watched_services.get_mut(key) {
+        service.unmanageable = reason;
+    }
+}
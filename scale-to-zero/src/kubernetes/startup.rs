@@ -0,0 +1,315 @@
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use k8s_openapi::api::core::v1::{Event, Pod};
+use k8s_openapi::chrono::{self, DateTime, Utc};
+use kube::api::{Api, ListParams};
+use kube::runtime::events::EventType;
+use kube::ResourceExt;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::events;
+use super::kruise;
+use super::models::{ServiceData, WATCHED_SERVICES};
+use super::statefulset::is_ready;
+use super::wake_trace::{self, Phase};
+use crate::metrics;
+
+// How long the pods of a woken workload are followed until one of them is ready
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(600);
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+// Waiting reasons of a container that won't go away without a change to the workload or cluster
+const CONTAINER_FAILURE_REASONS: [&str; 6] = [
+    "ErrImagePull",
+    "ImagePullBackOff",
+    "InvalidImageName",
+    "CreateContainerConfigError",
+    "CreateContainerError",
+    "CrashLoopBackOff",
+];
+
+// Service IPs whose startup is being followed
+static FOLLOWED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Follow the pods of a workload that was just scaled up, and report why none of them becomes
+// ready (unschedulable, image pull errors, crash loops) on the service instead of leaving its
+// traffic gated without a word
+pub async fn follow(service_ip: String, service: ServiceData) {
+    if !FOLLOWED.lock().unwrap().insert(service_ip.clone()) {
+        return;
+    }
+    if let Err(err) = follow_pods(&service_ip, &service).await {
+        warn!(target: "startup", "Failed to follow the startup of {} {}: {:#}", service.kind, service.name, err);
+    }
+    FOLLOWED.lock().unwrap().remove(&service_ip);
+}
+
+async fn follow_pods(service_ip: &str, service: &ServiceData) -> anyhow::Result<()> {
+    let client = super::client().await?;
+    let pods: Api<Pod> = Api::namespaced(client.clone(), &service.namespace);
+    let pod_events: Api<Event> = Api::namespaced(client, &service.namespace);
+    let params = ListParams::default().labels(&pod_selector(service).await?);
+    let started = Instant::now();
+    let mut reported: Option<String> = None;
+    let mut waiting_for_node = false;
+
+    while started.elapsed() < STARTUP_TIMEOUT {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let pods = pods.list(&params).await?;
+        if pods.iter().any(is_ready) {
+            if reported.is_some() {
+                info!(target: "startup", "{} {} started", service.kind, service.name);
+            }
+            set_wake_failure(service_ip, None);
+            return Ok(());
+        }
+
+        let mut failures = Vec::new();
+        for pod in pods.iter() {
+            // not stuck, the wake includes the provisioning of a node
+            if needs_node(pod) && triggered_scale_up(&pod_events, &pod.name_any()).await? {
+                if !waiting_for_node {
+                    waiting_for_node = true;
+                    report_waiting_for_node(service_ip, service, &pod.name_any()).await;
+                }
+                continue;
+            }
+            failures.extend(failure(pod));
+        }
+        if failures.is_empty() {
+            continue;
+        }
+        let failure = failures.join("; ");
+        if reported.as_ref() == Some(&failure) {
+            continue;
+        }
+        warn!(target: "startup", "{} {} is not starting: {}", service.kind, service.name, failure);
+        set_wake_failure(service_ip, Some(failure.clone()));
+        let published = events::publish(
+            &service.namespace,
+            &service.service_name,
+            EventType::Warning,
+            "WakeStalled".to_string(),
+            with_trace(service_ip, failure.clone()),
+            "Wake",
+        );
+        if let Err(err) = published.await {
+            warn!(target: "startup", "Failed to record startup failure of service {}: {}", service.service_name, err);
+        }
+        reported = Some(failure);
+    }
+    Ok(())
+}
+
+async fn report_waiting_for_node(service_ip: &str, service: &ServiceData, pod_name: &str) {
+    info!(target: "startup", "{} {} is waiting for the cluster autoscaler to add a node", service.kind, service.name);
+    metrics::WAKES_WAITING_FOR_NODES.inc();
+    let published = events::publish(
+        &service.namespace,
+        &service.service_name,
+        EventType::Normal,
+        "WaitingForNode".to_string(),
+        with_trace(
+            service_ip,
+            format!(
+                "No node can fit pod {}, the cluster autoscaler is adding one",
+                pod_name
+            ),
+        ),
+        "Wake",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "startup", "Failed to record node provisioning of service {}: {}", service.service_name, err);
+    }
+}
+
+// Events of a wake traced by this agent carry its trace id
+fn with_trace(service_ip: &str, note: String) -> String {
+    match wake_trace::trace_id(service_ip) {
+        Some(trace_id) => format!("{} (trace {})", note, trace_id),
+        None => note,
+    }
+}
+
+// Unschedulable for lack of CPU, memory or pods on the existing nodes
+fn needs_node(pod: &Pod) -> bool {
+    pod.status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .map(|conditions| {
+            conditions.iter().any(|c| {
+                c.type_ == "PodScheduled"
+                    && c.status == "False"
+                    && matches!(c.message.as_deref(), Some(message) if message.contains("Insufficient") || message.contains("Too many pods"))
+            })
+        })
+        .unwrap_or(false)
+}
+
+// Whether the cluster autoscaler is adding a node for the pod
+async fn triggered_scale_up(pod_events: &Api<Event>, pod_name: &str) -> anyhow::Result<bool> {
+    let params = ListParams::default().fields(&format!(
+        "involvedObject.kind=Pod,involvedObject.name={},reason=TriggeredScaleUp",
+        pod_name
+    ));
+    Ok(!pod_events.list(&params).await?.items.is_empty())
+}
+
+fn set_wake_failure(service_ip: &str, failure: Option<String>) {
+    if let Some(service) = WATCHED_SERVICES.lock().unwrap().get_mut(service_ip) {
+        service.wake_failure = failure;
+    }
+}
+
+// Label selector of the pods of the workload
+pub async fn pod_selector(service: &ServiceData) -> anyhow::Result<String> {
+    let client = super::client().await?;
+    let labels: BTreeMap<String, String> = match service.kind.as_str() {
+        "deployment" => {
+            let deployments: Api<Deployment> = Api::namespaced(client, &service.namespace);
+            deployments
+                .get(&service.name)
+                .await?
+                .spec
+                .and_then(|spec| spec.selector.match_labels)
+                .unwrap_or_default()
+        }
+        "statefulset" => {
+            let statefulsets: Api<StatefulSet> = Api::namespaced(client, &service.namespace);
+            statefulsets
+                .get(&service.name)
+                .await?
+                .spec
+                .and_then(|spec| spec.selector.match_labels)
+                .unwrap_or_default()
+        }
+        kind if kruise::is_kruise(kind) => {
+            let api = kruise::api(client, &service.namespace, kind).await?;
+            let workload = api.get(&service.name).await?;
+            workload
+                .data
+                .pointer("/spec/selector/matchLabels")
+                .and_then(|labels| labels.as_object())
+                .map(|labels| {
+                    labels
+                        .iter()
+                        .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+        _ => anyhow::bail!("Unknown workload type: {}", service.kind),
+    };
+    if labels.is_empty() {
+        anyhow::bail!("{} {} has no label selector", service.kind, service.name);
+    }
+    Ok(labels
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+// When the first pod ready after a wake was scheduled, had its containers started and became
+// ready, from its status. Pods ready since before the wake (an HPA replica left running) don't
+// count
+pub async fn pod_milestones(
+    service: &ServiceData,
+    received: DateTime<Utc>,
+) -> anyhow::Result<Vec<(Phase, DateTime<Utc>)>> {
+    let client = super::client().await?;
+    let pods: Api<Pod> = Api::namespaced(client, &service.namespace);
+    let params = ListParams::default().labels(&pod_selector(service).await?);
+    let pod = pods
+        .list(&params)
+        .await?
+        .into_iter()
+        .filter_map(|pod| Some((condition_time(&pod, "Ready")?, pod)))
+        .filter(|(ready, _)| *ready >= received - chrono::Duration::seconds(1))
+        .min_by_key(|(ready, _)| *ready);
+    let (ready, pod) = match pod {
+        Some(pod) => pod,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut milestones = vec![(Phase::Ready, ready)];
+    if let Some(scheduled) = condition_time(&pod, "PodScheduled") {
+        milestones.push((Phase::PodScheduled, scheduled));
+    }
+    // the last container to start, the pod can't be ready before it
+    let started = pod
+        .status
+        .as_ref()
+        .and_then(|status| status.container_statuses.as_ref())
+        .and_then(|statuses| {
+            statuses
+                .iter()
+                .map(|status| {
+                    status
+                        .state
+                        .as_ref()
+                        .and_then(|state| state.running.as_ref())
+                        .and_then(|running| running.started_at.as_ref())
+                        .map(|time| time.0)
+                })
+                .collect::<Option<Vec<_>>>()
+        })
+        .and_then(|times| times.into_iter().max());
+    if let Some(started) = started {
+        milestones.push((Phase::ContainerStarted, started));
+    }
+    Ok(milestones)
+}
+
+// When a condition of the pod last became true
+fn condition_time(pod: &Pod, type_: &str) -> Option<DateTime<Utc>> {
+    pod.status
+        .as_ref()?
+        .conditions
+        .as_ref()?
+        .iter()
+        .find(|c| c.type_ == type_ && c.status == "True")?
+        .last_transition_time
+        .as_ref()
+        .map(|time| time.0)
+}
+
+// Why the pod can't start, if it is stuck: not schedulable, or a container that can't be created
+// or keeps crashing
+fn failure(pod: &Pod) -> Option<String> {
+    let status = pod.status.as_ref()?;
+    let unschedulable = status
+        .conditions
+        .iter()
+        .flatten()
+        .find(|c| c.type_ == "PodScheduled" && c.status == "False");
+    if let Some(condition) = unschedulable {
+        return Some(format!(
+            "pod {} {}: {}",
+            pod.name_any(),
+            condition.reason.as_deref().unwrap_or("Unschedulable"),
+            condition.message.as_deref().unwrap_or_default()
+        ));
+    }
+    status
+        .container_statuses
+        .iter()
+        .flatten()
+        .find_map(|container| {
+            let waiting = container.state.as_ref()?.waiting.as_ref()?;
+            let reason = waiting.reason.as_deref()?;
+            if !CONTAINER_FAILURE_REASONS.contains(&reason) {
+                return None;
+            }
+            Some(format!(
+                "pod {} container {} {}: {}",
+                pod.name_any(),
+                container.name,
+                reason,
+                waiting.message.as_deref().unwrap_or_default()
+            ))
+        })
+}
@@ -0,0 +1,150 @@
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::core::v1::{Event, PersistentVolumeClaim, Pod};
+use kube::api::{Api, ListParams};
+use kube::runtime::events::EventType;
+use kube::ResourceExt;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::events;
+
+// Pod events meaning a volume of the pod can't be attached or mounted
+const VOLUME_FAILURE_REASONS: [&str; 2] = ["FailedAttachVolume", "FailedMount"];
+
+// This contains the last volume failure reported for each service, so it is reported once
+static REPORTED_FAILURES: Lazy<Mutex<HashMap<(String, String), String>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub enum Readiness {
+    Ready,
+    Waiting(String),
+    VolumeFailure(String),
+}
+
+// Whether the pods of a scaled up StatefulSet can serve. Replicas >= 1 is far from it: the PVCs of
+// each ordinal have to be bound, the volumes attached and the pods started in order
+pub async fn readiness(statefulset: &StatefulSet) -> anyhow::Result<Readiness> {
+    let client = super::client().await?;
+    let namespace = statefulset.namespace().unwrap_or_default();
+    let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), &namespace);
+    let pods: Api<Pod> = Api::namespaced(client.clone(), &namespace);
+    let pod_events: Api<Event> = Api::namespaced(client, &namespace);
+
+    let name = statefulset.name_any();
+    let spec = match statefulset.spec.as_ref() {
+        Some(spec) => spec,
+        None => return Ok(Readiness::Waiting("no spec".to_string())),
+    };
+    let claim_templates: Vec<String> = spec
+        .volume_claim_templates
+        .iter()
+        .flatten()
+        .map(|template| template.name_any())
+        .collect();
+
+    for ordinal in 0..spec.replicas.unwrap_or(1) {
+        let pod_name = format!("{}-{}", name, ordinal);
+        for template in claim_templates.iter() {
+            let claim = format!("{}-{}", template, pod_name);
+            let phase = pvcs
+                .get_opt(&claim)
+                .await?
+                .and_then(|pvc| pvc.status?.phase);
+            if phase.as_deref() != Some("Bound") {
+                return Ok(Readiness::Waiting(format!("PVC {} is not bound", claim)));
+            }
+        }
+
+        let pod = match pods.get_opt(&pod_name).await? {
+            Some(pod) => pod,
+            None => {
+                return Ok(Readiness::Waiting(format!(
+                    "pod {} is not created",
+                    pod_name
+                )))
+            }
+        };
+        if !is_ready(&pod) {
+            if let Some(failure) = volume_failure(&pod_events, &pod_name).await? {
+                return Ok(Readiness::VolumeFailure(failure));
+            }
+            return Ok(Readiness::Waiting(format!("pod {} is not ready", pod_name)));
+        }
+    }
+    Ok(Readiness::Ready)
+}
+
+pub fn is_ready(pod: &Pod) -> bool {
+    pod.status
+        .as_ref()
+        .and_then(|status| status.conditions.as_ref())
+        .map(|conditions| {
+            conditions
+                .iter()
+                .any(|c| c.type_ == "Ready" && c.status == "True")
+        })
+        .unwrap_or(false)
+}
+
+// Latest volume attach or mount failure of the pod, if any
+async fn volume_failure(pod_events: &Api<Event>, pod_name: &str) -> anyhow::Result<Option<String>> {
+    let params = ListParams::default().fields(&format!(
+        "involvedObject.kind=Pod,involvedObject.name={}",
+        pod_name
+    ));
+    Ok(pod_events
+        .list(&params)
+        .await?
+        .into_iter()
+        .filter(|event| {
+            matches!(event.reason.as_deref(), Some(reason) if VOLUME_FAILURE_REASONS.contains(&reason))
+        })
+        .max_by_key(|event| event.last_timestamp.as_ref().map(|time| time.0))
+        .map(|event| {
+            format!(
+                "{} {}: {}",
+                pod_name,
+                event.reason.unwrap_or_default(),
+                event.message.unwrap_or_default()
+            )
+        }))
+}
+
+// Forget the failures of services no longer watched, by (namespace, service name), returns the
+// number left
+pub fn retain(watched: &HashSet<(String, String)>) -> usize {
+    let mut reported = REPORTED_FAILURES.lock().unwrap();
+    reported.retain(|key, _| watched.contains(key));
+    reported.len()
+}
+
+pub fn clear_volume_failure(namespace: &str, service_name: &str) {
+    REPORTED_FAILURES
+        .lock()
+        .unwrap()
+        .remove(&(namespace.to_string(), service_name.to_string()));
+}
+
+// Surface a volume failure as an event on the service, once per distinct failure
+pub async fn report_volume_failure(namespace: &str, service_name: &str, failure: &str) {
+    let key = (namespace.to_string(), service_name.to_string());
+    {
+        let mut reported = REPORTED_FAILURES.lock().unwrap();
+        if reported.get(&key).map(String::as_str) == Some(failure) {
+            return;
+        }
+        reported.insert(key, failure.to_string());
+    }
+    let published = events::publish(
+        namespace,
+        service_name,
+        EventType::Warning,
+        "VolumeAttachFailed".to_string(),
+        failure.to_string(),
+        "Wake",
+    );
+    if let Err(err) = published.await {
+        log::warn!(target: "statefulset", "Failed to record volume failure of service {}: {}", service_name, err);
+    }
+}
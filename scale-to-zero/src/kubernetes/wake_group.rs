@@ -0,0 +1,98 @@
+use k8s_openapi::api::batch::v1::CronJob;
+use k8s_openapi::serde_json::json;
+use kube::api::{Api, Patch, PatchParams};
+use log::{info, warn};
+
+use super::models::{annotation, annotation_key, ServiceData, WakeGroupMember};
+use super::scaler::FIELD_MANAGER;
+
+// On a CronJob, set while it is suspended by the agent. A CronJob suspended by someone else is left
+// suspended on wake
+const SUSPENDED_ANNOTATION: &str = "suspended-while-idle";
+
+// Follow the replicas of the workload, however they changed (scale-down, wake, manual scale,
+// GitOps): the CronJobs of the wake group are suspended while it is at zero and resumed once it
+// isn't. Run by the controller on every reconcile of the service, so it never races itself
+pub async fn reconcile(service: &ServiceData, replicas: i32) {
+    if replicas == 0 {
+        suspend(service).await
+    } else {
+        resume(service).await
+    }
+}
+
+// Suspend the CronJobs of the wake group of a service that was scaled down, their runs would fail
+// or wake its dependencies while it sleeps
+async fn suspend(service: &ServiceData) {
+    for member in service.wake_group.iter() {
+        let result = match member {
+            WakeGroupMember::CronJob(name) => suspend_cronjob(&service.namespace, name).await,
+        };
+        if let Err(err) = result {
+            warn!(target: "wake_group", "Failed to suspend {} of service {}: {:#}", member, service.service_name, err);
+        }
+    }
+}
+
+// Resume the members of the wake group the agent suspended, on wake or once the service is no
+// longer watched
+pub async fn resume(service: &ServiceData) {
+    for member in service.wake_group.iter() {
+        let result = match member {
+            WakeGroupMember::CronJob(name) => resume_cronjob(&service.namespace, name).await,
+        };
+        if let Err(err) = result {
+            warn!(target: "wake_group", "Failed to resume {} of service {}: {:#}", member, service.service_name, err);
+        }
+    }
+}
+
+async fn suspend_cronjob(namespace: &str, name: &str) -> anyhow::Result<()> {
+    let cronjobs: Api<CronJob> = Api::namespaced(super::client().await?, namespace);
+    let cronjob = cronjobs.get(name).await?;
+    if cronjob.spec.as_ref().and_then(|spec| spec.suspend) == Some(true) {
+        return Ok(());
+    }
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                annotation_key(SUSPENDED_ANNOTATION): "true"
+            }
+        },
+        "spec": {
+            "suspend": true
+        }
+    }));
+    cronjobs.patch(name, &params(), &patch).await?;
+    info!(target: "wake_group", "Suspended CronJob {}", name);
+    Ok(())
+}
+
+async fn resume_cronjob(namespace: &str, name: &str) -> anyhow::Result<()> {
+    let cronjobs: Api<CronJob> = Api::namespaced(super::client().await?, namespace);
+    let cronjob = cronjobs.get(name).await?;
+    let annotations = cronjob.metadata.annotations.unwrap_or_default();
+    if annotation(&annotations, SUSPENDED_ANNOTATION).is_none() {
+        return Ok(());
+    }
+    let patch = Patch::Merge(json!({
+        "metadata": {
+            "annotations": {
+                annotation_key(SUSPENDED_ANNOTATION): null
+            }
+        },
+        "spec": {
+            "suspend": false
+        }
+    }));
+    cronjobs.patch(name, &params(), &patch).await?;
+    info!(target: "wake_group", "Resumed CronJob {}", name);
+    Ok(())
+}
+
+fn params() -> PatchParams {
+    PatchParams {
+        field_manager: Some(FIELD_MANAGER.to_string()),
+        ..Default::default()
+    }
+}
@@ -0,0 +1,116 @@
+use log::info;
+use once_cell::sync::Lazy;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+use super::models::Priority;
+use crate::config;
+use crate::metrics;
+
+// A wake waiting for a slot, the highest priority first and then the oldest
+struct Waiter {
+    priority: Priority,
+    sequence: u64,
+    ready: oneshot::Sender<()>,
+}
+
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+struct Queue {
+    available: usize,
+    sequence: u64,
+    waiting: BinaryHeap<Waiter>,
+}
+
+static QUEUE: Lazy<Mutex<Queue>> = Lazy::new(|| {
+    Mutex::new(Queue {
+        available: config::get().wake_concurrency,
+        sequence: 0,
+        waiting: BinaryHeap::new(),
+    })
+});
+
+// A wake allowed to patch its workload, the slot goes to the next waiting wake when dropped
+pub struct Slot(());
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        release();
+    }
+}
+
+// Waits for a slot while the receiver is pending, a slot handed over after the wait was dropped
+// is passed on
+struct Pending(oneshot::Receiver<()>);
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if self.0.try_recv().is_ok() {
+            release();
+        }
+    }
+}
+
+// Wait for one of the --wake-concurrency slots. When many services wake at once (cluster recovery,
+// end of a maintenance), the high priority ones get theirs first. None without a limit
+pub async fn acquire(service_name: &str, priority: Priority) -> Option<Slot> {
+    if config::get().wake_concurrency == 0 {
+        return None;
+    }
+    let mut pending = {
+        let mut queue = QUEUE.lock().unwrap();
+        if queue.available > 0 {
+            queue.available -= 1;
+            return Some(Slot(()));
+        }
+        let (ready, receiver) = oneshot::channel();
+        queue.sequence += 1;
+        let sequence = queue.sequence;
+        queue.waiting.push(Waiter {
+            priority,
+            sequence,
+            ready,
+        });
+        metrics::WAKES_QUEUED.set(queue.waiting.len() as i64);
+        Pending(receiver)
+    };
+    info!(target: "wake_queue", "Wake of service {} is queued behind the wakes in progress", service_name);
+    // the sender is only dropped once the slot is sent
+    let _ = (&mut pending.0).await;
+    Some(Slot(()))
+}
+
+fn release() {
+    let mut queue = QUEUE.lock().unwrap();
+    while let Some(waiter) = queue.waiting.pop() {
+        // a waiter that gave up doesn't take the slot
+        if waiter.ready.send(()).is_ok() {
+            metrics::WAKES_QUEUED.set(queue.waiting.len() as i64);
+            return;
+        }
+    }
+    metrics::WAKES_QUEUED.set(0);
+    queue.available += 1;
+}
@@ -0,0 +1,208 @@
+use k8s_openapi::chrono::{DateTime, Utc};
+use kube::runtime::events::EventType;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use super::events;
+use super::models::ServiceData;
+use super::startup;
+use crate::metrics;
+use crate::wake_drops;
+
+// The milestones of a wake after the event that triggered it, in order. Each one ends the phase
+// named by its label
+#[derive(Clone, Copy, PartialEq)]
+pub enum Phase {
+    PatchApplied,
+    PodScheduled,
+    ContainerStarted,
+    Ready,
+    GateOpened,
+}
+
+pub const PHASES: [Phase; 5] = [
+    Phase::PatchApplied,
+    Phase::PodScheduled,
+    Phase::ContainerStarted,
+    Phase::Ready,
+    Phase::GateOpened,
+];
+
+impl Phase {
+    pub fn label(&self) -> &'static str {
+        match self {
+            // our overhead: policy, hooks, lease and the patch of the workload
+            Phase::PatchApplied => "patch",
+            // the scheduler, and the cluster autoscaler when a node is added
+            Phase::PodScheduled => "scheduling",
+            // image pull, init containers and container creation
+            Phase::ContainerStarted => "container_start",
+            // the application until its readiness probe passes
+            Phase::Ready => "readiness",
+            // our overhead: seeing the endpoints and opening the gate
+            Phase::GateOpened => "gate",
+        }
+    }
+}
+
+// A wake followed from the event that triggered it until the gate opens. Its trace id (W3C format)
+// is on the log records and events of the wake and on the exemplar of its cold start
+struct Trace {
+    trace_id: String,
+    received: DateTime<Utc>,
+    milestones: Vec<(Phase, DateTime<Utc>)>,
+}
+
+// A wake whose gate didn't open within this long failed, the next wake starts a new trace
+const MAX_TRACE_AGE: Duration = Duration::from_secs(600);
+
+// This contains the wake in progress of each service IP, on the agent that scaled it up
+static TRACES: Lazy<Mutex<HashMap<String, Trace>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Start the trace of a wake from the time its event was received, the id of a wake already in
+// progress is reused
+pub fn start(service_ip: &str, service: &ServiceData, received: SystemTime) -> String {
+    let mut traces = TRACES.lock().unwrap();
+    if let Some(trace) = traces.get(service_ip) {
+        if (Utc::now() - trace.received).num_seconds() < MAX_TRACE_AGE.as_secs() as i64 {
+            return trace.trace_id.clone();
+        }
+    }
+    let trace_id = new_trace_id();
+    info!(target: "wake_trace", trace_id = trace_id.as_str(); "Waking {} {} of service {}", service.kind, service.name, service.service_name);
+    traces.insert(
+        service_ip.to_string(),
+        Trace {
+            trace_id: trace_id.clone(),
+            received: received.into(),
+            milestones: Vec::new(),
+        },
+    );
+    trace_id
+}
+
+// Forget the traces of services no longer watched and of wakes whose gate never opened, returns
+// the number left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    let mut traces = TRACES.lock().unwrap();
+    traces.retain(|ip, trace| {
+        watched.contains(ip)
+            && (Utc::now() - trace.received).num_seconds() < MAX_TRACE_AGE.as_secs() as i64
+    });
+    traces.len()
+}
+
+// Trace id of the wake in progress of a service, if this agent woke it
+pub fn trace_id(service_ip: &str) -> Option<String> {
+    TRACES
+        .lock()
+        .unwrap()
+        .get(service_ip)
+        .map(|trace| trace.trace_id.clone())
+}
+
+// Record a milestone of the wake in progress of a service, when it is reached
+pub fn mark(service_ip: &str, phase: Phase) {
+    if let Some(trace) = TRACES.lock().unwrap().get_mut(service_ip) {
+        trace.milestones.push((phase, Utc::now()));
+    }
+}
+
+// End the trace once the gate of the service opens, its duration is the cold start seen by the
+// clients. The pod milestones come from the status of the pod that became ready. Every agent
+// reports the packets it dropped meanwhile, the one that woke the service on its event
+pub async fn gate_opened(service_ip: &str, service: &ServiceData) {
+    let dropped = wake_drops::take(service_ip);
+    metrics::SERVICE_DROPPED_WHILE_WAKING
+        .with_label_values(&[&service.namespace, &service.service_name])
+        .inc_by(dropped);
+    let mut trace = match TRACES.lock().unwrap().remove(service_ip) {
+        Some(trace) => trace,
+        None => return,
+    };
+    let opened = Utc::now();
+    trace.milestones.push((Phase::GateOpened, opened));
+    match startup::pod_milestones(service, trace.received).await {
+        Ok(milestones) => trace.milestones.extend(milestones),
+        Err(err) => {
+            warn!(target: "wake_trace", "Failed to read the pod milestones of service {}: {:#}", service.service_name, err)
+        }
+    }
+
+    let seconds = seconds_between(trace.received, opened);
+    metrics::observe_cold_start(
+        &service.namespace,
+        &service.service_name,
+        seconds,
+        &trace.trace_id,
+    );
+    let phases = phase_durations(&trace);
+    for (phase, seconds) in phases.iter() {
+        metrics::WAKE_PHASE_SECONDS
+            .with_label_values(&[&service.namespace, &service.service_name, phase.label()])
+            .observe(*seconds);
+    }
+    let breakdown = phases
+        .iter()
+        .map(|(phase, seconds)| format!("{} {:.1}s", phase.label(), seconds))
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!(target: "wake_trace", trace_id = trace.trace_id.as_str(), dropped = dropped; "Service {} is serving again after {:.1}s ({}), {} packets dropped meanwhile", service.service_name, seconds, breakdown, dropped);
+    let published = events::publish(
+        &service.namespace,
+        &service.service_name,
+        EventType::Normal,
+        "Woken".to_string(),
+        format!(
+            "Serving again after a {:.1}s cold start: {}. {} packets dropped on this node meanwhile (trace {})",
+            seconds, breakdown, dropped, trace.trace_id
+        ),
+        "Wake",
+    );
+    if let Err(err) = published.await {
+        warn!(target: "wake_trace", "Failed to record wake event of service {}: {}", service.service_name, err);
+    }
+}
+
+// Duration of each phase whose start and end are both known. A phase whose start is missing, like
+// the pod milestones of a wake whose pod can't be found, isn't reported rather than being mixed
+// with the one before it
+fn phase_durations(trace: &Trace) -> Vec<(Phase, f64)> {
+    let mut durations = Vec::new();
+    let mut previous = Some(trace.received);
+    for phase in PHASES {
+        // the first time a milestone is reached, a wake can patch the workload again
+        let reached = trace
+            .milestones
+            .iter()
+            .filter(|(milestone, _)| *milestone == phase)
+            .map(|(_, time)| *time)
+            .min();
+        if let (Some(start), Some(end)) = (previous, reached) {
+            durations.push((phase, seconds_between(start, end)));
+        }
+        previous = reached;
+    }
+    durations
+}
+
+// Pod timestamps have a one second resolution and come from the clocks of other nodes
+fn seconds_between(start: DateTime<Utc>, end: DateTime<Utc>) -> f64 {
+    ((end - start).num_milliseconds() as f64 / 1000.0).max(0.0)
+}
+
+// 16 random bytes in hex, an all zero id is invalid
+fn new_trace_id() -> String {
+    let mut bytes = [0u8; 16];
+    loop {
+        let filled =
+            unsafe { libc::getrandom(bytes.as_mut_ptr() as *mut libc::c_void, bytes.len(), 0) };
+        if filled == bytes.len() as isize && bytes.iter().any(|byte| *byte != 0) {
+            break;
+        }
+    }
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,122 @@
+use k8s_openapi::chrono;
+use kube::runtime::events::EventType;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use super::events;
+use super::models::ServiceData;
+use crate::config;
+use crate::metrics;
+
+// Wakes are counted over the last hour
+const WINDOW: i64 = 3600;
+
+// The scale-down-time of a service is never extended further than this
+const MAX_EXTENSION: f64 = 16.0;
+
+// This contains the times (unix seconds) of the wakes of each service IP within the last hour
+static WAKES: Lazy<Mutex<HashMap<String, VecDeque<i64>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Service IPs currently waking too often, alerted once until they calm down
+static FLAGGED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Factor applied to the scale-down-time of the service IPs that were flagged, until they are next
+// scaled down
+static EXTENSIONS: Lazy<Mutex<HashMap<String, f64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Forget the services no longer watched, returns the number of services left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    FLAGGED.lock().unwrap().retain(|ip| watched.contains(ip));
+    EXTENSIONS
+        .lock()
+        .unwrap()
+        .retain(|ip, _| watched.contains(ip));
+    let mut wakes = WAKES.lock().unwrap();
+    wakes.retain(|ip, _| watched.contains(ip));
+    wakes.len()
+}
+
+// Count a wake of the service and flag it once it wakes more than --excessive-wakes times an hour,
+// usually a scale-down-time shorter than the pauses of its clients or a chatty dependency. Called
+// on the wake path, the event is published in the background
+pub fn record(service_ip: &str, service: &ServiceData) {
+    let opts = config::get();
+    if opts.excessive_wakes == 0 {
+        return;
+    }
+    let now = chrono::Utc::now().timestamp();
+    let count = {
+        let mut wakes = WAKES.lock().unwrap();
+        let wakes = wakes.entry(service_ip.to_string()).or_default();
+        while matches!(wakes.front(), Some(time) if now - time >= WINDOW) {
+            wakes.pop_front();
+        }
+        wakes.push_back(now);
+        wakes.len()
+    };
+
+    let labels = [service.namespace.as_str(), service.service_name.as_str()];
+    if count <= opts.excessive_wakes {
+        if FLAGGED.lock().unwrap().remove(service_ip) {
+            metrics::EXCESSIVE_WAKES.with_label_values(&labels).set(0);
+        }
+        return;
+    }
+    let flagged = FLAGGED.lock().unwrap().insert(service_ip.to_string());
+    // a service still waking too often after its extension ran out is extended again
+    let extension = opts.excessive_wake_extension.and_then(|factor| {
+        let mut extensions = EXTENSIONS.lock().unwrap();
+        if extensions.contains_key(service_ip) {
+            return None;
+        }
+        let extension = factor.min(MAX_EXTENSION);
+        extensions.insert(service_ip.to_string(), extension);
+        Some((service.scale_down_time as f64 * extension) as i64)
+    });
+    if !flagged {
+        if let Some(scale_down_time) = extension {
+            info!(target: "wakes", "Service {} still wakes too often, scale-down-time extended to {}s", service.service_name, scale_down_time);
+        }
+        return;
+    }
+    metrics::EXCESSIVE_WAKES.with_label_values(&labels).set(1);
+
+    let mut note = format!(
+        "{} wakes within the last hour, its scale-down-time may be shorter than the pauses of its clients",
+        count
+    );
+    if let Some(scale_down_time) = extension {
+        note = format!("{}, scale-down-time extended to {}s", note, scale_down_time);
+    }
+    warn!(target: "wakes", "Service {} wakes too often: {}", service.service_name, note);
+    let (namespace, service_name) = (service.namespace.clone(), service.service_name.clone());
+    tokio::spawn(async move {
+        let published = events::publish(
+            &namespace,
+            &service_name,
+            EventType::Warning,
+            "ExcessiveWakes".to_string(),
+            note,
+            "Wake",
+        );
+        if let Err(err) = published.await {
+            warn!(target: "wakes", "Failed to record excessive wakes event of service {}: {}", service_name, err);
+        }
+    });
+}
+
+// The extension of a service ends once it is scaled down, the next one only comes from wakes
+pub fn reset(service_ip: &str) {
+    EXTENSIONS.lock().unwrap().remove(service_ip);
+}
+
+// Scale-down-time of a service, extended if it was flagged for waking too often
+pub fn scale_down_time(service_ip: &str, scale_down_time: i64) -> i64 {
+    match EXTENSIONS.lock().unwrap().get(service_ip) {
+        Some(extension) => (scale_down_time as f64 * extension) as i64,
+        None => scale_down_time,
+    }
+}
@@ -0,0 +1,78 @@
+use k8s_openapi::chrono;
+use log::info;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+use super::models::WATCHED_SERVICES;
+use crate::config;
+use crate::metrics;
+use crate::utils;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WarmUp {
+    pub active: bool,
+    // Seconds left, 0 once it is over
+    pub remaining: u64,
+}
+
+// End of the warm-up of this agent
+static ENDS_AT: OnceCell<Instant> = OnceCell::new();
+
+// After a restart the agent rebuilds its state (watched services, endpoints, the activity shared by
+// the other agents) from scratch. For --warm-up seconds no service is scaled down, and wakes of
+// services that are up (stale pinned gates) only reopen their gate. Then the idle timers restart
+// and every gate is written again
+pub fn start() {
+    let opts = config::get();
+    // a simulation starts from nothing, there is no state to rebuild
+    let warm_up = match opts.simulate {
+        Some(_) => Duration::ZERO,
+        None => Duration::from_secs(opts.warm_up),
+    };
+    if ENDS_AT.set(Instant::now() + warm_up).is_err() || warm_up.is_zero() {
+        return;
+    }
+    metrics::WARMING_UP.set(1);
+    info!(target: "warm_up", "Warming up for {}s, services are not scaled down meanwhile", warm_up.as_secs());
+    tokio::spawn(async move {
+        tokio::time::sleep(warm_up).await;
+        finish();
+    });
+}
+
+pub fn active() -> bool {
+    ENDS_AT
+        .get()
+        .is_some_and(|ends_at| Instant::now() < *ends_at)
+}
+
+pub fn get() -> WarmUp {
+    let remaining = ENDS_AT.get().map_or(Duration::ZERO, |ends_at| {
+        ends_at.saturating_duration_since(Instant::now())
+    });
+    WarmUp {
+        active: !remaining.is_zero(),
+        remaining: remaining.as_secs(),
+    }
+}
+
+// Activity seen before the agent was fully up is incomplete, every service gets its whole
+// scale-down-time from here
+fn finish() {
+    let now = chrono::Utc::now().timestamp();
+    let (awake, asleep) = {
+        let mut watched_services = WATCHED_SERVICES.lock().unwrap();
+        for service in watched_services.values_mut() {
+            service.last_packet_time = service.last_packet_time.max(now);
+        }
+        let awake = watched_services
+            .values()
+            .filter(|service| service.backend_available)
+            .count();
+        (awake, watched_services.len() - awake)
+    };
+    utils::request_full_sync();
+    metrics::WARMING_UP.set(0);
+    info!(target: "warm_up", "Warm-up over with {} services up and {} down, idle timers restarted", awake, asleep);
+}
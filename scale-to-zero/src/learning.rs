@@ -0,0 +1,130 @@
+use aya::maps::{HashMap, MapData};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::config;
+use crate::destination_filter;
+use crate::kubernetes::models::{ObservedService, OBSERVED_SERVICES};
+use crate::utils;
+
+// Number of gaps kept per service to compute the suggested scale-down-time
+const MAX_GAPS: usize = 256;
+
+pub async fn observe(mut observed_map: HashMap<MapData, u32, u64>) {
+    let opts = config::get();
+    let mut last_report = Instant::now();
+    loop {
+        sync_observed_list(&mut observed_map);
+        update_activity(&observed_map);
+
+        if last_report.elapsed() >= Duration::from_secs(opts.learning_report_interval) {
+            report(opts.learning_min_idle);
+            last_report = Instant::now();
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// Make sure the kernel only tracks the services known to the controller
+fn sync_observed_list(observed_map: &mut HashMap<MapData, u32, u64>) {
+    let observed_ips: HashSet<u32> = OBSERVED_SERVICES
+        .lock()
+        .unwrap()
+        .keys()
+        .filter_map(|k| k.parse::<Ipv4Addr>().ok())
+        .map(u32::from)
+        .collect();
+
+    for ip in observed_ips.iter() {
+        if observed_map.get(ip, 0).is_err() {
+            destination_filter::insert(*ip);
+            if let Err(err) = observed_map.insert(ip, 0u64, 0) {
+                warn!(target: "learning", "Failed to observe {}: {}", Ipv4Addr::from(*ip), err);
+            }
+        }
+    }
+
+    let keys: Vec<u32> = observed_map.keys().filter_map(|k| k.ok()).collect();
+    for ip in keys {
+        if !observed_ips.contains(&ip) {
+            let _ = observed_map.remove(&ip);
+        }
+    }
+}
+
+fn update_activity(observed_map: &HashMap<MapData, u32, u64>) {
+    let mut observed = OBSERVED_SERVICES.lock().unwrap();
+    for entry in observed_map.iter() {
+        let (ip, last_seen) = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let service = match observed.get_mut(&Ipv4Addr::from(ip).to_string()) {
+            Some(service) => service,
+            None => continue,
+        };
+        if last_seen != 0 && last_seen > service.last_seen_ns {
+            let gap = (last_seen - service.last_seen_ns) / 1_000_000_000;
+            record_gap(service, gap);
+            service.last_seen_ns = last_seen;
+        }
+    }
+}
+
+fn record_gap(service: &mut ObservedService, gap: u64) {
+    if gap == 0 {
+        return;
+    }
+    service.longest_idle = service.longest_idle.max(gap);
+    if service.gaps.len() == MAX_GAPS {
+        service.gaps.pop_front();
+    }
+    service.gaps.push_back(gap);
+}
+
+fn report(min_idle: u64) {
+    let now = utils::monotonic_now_ns();
+    let observed = OBSERVED_SERVICES.lock().unwrap();
+    let mut candidates = 0;
+    for (ip, service) in observed.iter() {
+        let current_idle = now.saturating_sub(service.last_seen_ns) / 1_000_000_000;
+        let longest_idle = service.longest_idle.max(current_idle);
+        if longest_idle < min_idle {
+            continue;
+        }
+        candidates += 1;
+        info!(
+            target: "learning",
+            "Candidate {}/{} ({}): longest idle {}s, currently idle {}s, suggested scale-down-time: {}",
+            service.namespace,
+            service.name,
+            ip,
+            longest_idle,
+            current_idle,
+            recommend_scale_down_time(service.gaps.iter().copied(), min_idle, 95, 2.0)
+        );
+    }
+    info!(target: "learning", "{} of {} observed services are scale-to-zero candidates", candidates, observed.len());
+}
+
+// Suggest a timeout that outlasts the pauses within a burst of traffic: a percentile of the gaps
+// shorter than min_idle (longer ones are idle periods worth scaling down for), times the margin
+pub fn recommend_scale_down_time(
+    gaps: impl Iterator<Item = u64>,
+    min_idle: u64,
+    percentile: usize,
+    margin: f64,
+) -> u64 {
+    let mut short_gaps: Vec<u64> = gaps.filter(|gap| *gap < min_idle).collect();
+    if short_gaps.is_empty() {
+        return 60;
+    }
+    short_gaps.sort_unstable();
+    let gap = short_gaps[(short_gaps.len() - 1) * percentile / 100];
+
+    // round up to the next minute, never suggest less than a minute
+    let seconds = (gap as f64 * margin).ceil() as u64;
+    (seconds.div_ceil(60) * 60).max(60)
+}
@@ -0,0 +1,249 @@
+use k8s_openapi::serde_json::{Map, Value};
+use log::kv::{self, Key, Visitor};
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::{Lazy, OnceCell};
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::io::Write;
+use std::sync::{Mutex, RwLock};
+
+// Fields the eBPF program puts in front of its records
+const EBPF_FIELDS: [&str; 3] = ["program", "ifindex", "cpu"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    // `[time level target] message key=value...`
+    Text,
+    // one JSON object per record, the fields are keys of the object
+    Json,
+}
+
+static FORMAT: OnceCell<LogFormat> = OnceCell::new();
+
+// Filter of the records, in the RUST_LOG syntax of env_logger minus the regex part
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    // Level of the targets without a level of their own, error when not set
+    pub default: Option<LevelFilter>,
+    pub targets: BTreeMap<String, LevelFilter>,
+}
+
+impl Filter {
+    pub fn parse(spec: &str) -> anyhow::Result<Filter> {
+        let mut filter = Filter::default();
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let level = level
+                        .trim()
+                        .parse()
+                        .map_err(|_| anyhow::anyhow!("Unknown log level: {}", level))?;
+                    filter.targets.insert(target.trim().to_string(), level);
+                }
+                // a bare directive is either the default level or a target logging everything
+                None => match directive.parse() {
+                    Ok(level) => filter.default = Some(level),
+                    Err(_) => {
+                        filter
+                            .targets
+                            .insert(directive.to_string(), LevelFilter::Trace);
+                    }
+                },
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn spec(&self) -> String {
+        self.default
+            .map(|level| level.to_string().to_lowercase())
+            .into_iter()
+            .chain(
+                self.targets.iter().map(|(target, level)| {
+                    format!("{}={}", target, level.to_string().to_lowercase())
+                }),
+            )
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+// env_logger can't change its filter once installed, the logger is rebuilt on every change instead
+struct ReloadableLogger {
+    inner: RwLock<env_logger::Logger>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
+
+static FILTER: Lazy<Mutex<Filter>> = Lazy::new(|| {
+    // an invalid RUST_LOG is reported by init, once there is a logger
+    Mutex::new(Filter::parse(&rust_log()).unwrap_or_default())
+});
+
+fn rust_log() -> String {
+    std::env::var("RUST_LOG").unwrap_or_default()
+}
+
+static LOGGER: Lazy<ReloadableLogger> = Lazy::new(|| ReloadableLogger {
+    inner: RwLock::new(build(&FILTER.lock().unwrap())),
+});
+
+fn build(filter: &Filter) -> env_logger::Logger {
+    let format = *FORMAT.get().unwrap_or(&LogFormat::Text);
+    env_logger::Builder::new()
+        .parse_filters(&filter.spec())
+        .format(move |buf, record| {
+            let mut fields = Fields::default();
+            let _ = record.key_values().visit(&mut fields);
+            match format {
+                LogFormat::Text => {
+                    write!(
+                        buf,
+                        "[{} {:<5} {}] {}",
+                        buf.timestamp(),
+                        record.level(),
+                        record.target(),
+                        record.args()
+                    )?;
+                    for (key, value) in fields.0.iter() {
+                        write!(buf, " {}={}", key, value)?;
+                    }
+                    writeln!(buf)
+                }
+                LogFormat::Json => {
+                    let mut object = Map::new();
+                    object.insert("time".into(), buf.timestamp().to_string().into());
+                    object.insert("level".into(), record.level().as_str().into());
+                    object.insert("target".into(), record.target().into());
+                    object.insert("message".into(), record.args().to_string().into());
+                    for (key, value) in fields.0 {
+                        object.insert(key, value.into());
+                    }
+                    writeln!(buf, "{}", Value::Object(object))
+                }
+            }
+        })
+        .build()
+}
+
+// Key-values of a record, in order
+#[derive(Default)]
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> Visitor<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+// Install the logger, configured from RUST_LOG like env_logger
+pub fn init(format: LogFormat) {
+    let _ = FORMAT.set(format);
+    let logger: &'static ReloadableLogger = &LOGGER;
+    log::set_logger(logger).expect("logger is already installed");
+    log::set_max_level(logger.inner.read().unwrap().filter());
+    // the default filter only lets errors through
+    if let Err(err) = Filter::parse(&rust_log()) {
+        log::error!(target: "logging", "Ignoring RUST_LOG: {}", err);
+    }
+}
+
+pub fn filter() -> Filter {
+    FILTER.lock().unwrap().clone()
+}
+
+// Set the level of a target, or the default level without one. A target without a level falls
+// back to the default level again
+pub fn set_level(target: Option<&str>, level: Option<LevelFilter>) {
+    let mut filter = FILTER.lock().unwrap();
+    match (target, level) {
+        (Some(target), Some(level)) => {
+            filter.targets.insert(target.to_string(), level);
+        }
+        (Some(target), None) => {
+            filter.targets.remove(target);
+        }
+        (None, level) => filter.default = level,
+    }
+
+    let logger = build(&filter);
+    log::set_max_level(logger.filter());
+    *LOGGER.inner.write().unwrap() = logger;
+    log::info!(target: "logging", "Log filter set to {:?}", filter.spec());
+}
+
+// Handed to aya-log, turns the fields at the start of the eBPF records into key-values of the
+// record (the interface index into the interface name) before passing it to the logger
+pub struct EbpfLogger;
+
+impl Log for EbpfLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        log::logger().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let message = record.args().to_string();
+        let mut fields: Vec<(&str, String)> = Vec::new();
+        let mut rest = message.as_str();
+        while let Some((field, tail)) = rest.split_once(' ') {
+            match field.split_once('=') {
+                Some(("ifindex", "0")) => {}
+                Some(("ifindex", value)) => {
+                    let name = value.parse().ok().and_then(interface_name);
+                    fields.push(("interface", name.unwrap_or_else(|| value.to_string())));
+                }
+                Some((key, value)) if EBPF_FIELDS.contains(&key) => {
+                    fields.push((key, value.to_string()))
+                }
+                _ => break,
+            }
+            rest = tail;
+        }
+
+        let key_values: Vec<(&str, &str)> = fields
+            .iter()
+            .map(|(key, value)| (*key, value.as_str()))
+            .collect();
+        log::logger().log(
+            &Record::builder()
+                .args(format_args!("{}", rest))
+                .level(record.level())
+                .target(record.target())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .key_values(&key_values)
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        log::logger().flush()
+    }
+}
+
+fn interface_name(ifindex: u32) -> Option<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let name = unsafe { libc::if_indextoname(ifindex, buf.as_mut_ptr()) };
+    if name.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
@@ -1,86 +1,297 @@
-use aya::{
-    maps::{perf::AsyncPerfEventArray, HashMap},
-    programs::{Xdp, XdpFlags},
-    util::online_cpus,
+use activity::{ActivitySource, PacketSocketSource, XdpSource};
+use aya::maps::{
+    perf::AsyncPerfEventArray, Array, HashMap, MapData, PerCpuArray, PerCpuHashMap,
+    XskMap,
 };
-use bytes::BytesMut;
+use aya_log::BpfLogger;
 use log::{info, warn};
-use network_interface::NetworkInterface;
-use network_interface::NetworkInterfaceConfig;
-use scale_to_zero_common::PacketLog;
+use scale_to_zero_common::ServicePolicy;
 use tokio::task;
 
+mod activity;
+mod admin;
+mod agent_traffic;
+mod attach;
+mod auth;
+mod capture;
+mod config;
+mod custom_metrics;
+mod dashboard;
+mod destination_filter;
+mod diagnostics;
+#[cfg(all(test, feature = "e2e"))]
+mod e2e;
+mod gated_clients;
+mod hold;
 mod kubernetes;
+mod learning;
+mod logging;
+mod metrics;
+mod migration;
+mod policy;
+mod privileges;
+mod recorder;
+mod runtime;
+mod selftest;
+mod simulation;
+mod sni;
+mod standalone;
+mod talkers;
 mod utils;
+mod validate;
+mod wake_drops;
+mod waking_page;
 
-#[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
-    env_logger::init();
+fn main() -> Result<(), anyhow::Error> {
+    let opts = config::init();
+    logging::init(opts.log_format);
+    // The agent started by the loader of --drop-privileges gives up the capabilities of the setup
+    // before the runtime starts its threads, capabilities are per thread
+    let handoff = privileges::handoff()?;
+    if handoff.is_some() {
+        privileges::drop_for_agent()?;
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(opts, handoff))
+}
 
-    // Start kubernetes event watcher in background
-    task::spawn(async move {
-        kubernetes::controller::kube_event_watcher().await.unwrap();
-    });
+async fn run(
+    opts: &'static config::Options,
+    handoff: Option<privileges::Handoff>,
+) -> Result<(), anyhow::Error> {
+    // needs the agent built with --cfg tokio_unstable too
+    #[cfg(feature = "tokio-console")]
+    console_subscriber::init();
+    if opts.validate {
+        return validate::run(opts).await;
+    }
+    // This process only sets up the datapath, the network-facing agent runs in a child process
+    if opts.drop_privileges && handoff.is_none() && opts.simulate.is_none() {
+        let datapath = if opts.userspace_datapath {
+            None
+        } else {
+            load_datapath(opts)?
+        };
+        return privileges::run_loader(datapath).await;
+    }
+    policy::init(opts)?;
+    kubernetes::warm_up::start();
+
+    if let Some(path) = opts.standalone.as_ref() {
+        // The targets of the static config are woken and put to sleep by their executors
+        standalone::init(path)?;
+        task::spawn(standalone::scale_down());
+    } else {
+        // Start kubernetes event watcher in background
+        task::spawn(async move {
+            kubernetes::controller::kube_event_watcher().await.unwrap();
+        });
 
-    // Start kubernetes scaler in background
+        // Start kubernetes scaler in background
+        task::spawn(async move {
+            kubernetes::scaler::scale_down().await.unwrap();
+        });
+        task::spawn(kubernetes::scaler::wake_for_active_hours());
+
+        // Log output and mesh requests of the services that opted in count as activity, whatever
+        // the datapath
+        let sources: [Box<dyn ActivitySource>; 2] = [
+            Box::new(kubernetes::log_activity::LogSource),
+            Box::new(kubernetes::mesh::MeshSource),
+        ];
+        for source in sources {
+            info!("Starting {} activity source", source.name());
+            source.start()?;
+        }
+    }
+
+    // Start admin API in background
     task::spawn(async move {
-        kubernetes::scaler::scale_down().await.unwrap();
+        admin::serve(opts.admin_addr).await.unwrap();
     });
+    if let Some(addr) = opts.waking_page_addr {
+        let listener = waking_page::bind(addr).await?;
+        task::spawn(async move {
+            if let Err(err) = waking_page::serve(listener).await {
+                warn!("Waking page stopped: {:#}", err);
+            }
+        });
+    }
+
+    // Export per-service metrics in background
+    task::spawn(utils::export_service_metrics());
+    task::spawn(runtime::observe());
+    task::spawn(kubernetes::eviction::evict());
+
+    // Replay a recording in place of the eBPF datapath, no root or network interface needed
+    if let Some(recording) = opts.simulate.as_ref() {
+        let source: Box<dyn ActivitySource> = Box::new(simulation::ReplaySource::from_file(
+            recording,
+            opts.simulate_speed,
+        )?);
+        info!("Starting {} activity source", source.name());
+        source.start()?;
 
-    let mut bpf = utils::load_ebpf_code()?;
-
-    let program: &mut Xdp = bpf
-        .program_mut("xdp_scale_to_zero_fw")
-        .unwrap()
-        .try_into()?;
-    program.load()?;
-
-    // Deploy eBPF program to all network interfaces
-    let network_interfaces = NetworkInterface::show().unwrap();
-    let network_interfaces = network_interfaces
-        .iter()
-        .map(|itf| itf.name.clone())
-        .collect::<Vec<_>>();
-
-    // let attach_modes = [XdpFlags::default(), XdpFlags::SKB_MODE, XdpFlags::HW_MODE];
-    for itf in network_interfaces.iter() {
-        info!("Attach to interface {} with {:?}", itf, XdpFlags::SKB_MODE);
-        match program.attach(&itf, XdpFlags::SKB_MODE) {
-            Ok(_) => {}
-            Err(err) => {
-                warn!("Failed to detach from interface {}: {}", itf, err);
+        tokio::signal::ctrl_c().await?;
+        return Ok(());
+    }
+
+    // The maps of the datapath, handed over by the loader or taken from the one attached here
+    let (mut maps, datapath) = match handoff {
+        Some(handoff) => {
+            if let Some(program) = handoff.program {
+                selftest::init(program);
+            }
+            if let Some(status) = handoff.status {
+                task::spawn(attach::follow_status(status));
             }
+            (handoff.maps, None)
         }
+        None if opts.userspace_datapath => (std::collections::HashMap::new(), None),
+        None => match load_datapath(opts)? {
+            Some(mut datapath) => (datapath.take_maps(), Some(datapath)),
+            None => (std::collections::HashMap::new(), None),
+        },
+    };
+
+    // Idle detection only, gated traffic is not dropped
+    if maps.is_empty() {
+        let source: Box<dyn ActivitySource> = Box::new(PacketSocketSource::new()?);
+        info!("Starting {} activity source", source.name());
+        source.start()?;
+
+        tokio::signal::ctrl_c().await?;
+        return Ok(());
     }
 
+    let mut scalable_service_list: HashMap<MapData, u32, ServicePolicy> =
+        HashMap::try_from(maps.remove("SERVICE_LIST").unwrap())?;
+    utils::adopt_service_list(&scalable_service_list);
+
+    // Packets to destinations without an entry in any map skip the lookups once it is built
+    destination_filter::init(Array::try_from(maps.remove("DESTINATION_FILTER").unwrap())?);
+    task::spawn(destination_filter::maintain());
+
     // Initialize perf event array to receive messages from eBPF program
-    let mut perf_array = AsyncPerfEventArray::try_from(bpf.take_map("SCALE_REQUESTS").unwrap())?;
+    let perf_array = AsyncPerfEventArray::try_from(maps.remove("SCALE_REQUESTS").unwrap())?;
+    let wake_array = AsyncPerfEventArray::try_from(maps.remove("WAKE_REQUESTS").unwrap())?;
+    let source: Box<dyn ActivitySource> = Box::new(XdpSource::new(perf_array, wake_array));
+    info!("Starting {} activity source", source.name());
+    source.start()?;
 
-    // Poll perf event array in background
-    for cpu_id in online_cpus()? {
-        let mut buf = perf_array.open(cpu_id, None)?;
+    // Start the debug capture of gated packets, captures are requested through the admin API
+    let capture_map: HashMap<MapData, u32, u32> =
+        HashMap::try_from(maps.remove("CAPTURE_LIST").unwrap())?;
+    task::spawn(capture::sync_capture_list(capture_map));
+    capture::start_readers(AsyncPerfEventArray::try_from(
+        maps.remove("CAPTURED_PACKETS").unwrap(),
+    )?)?;
 
-        task::spawn(async move {
-            let mut buffers = (0..10)
-                .map(|_| BytesMut::with_capacity(1024))
-                .collect::<Vec<_>>();
-
-            loop {
-                let events = buf.read_events(&mut buffers).await.unwrap();
-                for buf in buffers.iter_mut().take(events.read) {
-                    let ptr = buf.as_ptr() as *const PacketLog;
-                    let data = unsafe { ptr.read_unaligned() };
-                    utils::process_packet(data).await;
-                }
-            }
-        });
+    // Track traffic to services that are not annotated and report scale-to-zero candidates
+    if opts.learning_mode {
+        let observed_map: HashMap<MapData, u32, u64> =
+            HashMap::try_from(maps.remove("OBSERVED_SERVICES").unwrap())?;
+        task::spawn(learning::observe(observed_map));
+    }
+
+    let dropped: PerCpuArray<MapData, u64> =
+        PerCpuArray::try_from(maps.remove("DROPPED_PACKETS").unwrap())?;
+    task::spawn(utils::export_dropped_packets(dropped));
+
+    // Hold the gated packets of latency-critical services instead of dropping them
+    if let Some(interface) = opts.hold_interface.as_ref() {
+        let sockets = XskMap::try_from(maps.remove("HELD_PACKETS").unwrap())?;
+        let hold_interface = Array::try_from(maps.remove("HOLD_INTERFACE").unwrap())?;
+        hold::start(sockets, hold_interface, interface, opts.hold_max_frames)?;
+    }
+
+    // Count packets sent to backend pods as activity of their service
+    let pod_map: HashMap<MapData, u32, u32> =
+        HashMap::try_from(maps.remove("POD_TO_SERVICE").unwrap())?;
+    task::spawn(utils::sync_pod_list(pod_map));
+
+    // Gate the addresses announced by bare-metal load balancers like the ClusterIP of their service
+    let load_balancer_map = HashMap::try_from(maps.remove("LOAD_BALANCER_IPS").unwrap())?;
+    task::spawn(utils::sync_load_balancer_ips(load_balancer_map));
+
+    // Gate the IPv6 addresses of dual-stack services like their IPv4 ClusterIP
+    let ipv6_map = HashMap::try_from(maps.remove("IPV6_SERVICES").unwrap())?;
+    task::spawn(utils::sync_ipv6_services(ipv6_map));
+
+    // Count packets sent to the node ports of backend pods with a hostPort or on the host network
+    let host_port_map = HashMap::try_from(maps.remove("HOST_PORTS").unwrap())?;
+    task::spawn(utils::sync_host_ports(host_port_map));
+
+    // Addresses the connects to gated services with gated-action redirect go to
+    let redirect_map = HashMap::try_from(maps.remove("GATE_REDIRECTS").unwrap())?;
+    task::spawn(utils::sync_gate_redirects(redirect_map));
+
+    // Clients gated while their service was scaled down, they size its wake
+    gated_clients::init(HashMap::try_from(maps.remove("GATED_CLIENTS").unwrap())?);
+
+    // Packets dropped while their service wakes, reported when its gate opens
+    wake_drops::init(PerCpuHashMap::try_from(maps.remove("WAKE_DROPS").unwrap())?);
+
+    // Traffic of the agent itself never counts as activity
+    agent_traffic::init(Array::try_from(maps.remove("AGENT_TRAFFIC").unwrap())?)?;
+
+    // Wake services by the server name of the TLS connections to shared entrypoints
+    if !opts.sni_entrypoints.is_empty() {
+        let mut entrypoints_map = HashMap::try_from(maps.remove("SNI_ENTRYPOINTS").unwrap())?;
+        sni::add_entrypoints(&mut entrypoints_map, &opts.sni_entrypoints)?;
+        sni::start_readers(AsyncPerfEventArray::try_from(
+            maps.remove("CLIENT_HELLOS").unwrap(),
+        )?)?;
+    }
+
+    // Without a loader the agent attaches the datapath to new interfaces itself
+    if let Some(datapath) = datapath {
+        // The programs of older agents were swapped out of the links, their maps are no longer
+        // used
+        migration::remove_stale(&opts.pin_path);
+        task::spawn(datapath.watch_interfaces());
     }
 
     // sync scalable_service_list with SCALABLE_PODS
-    let mut scalable_service_list: HashMap<_, u32, u32> =
-        HashMap::try_from(bpf.map_mut("SERVICE_LIST").unwrap()).unwrap();
     loop {
-        utils::sync_data(&mut scalable_service_list).await;
+        runtime::instrument("sync", utils::sync_data(&mut scalable_service_list)).await;
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     }
 }
+
+// Load the eBPF datapath and deploy it to all network interfaces, and to the ones created later. A
+// node where the XDP program can't be loaded or attached anywhere gets the userspace datapath
+fn load_datapath(opts: &config::Options) -> anyhow::Result<Option<attach::Datapath>> {
+    let mut bpf = match utils::load_ebpf_code(opts) {
+        Ok(bpf) => bpf,
+        // a build problem, not one of the node: the events would be read with the wrong layout
+        Err(err) if err.downcast_ref::<utils::AbiError>().is_some() => return Err(err),
+        // fail closed, the object is not the one that was shipped
+        Err(err) if err.downcast_ref::<utils::VerificationError>().is_some() => return Err(err),
+        Err(err) => {
+            warn!(
+                "Failed to load the eBPF program, falling back to the userspace datapath: {:#}",
+                err
+            );
+            return Ok(None);
+        }
+    };
+
+    // Records of the eBPF program go through the logger under the "ebpf" target
+    if let Err(err) = BpfLogger::init_with_logger(&mut bpf, logging::EbpfLogger) {
+        warn!("Failed to initialize the eBPF logger: {}", err);
+    }
+
+    match attach::Datapath::attach(bpf) {
+        Ok(datapath) => Ok(Some(datapath)),
+        Err(err) => {
+            warn!(
+                "Failed to attach the eBPF program, falling back to the userspace datapath: {:#}",
+                err
+            );
+            Ok(None)
+        }
+    }
+}
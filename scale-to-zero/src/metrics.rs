@@ -0,0 +1,619 @@
+use k8s_openapi::chrono;
+use once_cell::sync::Lazy;
+use prometheus::proto::MetricType;
+use prometheus::{
+    register_counter_vec, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec, CounterVec, Encoder,
+    Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+pub static SERVICE_LIST_ENTRIES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "scale_to_zero_service_list_entries",
+        "Number of entries in the SERVICE_LIST eBPF map"
+    )
+    .unwrap()
+});
+
+pub static SERVICE_LIST_CAPACITY: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "scale_to_zero_service_list_capacity",
+        "Maximum number of entries of the SERVICE_LIST eBPF map"
+    )
+    .unwrap()
+});
+
+pub static SYNC_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "scale_to_zero_sync_duration_seconds",
+        "Time taken to sync watched services into the SERVICE_LIST eBPF map",
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5]
+    )
+    .unwrap()
+});
+
+pub static RUNTIME_WORKERS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "scale_to_zero_runtime_workers",
+        "Number of worker threads of the tokio runtime"
+    )
+    .unwrap()
+});
+
+pub static RUNTIME_ALIVE_TASKS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "scale_to_zero_runtime_alive_tasks",
+        "Number of tasks alive in the tokio runtime"
+    )
+    .unwrap()
+});
+
+pub static RUNTIME_GLOBAL_QUEUE_DEPTH: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "scale_to_zero_runtime_global_queue_depth",
+        "Number of tasks waiting in the global queue of the tokio runtime"
+    )
+    .unwrap()
+});
+
+pub static RUNTIME_SCHEDULING_LAG: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "scale_to_zero_runtime_scheduling_lag_seconds",
+        "Delay of a timer of the tokio runtime past its deadline, sampled every second",
+        vec![0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]
+    )
+    .unwrap()
+});
+
+pub static CHANNEL_DEPTH: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "scale_to_zero_channel_depth",
+        "Number of events queued in the channels of the event pipeline",
+        &["channel"]
+    )
+    .unwrap()
+});
+
+pub static EVENT_PROCESSING_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "scale_to_zero_event_processing_seconds",
+        "Time taken to process an activity or wake event, a wake until its scale-up is done",
+        &["event"],
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0]
+    )
+    .unwrap()
+});
+
+pub static TASK_POLL_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "scale_to_zero_task_poll_seconds",
+        "Time taken by a poll of a task of the event pipeline, its worker thread runs nothing else meanwhile",
+        &["task"],
+        vec![0.00001, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0]
+    )
+    .unwrap()
+});
+
+pub static STATE_ENTRIES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "scale_to_zero_state_entries",
+        "Number of entries of each structure of the agent state kept per service",
+        &["structure"]
+    )
+    .unwrap()
+});
+
+pub static SYNC_FAILED_INSERTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_sync_failed_inserts_total",
+        "Number of failed inserts into the SERVICE_LIST eBPF map"
+    )
+    .unwrap()
+});
+
+pub static SYNC_DRIFT: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_sync_drift_total",
+        "Number of SERVICE_LIST entries found different from what the agent last wrote"
+    )
+    .unwrap()
+});
+
+pub static CONSISTENCY_REPAIRS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "scale_to_zero_consistency_repairs_total",
+        "Number of inconsistencies found twice in a row by the consistency check and repaired",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+pub static REPLICA_REVERTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "scale_to_zero_replica_reverts_total",
+        "Number of replica changes reverted by another manager",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static REPLICA_CONFLICTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_replica_conflicts_total",
+        "Number of replica changes that conflicted with another field manager"
+    )
+    .unwrap()
+});
+
+pub static SCALE_UPS_DEDUPLICATED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_scale_ups_deduplicated_total",
+        "Number of scale-ups left to another agent holding the scale-up lease"
+    )
+    .unwrap()
+});
+
+pub static WAKES_COALESCED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_wakes_coalesced_total",
+        "Number of wake requests that joined the wake in progress of their service"
+    )
+    .unwrap()
+});
+
+pub static WAKES_QUEUED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "scale_to_zero_wakes_queued",
+        "Number of wakes waiting for one of the --wake-concurrency slots"
+    )
+    .unwrap()
+});
+
+pub static WAKING_PAGE_REQUESTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_waking_page_requests_total",
+        "Number of requests answered by the built-in waking page"
+    )
+    .unwrap()
+});
+
+pub static WAKES_BLOCKED_BY_QUOTA: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_wakes_blocked_by_quota_total",
+        "Number of wakes skipped because a ResourceQuota of the namespace would reject their pods"
+    )
+    .unwrap()
+});
+
+pub static SCALE_RETRIES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_scale_retries_total",
+        "Number of scale patches retried after a transient API error"
+    )
+    .unwrap()
+});
+
+pub static SCALE_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_scale_failures_total",
+        "Number of scale patches given up on after their last attempt"
+    )
+    .unwrap()
+});
+
+pub static WAKES_WAITING_FOR_NODES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_wakes_waiting_for_nodes_total",
+        "Number of wakes whose pods wait for the cluster autoscaler to add a node"
+    )
+    .unwrap()
+});
+
+pub static PERF_EVENTS_LOST: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_perf_events_lost_total",
+        "Number of activity events lost because a perf buffer was full"
+    )
+    .unwrap()
+});
+
+pub static HELD_PACKETS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_held_packets_total",
+        "Number of gated packets of latency-critical services held for reinjection"
+    )
+    .unwrap()
+});
+
+pub static HELD_PACKETS_DROPPED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_held_packets_dropped_total",
+        "Number of held packets dropped because the hold buffer was full or they expired"
+    )
+    .unwrap()
+});
+
+pub static INTERFACE_ATTACHED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "scale_to_zero_interface_attached",
+        "Whether the datapath is attached to a network interface",
+        &["interface"]
+    )
+    .unwrap()
+});
+
+pub static XDP_REATTACHES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_xdp_reattaches_total",
+        "Number of times the XDP program was found detached from an interface and attached again"
+    )
+    .unwrap()
+});
+
+pub static DROPPED_PACKETS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "scale_to_zero_dropped_packets_total",
+        "Number of packets to scaled-down services dropped by the eBPF program, by L4 protocol",
+        &["protocol"]
+    )
+    .unwrap()
+});
+
+pub static WORKLOAD_UNMANAGEABLE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "scale_to_zero_workload_unmanageable",
+        "Whether a workload is left alone because it is paused, mid-rollout or owned by another operator",
+        &["namespace", "kind", "name"]
+    )
+    .unwrap()
+});
+
+pub static CLUSTER_UNDER_PRESSURE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "scale_to_zero_cluster_under_pressure",
+        "Whether a node reports one of the pressure conditions, lower priority services are scaled down earlier"
+    )
+    .unwrap()
+});
+
+pub static MAINTENANCE_MODE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "scale_to_zero_maintenance_mode",
+        "Whether the maintenance mode is on, no service is scaled down meanwhile"
+    )
+    .unwrap()
+});
+
+pub static WARMING_UP: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "scale_to_zero_warming_up",
+        "Whether the agent is warming up after its start, no service is scaled down meanwhile"
+    )
+    .unwrap()
+});
+
+pub static WAKE_QUOTA_EXCEEDED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "scale_to_zero_wake_quota_exceeded_total",
+        "Number of times a service used up its wake quota"
+    )
+    .unwrap()
+});
+
+pub static SERVICE_WAKES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "scale_to_zero_service_wakes_total",
+        "Number of times the agent scaled the workload of a service up",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static SERVICE_SCALE_DOWNS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "scale_to_zero_service_scale_downs_total",
+        "Number of times the agent scaled the workload of a service down",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static MONITOR_SCALE_DOWNS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "scale_to_zero_monitor_scale_downs_total",
+        "Number of times a monitored service would have been scaled down",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static MONITOR_WAKES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "scale_to_zero_monitor_wakes_total",
+        "Number of times a monitored service would have been woken",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static SERVICE_AWAKE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "scale_to_zero_service_awake",
+        "Whether the backends of a service are available (1) or scaled down (0)",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static SERVICE_IDLE_SECONDS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "scale_to_zero_service_idle_seconds",
+        "Seconds since the last packet to a service",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static SERVICE_SCALED_DOWN_SECONDS: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec!(
+        "scale_to_zero_service_scaled_down_seconds_total",
+        "Seconds a service spent scaled down",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static SERVICE_IDLE_GAPS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "scale_to_zero_service_idle_gap_seconds",
+        "Pauses of at least a second between packets to a service",
+        &["namespace", "service"],
+        vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0, 21600.0]
+    )
+    .unwrap()
+});
+
+pub static EXCESSIVE_WAKES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "scale_to_zero_excessive_wakes",
+        "Whether a service woke more than --excessive-wakes times within the last hour",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static SERVICE_PENT_UP_CLIENTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "scale_to_zero_service_pent_up_clients",
+        "Distinct clients whose packets were gated while the service was scaled down, as of its last wake",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static SERVICE_DROPPED_WHILE_WAKING: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "scale_to_zero_service_dropped_while_waking_total",
+        "Number of packets of a service dropped on this node between its wake request and its gate opening",
+        &["namespace", "service"]
+    )
+    .unwrap()
+});
+
+pub static COLD_START_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "scale_to_zero_cold_start_seconds",
+        "Time from the scale-up of a service by the agent until its gate opened",
+        &["namespace", "service"],
+        COLD_START_BUCKETS.to_vec()
+    )
+    .unwrap()
+});
+
+pub static WAKE_PHASE_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "scale_to_zero_wake_phase_seconds",
+        "Duration of each phase of the wakes of a service, from the event to the gate opening",
+        &["namespace", "service", "phase"],
+        vec![0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0]
+    )
+    .unwrap()
+});
+
+const COLD_START_BUCKETS: [f64; 11] = [
+    0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0, 300.0, 600.0,
+];
+
+// The last cold start of each bucket of each service, with the trace id of its wake, served as
+// OpenMetrics exemplars
+#[derive(Clone)]
+struct Exemplar {
+    trace_id: String,
+    value: f64,
+    timestamp: f64,
+}
+
+type ExemplarKey = (String, String);
+
+static COLD_START_EXEMPLARS: Lazy<Mutex<HashMap<ExemplarKey, Vec<Option<Exemplar>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn observe_cold_start(namespace: &str, service: &str, seconds: f64, trace_id: &str) {
+    COLD_START_SECONDS
+        .with_label_values(&[namespace, service])
+        .observe(seconds);
+    // the +Inf bucket is the last one
+    let bucket = COLD_START_BUCKETS
+        .iter()
+        .position(|bound| seconds <= *bound)
+        .unwrap_or(COLD_START_BUCKETS.len());
+    let mut exemplars = COLD_START_EXEMPLARS.lock().unwrap();
+    let buckets = exemplars
+        .entry((namespace.to_string(), service.to_string()))
+        .or_insert_with(|| vec![None; COLD_START_BUCKETS.len() + 1]);
+    buckets[bucket] = Some(Exemplar {
+        trace_id: trace_id.to_string(),
+        value: seconds,
+        timestamp: chrono::Utc::now().timestamp_millis() as f64 / 1000.0,
+    });
+}
+
+pub fn forget_cold_starts(namespace: &str, service: &str) {
+    let _ = COLD_START_SECONDS.remove_label_values(&[namespace, service]);
+    COLD_START_EXEMPLARS
+        .lock()
+        .unwrap()
+        .remove(&(namespace.to_string(), service.to_string()));
+}
+
+// Render every registered metric in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!(target: "metrics", "Failed to encode metrics: {}", err);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+// Render every registered metric in the OpenMetrics text format, which carries the exemplars of the
+// cold starts. Served to scrapers asking for it
+pub fn render_openmetrics() -> String {
+    let exemplars = COLD_START_EXEMPLARS.lock().unwrap().clone();
+    let mut out = String::new();
+    for family in prometheus::gather() {
+        let name = family.get_name();
+        let (kind, family_name) = match family.get_field_type() {
+            MetricType::COUNTER => ("counter", name.strip_suffix("_total").unwrap_or(name)),
+            MetricType::GAUGE => ("gauge", name),
+            MetricType::HISTOGRAM => ("histogram", name),
+            MetricType::SUMMARY => ("summary", name),
+            MetricType::UNTYPED => ("unknown", name),
+        };
+        let _ = writeln!(out, "# TYPE {} {}", family_name, kind);
+        let _ = writeln!(
+            out,
+            "# HELP {} {}",
+            family_name,
+            family.get_help().replace('\\', "\\\\").replace('\n', "\\n")
+        );
+        for metric in family.get_metric() {
+            let labels: Vec<(&str, &str)> = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value()))
+                .collect();
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let _ = writeln!(
+                        out,
+                        "{}_total{} {}",
+                        family_name,
+                        label_set(&labels, None),
+                        metric.get_counter().get_value()
+                    );
+                }
+                MetricType::GAUGE => {
+                    let _ = writeln!(
+                        out,
+                        "{}{} {}",
+                        name,
+                        label_set(&labels, None),
+                        metric.get_gauge().get_value()
+                    );
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let key = labels_key(&labels);
+                    let series_exemplars = (name == "scale_to_zero_cold_start_seconds")
+                        .then(|| key.and_then(|key| exemplars.get(&key)))
+                        .flatten();
+                    let mut bounds: Vec<(String, u64)> = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|bucket| {
+                            (
+                                bucket.get_upper_bound().to_string(),
+                                bucket.get_cumulative_count(),
+                            )
+                        })
+                        .collect();
+                    bounds.push(("+Inf".to_string(), histogram.get_sample_count()));
+                    for (index, (bound, count)) in bounds.iter().enumerate() {
+                        let _ = write!(
+                            out,
+                            "{}_bucket{} {}",
+                            name,
+                            label_set(&labels, Some(bound)),
+                            count
+                        );
+                        if let Some(Some(exemplar)) =
+                            series_exemplars.and_then(|buckets| buckets.get(index))
+                        {
+                            let _ = write!(
+                                out,
+                                " # {{trace_id=\"{}\"}} {} {}",
+                                exemplar.trace_id, exemplar.value, exemplar.timestamp
+                            );
+                        }
+                        out.push('\n');
+                    }
+                    let _ = writeln!(
+                        out,
+                        "{}_count{} {}",
+                        name,
+                        label_set(&labels, None),
+                        histogram.get_sample_count()
+                    );
+                    let _ = writeln!(
+                        out,
+                        "{}_sum{} {}",
+                        name,
+                        label_set(&labels, None),
+                        histogram.get_sample_sum()
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+// (namespace, service) of a series labeled with them
+fn labels_key(labels: &[(&str, &str)]) -> Option<ExemplarKey> {
+    let value = |name: &str| {
+        labels
+            .iter()
+            .find(|(label, _)| *label == name)
+            .map(|(_, value)| value.to_string())
+    };
+    Some((value("namespace")?, value("service")?))
+}
+
+fn label_set(labels: &[(&str, &str)], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}=\"{}\"",
+                name,
+                value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+            )
+        })
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", pairs.join(","))
+}
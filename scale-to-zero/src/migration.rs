@@ -0,0 +1,276 @@
+use anyhow::{bail, Context};
+use aya::maps::{HashMap, Map, MapData};
+use aya::{Bpf, Pod};
+use log::{info, warn};
+use scale_to_zero_common::{
+    PortRange, ServicePolicy, WakeThreshold, GATED_DROP, GATED_PASS, GATED_REDIRECT,
+    MAP_SCHEMA_VERSION, PROTOCOL_ALL, PROTOCOL_ICMP, PROTOCOL_OTHER, PROTOCOL_TCP, PROTOCOL_UDP,
+    SERVICE_AVAILABLE, SERVICE_HOLD, SERVICE_HOLD_UDP, SERVICE_POLICY_PORT_RANGES,
+};
+use std::path::{Path, PathBuf};
+
+// Flags of the SERVICE_LIST values of schema version 1, a u32 per service
+const V1_SERVICE_AVAILABLE: u32 = 1;
+const V1_SERVICE_HOLD: u32 = 1 << 1;
+const V1_SERVICE_IGNORE_TCP: u32 = 1 << 2;
+const V1_SERVICE_IGNORE_UDP: u32 = 1 << 3;
+const V1_SERVICE_IGNORE_ICMP: u32 = 1 << 4;
+const V1_SERVICE_IGNORE_OTHER: u32 = 1 << 5;
+const V1_SERVICE_HOLD_UDP: u32 = 1 << 6;
+const V1_SERVICE_GATE_PASS: u32 = 1 << 7;
+const V1_SERVICE_GATE_REDIRECT: u32 = 1 << 8;
+
+// Prefix of the directories the maps of each schema version are pinned in
+const MAPS_DIR_PREFIX: &str = "maps-v";
+
+// Entries of maps pinned with an older schema, converted to the layout of this agent. They are
+// written into its maps before its program is swapped into the pinned links, the old program keeps
+// gating with the old maps until then
+pub struct Migration {
+    from: u32,
+    service_list: Vec<(u32, ServicePolicy)>,
+    pod_to_service: Vec<(u32, u32)>,
+    observed_services: Vec<(u32, u64)>,
+}
+
+// Directory of the maps of this agent's schema, maps of another layout are never loaded into it
+pub fn maps_path(pin_path: &Path) -> PathBuf {
+    pin_path.join(format!("{}{}", MAPS_DIR_PREFIX, MAP_SCHEMA_VERSION))
+}
+
+// Get the pinned maps of the agent's schema ready before loading. Maps pinned by an agent of the
+// same schema are reused, the ones of an older schema are read and converted
+pub fn prepare(pin_path: &Path) -> anyhow::Result<Option<Migration>> {
+    std::fs::create_dir_all(pin_path)
+        .with_context(|| format!("Failed to create pin path {}", pin_path.display()))?;
+
+    let current = maps_path(pin_path);
+    match pinned_schema_version(&current) {
+        Ok(Some(version)) if version == MAP_SCHEMA_VERSION => {
+            info!("Reusing the maps pinned in {}", current.display());
+            return Ok(None);
+        }
+        Ok(_) => {}
+        Err(err) => warn!("Failed to read the schema of the pinned maps: {:#}", err),
+    }
+    // left by an agent that stopped before it finished its migration, the old maps are still there
+    if current.exists() {
+        std::fs::remove_dir_all(&current)?;
+    }
+
+    let (previous, version) = match previous_maps(pin_path) {
+        Some(previous) => previous,
+        None => return Ok(None),
+    };
+    if version == MAP_SCHEMA_VERSION {
+        // pinned by an agent from before the maps were versioned, the layout is the same
+        std::fs::create_dir_all(&current)?;
+        for entry in std::fs::read_dir(&previous)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                std::fs::rename(entry.path(), current.join(entry.file_name()))?;
+            }
+        }
+        info!(
+            "Moved the maps pinned in {} to {}",
+            previous.display(),
+            current.display()
+        );
+        return Ok(None);
+    }
+    match Migration::read(&previous, version) {
+        Ok(migration) => {
+            info!(
+                "Migrating the maps pinned with schema version {} to {}: {} service list entries",
+                version,
+                MAP_SCHEMA_VERSION,
+                migration.service_list.len()
+            );
+            Ok(Some(migration))
+        }
+        Err(err) => {
+            warn!(
+                "Pinned maps have schema version {}, this agent uses {} and starts with empty maps: {:#}",
+                version, MAP_SCHEMA_VERSION, err
+            );
+            Ok(None)
+        }
+    }
+}
+
+// Unpin the maps of other schemas once the program using them was swapped out of the links
+pub fn remove_stale(pin_path: &Path) {
+    let current = maps_path(pin_path);
+    let entries = match std::fs::read_dir(pin_path) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("Failed to list the pinned maps: {}", err);
+            return;
+        }
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let removed = match entry.file_type() {
+            // maps of an agent from before the maps were versioned
+            Ok(kind) if kind.is_file() => std::fs::remove_file(&path),
+            Ok(kind)
+                if kind.is_dir()
+                    && path != current
+                    && entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(MAPS_DIR_PREFIX) =>
+            {
+                std::fs::remove_dir_all(&path)
+            }
+            _ => continue,
+        };
+        match removed {
+            Ok(_) => info!("Unpinned the stale maps {}", path.display()),
+            Err(err) => warn!("Failed to unpin the stale maps {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl Migration {
+    fn read(path: &Path, version: u32) -> anyhow::Result<Migration> {
+        let service_list = match version {
+            1 => read_entries::<u32, u32>(path, "SERVICE_LIST")?
+                .into_iter()
+                .map(|(ip, flags)| (ip, policy_v1(flags)))
+                .collect(),
+            // version 3 added the source address to the events, the policies are the same
+            2 => read_entries::<u32, ServicePolicy>(path, "SERVICE_LIST")?,
+            _ => bail!("No migration from schema version {}", version),
+        };
+        // unchanged since version 1
+        Ok(Migration {
+            from: version,
+            service_list,
+            pod_to_service: read_entries(path, "POD_TO_SERVICE").unwrap_or_default(),
+            observed_services: read_entries(path, "OBSERVED_SERVICES").unwrap_or_default(),
+        })
+    }
+
+    // Fill the freshly created maps, before the program is attached
+    pub fn apply(self, bpf: &mut Bpf) -> anyhow::Result<()> {
+        write_entries(bpf, "SERVICE_LIST", self.service_list)?;
+        write_entries(bpf, "POD_TO_SERVICE", self.pod_to_service)?;
+        write_entries(bpf, "OBSERVED_SERVICES", self.observed_services)?;
+        info!("Migrated the pinned maps from schema version {}", self.from);
+        Ok(())
+    }
+}
+
+// Version 1 kept the flags, ignored protocols and gated action of a service in one u32. Wake
+// thresholds were in a map of their own that wasn't pinned, the service wakes on the first packet
+// until the controller syncs it
+fn policy_v1(value: u32) -> ServicePolicy {
+    let mut flags = 0;
+    for (old, new) in [
+        (V1_SERVICE_AVAILABLE, SERVICE_AVAILABLE),
+        (V1_SERVICE_HOLD, SERVICE_HOLD),
+        (V1_SERVICE_HOLD_UDP, SERVICE_HOLD_UDP),
+    ] {
+        if value & old != 0 {
+            flags |= new;
+        }
+    }
+    let mut protocols = PROTOCOL_ALL;
+    for (ignored, protocol) in [
+        (V1_SERVICE_IGNORE_TCP, PROTOCOL_TCP),
+        (V1_SERVICE_IGNORE_UDP, PROTOCOL_UDP),
+        (V1_SERVICE_IGNORE_ICMP, PROTOCOL_ICMP),
+        (V1_SERVICE_IGNORE_OTHER, PROTOCOL_OTHER),
+    ] {
+        if value & ignored != 0 {
+            protocols &= !protocol;
+        }
+    }
+    let gated_action = if value & V1_SERVICE_GATE_REDIRECT != 0 {
+        GATED_REDIRECT
+    } else if value & V1_SERVICE_GATE_PASS != 0 {
+        GATED_PASS
+    } else {
+        GATED_DROP
+    };
+    ServicePolicy {
+        flags,
+        protocols,
+        gated_action,
+        port_ranges: 0,
+        ports: [PortRange::default(); SERVICE_POLICY_PORT_RANGES],
+        wake_threshold: WakeThreshold {
+            packets: 0,
+            window_ms: 0,
+        },
+    }
+}
+
+// The newest maps pinned with another schema and their version: the directory of a version, or
+// the pin path itself for the maps of an agent from before the maps were versioned
+fn previous_maps(pin_path: &Path) -> Option<(PathBuf, u32)> {
+    let mut candidates = vec![pin_path.to_path_buf()];
+    if let Ok(entries) = std::fs::read_dir(pin_path) {
+        candidates.extend(
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| {
+                    entry
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with(MAPS_DIR_PREFIX)
+                })
+                .map(|entry| entry.path()),
+        );
+    }
+    candidates
+        .into_iter()
+        .filter_map(|path| match pinned_schema_version(&path) {
+            Ok(version) => Some((path, version?)),
+            Err(err) => {
+                warn!(
+                    "Failed to read the schema of the maps pinned in {}: {:#}",
+                    path.display(),
+                    err
+                );
+                None
+            }
+        })
+        // a newer agent was rolled back, its maps can't be converted
+        .filter(|(_, version)| *version <= MAP_SCHEMA_VERSION)
+        .max_by_key(|(_, version)| *version)
+}
+
+fn pinned_schema_version(maps: &Path) -> anyhow::Result<Option<u32>> {
+    let path = maps.join("MAP_SCHEMA");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let schema: aya::maps::Array<MapData, u32> =
+        aya::maps::Array::try_from(Map::Array(MapData::from_pin(path)?))?;
+    Ok(Some(schema.get(&0, 0)?))
+}
+
+fn read_entries<K: Pod, V: Pod>(maps: &Path, name: &str) -> anyhow::Result<Vec<(K, V)>> {
+    let path = maps.join(name);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let map: HashMap<MapData, K, V> = HashMap::try_from(Map::HashMap(MapData::from_pin(&path)?))
+        .with_context(|| format!("Failed to open the pinned {}", name))?;
+    Ok(map.iter().filter_map(|entry| entry.ok()).collect())
+}
+
+fn write_entries<K: Pod, V: Pod>(
+    bpf: &mut Bpf,
+    name: &str,
+    entries: Vec<(K, V)>,
+) -> anyhow::Result<()> {
+    let mut map: HashMap<&mut MapData, K, V> = HashMap::try_from(bpf.map_mut(name).unwrap())?;
+    for (key, value) in entries {
+        map.insert(key, value, 0)
+            .with_context(|| format!("Failed to migrate an entry of {}", name))?;
+    }
+    Ok(())
+}
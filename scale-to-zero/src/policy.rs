@@ -0,0 +1,244 @@
+use k8s_openapi::chrono;
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use crate::config::Options;
+use crate::kubernetes::models::{Priority, ServiceData, RECENT_WAKES};
+
+// A scale policy is asked at the decision points of the scaler and overrides the built-in rules.
+// `None` keeps the built-in decision, so a policy only has to answer what it cares about
+pub trait ScalePolicy: Send + Sync {
+    fn name(&self) -> &str;
+
+    // The service has been idle past its scale-down-time
+    fn should_scale_down(&self, _context: &PolicyContext) -> Option<bool> {
+        None
+    }
+
+    // Traffic reached the scaled down service
+    fn should_wake(&self, _context: &PolicyContext) -> Option<bool> {
+        None
+    }
+
+    // Replicas the workload is woken up with, `context.replicas` is the built-in number
+    fn target_replicas(&self, _context: &PolicyContext) -> Option<i32> {
+        None
+    }
+}
+
+// What a policy knows about the service it decides on
+#[derive(Debug, Clone, Serialize)]
+pub struct PolicyContext {
+    pub namespace: String,
+    pub service_name: String,
+    pub kind: String,
+    pub name: String,
+    pub priority: Priority,
+    // Seconds since the last packet, and the scale-down-time in effect
+    pub idle_seconds: i64,
+    pub scale_down_time: i64,
+    // Timestamps of the last wakes
+    pub recent_wakes: Vec<i64>,
+    // Distinct clients gated on this node while the service was scaled down, set for a wake
+    pub gated_clients: usize,
+    pub replicas: i32,
+}
+
+impl PolicyContext {
+    pub fn new(service_ip: &str, service: &ServiceData, scale_down_time: i64) -> Self {
+        PolicyContext {
+            namespace: service.namespace.clone(),
+            service_name: service.service_name.clone(),
+            kind: service.kind.clone(),
+            name: service.name.clone(),
+            priority: service.priority,
+            idle_seconds: chrono::Utc::now().timestamp() - service.last_packet_time,
+            scale_down_time,
+            recent_wakes: RECENT_WAKES
+                .lock()
+                .unwrap()
+                .get(service_ip)
+                .map(|wakes| wakes.iter().copied().collect())
+                .unwrap_or_default(),
+            gated_clients: 0,
+            replicas: 0,
+        }
+    }
+}
+
+static POLICY: OnceCell<Box<dyn ScalePolicy>> = OnceCell::new();
+
+// Install a policy, compiled in or loaded from --policy-plugin. There is one per agent
+#[cfg_attr(not(feature = "wasm-policy"), allow(dead_code))]
+pub fn register(policy: Box<dyn ScalePolicy>) -> anyhow::Result<()> {
+    let name = policy.name().to_string();
+    POLICY
+        .set(policy)
+        .map_err(|_| anyhow::anyhow!("A scale policy is already registered"))?;
+    info!(target: "policy", "Using scale policy {}", name);
+    Ok(())
+}
+
+// Load the WASM plugin given on the command line, if any
+pub fn init(opts: &Options) -> anyhow::Result<()> {
+    let path = match opts.policy_plugin.as_ref() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    #[cfg(feature = "wasm-policy")]
+    {
+        register(Box::new(wasm::WasmPolicy::load(path, opts.policy_fuel)?))
+    }
+    #[cfg(not(feature = "wasm-policy"))]
+    {
+        anyhow::bail!(
+            "--policy-plugin {} needs an agent built with the wasm-policy feature",
+            path.display()
+        )
+    }
+}
+
+// Name of the registered policy, if any
+pub fn name() -> Option<String> {
+    POLICY.get().map(|policy| policy.name().to_string())
+}
+
+pub fn should_scale_down(context: &PolicyContext) -> bool {
+    decide(context, "should_scale_down", |policy| {
+        policy.should_scale_down(context)
+    })
+    .unwrap_or(true)
+}
+
+pub fn should_wake(context: &PolicyContext) -> bool {
+    decide(context, "should_wake", |policy| policy.should_wake(context)).unwrap_or(true)
+}
+
+pub fn target_replicas(context: &PolicyContext) -> i32 {
+    match decide(context, "target_replicas", |policy| {
+        policy.target_replicas(context)
+    }) {
+        Some(replicas) if replicas >= 1 => replicas,
+        Some(replicas) => {
+            warn!(target: "policy", "Ignoring {} replicas for {} {}, a wake needs at least one", replicas, context.kind, context.name);
+            context.replicas
+        }
+        None => context.replicas,
+    }
+}
+
+fn decide<T: std::fmt::Debug>(
+    context: &PolicyContext,
+    decision: &str,
+    ask: impl FnOnce(&dyn ScalePolicy) -> Option<T>,
+) -> Option<T> {
+    let policy = POLICY.get()?;
+    let answer = ask(policy.as_ref());
+    if let Some(answer) = answer.as_ref() {
+        info!(target: "policy", "{} answered {:?} to {} for {} {}", policy.name(), answer, decision, context.kind, context.name);
+    }
+    answer
+}
+
+// Policies compiled to WebAssembly and run by wasmtime. The module exports its memory, an
+// `alloc(len) -> ptr` function and any of `should_scale_down`, `should_wake` and `target_replicas`,
+// which take the JSON of the PolicyContext as (ptr, len). A negative result, a missing export or a
+// trap keeps the built-in decision
+#[cfg(feature = "wasm-policy")]
+mod wasm {
+    use log::warn;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use wasmtime::{Config, Engine, Instance, Memory, Module, Store, TypedFunc};
+
+    use super::{PolicyContext, ScalePolicy};
+
+    struct Plugin {
+        store: Store<()>,
+        instance: Instance,
+        memory: Memory,
+        alloc: TypedFunc<i32, i32>,
+    }
+
+    pub struct WasmPolicy {
+        name: String,
+        // fuel given to each call, a plugin stuck in a loop traps instead of blocking the scaler
+        fuel: u64,
+        plugin: Mutex<Plugin>,
+    }
+
+    impl WasmPolicy {
+        pub fn load(path: &Path, fuel: u64) -> anyhow::Result<Self> {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            let engine = Engine::new(&config)?;
+            let module = Module::from_file(&engine, path)?;
+            let mut store = Store::new(&engine, ());
+            store.set_fuel(fuel)?;
+            // no imports, a plugin can't reach the network, the filesystem or the clock
+            let instance = Instance::new(&mut store, &module, &[])?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| anyhow::anyhow!("{} doesn't export its memory", path.display()))?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            Ok(WasmPolicy {
+                name: path.display().to_string(),
+                fuel,
+                plugin: Mutex::new(Plugin {
+                    store,
+                    instance,
+                    memory,
+                    alloc,
+                }),
+            })
+        }
+
+        fn call(&self, export: &str, context: &PolicyContext) -> Option<i32> {
+            let mut plugin = self.plugin.lock().unwrap();
+            let Plugin {
+                store,
+                instance,
+                memory,
+                alloc,
+            } = &mut *plugin;
+            let func = instance
+                .get_typed_func::<(i32, i32), i32>(&mut *store, export)
+                .ok()?;
+            let result = (|| -> anyhow::Result<i32> {
+                let input = k8s_openapi::serde_json::to_vec(context)?;
+                store.set_fuel(self.fuel)?;
+                let ptr = alloc.call(&mut *store, input.len() as i32)?;
+                memory.write(&mut *store, ptr as usize, &input)?;
+                func.call(&mut *store, (ptr, input.len() as i32))
+            })();
+            match result {
+                Ok(answer) if answer >= 0 => Some(answer),
+                Ok(_) => None,
+                Err(err) => {
+                    warn!(target: "policy", "{} of {} failed for {} {}: {:#}", export, self.name, context.kind, context.name, err);
+                    None
+                }
+            }
+        }
+    }
+
+    impl ScalePolicy for WasmPolicy {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn should_scale_down(&self, context: &PolicyContext) -> Option<bool> {
+            self.call("should_scale_down", context)
+                .map(|answer| answer != 0)
+        }
+
+        fn should_wake(&self, context: &PolicyContext) -> Option<bool> {
+            self.call("should_wake", context).map(|answer| answer != 0)
+        }
+
+        fn target_replicas(&self, context: &PolicyContext) -> Option<i32> {
+            self.call("target_replicas", context)
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::Context;
+use aya::maps::{Map, MapData};
+use k8s_openapi::serde_json;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use tokio::net::unix::pipe;
+use tokio::process::Command;
+
+use crate::attach::Datapath;
+use crate::config;
+use crate::diagnostics::{self, CAP_BPF, CAP_NET_RAW, CAP_PERFMON, CAP_SYS_ADMIN};
+use crate::migration;
+
+// Highest capability number of the kernels we run on, CAP_CHECKPOINT_RESTORE
+const CAP_LAST_CAP: u32 = 40;
+
+// _LINUX_CAPABILITY_VERSION_3, two 32 bit words per set
+const CAPABILITY_VERSION: u32 = 0x20080522;
+
+// Environment of the agent started by the loader, with the file descriptors it inherits: the maps
+// it takes over (NAME=kind:fd, comma separated), the XDP program of the self-test and the pipe the
+// attach statuses come through. The load failures of the loader are passed on as JSON, for the
+// health endpoint of the agent
+const MAPS_ENV: &str = "SCALE_TO_ZERO_AGENT_MAPS";
+const PROGRAM_ENV: &str = "SCALE_TO_ZERO_AGENT_PROGRAM";
+const STATUS_ENV: &str = "SCALE_TO_ZERO_AGENT_STATUS";
+const LOAD_FAILURES_ENV: &str = "SCALE_TO_ZERO_AGENT_LOAD_FAILURES";
+
+#[repr(C)]
+struct CapabilityHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapabilityData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+// What the loader hands over to the agent it started
+pub struct Handoff {
+    pub maps: HashMap<String, Map>,
+    // The XDP program, for the self-test. None with the userspace datapath
+    pub program: Option<OwnedFd>,
+    // Attach statuses of the interfaces, written by the loader as it attaches them
+    pub status: Option<pipe::Receiver>,
+}
+
+// With --drop-privileges the process started in the privileged container is the loader: it loads,
+// pins and attaches the datapath, then starts the network-facing agent as a child process and
+// keeps attaching the datapath to new interfaces. The agent drops every capability of the setup
+// before it starts a thread, and takes over the maps through the file descriptors it inherits.
+// Without a datapath (e.g. the userspace fallback) the agent is started with
+// --userspace-datapath. The loader exits with the agent
+pub async fn run_loader(datapath: Option<Datapath>) -> anyhow::Result<()> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command.args(std::env::args_os().skip(1));
+    let load_failures = serde_json::to_string(&*diagnostics::LOAD_FAILURES.lock().unwrap())?;
+    command.env(LOAD_FAILURES_ENV, load_failures);
+    // kept alive until the agent is started, they are closed in the loader then
+    let mut inherited = Vec::new();
+    let mut status = None;
+    match datapath {
+        Some(mut datapath) => {
+            let mut maps = Vec::new();
+            for (name, map) in datapath.take_maps() {
+                let (kind, data) = map_parts(&map)
+                    .with_context(|| format!("Map {} can't be handed to the agent", name))?;
+                let fd = inheritable(data.fd().as_fd())?;
+                maps.push(format!("{}={}:{}", name, kind, fd.as_raw_fd()));
+                inherited.push(fd);
+            }
+            command.env(MAPS_ENV, maps.join(","));
+
+            let program = inheritable(datapath.program_fd()?.as_fd())?;
+            command.env(PROGRAM_ENV, program.as_raw_fd().to_string());
+            inherited.push(program);
+
+            let (sender, receiver) = pipe::pipe()?;
+            let receiver = inheritable(receiver.as_fd())?;
+            command.env(STATUS_ENV, receiver.as_raw_fd().to_string());
+            inherited.push(receiver);
+            status = Some(sender);
+
+            // The programs of older agents were swapped out of the links, their maps are no longer
+            // used
+            migration::remove_stale(&config::get().pin_path);
+            tokio::spawn(datapath.watch_interfaces());
+        }
+        None => {
+            command.env(MAPS_ENV, "");
+            if !config::get().userspace_datapath {
+                command.arg("--userspace-datapath");
+            }
+        }
+    }
+
+    // the agent goes down with the loader
+    unsafe {
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut agent = command.spawn().context("Failed to start the agent")?;
+    drop(inherited);
+    info!(target: "privileges", "Started the agent as process {}", agent.id().unwrap_or_default());
+    if let Some(status) = status {
+        tokio::spawn(crate::attach::publish_status(status));
+    }
+
+    let exit = agent.wait().await?;
+    error!(target: "privileges", "The agent exited with {}, exiting", exit);
+    std::process::exit(exit.code().unwrap_or(1));
+}
+
+// The maps and file descriptors handed over by the loader, when this process is the agent it
+// started
+pub fn handoff() -> anyhow::Result<Option<Handoff>> {
+    let maps = match std::env::var(MAPS_ENV) {
+        Ok(maps) => maps,
+        Err(_) => return Ok(None),
+    };
+    if let Ok(load_failures) = std::env::var(LOAD_FAILURES_ENV) {
+        *diagnostics::LOAD_FAILURES.lock().unwrap() = serde_json::from_str(&load_failures)?;
+    }
+    let mut handoff = Handoff {
+        maps: HashMap::new(),
+        program: inherited_fd(PROGRAM_ENV)?,
+        status: inherited_fd(STATUS_ENV)?
+            .map(pipe::Receiver::from_owned_fd)
+            .transpose()?,
+    };
+    for map in maps.split(',').filter(|map| !map.is_empty()) {
+        let (name, kind, fd) = map
+            .split_once('=')
+            .and_then(|(name, map)| {
+                let (kind, fd) = map.split_once(':')?;
+                Some((name, kind, fd.parse::<RawFd>().ok()?))
+            })
+            .with_context(|| format!("Invalid map {} handed over by the loader", map))?;
+        let data = MapData::from_fd(unsafe { OwnedFd::from_raw_fd(fd) })?;
+        handoff
+            .maps
+            .insert(name.to_string(), map_from_parts(kind, data)?);
+    }
+    Ok(Some(handoff))
+}
+
+// The agent started by the loader keeps the file descriptors it inherited, and only the
+// capabilities it can't do without: CAP_PERFMON to open the perf buffers of the maps (CAP_SYS_ADMIN
+// before 5.8), CAP_NET_RAW for the AF_XDP sockets of --hold-interface or the packet socket of the
+// userspace datapath, and CAP_BPF where the kernel checks it on every map operation (before 6.5
+// with unprivileged BPF disabled). Capabilities are per thread, so this runs before the runtime
+// starts its threads. Everything else is dropped from the bounding set too, so neither the agent
+// nor the commands it runs get them back
+pub fn drop_for_agent() -> anyhow::Result<()> {
+    let opts = config::get();
+    let mut keep = Vec::new();
+    if matches!(diagnostics::kernel_version(), Some(version) if version >= (5, 8)) {
+        keep.push(CAP_PERFMON);
+    } else {
+        warn!(target: "privileges", "Keeping CAP_SYS_ADMIN, kernel {} has no CAP_PERFMON to open the perf buffers with", diagnostics::kernel_release());
+        keep.push(CAP_SYS_ADMIN);
+    }
+    if opts.hold_interface.is_some() || opts.userspace_datapath {
+        keep.push(CAP_NET_RAW);
+    }
+    if map_operations_need_cap_bpf() {
+        warn!(target: "privileges", "Keeping CAP_BPF, kernel {} checks it on map operations while unprivileged BPF is disabled", diagnostics::kernel_release());
+        keep.push(CAP_BPF);
+    }
+
+    // the bounding set is changed first, it needs CAP_SETPCAP which goes away with capset
+    for cap in (0..=CAP_LAST_CAP).filter(|cap| !keep.contains(cap)) {
+        let ret = unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) };
+        // capabilities unknown to an older kernel are not in its bounding set anyway
+        if ret != 0 && std::io::Error::last_os_error().raw_os_error() != Some(libc::EINVAL) {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    let ret = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL as libc::c_ulong,
+            0,
+            0,
+            0,
+        )
+    };
+    if ret != 0 {
+        warn!(target: "privileges", "Failed to clear the ambient capabilities: {}", std::io::Error::last_os_error());
+    }
+
+    let mut data = [CapabilityData::default(); 2];
+    for cap in keep.iter() {
+        let word = &mut data[(*cap / 32) as usize];
+        word.effective |= 1 << (cap % 32);
+        word.permitted |= 1 << (cap % 32);
+    }
+    let mut header = CapabilityHeader {
+        version: CAPABILITY_VERSION,
+        pid: 0,
+    };
+    let ret = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let kept: Vec<&str> = keep
+        .iter()
+        .map(|cap| match *cap {
+            CAP_PERFMON => "CAP_PERFMON",
+            CAP_SYS_ADMIN => "CAP_SYS_ADMIN",
+            CAP_NET_RAW => "CAP_NET_RAW",
+            _ => "CAP_BPF",
+        })
+        .collect();
+    info!(target: "privileges", "Dropped the privileges of the datapath setup, kept: [{}]", kept.join(", "));
+    Ok(())
+}
+
+// A duplicate of the file descriptor without FD_CLOEXEC, inherited by the agent
+fn inheritable(fd: BorrowedFd) -> std::io::Result<OwnedFd> {
+    let fd = unsafe { libc::dup(fd.as_raw_fd()) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn inherited_fd(env: &str) -> anyhow::Result<Option<OwnedFd>> {
+    match std::env::var(env) {
+        Ok(fd) => {
+            let fd: RawFd = fd.parse().with_context(|| format!("Invalid {}", env))?;
+            Ok(Some(unsafe { OwnedFd::from_raw_fd(fd) }))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+// The kinds of the maps the agent takes over, the map is rebuilt from its file descriptor by kind
+fn map_parts(map: &Map) -> Option<(&'static str, &MapData)> {
+    match map {
+        Map::Array(data) => Some(("array", data)),
+        Map::HashMap(data) => Some(("hash", data)),
+        Map::LruHashMap(data) => Some(("lru_hash", data)),
+        Map::PerCpuArray(data) => Some(("percpu_array", data)),
+        Map::PerCpuHashMap(data) => Some(("percpu_hash", data)),
+        Map::PerCpuLruHashMap(data) => Some(("percpu_lru_hash", data)),
+        Map::PerfEventArray(data) => Some(("perf_event_array", data)),
+        Map::XskMap(data) => Some(("xsk", data)),
+        _ => None,
+    }
+}
+
+fn map_from_parts(kind: &str, data: MapData) -> anyhow::Result<Map> {
+    Ok(match kind {
+        "array" => Map::Array(data),
+        "hash" => Map::HashMap(data),
+        "lru_hash" => Map::LruHashMap(data),
+        "percpu_array" => Map::PerCpuArray(data),
+        "percpu_hash" => Map::PerCpuHashMap(data),
+        "percpu_lru_hash" => Map::PerCpuLruHashMap(data),
+        "perf_event_array" => Map::PerfEventArray(data),
+        "xsk" => Map::XskMap(data),
+        kind => anyhow::bail!("Unknown map kind {}", kind),
+    })
+}
+
+// Before 6.5, every bpf() command on the fd of a map needs CAP_BPF while
+// kernel.unprivileged_bpf_disabled is set
+fn map_operations_need_cap_bpf() -> bool {
+    let disabled = std::fs::read_to_string("/proc/sys/kernel/unprivileged_bpf_disabled")
+        .map(|value| value.trim() != "0")
+        .unwrap_or(true);
+    disabled && !matches!(diagnostics::kernel_version(), Some(version) if version >= (6, 5))
+}
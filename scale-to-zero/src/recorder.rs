@@ -0,0 +1,75 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config;
+use crate::kubernetes::models::ActivityKind;
+
+// What an event did to its service
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventAction {
+    // Refreshed the idle timer
+    Activity,
+    // Requested a scale up
+    Wake,
+}
+
+// A line of the recording, in the JSON lines format replayed by --simulate
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedEvent {
+    pub timestamp: f64,
+    pub service_ip: Ipv4Addr,
+    pub action: EventAction,
+    pub source: ActivityKind,
+}
+
+// This contains the last --event-recording-len events of each watched service IP
+static EVENTS: Lazy<Mutex<HashMap<String, VecDeque<RecordedEvent>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn record(service_ip: Ipv4Addr, action: EventAction, source: ActivityKind) {
+    let len = config::get().event_recording_len;
+    if len == 0 {
+        return;
+    }
+    let event = RecordedEvent {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+        service_ip,
+        action,
+        source,
+    };
+    let mut events = EVENTS.lock().unwrap();
+    let ring = events.entry(service_ip.to_string()).or_default();
+    while ring.len() >= len {
+        ring.pop_front();
+    }
+    ring.push_back(event);
+}
+
+// Recorded events of a service, or of every service when none is given, oldest first
+pub fn events(service_ip: Option<&str>) -> Vec<RecordedEvent> {
+    let events = EVENTS.lock().unwrap();
+    let mut recorded: Vec<RecordedEvent> = match service_ip {
+        Some(service_ip) => events
+            .get(service_ip)
+            .map(|ring| ring.iter().cloned().collect())
+            .unwrap_or_default(),
+        None => events.values().flatten().cloned().collect(),
+    };
+    recorded.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+    recorded
+}
+
+// Forget the services no longer watched, returns the number of services left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    let mut events = EVENTS.lock().unwrap();
+    events.retain(|ip, _| watched.contains(ip));
+    events.len()
+}
@@ -0,0 +1,78 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::future::{poll_fn, Future};
+use std::pin::pin;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+
+use crate::metrics;
+
+const OBSERVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Events queued in a channel, None once all of its senders are gone
+type Depth = Box<dyn Fn() -> Option<usize> + Send>;
+
+// Channels of the event pipeline by name, a name can cover several channels (e.g. the consumers of
+// every CPU), their depths are added up
+static CHANNELS: Lazy<Mutex<Vec<(&'static str, Depth)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Export the depth of the channel as scale_to_zero_channel_depth{channel=name}
+pub fn watch_channel<T: Send + 'static>(name: &'static str, sender: &mpsc::Sender<T>) {
+    let sender = sender.downgrade();
+    let depth: Depth = Box::new(move || {
+        sender
+            .upgrade()
+            .map(|sender| sender.max_capacity() - sender.capacity())
+    });
+    CHANNELS.lock().unwrap().push((name, depth));
+}
+
+// Export the time taken by each poll of the future as scale_to_zero_task_poll_seconds{task=name}.
+// A long poll holds its worker thread, the tasks queued behind it wait
+pub async fn instrument<F: Future>(name: &'static str, future: F) -> F::Output {
+    let polls = metrics::TASK_POLL_SECONDS.with_label_values(&[name]);
+    let mut future = pin!(future);
+    poll_fn(|cx| {
+        let started = Instant::now();
+        let poll = future.as_mut().poll(cx);
+        polls.observe(started.elapsed().as_secs_f64());
+        poll
+    })
+    .await
+}
+
+// Sample the runtime every second. The lag of the sampling timer itself shows how long ready tasks
+// wait for a worker, e.g. behind a task blocking its thread
+pub async fn observe() {
+    let handle = Handle::current();
+    loop {
+        let started = Instant::now();
+        tokio::time::sleep(OBSERVE_INTERVAL).await;
+        let lag = started.elapsed().saturating_sub(OBSERVE_INTERVAL);
+        metrics::RUNTIME_SCHEDULING_LAG.observe(lag.as_secs_f64());
+
+        let runtime = handle.metrics();
+        metrics::RUNTIME_WORKERS.set(runtime.num_workers() as i64);
+        metrics::RUNTIME_ALIVE_TASKS.set(runtime.num_alive_tasks() as i64);
+        metrics::RUNTIME_GLOBAL_QUEUE_DEPTH.set(runtime.global_queue_depth() as i64);
+
+        let mut depths: HashMap<&'static str, usize> = HashMap::new();
+        CHANNELS
+            .lock()
+            .unwrap()
+            .retain(|(name, depth)| match depth() {
+                Some(depth) => {
+                    *depths.entry(name).or_default() += depth;
+                    true
+                }
+                None => false,
+            });
+        for (name, depth) in depths {
+            metrics::CHANNEL_DEPTH
+                .with_label_values(&[name])
+                .set(depth as i64);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use k8s_openapi::chrono;
+use log::info;
+use once_cell::sync::OnceCell;
+use scale_to_zero_common::SELFTEST_SOURCE;
+use serde::Serialize;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config;
+use crate::kubernetes::endpoints;
+use crate::kubernetes::models::{LAST_CALLED, WATCHED_SERVICES};
+
+const SELFTEST_SOURCE_PORT: u16 = 40000;
+const BPF_PROG_TEST_RUN: libc::c_long = 10;
+const XDP_ACTIONS: [&str; 5] = ["aborted", "drop", "pass", "tx", "redirect"];
+
+// The loaded XDP program, the crafted packets are run through it
+static PROGRAM: OnceCell<OwnedFd> = OnceCell::new();
+
+pub fn init(program: OwnedFd) {
+    let _ = PROGRAM.set(program);
+}
+
+#[derive(Debug, Serialize)]
+pub struct Step {
+    pub name: &'static str,
+    pub ok: bool,
+    // Seconds since the start of the test
+    pub seconds: f64,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub service: String,
+    pub service_ip: String,
+    pub passed: bool,
+    pub steps: Vec<Step>,
+}
+
+// Send a TCP SYN to the service through the XDP program and follow it down the pipeline: the
+// verdict of the program, the event reaching the agent, and for a scaled down service the wake,
+// its ready endpoints and the gate opening. Stops at the first step not reached within `timeout`.
+// The program leaves no state behind for packets from SELFTEST_SOURCE and skips the wake threshold
+// for them, the maps of the production traffic are untouched
+pub async fn run(
+    namespace: &str,
+    service_name: &str,
+    port: u16,
+    timeout: Duration,
+) -> anyhow::Result<Report> {
+    let program = PROGRAM
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("Self-test needs the eBPF datapath"))?;
+    let (service_ip, service) = WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, service)| service.namespace == namespace && service.service_name == service_name)
+        .map(|(ip, service)| (ip.clone(), service.clone()))
+        .ok_or_else(|| anyhow::anyhow!("Service {}/{} is not watched", namespace, service_name))?;
+    let address: Ipv4Addr = service_ip.parse()?;
+    info!(target: "selftest", "Self-test of service {}/{} ({}:{})", namespace, service_name, address, port);
+
+    let started = Instant::now();
+    let started_at = SystemTime::now();
+    let started_secs = chrono::Utc::now().timestamp();
+    let deadline = started + timeout;
+    let mut report = Report {
+        service: format!("{}/{}", namespace, service_name),
+        service_ip: service_ip.clone(),
+        passed: false,
+        steps: Vec::new(),
+    };
+    let mut step = |name: &'static str, ok: bool, detail: String| {
+        report.steps.push(Step {
+            name,
+            ok,
+            seconds: started.elapsed().as_secs_f64(),
+            detail,
+        });
+        ok
+    };
+
+    let verdict = test_run(program, &syn_packet(address, port))?;
+    let verdict_name = XDP_ACTIONS
+        .get(verdict as usize)
+        .copied()
+        .unwrap_or("unknown");
+    let detail = format!("verdict {}", verdict_name);
+    if !step("packet", verdict != 0, detail) {
+        return Ok(report);
+    }
+
+    if service.backend_available {
+        let seen = wait(deadline, || {
+            WATCHED_SERVICES
+                .lock()
+                .unwrap()
+                .get(&service_ip)
+                .is_some_and(|service| service.last_packet_time >= started_secs)
+        })
+        .await;
+        step("activity", seen, "idle timer refreshed".to_string());
+        report.passed = seen;
+        return Ok(report);
+    }
+
+    let woken = wait(deadline, || {
+        let called = LAST_CALLED
+            .lock()
+            .unwrap()
+            .get(&service_ip)
+            .is_some_and(|time| *time >= started_at);
+        called || available(&service_ip)
+    })
+    .await;
+    if !step("wake", woken, "wake event processed".to_string()) {
+        return Ok(report);
+    }
+    // the standalone mode has no EndpointSlices to look at
+    if config::get().standalone.is_none() {
+        let ready = wait(deadline, || endpoints::ready_endpoints(&service_ip) > 0).await;
+        if !step("ready", ready, "ready endpoints".to_string()) {
+            return Ok(report);
+        }
+    }
+    let opened = wait(deadline, || available(&service_ip)).await;
+    step("gate", opened, "gate opened".to_string());
+    report.passed = opened;
+    Ok(report)
+}
+
+fn available(service_ip: &str) -> bool {
+    WATCHED_SERVICES
+        .lock()
+        .unwrap()
+        .get(service_ip)
+        .is_some_and(|service| service.backend_available)
+}
+
+// Poll the condition every 100ms until the deadline
+async fn wait(deadline: Instant, mut condition: impl FnMut() -> bool) -> bool {
+    loop {
+        if condition() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Ethernet, IPv4 and TCP headers of a SYN from SELFTEST_SOURCE
+fn syn_packet(address: Ipv4Addr, port: u16) -> Vec<u8> {
+    let mut packet = vec![0u8; 14 + 20 + 20];
+    // IPv4 ethertype, the MAC addresses stay zero
+    packet[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+    let ip = &mut packet[14..34];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&40u16.to_be_bytes());
+    ip[8] = 64;
+    ip[9] = 6;
+    ip[12..16].copy_from_slice(&SELFTEST_SOURCE.to_be_bytes());
+    ip[16..20].copy_from_slice(&address.octets());
+    let mut sum: u32 = ip
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum();
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum = (sum & 0xffff) + (sum >> 16);
+    ip[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    let tcp = &mut packet[34..54];
+    tcp[0..2].copy_from_slice(&SELFTEST_SOURCE_PORT.to_be_bytes());
+    tcp[2..4].copy_from_slice(&port.to_be_bytes());
+    // header length of 5 words, SYN
+    tcp[12] = 0x50;
+    tcp[13] = 0x02;
+    tcp[14..16].copy_from_slice(&65535u16.to_be_bytes());
+    packet
+}
+
+// The test part of bpf_attr
+#[repr(C)]
+#[derive(Default)]
+struct TestRunAttr {
+    prog_fd: u32,
+    retval: u32,
+    data_size_in: u32,
+    data_size_out: u32,
+    data_in: u64,
+    data_out: u64,
+    repeat: u32,
+    duration: u32,
+    ctx_size_in: u32,
+    ctx_size_out: u32,
+    ctx_in: u64,
+    ctx_out: u64,
+    flags: u32,
+    cpu: u32,
+    batch_size: u32,
+}
+
+// Run the program on the packet with BPF_PROG_TEST_RUN, returns its verdict
+fn test_run(program: &OwnedFd, packet: &[u8]) -> anyhow::Result<u32> {
+    let mut attr = TestRunAttr {
+        prog_fd: program.as_raw_fd() as u32,
+        data_size_in: packet.len() as u32,
+        data_in: packet.as_ptr() as u64,
+        repeat: 1,
+        ..Default::default()
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            BPF_PROG_TEST_RUN,
+            &mut attr as *mut TestRunAttr,
+            mem::size_of::<TestRunAttr>(),
+        )
+    };
+    if ret < 0 {
+        return Err(anyhow::anyhow!(
+            "BPF_PROG_TEST_RUN failed: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(attr.retval)
+}
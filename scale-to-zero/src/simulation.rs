@@ -0,0 +1,212 @@
+use anyhow::Context;
+use k8s_openapi::serde_json;
+use log::info;
+use scale_to_zero_common::PacketLog;
+use serde::Deserialize;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::task;
+use tokio::time::{sleep_until, Instant};
+
+use crate::activity::ActivitySource;
+use crate::kubernetes::models::{ActivityKind, WATCHED_SERVICES};
+use crate::recorder::EventAction;
+use crate::utils;
+
+const ETH_HDR_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+
+struct Record {
+    timestamp: f64,
+    ipv4_address: u32,
+    // What the event did when it was recorded by the admin API events, None for packets
+    event: Option<(EventAction, ActivityKind)>,
+}
+
+struct RecordedPacket {
+    offset: Duration,
+    ipv4_address: u32,
+    event: Option<(EventAction, ActivityKind)>,
+}
+
+// A line of a JSON recording, the output of the admin API capture and events can be replayed as is
+#[derive(Deserialize)]
+struct JsonRecord {
+    timestamp: f64,
+    service_ip: Ipv4Addr,
+    action: Option<EventAction>,
+    source: Option<ActivityKind>,
+}
+
+// Replays a recording of packets in place of the XDP program, deciding to pass or gate every
+// packet the same way the XDP program does
+pub struct ReplaySource {
+    packets: Vec<RecordedPacket>,
+    speed: f64,
+}
+
+impl ReplaySource {
+    pub fn from_file(path: &Path, speed: f64) -> anyhow::Result<Self> {
+        if speed <= 0.0 {
+            anyhow::bail!("Replay speed must be positive, got {}", speed);
+        }
+        let data = std::fs::read(path)
+            .with_context(|| format!("Failed to read recording {}", path.display()))?;
+
+        let records = if is_pcap(&data) {
+            parse_pcap(&data)
+        } else {
+            parse_json(&data)
+        }
+        .with_context(|| format!("Failed to parse recording {}", path.display()))?;
+
+        Ok(ReplaySource {
+            packets: to_recorded_packets(records),
+            speed,
+        })
+    }
+}
+
+impl ActivitySource for ReplaySource {
+    fn name(&self) -> &'static str {
+        "replay"
+    }
+
+    fn start(self: Box<Self>) -> anyhow::Result<()> {
+        info!(target: "simulation", "Replaying {} packets at {}x speed", self.packets.len(), self.speed);
+        task::spawn(async move {
+            let started = Instant::now();
+            for packet in self.packets.iter() {
+                sleep_until(started + packet.offset.div_f64(self.speed)).await;
+                match packet.event {
+                    // recorded events replay what they did, whatever the state of the service
+                    Some((action, ActivityKind::Packets)) => {
+                        utils::process_packet(PacketLog {
+                            ipv4_address: packet.ipv4_address,
+                            action: if action == EventAction::Wake { 1 } else { 0 },
+                            source_address: 0,
+                        })
+                        .await
+                    }
+                    Some((_, kind)) => {
+                        utils::process_activity(packet.ipv4_address.into(), kind).await
+                    }
+                    None => {
+                        if let Some(packet_log) = gate(packet.ipv4_address) {
+                            utils::process_packet(packet_log).await;
+                        }
+                    }
+                }
+            }
+            info!(target: "simulation", "Replay finished");
+        });
+        Ok(())
+    }
+}
+
+// Same decision as the XDP program: unwatched destinations pass silently, watched ones are
+// reported as activity and request a scale up while their backends are unavailable
+fn gate(ipv4_address: u32) -> Option<PacketLog> {
+    let services = WATCHED_SERVICES.lock().unwrap();
+    let service = services.get(&Ipv4Addr::from(ipv4_address).to_string())?;
+    Some(PacketLog {
+        ipv4_address,
+        action: if service.backend_available { 0 } else { 1 },
+        source_address: 0,
+    })
+}
+
+fn to_recorded_packets(mut records: Vec<Record>) -> Vec<RecordedPacket> {
+    records.sort_by(|a, b| a.timestamp.total_cmp(&b.timestamp));
+    let first = records.first().map(|r| r.timestamp).unwrap_or_default();
+    records
+        .into_iter()
+        .map(|record| RecordedPacket {
+            offset: Duration::from_secs_f64(record.timestamp - first),
+            ipv4_address: record.ipv4_address,
+            event: record.event,
+        })
+        .collect()
+}
+
+fn parse_json(data: &[u8]) -> anyhow::Result<Vec<Record>> {
+    let data = std::str::from_utf8(data).context("Recording is neither pcap nor UTF-8 JSON")?;
+    let mut records = Vec::new();
+    for (n, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: JsonRecord = serde_json::from_str(line)
+            .with_context(|| format!("Invalid record on line {}", n + 1))?;
+        records.push(Record {
+            timestamp: record.timestamp,
+            ipv4_address: record.service_ip.into(),
+            event: record
+                .action
+                .map(|action| (action, record.source.unwrap_or(ActivityKind::Packets))),
+        });
+    }
+    Ok(records)
+}
+
+fn is_pcap(data: &[u8]) -> bool {
+    data.len() >= 4
+        && matches!(
+            u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            0xa1b2c3d4 | 0xa1b23c4d | 0xd4c3b2a1 | 0x4d3cb2a1
+        )
+}
+
+// Read the destination of every IPv4 packet of an ethernet pcap recording
+fn parse_pcap(data: &[u8]) -> anyhow::Result<Vec<Record>> {
+    if data.len() < 24 {
+        anyhow::bail!("Truncated pcap header");
+    }
+    let (big_endian, nanos) = match u32::from_le_bytes([data[0], data[1], data[2], data[3]]) {
+        0xa1b2c3d4 => (false, false),
+        0xa1b23c4d => (false, true),
+        0xd4c3b2a1 => (true, false),
+        _ => (true, true),
+    };
+    let read_u32 = |b: &[u8]| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    };
+    if read_u32(&data[20..24]) != 1 {
+        anyhow::bail!("Only ethernet pcap recordings are supported");
+    }
+
+    let mut records = Vec::new();
+    let mut offset = 24;
+    while offset + 16 <= data.len() {
+        let ts_sec = read_u32(&data[offset..]);
+        let ts_frac = read_u32(&data[offset + 4..]);
+        let start = offset + 16;
+        let end = start + read_u32(&data[offset + 8..]) as usize;
+        if end > data.len() {
+            break;
+        }
+        let packet = &data[start..end];
+        offset = end;
+
+        // Only IPv4 packets are gated by the XDP program
+        if packet.len() < ETH_HDR_LEN + 20
+            || u16::from_be_bytes([packet[12], packet[13]]) != ETHERTYPE_IPV4
+        {
+            continue;
+        }
+        let dst = u32::from_be_bytes([packet[30], packet[31], packet[32], packet[33]]);
+        let timestamp = ts_sec as f64 + ts_frac as f64 / if nanos { 1e9 } else { 1e6 };
+        records.push(Record {
+            timestamp,
+            ipv4_address: dst,
+            event: None,
+        });
+    }
+    Ok(records)
+}
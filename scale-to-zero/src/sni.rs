@@ -0,0 +1,283 @@
+use aya::{
+    maps::{perf::AsyncPerfEventArray, HashMap, MapData},
+    util::online_cpus,
+};
+use bytes::BytesMut;
+use log::{debug, info, warn};
+use scale_to_zero_common::CaptureHeader;
+use std::mem;
+use std::net::Ipv4Addr;
+use tokio::task;
+
+use crate::activity;
+use crate::destination_filter;
+use crate::kubernetes::ingress;
+use crate::kubernetes::models::WATCHED_SERVICES;
+use crate::kubernetes::scaler;
+
+const ETH_HDR_LEN: usize = 14;
+// TLS extension carrying the server name, and the type of a host name in it
+const SERVER_NAME_EXTENSION: u16 = 0;
+const HOST_NAME: u8 = 0;
+
+// Mark the IPs whose TLS connections wake services by server name
+pub fn add_entrypoints(
+    entrypoints_map: &mut HashMap<MapData, u32, u32>,
+    entrypoints: &[Ipv4Addr],
+) -> anyhow::Result<()> {
+    for entrypoint in entrypoints {
+        destination_filter::insert(u32::from(*entrypoint));
+        entrypoints_map.insert(u32::from(*entrypoint), 0, 0)?;
+        info!(target: "sni", "Waking services by TLS server name on {}", entrypoint);
+    }
+    Ok(())
+}
+
+pub fn start_readers(mut perf_array: AsyncPerfEventArray<MapData>) -> anyhow::Result<()> {
+    for cpu_id in online_cpus()? {
+        let mut buf = perf_array.open(cpu_id, None)?;
+
+        task::spawn(async move {
+            let mut buffers = (0..10)
+                .map(|_| BytesMut::with_capacity(2048))
+                .collect::<Vec<_>>();
+
+            loop {
+                let events = match buf.read_events(&mut buffers).await {
+                    Ok(events) => events,
+                    Err(err) => {
+                        warn!(target: "sni", "Failed to read the ClientHellos of CPU {}: {}", cpu_id, err);
+                        tokio::time::sleep(activity::READ_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                };
+                for buf in buffers.iter_mut().take(events.read) {
+                    let ptr = buf.as_ptr() as *const CaptureHeader;
+                    let header = unsafe { ptr.read_unaligned() };
+                    let start = mem::size_of::<CaptureHeader>();
+                    let end = (start + header.captured_len as usize).min(buf.len());
+                    match server_name(&buf[start..end]) {
+                        Some(server_name) => {
+                            task::spawn(wake(server_name));
+                        }
+                        None => {
+                            debug!(target: "sni", "No server name in a ClientHello sent to {}", Ipv4Addr::from(header.ipv4_address));
+                        }
+                    }
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+// Wake the sleeping services behind a server name
+async fn wake(server_name: String) {
+    let service_ips = services_for_server_name(&server_name);
+    if service_ips.is_empty() {
+        debug!(target: "sni", "No watched service for server name {}", server_name);
+        return;
+    }
+    let sleeping: Vec<String> = {
+        let watched_services = WATCHED_SERVICES.lock().unwrap();
+        service_ips
+            .into_iter()
+            .filter(|ip| matches!(watched_services.get(ip), Some(service) if !service.backend_available))
+            .collect()
+    };
+    for service_ip in sleeping {
+        info!(target: "sni", "Waking {} for server name {}", service_ip, server_name);
+        // every connection of the client during the wake ends up here, they are rate limited
+        if let Err(err) = scaler::scale_up(service_ip.clone()).await {
+            debug!(target: "sni", "Failed to wake {}: {}", service_ip, err);
+        }
+    }
+}
+
+// Services with the server name in their server-names annotation, exact names before wildcards.
+// Without one, the hosts of the Ingresses and HTTPRoutes are used
+fn services_for_server_name(server_name: &str) -> Vec<String> {
+    let watched_services = WATCHED_SERVICES.lock().unwrap();
+    let matching = |exact: bool| -> Vec<String> {
+        watched_services
+            .iter()
+            .filter(|(_, service)| {
+                service.server_names.iter().any(|name| {
+                    if exact {
+                        name.eq_ignore_ascii_case(server_name)
+                    } else {
+                        ingress::host_matches(name, server_name)
+                    }
+                })
+            })
+            .map(|(ip, _)| ip.clone())
+            .collect()
+    };
+    let mut service_ips = matching(true);
+    if service_ips.is_empty() {
+        service_ips = matching(false);
+    }
+    drop(watched_services);
+
+    if service_ips.is_empty() {
+        service_ips = ingress::services_for_host(server_name);
+    }
+    service_ips
+}
+
+// Server name of the ClientHello in a packet, which starts at the ethernet header
+fn server_name(packet: &[u8]) -> Option<String> {
+    let ip = packet.get(ETH_HDR_LEN..)?;
+    let ihl = (*ip.first()? & 0x0f) as usize * 4;
+    let tcp = ip.get(ihl..)?;
+    let data_offset = (*tcp.get(12)? >> 4) as usize * 4;
+    let mut tls = Reader(tcp.get(data_offset..)?);
+
+    // TLS record header, then the handshake header
+    tls.skip(5)?;
+    tls.skip(4)?;
+    // client version and random
+    tls.skip(2 + 32)?;
+    let session_id_len = tls.u8()? as usize;
+    tls.skip(session_id_len)?;
+    let cipher_suites_len = tls.u16()? as usize;
+    tls.skip(cipher_suites_len)?;
+    let compression_methods_len = tls.u8()? as usize;
+    tls.skip(compression_methods_len)?;
+
+    let extensions_len = tls.u16()? as usize;
+    let mut extensions = Reader(tls.take(extensions_len)?);
+    while let Some(extension_type) = extensions.u16() {
+        let len = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(len)?);
+        if extension_type != SERVER_NAME_EXTENSION {
+            continue;
+        }
+        let list_len = extension.u16()? as usize;
+        let mut names = Reader(extension.take(list_len)?);
+        while let Some(name_type) = names.u8() {
+            let name_len = names.u16()? as usize;
+            let name = names.take(name_len)?;
+            if name_type == HOST_NAME {
+                return std::str::from_utf8(name).ok().map(str::to_lowercase);
+            }
+        }
+        return None;
+    }
+    None
+}
+
+// Bounds checked cursor over the bytes of a ClientHello, which is truncated anywhere when it spans
+// several segments
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUPPORTED_VERSIONS_EXTENSION: u16 = 43;
+
+    fn extension(extension_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut extension = extension_type.to_be_bytes().to_vec();
+        extension.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        extension.extend_from_slice(data);
+        extension
+    }
+
+    fn server_name_extension(names: &[(u8, &str)]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for (name_type, name) in names {
+            list.push(*name_type);
+            list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            list.extend_from_slice(name.as_bytes());
+        }
+        let mut data = (list.len() as u16).to_be_bytes().to_vec();
+        data.extend_from_slice(&list);
+        extension(SERVER_NAME_EXTENSION, &data)
+    }
+
+    // A ClientHello in a single segment, from the ethernet header
+    fn packet(extensions: &[u8]) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        // session id, one cipher suite, the null compression method
+        hello.push(0);
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(extensions);
+
+        let mut packet = vec![0; ETH_HDR_LEN];
+        let mut ip = vec![0; 20];
+        ip[0] = 0x45;
+        packet.extend_from_slice(&ip);
+        let mut tcp = vec![0; 20];
+        tcp[12] = 0x50;
+        packet.extend_from_slice(&tcp);
+        // handshake record, then the ClientHello handshake header
+        packet.extend_from_slice(&[0x16, 0x03, 0x01]);
+        packet.extend_from_slice(&((hello.len() + 4) as u16).to_be_bytes());
+        packet.push(0x01);
+        packet.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        packet.extend_from_slice(&hello);
+        packet
+    }
+
+    #[test]
+    fn full_client_hello() {
+        let mut extensions = extension(SUPPORTED_VERSIONS_EXTENSION, &[0x02, 0x03, 0x04]);
+        extensions.extend(server_name_extension(&[(HOST_NAME, "App.Example.com")]));
+        assert_eq!(
+            server_name(&packet(&extensions)),
+            Some("app.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn truncated_client_hello() {
+        let packet = packet(&server_name_extension(&[(HOST_NAME, "app.example.com")]));
+        // every cut lands in a length field or the bytes it covers
+        for len in 0..packet.len() {
+            assert_eq!(server_name(&packet[..len]), None, "cut at {}", len);
+        }
+    }
+
+    #[test]
+    fn host_name_after_another_name_type() {
+        let extensions = server_name_extension(&[(1, "ignored"), (HOST_NAME, "app.example.com")]);
+        assert_eq!(
+            server_name(&packet(&extensions)),
+            Some("app.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn without_server_name_extension() {
+        let extensions = extension(SUPPORTED_VERSIONS_EXTENSION, &[0x02, 0x03, 0x04]);
+        assert_eq!(server_name(&packet(&extensions)), None);
+    }
+}
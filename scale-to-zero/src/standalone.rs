@@ -0,0 +1,489 @@
+use anyhow::Context;
+use k8s_openapi::chrono;
+use k8s_openapi::serde_json;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use scale_to_zero_common::PROTOCOL_ALL;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UdpSocket, UnixStream};
+use tokio::process::Command;
+
+use crate::agent_traffic;
+use crate::kubernetes::controller;
+use crate::kubernetes::maintenance;
+use crate::kubernetes::models::{ActivityKind, ServiceData, WATCHED_SERVICES};
+use crate::kubernetes::warm_up;
+use crate::metrics;
+
+// Kind and namespace of the targets of the static config, they show up as such in the admin API
+// and the metrics
+const KIND: &str = "standalone";
+const NAMESPACE: &str = "standalone";
+
+// Default idle time (seconds) of a target and time an executor gets to complete
+const DEFAULT_SCALE_DOWN_TIME: i64 = 300;
+const DEFAULT_EXECUTOR_TIMEOUT: u64 = 120;
+
+// The static config given with --standalone, a JSON file mapping IPs to the executors waking them
+// up and putting them to sleep
+#[derive(Deserialize)]
+struct StandaloneConfig {
+    targets: Vec<Target>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Target {
+    name: String,
+    ip: Ipv4Addr,
+    #[serde(default = "default_scale_down_time")]
+    scale_down_time: i64,
+    wake: Executor,
+    sleep: Executor,
+    // Whether the target is running when the agent starts, it is woken by traffic otherwise
+    #[serde(default = "default_running")]
+    running: bool,
+}
+
+fn default_scale_down_time() -> i64 {
+    DEFAULT_SCALE_DOWN_TIME
+}
+
+fn default_running() -> bool {
+    true
+}
+
+// What wakes a target up or puts it to sleep. An executor returns once the target is up, the gate
+// opens then
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase", rename_all_fields = "kebab-case")]
+enum Executor {
+    // A command run without a shell, e.g. ["virsh", "start", "jellyfin"]
+    Exec {
+        command: Vec<String>,
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+    // An HTTP request, a status other than 2xx fails it
+    Http {
+        url: String,
+        #[serde(default = "default_method")]
+        method: String,
+        body: Option<String>,
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+    // A Wake-on-LAN magic packet to the MAC address of a bare-metal machine. With wait-port, it
+    // completes once the port of the target accepts connections
+    Wol {
+        mac: String,
+        #[serde(default = "default_wol_broadcast")]
+        broadcast: SocketAddr,
+        wait_port: Option<u16>,
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+    // A command run on the machine over ssh, in batch mode so it never waits for a password
+    Ssh {
+        host: String,
+        #[serde(default = "default_ssh_command")]
+        command: String,
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+    // A chassis power action (on, soft, off...) through the BMC of the machine with ipmitool. The
+    // password is read from password-file, or from IPMI_PASSWORD without one
+    Ipmi {
+        host: String,
+        user: String,
+        password_file: Option<PathBuf>,
+        #[serde(default = "default_ipmi_action")]
+        action: String,
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+    // A container action (start, stop, pause, unpause) through the Docker API on a unix socket,
+    // Podman serves the same API. With wait-port, a start completes once the port accepts
+    // connections
+    Container {
+        name: String,
+        action: ContainerAction,
+        #[serde(default = "default_container_socket")]
+        socket: PathBuf,
+        wait_port: Option<u16>,
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ContainerAction {
+    Start,
+    Stop,
+    Pause,
+    Unpause,
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_EXECUTOR_TIMEOUT
+}
+
+fn default_method() -> String {
+    "POST".to_string()
+}
+
+fn default_wol_broadcast() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::BROADCAST, 9))
+}
+
+fn default_ssh_command() -> String {
+    "sudo systemctl poweroff".to_string()
+}
+
+fn default_ipmi_action() -> String {
+    "soft".to_string()
+}
+
+fn default_container_socket() -> PathBuf {
+    PathBuf::from("/var/run/docker.sock")
+}
+
+impl Executor {
+    async fn run(&self, ip: Ipv4Addr) -> anyhow::Result<()> {
+        match self {
+            Executor::Exec { command, timeout } => {
+                let (program, args) = command
+                    .split_first()
+                    .ok_or_else(|| anyhow::anyhow!("The command is empty"))?;
+                run_command(program, args, *timeout).await
+            }
+            Executor::Wol {
+                mac,
+                broadcast,
+                wait_port,
+                timeout,
+            } => {
+                send_magic_packet(mac, *broadcast).await?;
+                match wait_port {
+                    Some(port) => wait_for_port(SocketAddr::from((ip, *port)), *timeout).await,
+                    None => Ok(()),
+                }
+            }
+            Executor::Ssh {
+                host,
+                command,
+                timeout,
+            } => {
+                let args = [
+                    "-o".to_string(),
+                    "BatchMode=yes".to_string(),
+                    host.clone(),
+                    command.clone(),
+                ];
+                run_command("ssh", &args, *timeout).await
+            }
+            Executor::Ipmi {
+                host,
+                user,
+                password_file,
+                action,
+                timeout,
+            } => {
+                let mut args = vec!["-I", "lanplus", "-H", host.as_str(), "-U", user.as_str()];
+                match password_file.as_ref().and_then(|path| path.to_str()) {
+                    Some(path) => args.extend(["-f", path]),
+                    None => args.push("-E"),
+                }
+                args.extend(["chassis", "power", action.as_str()]);
+                let args: Vec<String> = args.into_iter().map(String::from).collect();
+                run_command("ipmitool", &args, *timeout).await
+            }
+            Executor::Container {
+                name,
+                action,
+                socket,
+                wait_port,
+                timeout,
+            } => {
+                let path = match action {
+                    ContainerAction::Start => format!("/containers/{}/start", name),
+                    ContainerAction::Stop => format!("/containers/{}/stop", name),
+                    ContainerAction::Pause => format!("/containers/{}/pause", name),
+                    ContainerAction::Unpause => format!("/containers/{}/unpause", name),
+                };
+                tokio::time::timeout(Duration::from_secs(*timeout), post_unix(socket, &path))
+                    .await
+                    .map_err(|_| {
+                        anyhow::anyhow!("{} of {} did not complete within {}s", path, name, timeout)
+                    })??;
+                match (action, wait_port) {
+                    (ContainerAction::Start | ContainerAction::Unpause, Some(port)) => {
+                        wait_for_port(SocketAddr::from((ip, *port)), *timeout).await
+                    }
+                    _ => Ok(()),
+                }
+            }
+            Executor::Http {
+                url,
+                method,
+                body,
+                timeout,
+            } => {
+                let (status, _) = agent_traffic::request(
+                    method,
+                    url,
+                    body.clone().unwrap_or_default(),
+                    Duration::from_secs(*timeout),
+                )
+                .await?;
+                if !status.is_success() {
+                    anyhow::bail!("{} {} returned {}", method, url, status);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn run_command(program: &str, args: &[String], timeout: u64) -> anyhow::Result<()> {
+    let output = tokio::time::timeout(
+        Duration::from_secs(timeout),
+        Command::new(program).args(args).kill_on_drop(true).output(),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("{} did not complete within {}s", program, timeout))??;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} exited with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+// Six 0xff bytes followed by the MAC address sixteen times
+async fn send_magic_packet(mac: &str, broadcast: SocketAddr) -> anyhow::Result<()> {
+    let bytes = mac
+        .split([':', '-'])
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .ok()
+        .filter(|bytes| bytes.len() == 6)
+        .ok_or_else(|| anyhow::anyhow!("{} is not a MAC address", mac))?;
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend_from_slice(&bytes);
+    }
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(&packet, broadcast).await?;
+    Ok(())
+}
+
+// POST to the API of the container engine, over HTTP/1.0 so the engine closes the connection once
+// it answered. 304 means the container already is in the requested state
+async fn post_unix(socket: &Path, path: &str) -> anyhow::Result<()> {
+    let mut stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    let request = format!(
+        "POST {} HTTP/1.0\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+        path
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| anyhow::anyhow!("Unexpected response from {}", socket.display()))?;
+    if !(200..300).contains(&status) && status != 304 {
+        let body = response.split_once("\r\n\r\n").map(|(_, body)| body);
+        anyhow::bail!(
+            "POST {} returned {}: {}",
+            path,
+            status,
+            body.unwrap_or_default().trim()
+        );
+    }
+    Ok(())
+}
+
+// Wait until a machine that was powered on accepts connections
+async fn wait_for_port(address: SocketAddr, timeout: u64) -> anyhow::Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+    loop {
+        // from the agent's ports, the connect would be activity of the machine otherwise
+        let attempt = tokio::time::timeout(Duration::from_secs(2), agent_traffic::connect(address));
+        if let Ok(Ok(_)) = attempt.await {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!("{} did not accept connections within {}s", address, timeout);
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+}
+
+// This contains the executors of each target IP
+static TARGETS: Lazy<Mutex<HashMap<String, Target>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Targets whose wake executor is running, an executor outlasting the rate limit of the wakes isn't
+// started twice
+static WAKING: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+fn read_config(path: &Path) -> anyhow::Result<StandaloneConfig> {
+    let config: StandaloneConfig = serde_json::from_slice(
+        &std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?,
+    )
+    .with_context(|| format!("Failed to parse {}", path.display()))?;
+    let mut ips = HashSet::new();
+    for target in config.targets.iter() {
+        if !ips.insert(target.ip) {
+            anyhow::bail!("{} is the IP of several targets", target.ip);
+        }
+    }
+    Ok(config)
+}
+
+// Parse the static config without gating anything, for --validate. Returns the number of targets
+pub fn check(path: &Path) -> anyhow::Result<usize> {
+    Ok(read_config(path)?.targets.len())
+}
+
+// Load the static config, its targets take the place of the annotated services
+pub fn init(path: &Path) -> anyhow::Result<()> {
+    let config = read_config(path)?;
+
+    let now = chrono::Utc::now().timestamp();
+    let mut targets = TARGETS.lock().unwrap();
+    let mut watched_services = WATCHED_SERVICES.lock().unwrap();
+    for target in config.targets {
+        let ip = target.ip.to_string();
+        info!(target: "standalone", "Gating {} on {}", target.name, ip);
+        watched_services.insert(
+            ip.clone(),
+            ServiceData {
+                scale_down_time: target.scale_down_time,
+                last_packet_time: now,
+                kind: KIND.to_string(),
+                name: target.name.clone(),
+                namespace: NAMESPACE.to_string(),
+                service_name: target.name.clone(),
+                backend_available: target.running,
+                monitor: false,
+                unmanageable: None,
+                hold_connections: false,
+                buffer_udp: false,
+                priority: Default::default(),
+                gated_action: Default::default(),
+                activity_protocols: PROTOCOL_ALL,
+                activity_ports: Vec::new(),
+                pre_wake_hook: None,
+                post_scale_down_hook: None,
+                wake_quota: None,
+                wake_threshold: Default::default(),
+                scale_down_condition: None,
+                queue_depth: None,
+                wake_group: Vec::new(),
+                patch_templates: None,
+                clients_per_replica: None,
+                checkpoint: false,
+                log_activity: None,
+                activity_sources: vec![ActivityKind::Packets],
+                placeholder_priority_class: None,
+                hpa: None,
+                server_names: Vec::new(),
+                load_balancer_ips: Vec::new(),
+                ipv6_addresses: Vec::new(),
+                http_port: None,
+                hook_status: Default::default(),
+                wake_failure: None,
+                active_hours: None,
+                policies: Vec::new(),
+            },
+        );
+        targets.insert(ip, target);
+    }
+    // there is no cluster to reconcile, the service list is complete
+    controller::mark_synced_without_cluster();
+    Ok(())
+}
+
+// Put the targets idle past their scale-down-time to sleep
+pub async fn scale_down() {
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        if maintenance::enabled() || warm_up::active() {
+            continue;
+        }
+        let now = chrono::Utc::now().timestamp();
+        let idle: Vec<String> = WATCHED_SERVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, service)| {
+                service.backend_available
+                    && now - service.last_packet_time > service.scale_down_time
+            })
+            .map(|(ip, _)| ip.clone())
+            .collect();
+        for service_ip in idle {
+            let target = match TARGETS.lock().unwrap().get(&service_ip).cloned() {
+                Some(target) => target,
+                None => continue,
+            };
+            info!(target: "standalone", "Putting {} to sleep", target.name);
+            // gated first, so no connection reaches a target on its way down
+            set_available(&service_ip, false);
+            if let Err(err) = target.sleep.run(target.ip).await {
+                warn!(target: "standalone", "Failed to put {} to sleep: {:#}", target.name, err);
+                set_available(&service_ip, true);
+                continue;
+            }
+            metrics::SERVICE_SCALE_DOWNS
+                .with_label_values(&[NAMESPACE, &target.name])
+                .inc();
+        }
+    }
+}
+
+// Wake a target up, the traffic stays gated until its wake executor completes
+pub async fn wake(service_ip: &str) -> anyhow::Result<()> {
+    let target = TARGETS
+        .lock()
+        .unwrap()
+        .get(service_ip)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a standalone target", service_ip))?;
+    if !WAKING.lock().unwrap().insert(service_ip.to_string()) {
+        return Ok(());
+    }
+    info!(target: "standalone", "Waking {}", target.name);
+    let woken = target.wake.run(target.ip).await;
+    WAKING.lock().unwrap().remove(service_ip);
+    woken.with_context(|| format!("Failed to wake {}", target.name))?;
+    set_available(service_ip, true);
+    metrics::SERVICE_WAKES
+        .with_label_values(&[NAMESPACE, &target.name])
+        .inc();
+    Ok(())
+}
+
+fn set_available(service_ip: &str, available: bool) {
+    if let Some(service) = WATCHED_SERVICES.lock().unwrap().get_mut(service_ip) {
+        service.backend_available = available;
+        service.last_packet_time = chrono::Utc::now().timestamp();
+    }
+}
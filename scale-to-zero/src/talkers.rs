@@ -0,0 +1,91 @@
+use k8s_openapi::chrono;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use crate::kubernetes::endpoints;
+
+// Sources kept per service, the least recently seen one makes room for a new one
+const MAX_TALKERS: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Talker {
+    wakes: u64,
+    activity: u64,
+    // unix seconds
+    last_seen: i64,
+}
+
+// A client of a service, with the pod or node its address belongs to when it is known
+#[derive(Debug, Clone, Serialize)]
+pub struct TopTalker {
+    pub address: Ipv4Addr,
+    pub client: Option<String>,
+    pub wakes: u64,
+    pub activity: u64,
+    pub last_seen: i64,
+}
+
+// This contains the wakes and activity events each source address caused, by service IP
+static TALKERS: Lazy<Mutex<HashMap<String, HashMap<u32, Talker>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Count an event of a service sent by a source address, either a wake or activity that refreshed
+// its idle timer
+pub fn record(service_ip: &str, source: u32, wake: bool) {
+    let now = chrono::Utc::now().timestamp();
+    let mut talkers = TALKERS.lock().unwrap();
+    let sources = talkers.entry(service_ip.to_string()).or_default();
+    if !sources.contains_key(&source) && sources.len() >= MAX_TALKERS {
+        if let Some(oldest) = sources
+            .iter()
+            .min_by_key(|(_, talker)| talker.last_seen)
+            .map(|(address, _)| *address)
+        {
+            sources.remove(&oldest);
+        }
+    }
+    let talker = sources.entry(source).or_default();
+    if wake {
+        talker.wakes += 1;
+    } else {
+        talker.activity += 1;
+    }
+    talker.last_seen = now;
+}
+
+// The sources that woke the service or kept it active the most, wakes first
+pub fn top(service_ip: &str, limit: usize) -> Vec<TopTalker> {
+    let mut top: Vec<TopTalker> = TALKERS
+        .lock()
+        .unwrap()
+        .get(service_ip)
+        .map(|sources| {
+            sources
+                .iter()
+                .map(|(address, talker)| TopTalker {
+                    address: Ipv4Addr::from(*address),
+                    client: None,
+                    wakes: talker.wakes,
+                    activity: talker.activity,
+                    last_seen: talker.last_seen,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    top.sort_by_key(|talker| std::cmp::Reverse((talker.wakes, talker.activity, talker.last_seen)));
+    top.truncate(limit);
+    for talker in top.iter_mut() {
+        talker.client = endpoints::client(talker.address);
+    }
+    top
+}
+
+// Forget the services no longer watched, returns the number of services left
+pub fn retain(watched: &HashSet<String>) -> usize {
+    let mut talkers = TALKERS.lock().unwrap();
+    talkers.retain(|ip, _| watched.contains(ip));
+    talkers.len()
+}
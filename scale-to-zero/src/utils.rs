@@ -1,79 +1,255 @@
 use aya::{
     include_bytes_aligned,
-    maps::{HashMap, MapData},
-    Bpf,
+    maps::{Array, HashMap, MapData, PerCpuArray},
+    Bpf, BpfLoader,
 };
+use anyhow::Context;
+use ed25519_dalek::pkcs8::DecodePublicKey;
+use ed25519_dalek::{Signature, VerifyingKey};
 use k8s_openapi::chrono;
-use log::{error, info};
-use scale_to_zero_common::PacketLog;
-use std::net::Ipv4Addr;
+use log::{debug, error, info, warn};
+use object::{Object, ObjectSection, ObjectSymbol};
+use once_cell::sync::Lazy;
+use scale_to_zero_common::{
+    abi, gate_redirect, gate_redirect_http, host_port_key, PacketLog, PortRange, ServicePolicy,
+    WakeThreshold, ABI_SYMBOL, DROPPED_ICMP, DROPPED_OTHER, DROPPED_TCP, DROPPED_UDP, GATED_DROP,
+    GATED_PASS, GATED_REDIRECT, MAP_SCHEMA_VERSION, SERVICE_AVAILABLE, SERVICE_HOLD,
+    SERVICE_HOLD_UDP, SERVICE_LIST_MAX_ENTRIES, SERVICE_POLICY_PORT_RANGES,
+};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
 
+use crate::config;
+use crate::custom_metrics;
+use crate::destination_filter;
+use crate::diagnostics;
 use crate::kubernetes;
+use crate::kubernetes::models::{ActivityKind, GatedAction, ServiceData};
+use crate::kubernetes::scaler::WakeError;
+use crate::kubernetes::wake_trace;
+use crate::metrics;
+use crate::migration;
+use crate::recorder::{self, EventAction};
+use crate::talkers;
+use crate::waking_page;
+
+pub const PROGRAM_NAME: &str = "xdp_scale_to_zero_fw";
+
+// Maps the agent expects to find in the eBPF object
+const REQUIRED_MAPS: [&str; 24] = [
+    "DROPPED_PACKETS",
+    "MAP_SCHEMA",
+    "SCALE_REQUESTS",
+    "WAKE_REQUESTS",
+    "SERVICE_LIST",
+    "POD_TO_SERVICE",
+    "HOST_PORTS",
+    "LOAD_BALANCER_IPS",
+    "IPV6_SERVICES",
+    "HELD_PACKETS",
+    "HOLD_INTERFACE",
+    "OBSERVED_SERVICES",
+    "CAPTURE_LIST",
+    "CAPTURED_PACKETS",
+    "GATED_CLIENTS",
+    "WAKE_DROPS",
+    "GATE_REDIRECTS",
+    "CONNECT_REPORTS",
+    "AGENT_TRAFFIC",
+    "SNI_ENTRYPOINTS",
+    "CLIENT_HELLOS",
+    "DESTINATION_FILTER",
+    "XDP_PROGRAMS",
+    "TC_PROGRAMS",
+];
+
+// Percentage of SERVICE_LIST capacity at which a warning is logged
+const SERVICE_LIST_WARN_PERCENT: usize = 90;
+
+// This contains the SERVICE_LIST entries as last written by the agent, used to detect drift
+static LAST_SYNCED: Lazy<Mutex<std::collections::HashMap<u32, ServicePolicy>>> =
+    Lazy::new(|| Mutex::new(std::collections::HashMap::new()));
+
+// This contains the SERVICE_LIST entries read back from the kernel after the last sync
+pub static SERVICE_LIST_SNAPSHOT: Lazy<Mutex<std::collections::HashMap<u32, ServicePolicy>>> =
+    Lazy::new(|| Mutex::new(std::collections::HashMap::new()));
+
+// This contains how the datapath was attached to each network interface, or why it was not
+pub static ATTACH_STATUS: Lazy<Mutex<std::collections::BTreeMap<String, String>>> =
+    Lazy::new(|| Mutex::new(std::collections::BTreeMap::new()));
+
+static CAPACITY_WARNED: AtomicBool = AtomicBool::new(false);
+
+// Set by the consistency check, the next sync writes every entry and removes the unknown ones
+// whatever it reads back
+static FULL_SYNC: AtomicBool = AtomicBool::new(false);
+
+pub fn request_full_sync() {
+    FULL_SYNC.store(true, Ordering::Relaxed);
+}
 
 pub async fn process_packet(packet_log: PacketLog) {
+    process_event(packet_log, ActivityKind::Packets).await
+}
+
+// Activity of a service seen by another source than the datapath, it never wakes the service
+pub async fn process_activity(address: Ipv4Addr, kind: ActivityKind) {
+    let packet_log = PacketLog {
+        ipv4_address: address.into(),
+        action: 0,
+        source_address: 0,
+    };
+    process_event(packet_log, kind).await
+}
+
+async fn process_event(packet_log: PacketLog, kind: ActivityKind) {
     let dist_addr = Ipv4Addr::from(packet_log.ipv4_address);
     if dist_addr.is_loopback() {
         return;
     }
+    let event = if packet_log.action == 1 {
+        "wake"
+    } else {
+        "activity"
+    };
+    let _timer = metrics::EVENT_PROCESSING_SECONDS
+        .with_label_values(&[event])
+        .start_timer();
 
-    {
+    let gap = {
         let mut services = kubernetes::models::WATCHED_SERVICES.lock().unwrap();
 
         match services.get_mut(&dist_addr.to_string()) {
             Some(service) => {
-                service.last_packet_time = chrono::Utc::now().timestamp();
+                let action = if packet_log.action == 1 {
+                    EventAction::Wake
+                } else {
+                    EventAction::Activity
+                };
+                recorder::record(dist_addr, action, kind);
+                let wake = packet_log.action == 1;
+                if packet_log.source_address != 0
+                    && (wake || service.activity_sources.contains(&kind))
+                {
+                    talkers::record(&dist_addr.to_string(), packet_log.source_address, wake);
+                }
+                if kind == ActivityKind::Packets {
+                    custom_metrics::record_packet(&dist_addr.to_string());
+                }
+                // only the sources selected for the service refresh its idle timer
+                if service.activity_sources.contains(&kind) {
+                    let now = chrono::Utc::now().timestamp();
+                    let gap = now - service.last_packet_time;
+                    service.last_packet_time = now;
+                    kubernetes::gossip::seen(&dist_addr.to_string(), now);
+                    Some((gap, service.namespace.clone(), service.service_name.clone()))
+                } else {
+                    None
+                }
             }
-            None => {}
+            None => None,
         }
+    };
+    // packets within the same second are one burst
+    if let Some((gap, namespace, service_name)) = gap.filter(|(gap, _, _)| *gap >= 1) {
+        record_idle_gap(
+            &dist_addr.to_string(),
+            &namespace,
+            &service_name,
+            gap as u64,
+        );
     }
     if packet_log.action == 1 {
         match kubernetes::scaler::scale_up(dist_addr.to_string()).await {
+            // every CPU that saw the packet gets here, the wake itself is logged once by the scaler
             Ok(_) => {
-                info!("Scaled up {}", dist_addr);
+                debug!("Scaled up {}", dist_addr);
+            }
+            Err(err) if err.is::<WakeError>() => {
+                debug!("Not scaling up {}: {}", dist_addr, err);
             }
             Err(err) => {
-                if !err.to_string().starts_with("Rate Limited: Function ") {
-                    error!("Failed to scale up {}: {}", dist_addr, err);
-                }
+                error!("Failed to scale up {}: {}", dist_addr, err);
             }
         }
     }
 }
 
-pub async fn sync_data(scalable_service_list: &mut HashMap<&mut MapData, u32, u32>) {
-    let pod_ips: std::collections::HashMap<u32, u32> = kubernetes::models::WATCHED_SERVICES
-        .lock()
-        .unwrap()
-        .iter()
-        .map(|(k, v)| {
-            (
-                k.parse::<Ipv4Addr>().unwrap().into(),
-                v.backend_available as u32,
-            )
-        })
-        .collect();
+fn record_idle_gap(service_ip: &str, namespace: &str, service_name: &str, gap: u64) {
+    metrics::SERVICE_IDLE_GAPS
+        .with_label_values(&[namespace, service_name])
+        .observe(gap as f64);
+    let mut idle_gaps = kubernetes::models::IDLE_GAPS.lock().unwrap();
+    let gaps = idle_gaps.entry(service_ip.to_string()).or_default();
+    if gaps.len() == kubernetes::models::IDLE_GAPS_LEN {
+        gaps.pop_front();
+    }
+    gaps.push_back(gap);
+}
+
+pub async fn sync_data(scalable_service_list: &mut HashMap<MapData, u32, ServicePolicy>) {
+    let started = Instant::now();
+    let pod_ips: std::collections::HashMap<u32, ServicePolicy> =
+        kubernetes::models::WATCHED_SERVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.parse::<Ipv4Addr>().unwrap().into(), service_policy(v)))
+            .collect();
+
+    let mut last_synced = LAST_SYNCED.lock().unwrap();
+    let full = FULL_SYNC.swap(false, Ordering::Relaxed);
 
     for (key, value) in pod_ips.clone() {
+        if full {
+            insert_service(scalable_service_list, &mut last_synced, key, value);
+            continue;
+        }
         match scalable_service_list.get(&key, 0) {
             Ok(old_value) => {
+                if last_synced.get(&key) != Some(&old_value) {
+                    metrics::SYNC_DRIFT.inc();
+                    warn!("Service list drifted: {:?} {:?}", key, old_value);
+                }
                 if old_value != value {
-                    let _ = scalable_service_list.insert(key, value, 0);
-                    info!("Update service list: {:?} {}", key, value)
+                    insert_service(scalable_service_list, &mut last_synced, key, value);
+                    info!("Update service list: {:?} {:?}", key, value)
                 }
             }
             Err(_) => {
-                let _ = scalable_service_list.insert(key, value, 0);
-                info!("Add service list: {:?} {}", key, value)
+                if last_synced.contains_key(&key) {
+                    metrics::SYNC_DRIFT.inc();
+                    warn!("Service list drifted: {:?} is missing", key);
+                }
+                insert_service(scalable_service_list, &mut last_synced, key, value);
+                info!("Add service list: {:?} {:?}", key, value)
             }
         }
     }
 
     let keys: Vec<_> = scalable_service_list.keys().collect();
+    let mut entries = 0;
     for key in keys {
         match key {
             Ok(ip) => {
-                if !pod_ips.contains_key(&ip) {
+                if pod_ips.contains_key(&ip) {
+                    entries += 1;
+                } else if !kubernetes::controller::services_synced() {
+                    // entries of a previous agent stay gated until every service was reconciled
+                    entries += 1;
+                } else {
+                    if !last_synced.contains_key(&ip) {
+                        metrics::SYNC_DRIFT.inc();
+                        warn!("Service list drifted: {:?} is unknown", ip);
+                    }
                     let _ = scalable_service_list.remove(&ip);
+                    last_synced.remove(&ip);
                     info!("Remove service list: {:?}", ip)
                 }
             }
@@ -82,20 +258,596 @@ pub async fn sync_data(scalable_service_list: &mut HashMap<&mut MapData, u32, u3
             }
         }
     }
+
+    *SERVICE_LIST_SNAPSHOT.lock().unwrap() = scalable_service_list
+        .iter()
+        .filter_map(|entry| entry.ok())
+        .collect();
+
+    metrics::SERVICE_LIST_ENTRIES.set(entries);
+    metrics::SERVICE_LIST_CAPACITY.set(SERVICE_LIST_MAX_ENTRIES as i64);
+    metrics::SYNC_DURATION.observe(started.elapsed().as_secs_f64());
+    check_capacity(entries as usize, pod_ips.len());
+}
+
+// Entries found in a pinned SERVICE_LIST were written by the previous agent, they are taken over as
+// synced instead of being reported as drift
+pub fn adopt_service_list(scalable_service_list: &HashMap<MapData, u32, ServicePolicy>) {
+    let entries: std::collections::HashMap<u32, ServicePolicy> = scalable_service_list
+        .iter()
+        .filter_map(|entry| entry.ok())
+        .collect();
+    if !entries.is_empty() {
+        info!("Reusing {} pinned service list entries", entries.len());
+    }
+    *LAST_SYNCED.lock().unwrap() = entries;
+}
+
+// Keys of SERVICE_LIST as last written or read back, including the entries of a previous agent
+pub fn service_list_keys() -> HashSet<u32> {
+    let mut keys: HashSet<u32> = LAST_SYNCED.lock().unwrap().keys().copied().collect();
+    keys.extend(SERVICE_LIST_SNAPSHOT.lock().unwrap().keys().copied());
+    keys
+}
+
+// Policy of a service in SERVICE_LIST
+pub fn service_policy(service: &ServiceData) -> ServicePolicy {
+    let mut flags = 0;
+    // the gate of a monitored service never closes
+    if service.backend_available || service.monitor {
+        flags |= SERVICE_AVAILABLE;
+    }
+    if service.hold_connections {
+        flags |= SERVICE_HOLD;
+    }
+    if service.buffer_udp {
+        flags |= SERVICE_HOLD_UDP;
+    }
+    let gated_action = match service.gated_action {
+        GatedAction::Drop => GATED_DROP,
+        GatedAction::Pass => GATED_PASS,
+        GatedAction::Redirect { .. } => GATED_REDIRECT,
+    };
+    let mut ports = [PortRange::default(); SERVICE_POLICY_PORT_RANGES];
+    for (range, (start, end)) in ports.iter_mut().zip(service.activity_ports.iter()) {
+        *range = PortRange {
+            start: *start,
+            end: *end,
+        };
+    }
+    ServicePolicy {
+        flags,
+        protocols: service.activity_protocols,
+        gated_action,
+        port_ranges: service.activity_ports.len().min(SERVICE_POLICY_PORT_RANGES) as u32,
+        ports,
+        wake_threshold: WakeThreshold {
+            packets: service.wake_threshold.packets,
+            window_ms: service.wake_threshold.window.saturating_mul(1000),
+        },
+    }
+}
+
+fn insert_service(
+    scalable_service_list: &mut HashMap<MapData, u32, ServicePolicy>,
+    last_synced: &mut std::collections::HashMap<u32, ServicePolicy>,
+    key: u32,
+    value: ServicePolicy,
+) {
+    destination_filter::insert(key);
+    match scalable_service_list.insert(key, value, 0) {
+        Ok(_) => {
+            last_synced.insert(key, value);
+        }
+        Err(err) => {
+            metrics::SYNC_FAILED_INSERTS.inc();
+            error!("Failed to insert {:?} into service list: {}", key, err);
+        }
+    }
+}
+
+// Warn once when SERVICE_LIST gets close to its capacity, new services can't be gated once it is full
+fn check_capacity(entries: usize, watched: usize) {
+    let threshold = SERVICE_LIST_MAX_ENTRIES as usize * SERVICE_LIST_WARN_PERCENT / 100;
+    if watched > SERVICE_LIST_MAX_ENTRIES as usize {
+        error!(
+            "{} services are watched but SERVICE_LIST can only hold {}, {} services are not gated",
+            watched,
+            SERVICE_LIST_MAX_ENTRIES,
+            watched - entries
+        );
+    }
+    if entries >= threshold {
+        if !CAPACITY_WARNED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "SERVICE_LIST is {}% full ({} of {} entries)",
+                entries * 100 / SERVICE_LIST_MAX_ENTRIES as usize,
+                entries,
+                SERVICE_LIST_MAX_ENTRIES
+            );
+        }
+    } else {
+        CAPACITY_WARNED.store(false, Ordering::Relaxed);
+    }
+}
+
+// sync the kernel pod list with the backend pods of the watched services
+pub async fn sync_pod_list(mut pod_map: HashMap<MapData, u32, u32>) {
+    loop {
+        let pod_ips: std::collections::HashMap<u32, u32> = kubernetes::models::POD_TO_SERVICE
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(pod_ip, (_, service_ip))| {
+                let pod_ip: Ipv4Addr = pod_ip.parse().ok()?;
+                let service_ip: Ipv4Addr = service_ip.parse().ok()?;
+                Some((pod_ip.into(), service_ip.into()))
+            })
+            .collect();
+
+        for (pod_ip, service_ip) in pod_ips.iter() {
+            if pod_map.get(pod_ip, 0).ok() != Some(*service_ip) {
+                destination_filter::insert(*pod_ip);
+                if let Err(err) = pod_map.insert(pod_ip, service_ip, 0) {
+                    warn!(
+                        "Failed to insert {} into pod list: {}",
+                        Ipv4Addr::from(*pod_ip),
+                        err
+                    );
+                }
+            }
+        }
+
+        let keys: Vec<u32> = pod_map.keys().filter_map(|k| k.ok()).collect();
+        for pod_ip in keys {
+            if !pod_ips.contains_key(&pod_ip) {
+                let _ = pod_map.remove(&pod_ip);
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+// sync the kernel load balancer addresses with the ones announced for the watched services
+pub async fn sync_load_balancer_ips(mut load_balancer_map: HashMap<MapData, u32, u32>) {
+    loop {
+        let load_balancer_ips: std::collections::HashMap<u32, u32> =
+            kubernetes::models::WATCHED_SERVICES
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|(service_ip, service)| {
+                    let service_ip: Ipv4Addr = service_ip.parse().ok()?;
+                    Some((service_ip, service.load_balancer_ips.clone()))
+                })
+                .flat_map(|(service_ip, load_balancer_ips)| {
+                    load_balancer_ips
+                        .into_iter()
+                        .map(move |address| (address.into(), service_ip.into()))
+                })
+                .collect();
+
+        for (address, service_ip) in load_balancer_ips.iter() {
+            if load_balancer_map.get(address, 0).ok() != Some(*service_ip) {
+                destination_filter::insert(*address);
+                if let Err(err) = load_balancer_map.insert(address, service_ip, 0) {
+                    warn!(
+                        "Failed to insert {} into load balancer addresses: {}",
+                        Ipv4Addr::from(*address),
+                        err
+                    );
+                }
+            }
+        }
+
+        let keys: Vec<u32> = load_balancer_map.keys().filter_map(|k| k.ok()).collect();
+        for address in keys {
+            if !load_balancer_ips.contains_key(&address) {
+                let _ = load_balancer_map.remove(&address);
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+// sync the kernel IPv6 addresses with the ones of the watched dual-stack services
+pub async fn sync_ipv6_services(mut ipv6_map: HashMap<MapData, [u8; 16], u32>) {
+    loop {
+        let ipv6_services: std::collections::HashMap<[u8; 16], u32> =
+            kubernetes::models::WATCHED_SERVICES
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|(service_ip, service)| {
+                    let service_ip: Ipv4Addr = service_ip.parse().ok()?;
+                    Some((service_ip, service.ipv6_addresses.clone()))
+                })
+                .flat_map(|(service_ip, ipv6_addresses)| {
+                    ipv6_addresses
+                        .into_iter()
+                        .map(move |address| (address.octets(), service_ip.into()))
+                })
+                .collect();
+
+        for (address, service_ip) in ipv6_services.iter() {
+            if ipv6_map.get(address, 0).ok() != Some(*service_ip) {
+                if let Err(err) = ipv6_map.insert(address, service_ip, 0) {
+                    warn!(
+                        "Failed to insert {} into IPv6 service addresses: {}",
+                        Ipv6Addr::from(*address),
+                        err
+                    );
+                }
+            }
+        }
+
+        let keys: Vec<[u8; 16]> = ipv6_map.keys().filter_map(|k| k.ok()).collect();
+        for address in keys {
+            if !ipv6_services.contains_key(&address) {
+                let _ = ipv6_map.remove(&address);
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+// sync the kernel host ports with the node addresses and ports of the watched services' pods
+pub async fn sync_host_ports(mut host_port_map: HashMap<MapData, u64, u32>) {
+    loop {
+        let host_ports: std::collections::HashMap<u64, u32> = kubernetes::models::HOST_PORTS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|((node_ip, port, protocol), (_, service_ip))| {
+                let node_ip: Ipv4Addr = node_ip.parse().ok()?;
+                let service_ip: Ipv4Addr = service_ip.parse().ok()?;
+                Some((
+                    host_port_key(node_ip.into(), *port, *protocol),
+                    service_ip.into(),
+                ))
+            })
+            .collect();
+
+        for (key, service_ip) in host_ports.iter() {
+            if host_port_map.get(key, 0).ok() != Some(*service_ip) {
+                destination_filter::insert((key >> 32) as u32);
+                if let Err(err) = host_port_map.insert(key, service_ip, 0) {
+                    warn!(
+                        "Failed to insert {} into host ports: {}",
+                        Ipv4Addr::from((key >> 32) as u32),
+                        err
+                    );
+                }
+            }
+        }
+
+        let keys: Vec<u64> = host_port_map.keys().filter_map(|k| k.ok()).collect();
+        for key in keys {
+            if !host_ports.contains_key(&key) {
+                let _ = host_port_map.remove(&key);
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+// sync the kernel redirect addresses with the watched services whose gated connects are redirected
+pub async fn sync_gate_redirects(mut redirect_map: HashMap<MapData, u32, u64>) {
+    loop {
+        let redirects: std::collections::HashMap<u32, u64> = kubernetes::models::WATCHED_SERVICES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(service_ip, service)| {
+                let target = match service.gated_action {
+                    GatedAction::Redirect {
+                        address: Some(address),
+                        port,
+                    } => gate_redirect(address.into(), port.unwrap_or(0)),
+                    GatedAction::Redirect { address: None, .. } => {
+                        let (address, port) = waking_page::target()?;
+                        gate_redirect_http(address.into(), port, service.http_port?)
+                    }
+                    _ => return None,
+                };
+                let service_ip: Ipv4Addr = service_ip.parse().ok()?;
+                Some((service_ip, service.load_balancer_ips.clone(), target))
+            })
+            // the replies to clients of an announced load balancer address come back from it
+            .flat_map(|(service_ip, load_balancer_ips, target)| {
+                std::iter::once(service_ip)
+                    .chain(load_balancer_ips)
+                    .map(move |address| (address.into(), target))
+            })
+            .collect();
+
+        for (service_ip, target) in redirects.iter() {
+            if redirect_map.get(service_ip, 0).ok() == Some(*target) {
+                continue;
+            }
+            if let Err(err) = redirect_map.insert(service_ip, target, 0) {
+                warn!(
+                    "Failed to insert {} into gate redirects: {}",
+                    Ipv4Addr::from(*service_ip),
+                    err
+                );
+            }
+        }
+
+        let keys: Vec<u32> = redirect_map.keys().filter_map(|k| k.ok()).collect();
+        for service_ip in keys {
+            if !redirects.contains_key(&service_ip) {
+                let _ = redirect_map.remove(&service_ip);
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+// Export the per-CPU drop counters of the eBPF program as a counter per protocol
+pub async fn export_dropped_packets(dropped: PerCpuArray<MapData, u64>) {
+    let protocols = [
+        (DROPPED_TCP, "tcp"),
+        (DROPPED_UDP, "udp"),
+        (DROPPED_ICMP, "icmp"),
+        (DROPPED_OTHER, "other"),
+    ];
+    let mut exported = [0u64; 4];
+    loop {
+        for (i, (index, protocol)) in protocols.iter().enumerate() {
+            let total = match dropped.get(index, 0) {
+                Ok(values) => values.iter().sum::<u64>(),
+                Err(_) => continue,
+            };
+            metrics::DROPPED_PACKETS
+                .with_label_values(&[protocol])
+                .inc_by(total.saturating_sub(exported[i]));
+            exported[i] = total;
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    }
+}
+
+// Export the state of every watched service, series of services that are no longer watched are
+// removed
+pub async fn export_service_metrics() {
+    let interval = std::time::Duration::from_secs(5);
+    let mut exported: HashSet<(String, String)> = HashSet::new();
+    loop {
+        let now = chrono::Utc::now().timestamp();
+        let services: Vec<((String, String), bool, i64)> = kubernetes::models::WATCHED_SERVICES
+            .lock()
+            .unwrap()
+            .values()
+            .map(|service| {
+                let labels = (service.namespace.clone(), service.service_name.clone());
+                (labels, service.backend_available, service.last_packet_time)
+            })
+            .collect();
+
+        let mut watched = HashSet::new();
+        for ((namespace, service), backend_available, last_packet_time) in services {
+            let labels = [namespace.as_str(), service.as_str()];
+            metrics::SERVICE_AWAKE
+                .with_label_values(&labels)
+                .set(backend_available as i64);
+            metrics::SERVICE_IDLE_SECONDS
+                .with_label_values(&labels)
+                .set((now - last_packet_time).max(0));
+            // created at zero so the series exists before the first scale-down
+            let scaled_down = metrics::SERVICE_SCALED_DOWN_SECONDS.with_label_values(&labels);
+            if !backend_available {
+                scaled_down.inc_by(interval.as_secs_f64());
+            }
+            watched.insert((namespace, service));
+        }
+
+        for (namespace, service) in exported.difference(&watched) {
+            let labels = [namespace.as_str(), service.as_str()];
+            let _ = metrics::SERVICE_AWAKE.remove_label_values(&labels);
+            let _ = metrics::SERVICE_IDLE_SECONDS.remove_label_values(&labels);
+            let _ = metrics::SERVICE_SCALED_DOWN_SECONDS.remove_label_values(&labels);
+            let _ = metrics::SERVICE_WAKES.remove_label_values(&labels);
+            let _ = metrics::SERVICE_SCALE_DOWNS.remove_label_values(&labels);
+            let _ = metrics::MONITOR_SCALE_DOWNS.remove_label_values(&labels);
+            let _ = metrics::MONITOR_WAKES.remove_label_values(&labels);
+            let _ = metrics::SERVICE_IDLE_GAPS.remove_label_values(&labels);
+            let _ = metrics::EXCESSIVE_WAKES.remove_label_values(&labels);
+            let _ = metrics::SERVICE_PENT_UP_CLIENTS.remove_label_values(&labels);
+            let _ = metrics::SERVICE_DROPPED_WHILE_WAKING.remove_label_values(&labels);
+            metrics::forget_cold_starts(namespace, service);
+            for phase in wake_trace::PHASES {
+                let _ = metrics::WAKE_PHASE_SECONDS.remove_label_values(&[
+                    namespace,
+                    service,
+                    phase.label(),
+                ]);
+            }
+        }
+        exported = watched;
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// Nanoseconds since boot, same clock as bpf_ktime_get_ns in the eBPF program
+pub fn monotonic_now_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
 }
 
-pub fn load_ebpf_code() -> anyhow::Result<Bpf> {
+pub fn load_ebpf_code(opts: &config::Options) -> anyhow::Result<Bpf> {
+    let migration = migration::prepare(&opts.pin_path)?;
+    let mut loader = BpfLoader::new();
+    loader.map_pin_path(migration::maps_path(&opts.pin_path));
+    let object = ebpf_object(opts)?;
+    let mut bpf = loader
+        .load(&object)
+        .map_err(|err| diagnostics::load_failed("the eBPF object", err.into()))?;
+    check_compatibility(&bpf)?;
+    if let Some(migration) = migration {
+        migration.apply(&mut bpf)?;
+    }
+
+    // written last, maps without it are migrated again by the next agent
+    let mut schema: Array<&mut MapData, u32> = Array::try_from(bpf.map_mut("MAP_SCHEMA").unwrap())?;
+    schema.set(0, MAP_SCHEMA_VERSION, 0)?;
+    Ok(bpf)
+}
+
+// The eBPF object, embedded or read from --bpf-object, once it is verified and its ABI checked
+pub fn ebpf_object(opts: &config::Options) -> anyhow::Result<Cow<'static, [u8]>> {
+    let object = match opts.bpf_object.as_ref() {
+        Some(path) => Cow::Owned(read_ebpf_file(path)?),
+        None => Cow::Borrowed(embedded_ebpf_object()),
+    };
+    verify_object(&object, opts)?;
+    check_abi(&object)?;
+    Ok(object)
+}
+
+fn embedded_ebpf_object() -> &'static [u8] {
     // This will include your eBPF object file as raw bytes at compile-time and load it at
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
     // reach for `Bpf::load_file` instead.
     #[cfg(debug_assertions)]
-    let bpf: Bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/debug/scale-to-zero"
-    ))?;
+    let object: &'static [u8] =
+        include_bytes_aligned!("../../target/bpfel-unknown-none/debug/scale-to-zero");
     #[cfg(not(debug_assertions))]
-    let bpf = Bpf::load(include_bytes_aligned!(
-        "../../target/bpfel-unknown-none/release/scale-to-zero"
-    ))?;
-    return Ok(bpf);
+    let object: &'static [u8] =
+        include_bytes_aligned!("../../target/bpfel-unknown-none/release/scale-to-zero");
+    object
+}
+
+// Unlike `Bpf::load_file`, the checksum and signature are verified on the exact bytes that get
+// loaded
+fn read_ebpf_file(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let object = std::fs::read(path)
+        .with_context(|| format!("Failed to read eBPF object {}", path.display()))?;
+    info!("Loading eBPF object from {}", path.display());
+    Ok(object)
+}
+
+// The agent never falls back to the userspace datapath on these, an object that can't be verified
+// is not what was shipped
+#[derive(Debug, thiserror::Error)]
+pub enum VerificationError {
+    #[error("Checksum mismatch for the eBPF object: expected {expected}, got {got}")]
+    Checksum { expected: String, got: String },
+    #[error("Failed to read {path}: {reason}")]
+    Unreadable { path: String, reason: String },
+    #[error("The signature of the eBPF object doesn't verify against {public_key}: {reason}")]
+    Signature { public_key: String, reason: String },
+}
+
+// Verify the object, embedded or read from --bpf-object, against the expected sha256 and the
+// detached ed25519 signature, whichever are given
+fn verify_object(object: &[u8], opts: &config::Options) -> Result<(), VerificationError> {
+    if let Some(expected) = opts.bpf_object_sha256.as_deref() {
+        let checksum = Sha256::digest(object)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        if !checksum.eq_ignore_ascii_case(expected.trim()) {
+            return Err(VerificationError::Checksum {
+                expected: expected.to_string(),
+                got: checksum,
+            });
+        }
+        info!("Verified the sha256 of the eBPF object");
+    }
+
+    if let (Some(signature), Some(public_key)) = (
+        opts.bpf_object_signature.as_ref(),
+        opts.bpf_object_public_key.as_ref(),
+    ) {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|err| VerificationError::Unreadable {
+                path: path.display().to_string(),
+                reason: err.to_string(),
+            })
+        };
+        let invalid = |reason: String| VerificationError::Signature {
+            public_key: public_key.display().to_string(),
+            reason,
+        };
+        let pem = String::from_utf8(read(public_key)?).map_err(|err| invalid(err.to_string()))?;
+        let key =
+            VerifyingKey::from_public_key_pem(&pem).map_err(|err| invalid(err.to_string()))?;
+        let signature =
+            Signature::from_slice(&read(signature)?).map_err(|err| invalid(err.to_string()))?;
+        key.verify_strict(object, &signature)
+            .map_err(|err| invalid(err.to_string()))?;
+        info!(
+            "Verified the signature of the eBPF object against {}",
+            public_key.display()
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AbiError {
+    #[error("eBPF object has no SCALE_TO_ZERO_ABI symbol, it was built for an older agent")]
+    Missing,
+    #[error("eBPF object ABI {object:?} doesn't match the ABI of the agent {agent:?} (version, then the sizes of PacketLog, CaptureHeader, WakeThreshold, WakeAttempts and ServicePolicy), rebuild the eBPF program with this agent")]
+    Mismatch { object: Vec<u32>, agent: Vec<u32> },
+}
+
+// Compare the ABI compiled into the eBPF object with the one of the agent before loading it, a
+// stale object would otherwise be loaded fine and its events read with the wrong layout
+fn check_abi(object: &[u8]) -> anyhow::Result<()> {
+    let file = object::File::parse(object).context("Failed to parse the eBPF object")?;
+    let symbol = file
+        .symbols()
+        .find(|symbol| symbol.name().ok() == Some(ABI_SYMBOL))
+        .ok_or(AbiError::Missing)?;
+    let section = match symbol.section_index() {
+        Some(index) => file.section_by_index(index)?,
+        None => return Err(AbiError::Missing.into()),
+    };
+    // the value of a symbol of a relocatable object is its offset in the section
+    let start = symbol.address() as usize;
+    let end = start + symbol.size() as usize;
+    let data = section.data()?.get(start..end).unwrap_or_default();
+    let object_abi: Vec<u32> = data
+        .chunks_exact(4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+
+    if object_abi != abi() {
+        return Err(AbiError::Mismatch {
+            object: object_abi,
+            agent: abi().to_vec(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+// Make sure the eBPF object provides everything the agent uses, an object built from another
+// version of the eBPF program would otherwise fail much later with an unwrap on a missing map
+fn check_compatibility(bpf: &Bpf) -> anyhow::Result<()> {
+    let missing_maps: Vec<&str> = REQUIRED_MAPS
+        .iter()
+        .copied()
+        .filter(|name| bpf.map(name).is_none())
+        .collect();
+    if !missing_maps.is_empty() {
+        anyhow::bail!(
+            "eBPF object is not compatible with this agent, missing maps: {}",
+            missing_maps.join(", ")
+        );
+    }
+    if bpf.program(PROGRAM_NAME).is_none() {
+        anyhow::bail!(
+            "eBPF object is not compatible with this agent, missing program: {}",
+            PROGRAM_NAME
+        );
+    }
+    Ok(())
 }
@@ -0,0 +1,275 @@
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use k8s_openapi::api::core::v1::Service;
+use kube::api::{Api, ListParams, PostParams};
+use kube::{Client, ResourceExt};
+
+use crate::auth;
+use crate::config::Options;
+use crate::diagnostics;
+use crate::kubernetes;
+use crate::kubernetes::controller;
+use crate::kubernetes::kruise;
+use crate::kubernetes::models::{
+    annotation, annotation_key, BUFFER_UDP_ANNOTATION, LATENCY_CRITICAL_ANNOTATION,
+};
+use crate::policy;
+use crate::standalone;
+use crate::utils;
+
+// Permissions the agent uses in each watched namespace, as (group, resource, verbs). Optional
+// features (hooks, checkpoints, mesh activity...) fail on their own without them, they are reported
+// but don't fail the validation
+const REQUIRED_PERMISSIONS: [(&str, &str, &[&str]); 5] = [
+    ("", "services", &["get", "list", "watch", "patch"]),
+    ("apps", "deployments", &["get", "list", "watch", "patch"]),
+    ("apps", "statefulsets", &["get", "list", "watch", "patch"]),
+    (
+        "coordination.k8s.io",
+        "leases",
+        &["get", "create", "update"],
+    ),
+    ("events.k8s.io", "events", &["create"]),
+];
+const OPTIONAL_PERMISSIONS: [(&str, &str, &[&str]); 13] = [
+    ("", "pods", &["get", "list", "create", "delete"]),
+    ("", "pods/log", &["get"]),
+    ("", "pods/proxy", &["get"]),
+    ("", "nodes/proxy", &["create"]),
+    ("", "configmaps", &["get"]),
+    ("", "resourcequotas", &["list"]),
+    ("apps", "deployments/scale", &["get", "patch"]),
+    ("apps", "statefulsets/scale", &["get", "patch"]),
+    ("discovery.k8s.io", "endpointslices", &["list", "watch"]),
+    ("autoscaling", "horizontalpodautoscalers", &["get", "patch"]),
+    ("batch", "jobs", &["get", "list", "create"]),
+    ("batch", "cronjobs", &["get", "patch"]),
+    ("coordination.k8s.io", "leases", &["list", "patch"]),
+];
+
+// Outcome of the checks, printed as they run
+#[derive(Default)]
+struct Report {
+    errors: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&self, check: &str, detail: impl AsRef<str>) {
+        println!("ok    {}: {}", check, detail.as_ref());
+    }
+
+    fn warn(&mut self, check: &str, detail: impl AsRef<str>) {
+        self.warnings += 1;
+        println!("warn  {}: {}", check, detail.as_ref());
+    }
+
+    fn error(&mut self, check: &str, detail: impl AsRef<str>) {
+        self.errors += 1;
+        println!("error {}: {}", check, detail.as_ref());
+    }
+
+    fn result(&mut self, check: &str, result: anyhow::Result<String>) {
+        match result {
+            Ok(detail) => self.ok(check, detail),
+            Err(err) => self.error(check, format!("{:#}", err)),
+        }
+    }
+}
+
+// Check the configuration, the annotations of the services in the watched namespaces, the RBAC
+// permissions of the agent and what the kernel offers, without attaching or patching anything.
+// Fails when any check errors, so it can gate a CI pipeline
+pub async fn run(opts: &Options) -> anyhow::Result<()> {
+    let mut report = Report::default();
+    check_config(opts, &mut report);
+    check_kernel(opts, &mut report);
+    if opts.standalone.is_none() {
+        match kubernetes::client().await {
+            Ok(client) => {
+                for namespace in kubernetes::namespaces(&client) {
+                    check_permissions(&client, &namespace, &mut report).await;
+                    check_services(&client, &namespace, opts, &mut report).await;
+                }
+            }
+            Err(err) => report.error("cluster", format!("Failed to build a client: {:#}", err)),
+        }
+    }
+
+    println!("{} errors, {} warnings", report.errors, report.warnings);
+    if report.errors > 0 {
+        anyhow::bail!("Validation failed with {} errors", report.errors);
+    }
+    Ok(())
+}
+
+fn check_config(opts: &Options, report: &mut Report) {
+    if !opts.userspace_datapath && opts.simulate.is_none() {
+        report.result(
+            "ebpf-object",
+            utils::ebpf_object(opts).map(|object| format!("{} bytes, verified", object.len())),
+        );
+    }
+    if let Some(path) = opts.standalone.as_ref() {
+        report.result(
+            "standalone",
+            standalone::check(path).map(|targets| format!("{} targets", targets)),
+        );
+    }
+    if opts.policy_plugin.is_some() {
+        report.result(
+            "policy-plugin",
+            policy::init(opts).map(|_| "loaded".to_string()),
+        );
+    }
+    if opts.admin_tls_cert.is_some() {
+        report.result(
+            "admin-tls",
+            auth::tls_config(opts).map(|_| "certificate and key loaded".to_string()),
+        );
+    }
+}
+
+fn check_kernel(opts: &Options, report: &mut Report) {
+    if opts.userspace_datapath || opts.simulate.is_some() {
+        return;
+    }
+    let kernel = diagnostics::kernel_release();
+    let missing = diagnostics::missing_features();
+    if missing.is_empty() {
+        report.ok("kernel", kernel);
+    } else {
+        // the agent falls back to the userspace datapath, it still runs
+        report.warn(
+            "kernel",
+            format!("{} is missing {}", kernel, missing.join(", ")),
+        );
+    }
+}
+
+async fn check_permissions(client: &Client, namespace: &str, report: &mut Report) {
+    let reviews: Api<SelfSubjectAccessReview> = Api::all(client.clone());
+    for (permissions, required) in [
+        (&REQUIRED_PERMISSIONS[..], true),
+        (&OPTIONAL_PERMISSIONS[..], false),
+    ] {
+        for (group, resource, verbs) in permissions {
+            let mut denied = Vec::new();
+            for verb in verbs.iter() {
+                let (resource, subresource) = match resource.split_once('/') {
+                    Some((resource, subresource)) => (resource, Some(subresource.to_string())),
+                    None => (*resource, None),
+                };
+                let review = SelfSubjectAccessReview {
+                    spec: SelfSubjectAccessReviewSpec {
+                        resource_attributes: Some(ResourceAttributes {
+                            group: Some(group.to_string()),
+                            resource: Some(resource.to_string()),
+                            subresource,
+                            verb: Some(verb.to_string()),
+                            namespace: Some(namespace.to_string()),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                match reviews.create(&PostParams::default(), &review).await {
+                    Ok(review) if review.status.as_ref().is_some_and(|status| status.allowed) => {}
+                    Ok(_) => denied.push(verb.to_string()),
+                    Err(err) => {
+                        report.error(
+                            "rbac",
+                            format!("Failed to review the permissions in {}: {}", namespace, err),
+                        );
+                        return;
+                    }
+                }
+            }
+            let check = format!("rbac {}/{}", namespace, resource);
+            if denied.is_empty() {
+                report.ok(&check, verbs.join(", "));
+            } else if required {
+                report.error(&check, format!("missing {}", denied.join(", ")));
+            } else {
+                report.warn(
+                    &check,
+                    format!("missing {}, the features using it fail", denied.join(", ")),
+                );
+            }
+        }
+    }
+}
+
+// Parse the annotations of every annotated service the way the controller does, and check their
+// workload exists
+async fn check_services(client: &Client, namespace: &str, opts: &Options, report: &mut Report) {
+    let services: Api<Service> = Api::namespaced(client.clone(), namespace);
+    let services = match services.list(&ListParams::default()).await {
+        Ok(services) => services,
+        Err(err) => {
+            report.error(
+                &format!("namespace {}", namespace),
+                format!("Failed to list services: {}", err),
+            );
+            return;
+        }
+    };
+    let annotated: Vec<&Service> = services
+        .iter()
+        .filter(|s| controller::is_annotated(s))
+        .collect();
+    report.ok(
+        &format!("namespace {}", namespace),
+        format!("{} annotated services", annotated.len()),
+    );
+    for s in annotated {
+        let check = format!("service {}/{}", namespace, s.name_any());
+        report.result(&check, check_service(client, namespace, s).await);
+        // only packets received on the hold interface are held, there is none to receive them on
+        if opts.hold_interface.is_none() {
+            for name in [LATENCY_CRITICAL_ANNOTATION, BUFFER_UDP_ANNOTATION] {
+                if annotation(s.annotations(), name).map(String::as_str) == Some("true") {
+                    report.warn(
+                        &check,
+                        format!(
+                            "{} needs --hold-interface, the gated packets are dropped",
+                            annotation_key(name)
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+async fn check_service(client: &Client, namespace: &str, s: &Service) -> anyhow::Result<String> {
+    let workload = controller::workload_reference(s)
+        .ok_or_else(|| anyhow::anyhow!("Invalid reference annotation"))?;
+    let scale_down_time = controller::scale_down_time(s)?;
+    let service = controller::service_data(s, workload, scale_down_time, false)?;
+    let exists = match service.kind.as_str() {
+        "deployment" => {
+            let deployments: Api<Deployment> = Api::namespaced(client.clone(), namespace);
+            deployments.get_opt(&service.name).await?.is_some()
+        }
+        "statefulset" => {
+            let statefulsets: Api<StatefulSet> = Api::namespaced(client.clone(), namespace);
+            statefulsets.get_opt(&service.name).await?.is_some()
+        }
+        kind if kruise::is_kruise(kind) => {
+            let api = kruise::api(client.clone(), namespace, kind).await?;
+            api.get_opt(&service.name).await?.is_some()
+        }
+        kind => anyhow::bail!("Unknown workload type: {}", kind),
+    };
+    if !exists {
+        anyhow::bail!("{} {} doesn't exist", service.kind, service.name);
+    }
+    Ok(format!(
+        "{} {}, scale-down-time {}s",
+        service.kind, service.name, service.scale_down_time
+    ))
+}
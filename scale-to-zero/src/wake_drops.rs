@@ -0,0 +1,35 @@
+use aya::maps::{MapData, PerCpuHashMap};
+use log::warn;
+use once_cell::sync::Lazy;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+// Packets dropped per service while it was waking, counted by the datapath on every CPU
+static WAKE_DROPS: Lazy<Mutex<Option<PerCpuHashMap<MapData, u32, u64>>>> =
+    Lazy::new(|| Mutex::new(None));
+
+pub fn init(map: PerCpuHashMap<MapData, u32, u64>) {
+    *WAKE_DROPS.lock().unwrap() = Some(map);
+}
+
+// Packets of a service dropped on this node since it asked for a wake. The entry is removed so the
+// next wake starts from zero
+pub fn take(service_ip: &str) -> u64 {
+    let service = match service_ip.parse::<Ipv4Addr>() {
+        Ok(address) => u32::from(address),
+        Err(_) => return 0,
+    };
+    let mut wake_drops = WAKE_DROPS.lock().unwrap();
+    let map = match wake_drops.as_mut() {
+        Some(map) => map,
+        None => return 0,
+    };
+    let dropped = match map.get(&service, 0) {
+        Ok(values) => values.iter().sum::<u64>(),
+        Err(_) => return 0,
+    };
+    if let Err(err) = map.remove(&service) {
+        warn!(target: "wake_drops", "Failed to reset the wake drops of {}: {}", service_ip, err);
+    }
+    dropped
+}
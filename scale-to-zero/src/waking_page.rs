@@ -0,0 +1,112 @@
+use anyhow::Context;
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::any,
+    Json, Router,
+};
+use k8s_openapi::serde_json::json;
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::TcpListener;
+
+use crate::config;
+use crate::metrics;
+
+// Page served when --waking-page is not set, {host} and {retry_after} are replaced
+const DEFAULT_PAGE: &str = include_str!("waking.html");
+
+static PAGE: Lazy<String> = Lazy::new(|| {
+    let path = match config::get().waking_page.as_ref() {
+        Some(path) => path,
+        None => return DEFAULT_PAGE.to_string(),
+    };
+    match std::fs::read_to_string(path) {
+        Ok(page) => page,
+        Err(err) => {
+            warn!(target: "waking_page", "Failed to read {}, serving the default page: {}", path.display(), err);
+            DEFAULT_PAGE.to_string()
+        }
+    }
+});
+
+// Address and port the redirects of gated-action redirect without an address go to. The page has
+// to be reached through an address of the node, --node-ip when it listens on all of them
+pub fn target() -> Option<(Ipv4Addr, u16)> {
+    let opts = config::get();
+    let addr = match opts.waking_page_addr {
+        Some(SocketAddr::V4(addr)) => addr,
+        _ => return None,
+    };
+    if !addr.ip().is_unspecified() {
+        return Some((*addr.ip(), addr.port()));
+    }
+    opts.node_ip.map(|node_ip| (node_ip, addr.port()))
+}
+
+// Bound at the start, an address that can't be bound stops the agent rather than leaving the
+// redirected clients with a refused connection
+pub async fn bind(addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to bind the waking page to {}", addr))?;
+    info!(target: "waking_page", "Waking page listening on {}", addr);
+    Ok(listener)
+}
+
+// Answer every request with the waking page, whatever the path and method, so redirected clients
+// are told to retry instead of seeing a refused connection
+pub async fn serve(listener: TcpListener) -> anyhow::Result<()> {
+    Lazy::force(&PAGE);
+    let app = Router::new()
+        .route("/", any(waking))
+        .route("/*path", any(waking));
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+// 503 with Retry-After, as JSON for API clients asking for it
+async fn waking(headers: HeaderMap) -> Response {
+    metrics::WAKING_PAGE_REQUESTS.inc();
+    let retry_after = config::get().waking_page_retry_after;
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(|host| host.split(':').next().unwrap_or(host))
+        .unwrap_or("The service");
+    let json = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    let headers = [
+        (header::RETRY_AFTER, retry_after.to_string()),
+        (header::CACHE_CONTROL, "no-store".to_string()),
+    ];
+    if json {
+        let body = json!({
+            "status": "waking",
+            "host": host,
+            "retry_after": retry_after,
+        });
+        return (StatusCode::SERVICE_UNAVAILABLE, headers, Json(body)).into_response();
+    }
+    let page = PAGE
+        .replace("{host}", &escape(host))
+        .replace("{retry_after}", &retry_after.to_string());
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        headers,
+        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+        page,
+    )
+        .into_response()
+}
+
+// The Host header is the client's, it goes in the page escaped
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}